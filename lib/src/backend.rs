@@ -0,0 +1,784 @@
+//! This file contains the hypervisor backends VMs can run on, everything that
+//! depends on which hypervisor runs a VM (the command that boots it, how it's
+//! found, stopped and attached to) goes through the `Backend` trait.
+//!
+//! - `qemu` -> the default, everything autovirt does works with it
+//! - `cloud-hypervisor` -> boots the image with the rust-hypervisor-firmware
+//!   (`hypervisors.cloud-hypervisor.firmware` in autovirt.json)
+//! - `firecracker` -> boots a kernel (`hypervisors.firecracker.kernel`) with
+//!   the image as a raw root disk, meant for short lived CI VMs
+//!
+//! The backend is picked per VM (`create --backend`, kept as `backend` in the
+//! VM's entry). The microVM backends don't have user networking (port
+//! forwards), shares, data disks, hotplug or QMP, they get their cloud-init
+//! data from a seed image and their network from a tap device if
+//! `hypervisors.<backend>.tap` is set.
+//!
+//! The binaries can be overridden with `AUTOVIRT_QEMU_BIN`,
+//! `AUTOVIRT_CLOUD_HYPERVISOR_BIN` and `AUTOVIRT_FIRECRACKER_BIN` (i.e. -> to
+//! run a fake hypervisor for testing).
+//!
+//! ---
+
+use serde_json::{json, Value};
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::console;
+use crate::disk;
+use crate::filesystem;
+use crate::guestagent;
+use crate::qemucmd::{self, QemuInvocation};
+use crate::qmp;
+
+/// The backends a VM can run on.
+pub const BACKENDS: [&str; 3] = ["qemu", "cloud-hypervisor", "firecracker"];
+
+/// Everything a backend needs to know to boot a VM.
+#[derive(Debug, Clone)]
+pub struct VmSpec {
+    pub name: String,
+    pub memory_mb: String,
+    pub cpus: String,
+    pub image_path: String,
+    pub disk_format: String,
+    /// The user networking port forwards (qemu only)
+    pub port_forwards: String,
+    /// The image with the cloud-init data (the imds server is used if there's
+    /// none)
+    pub seed_image: Option<PathBuf>,
+    /// Run in the background
+    pub detach: bool,
+}
+
+impl VmSpec {
+    /// Gets the spec of a VM from its entry in the autovirt config file.
+    pub fn from_config(vm_name: &str, port_forwards: &str, detach: bool) -> Result<VmSpec, Box<dyn Error>> {
+        let setting = |key: &str| {
+            filesystem::get_value_from_autovirt_json(&format!("vms.{}.{}", vm_name, key))
+                .and_then(|v| v.as_str().map(String::from))
+        };
+
+        // the seed image is only used if the VM gets its data from one
+        let seed_image = filesystem::vm_data_dir_path(vm_name)
+            .map(|dir| dir.join("seed.iso"))
+            .ok()
+            .filter(|path| setting("datasource").as_deref() == Some("seed") && path.is_file());
+
+        Ok(VmSpec {
+            name: vm_name.to_string(),
+            memory_mb: setting("memory_mb").unwrap_or_else(|| "512".to_string()),
+            cpus: setting("cpus").unwrap_or_else(|| "1".to_string()),
+            image_path: setting("image_path").unwrap_or_default(),
+            disk_format: disk::vm_disk_format(vm_name)?,
+            port_forwards: port_forwards.to_string(),
+            seed_image,
+            detach,
+        })
+    }
+}
+
+/// A hypervisor that can run VMs.
+pub trait Backend {
+    fn name(&self) -> &'static str;
+
+    /// The binary that's run if its env var isn't set.
+    fn default_binary(&self) -> &'static str;
+
+    /// The env var that overrides the binary.
+    fn binary_env(&self) -> &'static str;
+
+    /// The disk format VM images have to be in (any format if `None`).
+    fn disk_format(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// The hypervisor binary to run.
+    fn binary(&self) -> String {
+        std::env::var(self.binary_env())
+            .ok()
+            .filter(|b| !b.is_empty())
+            .unwrap_or_else(|| self.default_binary().to_string())
+    }
+
+    /// Builds the command that boots a VM.
+    fn command(&self, spec: &VmSpec) -> Result<Command, Box<dyn Error>>;
+
+    /// Runs the command from `command`, in the foreground with the console
+    /// attached or in the background (`spec.detach`). Gives back the exit
+    /// status or `None` if the user detached from the console.
+    ///
+    /// ---
+    fn start(&self, spec: &VmSpec, command: &mut Command) -> Result<Option<ExitStatus>, Box<dyn Error>>;
+
+    /// The file with the pid of a VM's hypervisor process.
+    fn pid_file(&self, vm_name: &str) -> PathBuf {
+        vm_file(vm_name, &format!("{}.pid", self.name()))
+    }
+
+    /// Something that's in the args of the VM's process (so a stale pid file
+    /// with a reused pid isn't mistaken for the VM).
+    ///
+    /// ---
+    fn cmdline_marker(&self, vm_name: &str) -> String {
+        self.pid_file(vm_name).to_string_lossy().to_string()
+    }
+
+    /// Gets the pid of a VM's hypervisor process if the VM is running.
+    fn pid(&self, vm_name: &str) -> Option<u32> {
+        let pid: u32 = fs::read_to_string(self.pid_file(vm_name)).ok()?.trim().parse().ok()?;
+        let cmdline = fs::read(format!("/proc/{}/cmdline", pid)).ok()?;
+        String::from_utf8_lossy(&cmdline).contains(&self.cmdline_marker(vm_name)).then_some(pid)
+    }
+
+    /// Asks a VM to shut down.
+    fn shutdown(&self, vm_name: &str) -> Result<(), Box<dyn Error>>;
+
+    /// Stops a VM that didn't shut down.
+    fn kill(&self, _vm_name: &str, pid: u32) -> Result<(), Box<dyn Error>> {
+        Command::new("kill").arg("-KILL").arg(pid.to_string()).status()?;
+        Ok(())
+    }
+
+    /// Attaches the terminal to a running VM's console, gives back if the
+    /// user detached.
+    ///
+    /// ---
+    fn console(&self, vm_name: &str) -> Result<bool, Box<dyn Error>> {
+        console::attach_console(vm_name)
+    }
+}
+
+/// A file in a VM's data directory (without making the directory, the pid
+/// file of any VM name can be looked for).
+///
+/// ---
+fn vm_file(vm_name: &str, name: &str) -> PathBuf {
+    filesystem::get_autovirt_data_dir()
+        .unwrap_or_default()
+        .join("_data/vms")
+        .join(vm_name)
+        .join(name)
+}
+
+/// Gets a `hypervisors.<backend>.<key>` setting from autovirt.json.
+fn hypervisor_setting(backend: &str, key: &str) -> Option<String> {
+    filesystem::get_value_from_autovirt_json(&format!("hypervisors.{}.{}", backend, key))
+        .and_then(|v| v.as_str().map(String::from))
+        .filter(|v| !v.is_empty())
+}
+
+fn required_setting(backend: &str, key: &str, what: &str) -> Result<String, Box<dyn Error>> {
+    let path = hypervisor_setting(backend, key).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("The {} backend needs {} (set hypervisors.{}.{} in autovirt.json)", backend, what, backend, key),
+        )
+    })?;
+    if !PathBuf::from(&path).is_file() {
+        return Err(Box::new(io::Error::new(io::ErrorKind::NotFound, format!("{} not found -> {}", what, path))));
+    }
+    Ok(path)
+}
+
+/// Gets a backend by name.
+pub fn backend(name: &str) -> Result<Box<dyn Backend>, Box<dyn Error>> {
+    match name {
+        "qemu" => Ok(Box::new(Qemu)),
+        "cloud-hypervisor" => Ok(Box::new(CloudHypervisor)),
+        "firecracker" => Ok(Box::new(Firecracker)),
+        other => Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unknown backend '{}' (one of -> {})", other, BACKENDS.join(", ")),
+        ))),
+    }
+}
+
+/// The name of the backend a VM runs on (qemu for VMs from before there
+/// were backends).
+///
+/// ---
+pub fn vm_backend_name(vm_name: &str) -> String {
+    filesystem::get_value_from_autovirt_json(&format!("vms.{}.backend", vm_name))
+        .and_then(|v| v.as_str().map(String::from))
+        .unwrap_or_else(|| "qemu".to_string())
+}
+
+/// Gets the backend a VM runs on.
+pub fn for_vm(vm_name: &str) -> Box<dyn Backend> {
+    backend(&vm_backend_name(vm_name)).unwrap_or_else(|_| Box::new(Qemu))
+}
+
+/// Stops a VM, asking it to shut down and waiting for it to go away. If it's
+/// still running after the timeout it's killed if `force` is set or an error
+/// is returned if it isn't.
+///
+/// ---
+pub fn stop(backend: &dyn Backend, vm_name: &str, force: bool, timeout: Duration) -> Result<(), Box<dyn Error>> {
+    let pid = match backend.pid(vm_name) {
+        Some(pid) => pid,
+        None => {
+            println!("INFO:: VM is not running -> {}", vm_name);
+            return Ok(());
+        }
+    };
+
+    println!("LOG:: Shutting down VM -> {}", vm_name);
+    let shutdown = backend.shutdown(vm_name);
+    if let Err(e) = &shutdown {
+        eprintln!("ERROR: Failed to ask VM {} to shut down -> {}", vm_name, e);
+    }
+
+    if shutdown.is_ok() {
+        let started = Instant::now();
+        while started.elapsed() < timeout {
+            if backend.pid(vm_name).is_none() {
+                println!("LOG:: VM stopped -> {}", vm_name);
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(500));
+        }
+    }
+
+    if !force {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("VM '{}' didn't shut down (use --force to kill it)", vm_name),
+        )));
+    }
+
+    println!("LOG:: Killing VM -> {}", vm_name);
+    backend.kill(vm_name, pid)?;
+
+    let started = Instant::now();
+    while backend.pid(vm_name).is_some() && started.elapsed() < Duration::from_secs(10) {
+        thread::sleep(Duration::from_millis(200));
+    }
+    println!("LOG:: VM stopped -> {}", vm_name);
+    Ok(())
+}
+
+/// QEMU (`qemu-system-x86_64`), with user networking, QMP, the guest agent,
+/// shares and everything else (the command is built in qemucmd.rs).
+///
+/// ---
+pub struct Qemu;
+
+impl Backend for Qemu {
+    fn name(&self) -> &'static str {
+        "qemu"
+    }
+
+    fn default_binary(&self) -> &'static str {
+        "qemu-system-x86_64"
+    }
+
+    fn binary_env(&self) -> &'static str {
+        "AUTOVIRT_QEMU_BIN"
+    }
+
+    fn command(&self, spec: &VmSpec) -> Result<Command, Box<dyn Error>> {
+        Ok(QemuInvocation::from_spec(spec, self.binary())?.command())
+    }
+
+    fn start(&self, spec: &VmSpec, command: &mut Command) -> Result<Option<ExitStatus>, Box<dyn Error>> {
+        qemucmd::prepare(&spec.name)?;
+        if !spec.detach {
+            return console::run_attached(&spec.name, command);
+        }
+        // qemu daemonizes itself once the VM is up
+        let status = command.status()?;
+        if status.success() {
+            if let Err(e) = console::spawn_serial_logger(&spec.name) {
+                eprintln!("ERROR: Failed to start the console logger -> {}", e);
+            }
+        }
+        Ok(Some(status))
+    }
+
+    fn pid_file(&self, vm_name: &str) -> PathBuf {
+        // qemu writes it itself (see run::runtime_args)
+        vm_file(vm_name, "qemu.pid")
+    }
+
+    fn shutdown(&self, vm_name: &str) -> Result<(), Box<dyn Error>> {
+        // the guest agent shuts the VM down cleanly even if it ignores ACPI
+        guestagent::shutdown(vm_name).or_else(|_| {
+            qmp::connect_vm(vm_name).and_then(|mut client| client.execute("system_powerdown", None)).map(|_| ())
+        })
+    }
+
+    fn kill(&self, vm_name: &str, pid: u32) -> Result<(), Box<dyn Error>> {
+        let quit = qmp::connect_vm(vm_name).and_then(|mut client| client.execute("quit", None));
+        if quit.is_err() {
+            Command::new("kill").arg("-KILL").arg(pid.to_string()).status()?;
+        }
+        Ok(())
+    }
+}
+
+/// Sends a `PUT` to a hypervisor's http api on a unix socket (the api of
+/// cloud-hypervisor and Firecracker).
+///
+/// ---
+fn api_put(socket_path: &PathBuf, path: &str, body: &str) -> Result<(), Box<dyn Error>> {
+    let mut stream = UnixStream::connect(socket_path)
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to connect to {:?} -> {}", socket_path, e)))?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    write!(
+        stream,
+        "PUT {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        body.len(),
+        body
+    )?;
+
+    let mut response = String::new();
+    let _ = stream.read_to_string(&mut response);
+    let status_line = response.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(Box::new(io::Error::other(format!("PUT {} failed -> {}", path, status_line)))),
+    }
+}
+
+/// Runs a microVM hypervisor, in the background (its output going to
+/// `output_log`) or in the foreground. The pid file is written here since
+/// they don't write one themselves.
+///
+/// ---
+fn spawn_microvm(
+    backend: &dyn Backend,
+    spec: &VmSpec,
+    command: &mut Command,
+    output_log: PathBuf,
+    attach_serial: bool,
+) -> Result<Option<ExitStatus>, Box<dyn Error>> {
+    if spec.detach {
+        let log = OpenOptions::new().create(true).append(true).open(&output_log)?;
+        command.stdin(Stdio::null()).stdout(log.try_clone()?).stderr(log);
+    } else if attach_serial {
+        // the console is attached to the serial socket instead
+        let log = File::create(&output_log)?;
+        command.stdin(Stdio::null()).stdout(log.try_clone()?).stderr(log);
+    }
+
+    let mut child = command.process_group(0).spawn()?;
+    fs::write(backend.pid_file(&spec.name), child.id().to_string())?;
+
+    if !spec.detach {
+        if attach_serial {
+            return console::attach_child(&spec.name, &mut child);
+        }
+        return Ok(Some(child.wait()?));
+    }
+
+    // it may fail on bad settings right away
+    thread::sleep(Duration::from_millis(500));
+    if let Some(status) = child.try_wait()? {
+        eprintln!("ERROR: {} exited right away (see {:?})", backend.name(), output_log);
+        return Ok(Some(status));
+    }
+    if attach_serial {
+        if let Err(e) = console::spawn_serial_logger(&spec.name) {
+            eprintln!("ERROR: Failed to start the console logger -> {}", e);
+        }
+    }
+    Ok(Some(ExitStatus::from_raw(0)))
+}
+
+fn warn_port_forwards(backend: &str, spec: &VmSpec) {
+    if !spec.port_forwards.is_empty() {
+        println!("WARNING:: Port forwards only work with the qemu backend, {} VMs are reached over their tap device", backend);
+    }
+}
+
+/// cloud-hypervisor, booting the image with the rust-hypervisor-firmware.
+pub struct CloudHypervisor;
+
+impl CloudHypervisor {
+    fn api_socket(&self, vm_name: &str) -> PathBuf {
+        vm_file(vm_name, "cloud-hypervisor.sock")
+    }
+}
+
+impl Backend for CloudHypervisor {
+    fn name(&self) -> &'static str {
+        "cloud-hypervisor"
+    }
+
+    fn default_binary(&self) -> &'static str {
+        "cloud-hypervisor"
+    }
+
+    fn binary_env(&self) -> &'static str {
+        "AUTOVIRT_CLOUD_HYPERVISOR_BIN"
+    }
+
+    fn command(&self, spec: &VmSpec) -> Result<Command, Box<dyn Error>> {
+        let firmware = required_setting(self.name(), "firmware", "a firmware (hypervisor-fw or CLOUDHV.fd)")?;
+        let api_socket = self.api_socket(&spec.name);
+        let serial_socket = vm_file(&spec.name, "serial.sock");
+
+        let mut disks = vec![format!("path={}", spec.image_path)];
+        if let Some(seed_path) = &spec.seed_image {
+            disks.push(format!("path={},readonly=on", seed_path.to_string_lossy()));
+        }
+
+        let mut command = Command::new(self.binary());
+        command
+            .arg("--api-socket")
+            .arg(format!("path={}", api_socket.to_string_lossy()))
+            .arg("--cpus")
+            .arg(format!("boot={}", spec.cpus))
+            .arg("--memory")
+            .arg(format!("size={}M", spec.memory_mb))
+            .arg("--kernel")
+            .arg(firmware)
+            .arg("--disk")
+            .args(disks)
+            .arg("--serial")
+            .arg(format!("socket={}", serial_socket.to_string_lossy()))
+            .arg("--console")
+            .arg("off");
+        if let Some(tap) = hypervisor_setting(self.name(), "tap") {
+            command.arg("--net").arg(format!("tap={}", tap));
+        }
+        Ok(command)
+    }
+
+    fn start(&self, spec: &VmSpec, command: &mut Command) -> Result<Option<ExitStatus>, Box<dyn Error>> {
+        warn_port_forwards(self.name(), spec);
+        filesystem::get_vm_data_dir(&spec.name)?;
+        // cloud-hypervisor won't start if its socket is still there and a
+        // stale serial socket would make the logger connect to nothing
+        let _ = fs::remove_file(self.api_socket(&spec.name));
+        let _ = fs::remove_file(vm_file(&spec.name, "serial.sock"));
+        spawn_microvm(self, spec, command, vm_file(&spec.name, "cloud-hypervisor.log"), true)
+    }
+
+    fn cmdline_marker(&self, vm_name: &str) -> String {
+        self.api_socket(vm_name).to_string_lossy().to_string()
+    }
+
+    fn shutdown(&self, vm_name: &str) -> Result<(), Box<dyn Error>> {
+        api_put(&self.api_socket(vm_name), "/api/v1/vm.power-button", "")
+    }
+}
+
+/// Firecracker, booting a kernel with the image as the root disk.
+pub struct Firecracker;
+
+impl Firecracker {
+    fn api_socket(&self, vm_name: &str) -> PathBuf {
+        vm_file(vm_name, "firecracker.sock")
+    }
+
+    fn config_path(&self, vm_name: &str) -> PathBuf {
+        vm_file(vm_name, "firecracker.json")
+    }
+
+    /// The config file firecracker boots a VM with.
+    fn config(&self, spec: &VmSpec) -> Result<Value, Box<dyn Error>> {
+        if spec.disk_format != "raw" {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Firecracker needs a raw disk but {} is {} (VMs made with --backend firecracker get one)", spec.name, spec.disk_format),
+            )));
+        }
+        let kernel = required_setting(self.name(), "kernel", "a kernel (vmlinux)")?;
+        let boot_args = hypervisor_setting(self.name(), "boot_args")
+            .unwrap_or_else(|| "console=ttyS0 reboot=k panic=1 pci=off root=/dev/vda1 rw".to_string());
+
+        let mut drives = vec![json!({
+            "drive_id": "rootfs",
+            "path_on_host": spec.image_path,
+            "is_root_device": true,
+            "is_read_only": false,
+        })];
+        if let Some(seed_path) = &spec.seed_image {
+            drives.push(json!({
+                "drive_id": "seed",
+                "path_on_host": seed_path.to_string_lossy(),
+                "is_root_device": false,
+                "is_read_only": true,
+            }));
+        }
+        let network_interfaces: Vec<_> = hypervisor_setting(self.name(), "tap")
+            .map(|tap| json!({ "iface_id": "eth0", "host_dev_name": tap }))
+            .into_iter()
+            .collect();
+        Ok(json!({
+            "boot-source": { "kernel_image_path": kernel, "boot_args": boot_args },
+            "drives": drives,
+            "machine-config": {
+                "vcpu_count": spec.cpus.parse::<u64>().unwrap_or(1),
+                "mem_size_mib": spec.memory_mb.parse::<u64>().unwrap_or(512),
+            },
+            "network-interfaces": network_interfaces,
+        }))
+    }
+}
+
+impl Backend for Firecracker {
+    fn name(&self) -> &'static str {
+        "firecracker"
+    }
+
+    fn default_binary(&self) -> &'static str {
+        "firecracker"
+    }
+
+    fn binary_env(&self) -> &'static str {
+        "AUTOVIRT_FIRECRACKER_BIN"
+    }
+
+    fn disk_format(&self) -> Option<&'static str> {
+        Some("raw")
+    }
+
+    fn command(&self, spec: &VmSpec) -> Result<Command, Box<dyn Error>> {
+        // the config is written when the VM is started, it's made here too so
+        // bad settings are found before that
+        self.config(spec)?;
+        let mut command = Command::new(self.binary());
+        command
+            .arg("--api-sock")
+            .arg(self.api_socket(&spec.name))
+            .arg("--config-file")
+            .arg(self.config_path(&spec.name));
+        Ok(command)
+    }
+
+    fn start(&self, spec: &VmSpec, command: &mut Command) -> Result<Option<ExitStatus>, Box<dyn Error>> {
+        warn_port_forwards(self.name(), spec);
+        filesystem::get_vm_data_dir(&spec.name)?;
+        fs::write(self.config_path(&spec.name), serde_json::to_string_pretty(&self.config(spec)?)?)?;
+        // firecracker won't start if its socket is still there
+        let _ = fs::remove_file(self.api_socket(&spec.name));
+        // the serial console is firecracker's stdout so in the background it
+        // goes straight into the console log (`autovirt logs` works)
+        spawn_microvm(self, spec, command, vm_file(&spec.name, "console.log"), false)
+    }
+
+    fn cmdline_marker(&self, vm_name: &str) -> String {
+        self.api_socket(vm_name).to_string_lossy().to_string()
+    }
+
+    fn shutdown(&self, vm_name: &str) -> Result<(), Box<dyn Error>> {
+        api_put(&self.api_socket(vm_name), "/actions", r#"{"action_type": "SendCtrlAltDel"}"#)
+    }
+
+    fn console(&self, vm_name: &str) -> Result<bool, Box<dyn Error>> {
+        Err(Box::new(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Firecracker VMs have no console to attach to, see `autovirt logs {}`", vm_name),
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cloudinit;
+
+    /// Records its args, writes a pid file and answers shutdowns like the
+    /// real hypervisors (see the top of the script).
+    const FAKE_HYPERVISOR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/fake-hypervisor.py");
+
+    /// Makes a stopped VM on a backend with the fake hypervisor as its binary.
+    fn fake_vm(name: &str, backend: &str, disk_format: &str) -> VmSpec {
+        for env in ["AUTOVIRT_QEMU_BIN", "AUTOVIRT_CLOUD_HYPERVISOR_BIN", "AUTOVIRT_FIRECRACKER_BIN"] {
+            std::env::set_var(env, FAKE_HYPERVISOR);
+        }
+        // any file does for the firmware/kernel
+        filesystem::insert_value_into_autovirt_json_object(
+            "hypervisors",
+            json!({
+                "cloud-hypervisor": { "firmware": FAKE_HYPERVISOR },
+                "firecracker": { "kernel": FAKE_HYPERVISOR },
+            }),
+        );
+        let data_dir = filesystem::get_vm_data_dir(name).unwrap();
+        let image_path = data_dir.join("disk.img").to_string_lossy().to_string();
+        filesystem::insert_value_into_autovirt_json_object(
+            &format!("vms.{}", name),
+            json!({
+                "name": name,
+                "backend": backend,
+                "memory_mb": "256",
+                "cpus": "2",
+                "image_path": image_path,
+                "disk_format": disk_format,
+                "datasource": "imds",
+            }),
+        );
+        let home = std::env::var("HOME").unwrap();
+        let _ = fs::remove_file(format!("{}/fake-hypervisor.argv", home));
+        let _ = fs::remove_file(format!("{}/fake-hypervisor.requests", home));
+        VmSpec::from_config(name, "", true).unwrap()
+    }
+
+    fn args(command: &Command) -> Vec<String> {
+        command.get_args().map(|arg| arg.to_string_lossy().to_string()).collect()
+    }
+
+    fn recorded(file: &str) -> String {
+        fs::read_to_string(format!("{}/fake-hypervisor.{}", std::env::var("HOME").unwrap(), file)).unwrap_or_default()
+    }
+
+    fn wait_until_stopped(backend: &dyn Backend, vm_name: &str) {
+        let started = Instant::now();
+        while backend.pid(vm_name).is_some() {
+            assert!(started.elapsed() < Duration::from_secs(10), "{} didn't stop", vm_name);
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// Starts the VM, checks the fake got the command's args and is found by
+    /// its pid, then shuts it down and (after another start) kills it.
+    ///
+    /// ---
+    fn check_lifecycle(backend: &dyn Backend, spec: &VmSpec, shutdown_request: &str) {
+        let mut command = backend.command(spec).unwrap();
+        let status = backend.start(spec, &mut command).unwrap();
+        assert!(status.is_some_and(|s| s.success()), "start failed -> {:?}", status);
+
+        let argv: Vec<String> = serde_json::from_str(&recorded("argv")).unwrap();
+        assert_eq!(argv, args(&command));
+        let pid = backend.pid(&spec.name).expect("the VM isn't found running");
+        assert_eq!(fs::read_to_string(backend.pid_file(&spec.name)).unwrap().trim(), pid.to_string());
+
+        stop(backend, &spec.name, false, Duration::from_secs(10)).unwrap();
+        assert_eq!(backend.pid(&spec.name), None);
+        assert!(recorded("requests").lines().any(|line| line == shutdown_request), "{}", recorded("requests"));
+
+        let mut command = backend.command(spec).unwrap();
+        backend.start(spec, &mut command).unwrap();
+        let pid = backend.pid(&spec.name).expect("the VM isn't found running");
+        backend.kill(&spec.name, pid).unwrap();
+        wait_until_stopped(backend, &spec.name);
+    }
+
+    fn have_python() -> bool {
+        if !cloudinit::command_exists("python3") {
+            eprintln!("python3 isn't installed (for the fake hypervisor), skipping");
+            return false;
+        }
+        true
+    }
+
+    // the serial logger that's started for qemu and cloud-hypervisor VMs is
+    // this test binary (`serial-logger <vm>` matches no tests so it just exits)
+
+    #[test]
+    fn qemu_backend() {
+        let _home = filesystem::test_home();
+        if !have_python() {
+            return;
+        }
+        let spec = fake_vm("qemuvm", "qemu", "qcow2");
+        let data_dir = filesystem::get_vm_data_dir("qemuvm").unwrap();
+
+        let command = Qemu.command(&spec).unwrap();
+        assert_eq!(command.get_program(), FAKE_HYPERVISOR);
+        let args = args(&command);
+        let pid_file = data_dir.join("qemu.pid").to_string_lossy().to_string();
+        assert!(args.windows(2).any(|pair| pair == ["-pidfile", pid_file.as_str()]), "{:?}", args);
+        assert!(args.contains(&"-daemonize".to_string()));
+
+        check_lifecycle(&Qemu, &spec, "qmp system_powerdown");
+        // killed with QMP `quit` before falling back to SIGKILL
+        assert!(recorded("requests").lines().any(|line| line == "qmp quit"));
+    }
+
+    #[test]
+    fn cloud_hypervisor_backend() {
+        let _home = filesystem::test_home();
+        if !have_python() {
+            return;
+        }
+        let spec = fake_vm("chvm", "cloud-hypervisor", "qcow2");
+        let data_dir = filesystem::get_vm_data_dir("chvm").unwrap();
+        let api_socket = data_dir.join("cloud-hypervisor.sock");
+        let serial_socket = data_dir.join("serial.sock");
+
+        // building the command doesn't touch the VM's files
+        fs::write(&api_socket, "stale").unwrap();
+        fs::write(&serial_socket, "stale").unwrap();
+        let command = CloudHypervisor.command(&spec).unwrap();
+        assert_eq!(fs::read_to_string(&api_socket).unwrap(), "stale");
+        assert_eq!(fs::read_to_string(&serial_socket).unwrap(), "stale");
+
+        assert_eq!(
+            args(&command),
+            [
+                "--api-socket".to_string(),
+                format!("path={}", api_socket.to_string_lossy()),
+                "--cpus".to_string(),
+                "boot=2".to_string(),
+                "--memory".to_string(),
+                "size=256M".to_string(),
+                "--kernel".to_string(),
+                FAKE_HYPERVISOR.to_string(),
+                "--disk".to_string(),
+                format!("path={}", spec.image_path),
+                "--serial".to_string(),
+                format!("socket={}", serial_socket.to_string_lossy()),
+                "--console".to_string(),
+                "off".to_string(),
+            ]
+        );
+
+        check_lifecycle(&CloudHypervisor, &spec, "PUT /api/v1/vm.power-button");
+    }
+
+    #[test]
+    fn firecracker_backend() {
+        let _home = filesystem::test_home();
+        if !have_python() {
+            return;
+        }
+        let spec = fake_vm("fcvm", "firecracker", "raw");
+        let data_dir = filesystem::get_vm_data_dir("fcvm").unwrap();
+        let api_socket = data_dir.join("firecracker.sock");
+        let config_path = data_dir.join("firecracker.json");
+
+        // building the command doesn't touch the VM's files
+        let _ = fs::remove_file(&config_path);
+        fs::write(&api_socket, "stale").unwrap();
+        let command = Firecracker.command(&spec).unwrap();
+        assert!(!config_path.exists());
+        assert_eq!(fs::read_to_string(&api_socket).unwrap(), "stale");
+
+        assert_eq!(
+            args(&command),
+            [
+                "--api-sock".to_string(),
+                api_socket.to_string_lossy().to_string(),
+                "--config-file".to_string(),
+                config_path.to_string_lossy().to_string(),
+            ]
+        );
+
+        check_lifecycle(&Firecracker, &spec, r#"PUT /actions {"action_type": "SendCtrlAltDel"}"#);
+        let config: Value = serde_json::from_str(&fs::read_to_string(&config_path).unwrap()).unwrap();
+        assert_eq!(config["drives"][0]["path_on_host"], json!(spec.image_path));
+        assert_eq!(config["machine-config"], json!({ "vcpu_count": 2, "mem_size_mib": 256 }));
+    }
+
+    #[test]
+    fn firecracker_needs_a_raw_disk() {
+        let _home = filesystem::test_home();
+        let spec = fake_vm("fcqcow", "firecracker", "qcow2");
+        let error = Firecracker.command(&spec).unwrap_err();
+        assert!(error.to_string().contains("needs a raw disk"), "{}", error);
+    }
+}
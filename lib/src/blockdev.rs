@@ -0,0 +1,278 @@
+//! This file contains the things that build the qemu args for a VM's disks.
+//!
+//! Disks are attached with `-blockdev` (a file node, a format node and an
+//! optional throttle node on top) and a `-device` for the bus the disk is on
+//! (virtio-blk by default) instead of `-hda`/`-drive` so that the cache mode,
+//! aio backend, discard and IO limits can be set for every disk.
+//!
+//! The settings are per VM (`disk_io` in the VM's entry) and apply to the main
+//! disk and all the data disks.
+//!
+//! ---
+
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::io;
+use std::path::Path;
+
+use crate::disk;
+use crate::filesystem;
+use crate::run;
+
+/// The buses the main disk can be on.
+pub const MAIN_DISK_BUSES: [&str; 3] = ["virtio", "scsi", "ide"];
+
+/// The cache modes (same names as the old `-drive cache=` option).
+pub const CACHE_MODES: [&str; 5] = ["none", "writeback", "writethrough", "directsync", "unsafe"];
+
+/// The aio backends.
+pub const AIO_BACKENDS: [&str; 3] = ["threads", "native", "io_uring"];
+
+/// The disk IO settings of a VM.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiskIoOptions {
+    /// The bus the main disk is on (data disks have their own)
+    pub bus: String,
+    pub cache: String,
+    pub aio: String,
+    /// Pass discard/TRIM from the guest through to the image file
+    pub discard: bool,
+    /// IO operations per second limit (per disk)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iops: Option<u64>,
+    /// Bytes per second limit (per disk)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bps: Option<u64>,
+}
+
+impl Default for DiskIoOptions {
+    fn default() -> Self {
+        DiskIoOptions {
+            bus: "virtio".to_string(),
+            cache: "writeback".to_string(),
+            aio: "threads".to_string(),
+            discard: true,
+            iops: None,
+            bps: None,
+        }
+    }
+}
+
+impl DiskIoOptions {
+    /// Checks the settings are valid (and work together).
+    pub fn check(&self) -> Result<(), Box<dyn Error>> {
+        let invalid = |what: &str, value: &str, valid: &[&str]| -> Box<dyn Error> {
+            Box::new(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid disk {} '{}' (valid: {})", what, value, valid.join(", ")),
+            ))
+        };
+
+        if !MAIN_DISK_BUSES.contains(&self.bus.as_str()) {
+            return Err(invalid("bus", &self.bus, &MAIN_DISK_BUSES));
+        }
+        if !CACHE_MODES.contains(&self.cache.as_str()) {
+            return Err(invalid("cache mode", &self.cache, &CACHE_MODES));
+        }
+        if !AIO_BACKENDS.contains(&self.aio.as_str()) {
+            return Err(invalid("aio backend", &self.aio, &AIO_BACKENDS));
+        }
+        if self.aio == "native" && !self.cache_direct() {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "aio=native needs the 'none' or 'directsync' cache mode",
+            )));
+        }
+        if self.iops == Some(0) || self.bps == Some(0) {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "IO limits have to be more than 0",
+            )));
+        }
+        Ok(())
+    }
+
+    /// Bypass the host page cache (O_DIRECT).
+    fn cache_direct(&self) -> bool {
+        matches!(self.cache.as_str(), "none" | "directsync")
+    }
+
+    /// Ignore flush requests from the guest.
+    fn cache_no_flush(&self) -> bool {
+        self.cache == "unsafe"
+    }
+
+    /// The guest sees a write cache (which it has to flush).
+    fn write_cache(&self) -> bool {
+        !matches!(self.cache.as_str(), "writethrough" | "directsync")
+    }
+}
+
+/// Gets the disk IO settings of a VM. VMs made before these settings existed
+/// keep their main disk on IDE (what `-hda` gave them).
+///
+/// ---
+pub fn load_disk_io_options(vm_name: &str) -> DiskIoOptions {
+    filesystem::get_value_from_autovirt_json(&format!("vms.{}.disk_io", vm_name))
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_else(|| DiskIoOptions {
+            bus: "ide".to_string(),
+            ..Default::default()
+        })
+}
+
+/// Stores the disk IO settings of a VM.
+fn save_disk_io_options(vm_name: &str, options: &DiskIoOptions) {
+    filesystem::insert_value_into_autovirt_json_object(
+        &format!("vms.{}.disk_io", vm_name),
+        serde_json::to_value(options).expect("ERROR: Failed to jsonify disk io options"),
+    );
+}
+
+/// Checks and stores new disk IO settings for an existing VM.
+pub fn set_disk_io_options(vm_name: &str, options: &DiskIoOptions) -> Result<(), Box<dyn Error>> {
+    disk::vm_image_path(vm_name)?;
+    options.check()?;
+    save_disk_io_options(vm_name, options);
+
+    println!(
+        "LOG:: Disk settings updated for VM {} -> bus={} cache={} aio={} discard={} iops={} bps={}",
+        vm_name,
+        options.bus,
+        options.cache,
+        options.aio,
+        options.discard,
+        options.iops.map(|i| i.to_string()).unwrap_or_else(|| "-".to_string()),
+        options.bps.map(|b| b.to_string()).unwrap_or_else(|| "-".to_string()),
+    );
+    if run::is_vm_running(vm_name) {
+        println!("INFO:: The VM is running, the new settings are used the next time it starts");
+    }
+    Ok(())
+}
+
+/// Builds the `-blockdev`/`-object` args for a disk image and gives back the
+/// node name of the top node (which the `-device` uses).
+///
+/// ---
+fn blockdev_args(
+    id: &str,
+    image_path: &str,
+    format: &str,
+    options: &DiskIoOptions,
+    args: &mut Vec<String>,
+) -> String {
+    let on_off = |b: bool| if b { "on" } else { "off" };
+    let discard = if options.discard { "unmap" } else { "ignore" };
+    let cache = format!(
+        "cache.direct={},cache.no-flush={}",
+        on_off(options.cache_direct()),
+        on_off(options.cache_no_flush())
+    );
+
+    args.push("-blockdev".to_string());
+    args.push(format!(
+        "driver=file,node-name={id}-file,filename={},aio={},{cache},discard={discard}",
+        disk::escape_option_value(image_path),
+        options.aio,
+    ));
+
+    args.push("-blockdev".to_string());
+    args.push(format!(
+        "driver={format},node-name={id}-fmt,file={id}-file,{cache},discard={discard}"
+    ));
+
+    if options.iops.is_none() && options.bps.is_none() {
+        return format!("{}-fmt", id);
+    }
+
+    let mut limits = Vec::new();
+    if let Some(iops) = options.iops {
+        limits.push(format!("limits.iops-total={}", iops));
+    }
+    if let Some(bps) = options.bps {
+        limits.push(format!("limits.bps-total={}", bps));
+    }
+    args.push("-object".to_string());
+    args.push(format!("throttle-group,id={id}-limits,{}", limits.join(",")));
+
+    args.push("-blockdev".to_string());
+    args.push(format!(
+        "driver=throttle,node-name={id}-throttle,throttle-group={id}-limits,file={id}-fmt"
+    ));
+    format!("{}-throttle", id)
+}
+
+/// Builds the `-device` arg for a disk on a bus.
+fn device_arg(bus: &str, node: &str, id: &str, serial: Option<&str>, options: &DiskIoOptions) -> String {
+    let device = match bus {
+        "scsi" => "scsi-hd,bus=scsi0.0",
+        "nvme" => "nvme",
+        "ide" => "ide-hd,bus=ide.0",
+        _ => "virtio-blk-pci",
+    };
+
+    let mut device_arg = format!("{},drive={},id={}", device, node, id);
+    if let Some(serial) = serial {
+        device_arg.push_str(&format!(",serial={}", serial));
+    }
+    // nvme has no write-cache property (it always has a volatile cache)
+    if bus != "nvme" {
+        device_arg.push_str(&format!(",write-cache={}", if options.write_cache() { "on" } else { "off" }));
+    }
+    device_arg
+}
+
+/// Builds all the qemu args for a VM's disks (the main disk, the attached data
+/// disks and a scsi controller if any of them need one).
+///
+/// ---
+pub fn vm_disk_args(vm_name: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let options = load_disk_io_options(vm_name);
+    options.check()?;
+
+    let image_path = disk::vm_image_path(vm_name)?;
+    let format = disk::vm_disk_format(vm_name)?;
+    let data_disks: Vec<disk::DataDisk> = disk::load_data_disks(vm_name).into_iter().filter(|d| d.attached).collect();
+
+    let mut args = Vec::new();
+
+    if options.bus == "scsi" || data_disks.iter().any(|d| d.bus == "scsi") {
+        args.push("-device".to_string());
+        args.push("virtio-scsi-pci,id=scsi0".to_string());
+    }
+
+    let node = blockdev_args("main", &image_path, &format, &options, &mut args);
+    args.push("-device".to_string());
+    args.push(format!("{},bootindex=0", device_arg(&options.bus, &node, "disk-main", None, &options)));
+
+    for data_disk in &data_disks {
+        let id = format!("data-{}", data_disk.name);
+        let node = blockdev_args(&id, &data_disk.path, &data_disk.format, &options, &mut args);
+        args.push("-device".to_string());
+        args.push(device_arg(
+            &data_disk.bus,
+            &node,
+            &format!("disk-{}", data_disk.name),
+            Some(&data_disk.serial),
+            &options,
+        ));
+    }
+
+    Ok(args)
+}
+
+/// Builds the qemu args for attaching a cloud-init seed image as a cdrom.
+pub fn seed_cdrom_args(seed_path: &Path) -> Vec<String> {
+    vec![
+        "-blockdev".to_string(),
+        format!(
+            "driver=file,node-name=seed-file,filename={},read-only=on",
+            disk::escape_option_value(&seed_path.to_string_lossy())
+        ),
+        "-blockdev".to_string(),
+        "driver=raw,node-name=seed-fmt,file=seed-file,read-only=on".to_string(),
+        "-device".to_string(),
+        "ide-cd,drive=seed-fmt,bus=ide.1,id=seed".to_string(),
+    ]
+}
@@ -0,0 +1,255 @@
+//! This file contains image bundles, tarballs of downloaded images with their
+//! catalog entries and checksums so a host without internet can be seeded
+//! from one that has it (`autovirt image export` and `image import-bundle`).
+//!
+//! A bundle is a plain tar with:
+//! - `manifest.json` -> the catalog entry and download entry of every image
+//! - `SHA256SUMS` -> the checksums of the image files (`sha256sum -c` works)
+//! - `images/<file>` -> the image files
+//!
+//! ---
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::catalog::{self, CatalogImage};
+use crate::diskspace;
+use crate::download;
+use crate::filesystem;
+use crate::images::{self, ImageVersion};
+use crate::signature::Verification;
+use crate::unpack;
+
+const MANIFEST_NAME: &str = "manifest.json";
+const CHECKSUMS_NAME: &str = "SHA256SUMS";
+const BUNDLE_VERSION: u32 = 1;
+
+/// An image in a bundle.
+#[derive(Debug, Serialize, Deserialize)]
+struct BundleImage {
+    catalog: CatalogImage,
+    /// The path of the image file in the bundle
+    file: String,
+    filename: String,
+    format: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    serial: Option<String>,
+    /// `sha256:<hash>` of the image file
+    digest: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    downloaded_at: Option<String>,
+    /// How it was verified when it was downloaded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    verification: Option<Verification>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BundleManifest {
+    autovirt_bundle: u32,
+    created_at: String,
+    images: BTreeMap<String, BundleImage>,
+}
+
+/// Adds a file made in memory to a tarball.
+fn append_bytes<W: Write>(builder: &mut tar::Builder<W>, name: &str, data: &[u8]) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(chrono::Utc::now().timestamp().max(0) as u64);
+    header.set_cksum();
+    builder.append_data(&mut header, name, data)
+}
+
+/// Makes a bundle of the current file of some downloaded images (all of them
+/// with `all`).
+///
+/// ---
+pub fn export_images(names: &[String], all: bool, output: &str) -> Result<(), Box<dyn Error>> {
+    let names: Vec<String> = match all {
+        true => filesystem::get_value_from_autovirt_json("downloaded_images")
+            .and_then(|v| v.as_object().map(|images| images.keys().cloned().collect()))
+            .unwrap_or_default(),
+        false => names.to_vec(),
+    };
+    if names.is_empty() {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "No images to export (give their names or --all)",
+        )));
+    }
+
+    let downloads_dir = images::downloads_dir()?;
+    let mut manifest = BundleManifest {
+        autovirt_bundle: BUNDLE_VERSION,
+        created_at: chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        images: BTreeMap::new(),
+    };
+    let mut checksums = String::new();
+    for name in &names {
+        let image = catalog::find_image(name)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Image '{}' not found", name)))?;
+        let downloaded = images::downloaded_image(name)
+            .filter(|d| downloads_dir.join(&d.filename).is_file())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Image '{}' isn't downloaded", name)))?;
+
+        let path = downloads_dir.join(&downloaded.filename);
+        let digest = match downloaded.digest.as_deref().and_then(|d| d.strip_prefix("sha256:")) {
+            Some(sha256) => sha256.to_string(),
+            None => download::file_checksum(&path.to_string_lossy(), "sha256")?,
+        };
+        let file = format!("images/{}", downloaded.filename);
+        checksums.push_str(&format!("{}  {}\n", digest, file));
+        manifest.images.insert(
+            name.clone(),
+            BundleImage {
+                catalog: image,
+                file,
+                filename: downloaded.filename,
+                format: downloaded.format,
+                serial: downloaded.serial,
+                digest: format!("sha256:{}", digest),
+                url: downloaded.url,
+                downloaded_at: downloaded.downloaded_at,
+                verification: downloaded.verification,
+            },
+        );
+    }
+
+    // written next to where it goes so a half written bundle never looks
+    // like a whole one
+    let part_path = format!("{}.part", output);
+    let mut builder = tar::Builder::new(BufWriter::new(File::create(&part_path)?));
+    let result = (|| -> Result<(), Box<dyn Error>> {
+        append_bytes(&mut builder, MANIFEST_NAME, serde_json::to_string_pretty(&manifest)?.as_bytes())?;
+        append_bytes(&mut builder, CHECKSUMS_NAME, checksums.as_bytes())?;
+        for (name, image) in &manifest.images {
+            println!("LOG:: Adding {} -> {}", name, image.file);
+            builder.append_path_with_name(downloads_dir.join(&image.filename), &image.file)?;
+        }
+        builder.into_inner()?.flush()?;
+        Ok(())
+    })();
+    if let Err(e) = result {
+        let _ = fs::remove_file(&part_path);
+        return Err(e);
+    }
+    fs::rename(&part_path, output)?;
+
+    let size = fs::metadata(output).map(|m| m.len()).unwrap_or(0);
+    println!("LOG:: Exported {} images to {} ({})", manifest.images.len(), output, diskspace::human_size(size));
+    Ok(())
+}
+
+/// Seeds `_data/downloads` from a bundle, the images (and the catalog entries
+/// of the ones this host doesn't know) are added like they were downloaded.
+///
+/// ---
+pub fn import_bundle(bundle_path: &str) -> Result<(), Box<dyn Error>> {
+    let mut archive = tar::Archive::new(BufReader::new(
+        File::open(bundle_path).map_err(|e| io::Error::new(e.kind(), format!("Failed to open {} -> {}", bundle_path, e)))?,
+    ));
+    let downloads_dir = images::downloads_dir()?;
+    fs::create_dir_all(&downloads_dir)?;
+
+    let mut manifest: Option<BundleManifest> = None;
+    let mut imported = 0;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_path = entry.path()?.to_string_lossy().to_string();
+
+        if entry_path == MANIFEST_NAME {
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents)?;
+            let parsed: BundleManifest = serde_json::from_str(&contents)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid bundle manifest -> {}", e)))?;
+            if parsed.autovirt_bundle > BUNDLE_VERSION {
+                return Err(Box::new(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("The bundle is version {}, this autovirt knows up to {}", parsed.autovirt_bundle, BUNDLE_VERSION),
+                )));
+            }
+            println!("INFO: Bundle made at {} with -> {}", parsed.created_at, parsed.images.keys().cloned().collect::<Vec<_>>().join(", "));
+            manifest = Some(parsed);
+            continue;
+        }
+        if entry_path == CHECKSUMS_NAME {
+            continue;
+        }
+
+        let manifest = manifest
+            .as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Not an autovirt bundle (no manifest.json first)"))?;
+        let Some((name, image)) = manifest.images.iter().find(|(_, image)| image.file == entry_path) else {
+            println!("WARNING:: Skipping {} (not in the manifest)", entry_path);
+            continue;
+        };
+        // the file name ends up in a path so it can't be allowed to go
+        // anywhere else
+        if Path::new(&image.filename).file_name().map(|f| f.to_string_lossy() != image.filename).unwrap_or(true) {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid file name in the bundle -> {}", image.filename),
+            )));
+        }
+
+        let file_path = downloads_dir.join(&image.filename);
+        let have_it = images::downloaded_image(name)
+            .is_some_and(|d| d.digest.as_deref() == Some(image.digest.as_str()) && file_path.is_file());
+        if !have_it {
+            let part_path = downloads_dir.join(format!("{}.part", image.filename));
+            let mut reader = unpack::HashingReader::new(&mut entry, "sha256")?;
+            let mut out = BufWriter::new(File::create(&part_path)?);
+            io::copy(&mut reader, &mut out)?;
+            out.flush()?;
+            let digest = format!("sha256:{}", reader.finish()?);
+            if digest != image.digest {
+                fs::remove_file(&part_path)?;
+                return Err(Box::new(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Checksum mismatch for {} (expected {}, got {})", name, image.digest, digest),
+                )));
+            }
+            fs::rename(&part_path, &file_path)?;
+            println!("LOG:: {} -> {} (checksum OK)", name, file_path.to_string_lossy());
+        } else {
+            println!("INFO: {} is already here -> {}", name, file_path.to_string_lossy());
+        }
+
+        // the catalog entry is only added for images this host doesn't know
+        // (its own catalog wins)
+        if catalog::find_image(name).is_none() {
+            let mut entry = serde_json::to_value(&image.catalog)?;
+            if image.catalog.url.starts_with("file://") {
+                entry["imported"] = serde_json::Value::Bool(true);
+            }
+            filesystem::insert_value_into_autovirt_json_object(&format!("images.{}", name), entry);
+            println!("LOG:: Added the catalog entry for {}", name);
+        }
+        images::record_download(
+            name,
+            image.serial.as_deref(),
+            ImageVersion {
+                filename: image.filename.clone(),
+                format: image.format.clone(),
+                digest: Some(image.digest.clone()),
+                url: image.url.clone(),
+                downloaded_at: image.downloaded_at.clone(),
+                last_used: None,
+                verification: image.verification.clone(),
+            },
+        );
+        imported += 1;
+    }
+
+    if manifest.is_none() {
+        return Err(Box::new(io::Error::new(io::ErrorKind::InvalidData, "Not an autovirt bundle (no manifest.json)")));
+    }
+    println!("LOG:: Imported {} images from {}", imported, bundle_path);
+    Ok(())
+}
@@ -0,0 +1,261 @@
+//! This file contains the image catalog which is the list of cloud images
+//! autovirt knows how to download (and what it needs to know about them to
+//! make VMs from them).
+//!
+//! The catalog is `conf/catalog.json` which is built into the binary. It can
+//! be added to or changed without a new build with `~/.autovirt/catalog.json`
+//! (same layout, its entries win) and single images can still be changed with
+//! their entry under `images` in autovirt.json (the old `link`/`filename`
+//! entries work too).
+//!
+//! Images can have an index (simplestreams or a directory listing) to find
+//! their newest release, see imageindex.rs.
+//!
+//! Images can have a `signature` for their checksums and the catalog has the
+//! system paths of the distro keyrings to check it with, see signature.rs.
+//!
+//! Every image belongs to an OS family which has the things cloud-init has to
+//! do differently on it (the admin group, the shell, the init system).
+//!
+//! ---
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+
+use crate::filesystem;
+use crate::imageindex::ImageIndex;
+use crate::signature::SignatureSpec;
+
+/// The catalog that ships with autovirt.
+const BUILTIN_CATALOG: &str = include_str!("conf/catalog.json");
+
+/// An image that can be downloaded and made into VMs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CatalogImage {
+    #[serde(default)]
+    pub description: String,
+    /// The OS family (see `OsFamily`)
+    #[serde(default = "default_os_family")]
+    pub os_family: String,
+    #[serde(alias = "link")]
+    pub url: String,
+    /// Servers with the same layout as the one `url` is on, tried in order
+    /// when it doesn't work (see fetch.rs)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mirrors: Vec<String>,
+    /// The name the image is saved as in `_data/downloads`
+    pub filename: String,
+    /// The format of the image once it's downloaded (and decompressed)
+    #[serde(default = "default_format")]
+    pub format: String,
+    /// How the download is compressed (none, xz, gz, zst, tar, tar.gz...),
+    /// it's unpacked while it's downloaded (see unpack.rs)
+    #[serde(default = "default_compression")]
+    pub compression: String,
+    /// The file in a tarball that's the image (the first disk image if not set)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archive_member: Option<String>,
+    /// The url of the checksum file (SHA256SUMS or alike) for the download
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum_url: Option<String>,
+    #[serde(default = "default_checksum_type")]
+    pub checksum_type: String,
+    /// What the checksum file has the checksum of, the `download` (what most
+    /// distros do) or the unpacked `image`
+    #[serde(default = "default_checksum_of")]
+    pub checksum_of: String,
+    /// How the checksum file (or simplestreams index) is signed, downloads
+    /// without one are refused unless `--insecure` (see signature.rs)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<SignatureSpec>,
+    /// The user the image's cloud-init makes by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_user: Option<String>,
+    /// The datasource VMs have to use (for images that can't use every one)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub datasource: Option<String>,
+    /// Where the releases are listed (`{serial}` in the urls is filled in
+    /// with the newest one, see imageindex.rs)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<ImageIndex>,
+}
+
+/// The things that differ between OS families when setting up a VM.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OsFamily {
+    /// The group that gets admin rights (sudo on debian, wheel elsewhere)
+    pub admin_group: String,
+    pub shell: String,
+    /// systemd or openrc
+    pub init: String,
+}
+
+impl Default for OsFamily {
+    fn default() -> Self {
+        OsFamily {
+            admin_group: "sudo".to_string(),
+            shell: "/bin/bash".to_string(),
+            init: "systemd".to_string(),
+        }
+    }
+}
+
+impl OsFamily {
+    /// The commands that enable and start a service in the VM.
+    pub fn enable_service_commands(&self, service: &str) -> Vec<String> {
+        match self.init.as_str() {
+            "openrc" => vec![
+                format!("rc-update add {} default", service),
+                format!("rc-service {} start", service),
+            ],
+            _ => vec![format!("systemctl enable --now {}", service)],
+        }
+    }
+}
+
+fn default_os_family() -> String {
+    "debian".to_string()
+}
+
+fn default_format() -> String {
+    "qcow2".to_string()
+}
+
+fn default_compression() -> String {
+    "none".to_string()
+}
+
+fn default_checksum_type() -> String {
+    "sha256".to_string()
+}
+
+fn default_checksum_of() -> String {
+    "download".to_string()
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct CatalogFile {
+    #[serde(default)]
+    families: BTreeMap<String, OsFamily>,
+    #[serde(default)]
+    images: BTreeMap<String, Value>,
+    /// Where the distro keyring packages put their keyrings
+    #[serde(default)]
+    keyrings: BTreeMap<String, Vec<String>>,
+}
+
+/// Adds the keys of `over` on top of `base` (both json objects).
+fn merge_object(base: &mut Value, over: &Value) {
+    if let (Some(base), Some(over)) = (base.as_object_mut(), over.as_object()) {
+        for (key, value) in over {
+            // `link` is the old name for `url` so one replaces the other
+            match key.as_str() {
+                "link" => base.remove("url"),
+                "url" => base.remove("link"),
+                _ => None,
+            };
+            base.insert(key.clone(), value.clone());
+        }
+    }
+}
+
+/// Loads the built-in catalog with the user's catalog file on top of it.
+fn load_catalog_file() -> CatalogFile {
+    let mut catalog: CatalogFile =
+        serde_json::from_str(BUILTIN_CATALOG).expect("ERROR: The built-in image catalog is invalid");
+
+    let user_catalog_path = filesystem::get_autovirt_data_dir().map(|dir| dir.join("catalog.json"));
+    if let Some(contents) = user_catalog_path.as_ref().and_then(|path| fs::read_to_string(path).ok()) {
+        match serde_json::from_str::<CatalogFile>(&contents) {
+            Ok(user_catalog) => {
+                catalog.families.extend(user_catalog.families);
+                catalog.keyrings.extend(user_catalog.keyrings);
+                for (name, entry) in user_catalog.images {
+                    match catalog.images.get_mut(&name) {
+                        Some(base) => merge_object(base, &entry),
+                        None => {
+                            catalog.images.insert(name, entry);
+                        }
+                    }
+                }
+            }
+            Err(e) => eprintln!("WARNING:: Ignoring {:?} -> {}", user_catalog_path.unwrap_or_default(), e),
+        }
+    }
+
+    catalog
+}
+
+/// Gets all the images (the catalog with the autovirt.json `images` entries
+/// on top), skipping entries that don't have what's needed.
+///
+/// ---
+pub fn all_images() -> BTreeMap<String, CatalogImage> {
+    let mut entries = load_catalog_file().images;
+
+    let config_images = filesystem::get_value_from_autovirt_json("images")
+        .and_then(|v| v.as_object().cloned())
+        .unwrap_or_default();
+    for (name, entry) in config_images {
+        match entries.get_mut(&name) {
+            Some(base) => merge_object(base, &entry),
+            None => {
+                entries.insert(name, entry);
+            }
+        }
+    }
+
+    entries
+        .into_iter()
+        .filter_map(|(name, entry)| match serde_json::from_value::<CatalogImage>(entry) {
+            Ok(image) => Some((name, image)),
+            Err(e) => {
+                eprintln!("WARNING:: Ignoring image '{}' -> {}", name, e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Finds an image by name.
+pub fn find_image(name: &str) -> Option<CatalogImage> {
+    all_images().remove(name)
+}
+
+/// Gets an OS family by name (debian-like if it isn't known).
+pub fn os_family(name: &str) -> OsFamily {
+    load_catalog_file().families.remove(name).unwrap_or_default()
+}
+
+/// Gets the names of all the OS families.
+pub fn os_family_names() -> Vec<String> {
+    load_catalog_file().families.into_keys().collect()
+}
+
+/// Gets the system paths a keyring can be at (empty if it isn't known).
+pub fn keyring_paths(name: &str) -> Vec<String> {
+    load_catalog_file().keyrings.remove(name).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_signatures_have_keyrings() {
+        let catalog: CatalogFile = serde_json::from_str(BUILTIN_CATALOG).unwrap();
+        for (name, entry) in &catalog.images {
+            let image: CatalogImage = serde_json::from_value(entry.clone()).unwrap();
+            if let Some(signature) = image.signature {
+                assert!(
+                    catalog.keyrings.get(&signature.keyring).is_some_and(|paths| !paths.is_empty()),
+                    "{} is signed with keyring '{}' which isn't in `keyrings`",
+                    name,
+                    signature.keyring
+                );
+            }
+        }
+    }
+}
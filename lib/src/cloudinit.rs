@@ -0,0 +1,794 @@
+//! This file contains things related to generating the cloud-init config files
+//! (mainly user-data) for VMs.
+//!
+//! The user-data used to be a fixed template in initdata.rs that had its
+//! placeholders swapped out with `str::replace` which broke when a password had
+//! yaml characters in it. Now the user-data is built from the structs in here
+//! and serialised as actual yaml so everything is quoted properly.
+//!
+//! If a VM has extra parts (shell scripts, boothooks etc.) then the user-data
+//! is a multi-part MIME archive with the cloud-config as the first part. The
+//! payload is served by the imds server from the VM's data directory or packed
+//! into a NoCloud seed image (see `write_vm_cloud_init_files`).
+//!
+//! ---
+
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value as YamlValue;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::catalog;
+use crate::filesystem;
+
+/// The `#cloud-config` document for a VM.
+///
+/// Only the things autovirt knows how to set are in here. Anything else can be
+/// added with `--user-data-file` which gets merged on top of this (see
+/// `render_user_data`).
+///
+/// ---
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct CloudConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+
+    #[serde(default)]
+    pub users: Vec<CloudUser>,
+
+    #[serde(default)]
+    pub chpasswd: ChPasswd,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub package_update: Option<bool>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub packages: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub runcmd: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub write_files: Vec<WriteFile>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mounts: Vec<Vec<String>>,
+
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub disk_setup: BTreeMap<String, DiskSetup>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fs_setup: Vec<FsSetup>,
+}
+
+/// A user entry under `users:` in the cloud config.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct CloudUser {
+    pub name: String,
+    pub plain_text_passwd: String,
+    pub lock_passwd: bool,
+    pub sudo: String,
+    pub groups: String,
+    pub shell: String,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ssh_authorized_keys: Vec<String>,
+}
+
+/// The `chpasswd:` section (only used so that the password doesn't expire on
+/// first login).
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ChPasswd {
+    pub expire: bool,
+}
+
+/// A file to be written in the VM on first boot (`write_files:`).
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct WriteFile {
+    pub path: String,
+    pub content: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<String>,
+}
+
+/// How a disk gets partitioned on first boot (`disk_setup:`, keyed by device).
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct DiskSetup {
+    pub table_type: String,
+    pub layout: bool,
+    pub overwrite: bool,
+}
+
+/// A filesystem to make on first boot (`fs_setup:`).
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct FsSetup {
+    pub label: String,
+    pub filesystem: String,
+    pub device: String,
+    pub partition: String,
+}
+
+/// Options from the cli (create command) used to build the cloud config.
+///
+/// ---
+#[derive(Debug, Default, Clone)]
+pub struct UserDataOptions {
+    pub packages: Vec<String>,
+    pub runcmd: Vec<String>,
+    pub write_files: Vec<String>,
+    pub mounts: Vec<String>,
+    pub timezone: Option<String>,
+    pub hostname: Option<String>,
+    pub user_data_file: Option<String>,
+    pub scripts: Vec<String>,
+    pub boothooks: Vec<String>,
+    pub part_handlers: Vec<String>,
+    pub include_urls: Vec<String>,
+    /// How the VM gets its cloud-init data (`imds` or `seed`).
+    pub datasource: String,
+}
+
+/// An extra part of a multi-part user-data archive.
+///
+/// The contents of each part are kept in a file in the VM's data directory so
+/// the exact same payload can be rendered again later on.
+///
+/// ---
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserDataPart {
+    /// The MIME subtype (`x-shellscript`, `cloud-boothook`, `part-handler`,
+    /// `x-include-url`) which is used as `text/<part_type>`.
+    pub part_type: String,
+    pub filename: String,
+    pub path: String,
+}
+
+/// Builds the cloud config for a new VM from the user details and the extra
+/// options given to the create command.
+///
+/// The ssh key is the *contents* of the public key file (not the path) and can
+/// be empty if no key was given.
+///
+/// `--write-file` args are read here (in the `src:dst` format) so the contents
+/// of the local file end up in the cloud config.
+///
+/// The admin group, shell and how the guest agent is started come from the
+/// image's OS family.
+///
+/// ---
+pub fn build_cloud_config(
+    user: &str,
+    pass: &str,
+    ssh_key: &str,
+    options: &UserDataOptions,
+    os_family: &catalog::OsFamily,
+) -> Result<CloudConfig, Box<dyn Error>> {
+    let mut ssh_authorized_keys = Vec::new();
+    if !ssh_key.trim().is_empty() {
+        ssh_authorized_keys.push(ssh_key.trim().to_string());
+    }
+
+    let mut write_files = Vec::new();
+    for spec in &options.write_files {
+        write_files.push(parse_write_file(spec)?);
+    }
+
+    let mut mounts = Vec::new();
+    for spec in &options.mounts {
+        mounts.push(parse_mount(spec)?);
+    }
+
+    // autovirt uses the guest agent for ips, guest-exec, clean shutdowns etc.
+    let mut packages = options.packages.clone();
+    if !packages.iter().any(|p| p == "qemu-guest-agent") {
+        packages.push("qemu-guest-agent".to_string());
+    }
+    let mut runcmd = os_family.enable_service_commands("qemu-guest-agent");
+    runcmd.extend(options.runcmd.iter().cloned());
+
+    Ok(CloudConfig {
+        hostname: options.hostname.clone(),
+        timezone: options.timezone.clone(),
+        users: vec![CloudUser {
+            name: user.to_string(),
+            plain_text_passwd: pass.to_string(),
+            lock_passwd: false,
+            sudo: "ALL=(ALL) NOPASSWD:ALL".to_string(),
+            groups: os_family.admin_group.clone(),
+            shell: os_family.shell.clone(),
+            ssh_authorized_keys,
+        }],
+        chpasswd: ChPasswd::default(),
+        package_update: Some(true),
+        packages,
+        runcmd,
+        write_files,
+        mounts,
+        ..Default::default()
+    })
+}
+
+/// Adds what's needed to partition, format and mount a data disk on first boot
+/// to a cloud config. The disk gets one partition with a filesystem labeled
+/// `label` which is mounted by that label.
+///
+/// ---
+pub fn add_data_disk_setup(config: &mut CloudConfig, device: &str, label: &str, filesystem: &str, mountpoint: &str) {
+    config.disk_setup.insert(
+        device.to_string(),
+        DiskSetup {
+            table_type: "gpt".to_string(),
+            layout: true,
+            overwrite: false,
+        },
+    );
+    config.fs_setup.push(FsSetup {
+        label: label.to_string(),
+        filesystem: filesystem.to_string(),
+        device: device.to_string(),
+        partition: "auto".to_string(),
+    });
+    config.mounts.push(vec![
+        format!("LABEL={}", label),
+        mountpoint.to_string(),
+        filesystem.to_string(),
+        "defaults,nofail".to_string(),
+        "0".to_string(),
+        "2".to_string(),
+    ]);
+}
+
+/// Removes everything `add_data_disk_setup` added for a disk.
+pub fn remove_data_disk_setup(config: &mut CloudConfig, device: &str, label: &str) {
+    config.disk_setup.remove(device);
+    config.fs_setup.retain(|fs| fs.device != device);
+    let label_device = format!("LABEL={}", label);
+    config.mounts.retain(|mount| mount.first() != Some(&label_device));
+}
+
+/// Gets the cloud config stored in a VM's entry in the autovirt config file.
+pub fn load_vm_cloud_config(vm_name: &str) -> Result<CloudConfig, Box<dyn Error>> {
+    match filesystem::get_value_from_autovirt_json(&format!("vms.{}.cloud_config", vm_name)) {
+        Some(value) => Ok(serde_json::from_value(value)?),
+        None => Err(Box::new(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("VM '{}' has no cloud config (created with an older autovirt?)", vm_name),
+        ))),
+    }
+}
+
+/// Stores the cloud config in a VM's entry in the autovirt config file.
+pub fn save_vm_cloud_config(vm_name: &str, config: &CloudConfig) {
+    filesystem::insert_value_into_autovirt_json_object(
+        &format!("vms.{}.cloud_config", vm_name),
+        serde_json::to_value(config).expect("ERROR: Failed to jsonify cloud config"),
+    );
+}
+
+/// Parses a `--write-file` arg (`src:dst`) into a `write_files` entry by
+/// reading the local `src` file. The permissions of the local file are kept.
+///
+/// ---
+fn parse_write_file(spec: &str) -> Result<WriteFile, Box<dyn Error>> {
+    let (src, dst) = match spec.split_once(':') {
+        Some((src, dst)) if !src.is_empty() && dst.starts_with('/') => (src, dst),
+        _ => {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid --write-file '{}' (expected src:/absolute/dst)", spec),
+            )));
+        }
+    };
+
+    let content = fs::read_to_string(src)
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to read '{}' -> {}", src, e)))?;
+
+    let permissions = {
+        use std::os::unix::fs::PermissionsExt;
+        fs::metadata(src)
+            .ok()
+            .map(|m| format!("0{:o}", m.permissions().mode() & 0o777))
+    };
+
+    Ok(WriteFile {
+        path: dst.to_string(),
+        content,
+        permissions,
+    })
+}
+
+/// Parses a `--mount` arg (`device:mountpoint[:fstype[:options]]`) into a
+/// cloud-init `mounts` entry.
+///
+/// The device can have `:` in it (NFS `host:/export`, `[::1]:/export`) so the
+/// mountpoint is the last field that's an absolute path, the fstype and
+/// options never start with a `/`.
+///
+/// ---
+fn parse_mount(spec: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let parts: Vec<&str> = spec.split(':').collect();
+    let mountpoint_index = parts.iter().skip(1).rposition(|part| part.starts_with('/')).map(|i| i + 1);
+    let (device, mountpoint, rest) = match mountpoint_index {
+        Some(i) if parts.len() - i <= 3 && !parts[..i].join(":").is_empty() => {
+            (parts[..i].join(":"), parts[i], &parts[i + 1..])
+        }
+        _ => {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid --mount '{}' (expected device:/mountpoint[:fstype[:options]])", spec),
+            )));
+        }
+    };
+
+    Ok(vec![
+        device,
+        mountpoint.to_string(),
+        rest.first().unwrap_or(&"auto").to_string(),
+        rest.get(1).unwrap_or(&"defaults,nofail").to_string(),
+        "0".to_string(),
+        "2".to_string(),
+    ])
+}
+
+/// Renders the final user-data file contents (with the `#cloud-config` header)
+/// for a cloud config.
+///
+/// If `extra_user_data` is given (the contents of a `--user-data-file`) it is
+/// merged on top of the generated config:
+///
+/// - mappings are merged key by key
+/// - lists are appended to (so extra `packages`/`runcmd` etc. are added)
+/// - `users` entries with the same `name` as a generated user are merged into
+///   it (i.e. to give the VM's user more groups or keys), other users are added
+/// - everything else is replaced by the value in the user's file
+///
+/// ---
+pub fn render_user_data(
+    config: &CloudConfig,
+    extra_user_data: Option<&str>,
+) -> Result<String, Box<dyn Error>> {
+    let mut user_data = serde_yaml::to_value(config)?;
+
+    if let Some(extra) = extra_user_data {
+        let extra_value: YamlValue = serde_yaml::from_str(extra)?;
+        match extra_value {
+            YamlValue::Mapping(_) => merge_yaml(&mut user_data, extra_value),
+            // an empty file (or one with only comments) is just nothing
+            YamlValue::Null => {}
+            _ => {
+                return Err(Box::new(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "The user-data file must be a cloud-config yaml mapping",
+                )));
+            }
+        }
+    }
+
+    Ok(format!("#cloud-config\n{}", serde_yaml::to_string(&user_data)?))
+}
+
+/// Merges `overlay` into `base` (see `render_user_data` for the rules).
+fn merge_yaml(base: &mut YamlValue, overlay: YamlValue) {
+    match (base, overlay) {
+        (YamlValue::Mapping(base_map), YamlValue::Mapping(overlay_map)) => {
+            for (key, value) in overlay_map {
+                match (base_map.get_mut(&key), value) {
+                    (Some(YamlValue::Sequence(base_users)), YamlValue::Sequence(users)) if key.as_str() == Some("users") => {
+                        merge_users(base_users, users);
+                    }
+                    (Some(existing), value) => merge_yaml(existing, value),
+                    (None, value) => {
+                        base_map.insert(key, value);
+                    }
+                }
+            }
+        }
+        (YamlValue::Sequence(base_seq), YamlValue::Sequence(overlay_seq)) => {
+            base_seq.extend(overlay_seq);
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Merges the `users` of the user's file into the generated ones by name.
+fn merge_users(base_users: &mut Vec<YamlValue>, overlay_users: Vec<YamlValue>) {
+    for user in overlay_users {
+        let name = user.get("name").cloned();
+        match name.and_then(|name| base_users.iter_mut().find(|u| u.get("name") == Some(&name))) {
+            Some(existing) => merge_yaml(existing, user),
+            None => base_users.push(user),
+        }
+    }
+}
+
+/// Reads a `--user-data-file` making sure that it is actually valid yaml before
+/// anything else is done with the VM.
+///
+/// ---
+pub fn read_user_data_file(path: &Path) -> Result<String, Box<dyn Error>> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to read {:?} -> {}", path, e)))?;
+    serde_yaml::from_str::<YamlValue>(&contents)?;
+    Ok(contents)
+}
+
+/// Copies the extra parts given to the create command (scripts, boothooks,
+/// part handlers and include urls) into the VM's data directory and returns
+/// the parts to be stored in the VM's entry in the config file.
+///
+/// Include urls are all put into a single `#include` part.
+///
+/// ---
+pub fn collect_user_data_parts(
+    vm_data_dir: &Path,
+    options: &UserDataOptions,
+) -> Result<Vec<UserDataPart>, Box<dyn Error>> {
+    let parts_dir = vm_data_dir.join("parts");
+    fs::create_dir_all(&parts_dir)?;
+
+    let mut parts = Vec::new();
+    let file_parts = options.scripts.iter().map(|p| ("x-shellscript", p))
+        .chain(options.boothooks.iter().map(|p| ("cloud-boothook", p)))
+        .chain(options.part_handlers.iter().map(|p| ("part-handler", p)));
+
+    for (part_type, src) in file_parts {
+        let src_path = Path::new(src);
+        let basename = src_path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| "part".to_string());
+        let filename = format!("{:02}-{}", parts.len(), basename);
+        let dst_path = parts_dir.join(&filename);
+
+        fs::copy(src_path, &dst_path)
+            .map_err(|e| io::Error::new(e.kind(), format!("Failed to copy '{}' -> {}", src, e)))?;
+
+        parts.push(UserDataPart {
+            part_type: part_type.to_string(),
+            filename: basename,
+            path: dst_path.to_string_lossy().to_string(),
+        });
+    }
+
+    if !options.include_urls.is_empty() {
+        let filename = format!("{:02}-include.txt", parts.len());
+        let dst_path = parts_dir.join(&filename);
+        fs::write(&dst_path, format!("#include\n{}\n", options.include_urls.join("\n")))?;
+        parts.push(UserDataPart {
+            part_type: "x-include-url".to_string(),
+            filename: "include.txt".to_string(),
+            path: dst_path.to_string_lossy().to_string(),
+        });
+    }
+
+    Ok(parts)
+}
+
+/// Makes sure all the part files given to the create command can actually be
+/// read (and that a seed image can be made if needed) before the VM is
+/// created.
+///
+/// ---
+pub fn check_user_data_options(options: &UserDataOptions) -> Result<(), Box<dyn Error>> {
+    for src in options.scripts.iter().chain(&options.boothooks).chain(&options.part_handlers) {
+        fs::read(src).map_err(|e| io::Error::new(e.kind(), format!("Failed to read '{}' -> {}", src, e)))?;
+    }
+
+    if options.datasource == "seed" && seed_image_tool().is_none() {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::NotFound,
+            "None of cloud-localds, genisoimage, mkisofs or xorriso are installed (needed for the seed datasource)",
+        )));
+    }
+
+    Ok(())
+}
+
+/// Builds a multi-part MIME archive from the cloud-config and extra parts.
+///
+/// The boundary is picked so that it doesn't show up in any of the parts.
+///
+/// ---
+pub fn render_multipart(cloud_config: &str, parts: &[(String, String, String)]) -> String {
+    let mut boundary = "==AUTOVIRT-BOUNDARY==".to_string();
+    let mut counter = 0;
+    while cloud_config.contains(&boundary) || parts.iter().any(|(_, _, c)| c.contains(&boundary)) {
+        counter += 1;
+        boundary = format!("==AUTOVIRT-BOUNDARY-{}==", counter);
+    }
+
+    let mut payload = format!(
+        "Content-Type: multipart/mixed; boundary=\"{}\"\nMIME-Version: 1.0\n\n",
+        boundary
+    );
+
+    let all_parts = std::iter::once(("cloud-config", "cloud-config.txt", cloud_config))
+        .chain(parts.iter().map(|(t, f, c)| (t.as_str(), f.as_str(), c.as_str())));
+
+    for (part_type, filename, content) in all_parts {
+        let encoding = if content.is_ascii() { "7bit" } else { "8bit" };
+        payload.push_str(&format!("--{}\n", boundary));
+        payload.push_str(&format!("Content-Type: text/{}; charset=\"utf-8\"\n", part_type));
+        payload.push_str("MIME-Version: 1.0\n");
+        payload.push_str(&format!("Content-Transfer-Encoding: {}\n", encoding));
+        payload.push_str(&format!("Content-Disposition: attachment; filename=\"{}\"\n\n", filename));
+        payload.push_str(content);
+        if !content.ends_with('\n') {
+            payload.push('\n');
+        }
+    }
+    payload.push_str(&format!("--{}--\n", boundary));
+
+    payload
+}
+
+/// Renders the exact user-data payload for an existing VM from its entry in
+/// the autovirt config file and the files in its data directory.
+///
+/// This is a plain `#cloud-config` if the VM has no extra parts and a
+/// multi-part MIME archive if it does.
+///
+/// ---
+pub fn render_vm_user_data(vm_name: &str) -> Result<String, Box<dyn Error>> {
+    let vm_data = filesystem::get_value_from_autovirt_json(&format!("vms.{}", vm_name))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("VM '{}' not found", vm_name)))?;
+
+    let cloud_config = load_vm_cloud_config(vm_name)?;
+
+    // only the VMs made with a user-data file of their own have one, a stray
+    // `user-data-extra` left in the directory isn't merged in
+    let extra_user_data = if vm_data.get("user_data_extra").and_then(|v| v.as_bool()).unwrap_or(false) {
+        let extra_path = filesystem::vm_data_dir_path(vm_name)?.join("user-data-extra");
+        Some(fs::read_to_string(&extra_path)
+            .map_err(|e| io::Error::new(e.kind(), format!("Failed to read '{}' -> {}", extra_path.display(), e)))?)
+    } else {
+        None
+    };
+    let rendered_cloud_config = render_user_data(&cloud_config, extra_user_data.as_deref())?;
+
+    let parts: Vec<UserDataPart> = match vm_data.get("cloud_init_parts") {
+        Some(value) => serde_json::from_value(value.clone())?,
+        None => Vec::new(),
+    };
+
+    if parts.is_empty() {
+        return Ok(rendered_cloud_config);
+    }
+
+    let mut part_contents = Vec::new();
+    for part in parts {
+        let content = fs::read_to_string(&part.path)
+            .map_err(|e| io::Error::new(e.kind(), format!("Failed to read part '{}' -> {}", part.path, e)))?;
+        part_contents.push((part.part_type, part.filename, content));
+    }
+
+    Ok(render_multipart(&rendered_cloud_config, &part_contents))
+}
+
+/// Renders the meta-data for a VM (the instance id is what cloud-init uses to
+/// tell if it's the first boot of an instance).
+///
+/// ---
+pub fn render_vm_meta_data(vm_name: &str) -> String {
+    let hostname = filesystem::get_value_from_autovirt_json(&format!("vms.{}.cloud_config.hostname", vm_name))
+        .and_then(|v| v.as_str().map(String::from))
+        .unwrap_or_else(|| vm_name.to_string());
+
+    format!("instance-id: autovirt-{}\nlocal-hostname: {}\n", vm_name, hostname)
+}
+
+/// Writes the user-data, meta-data and vendor-data for a VM to its data
+/// directory (which is served by the imds server under `/<vm name>/`).
+///
+/// If the VM uses the `seed` datasource then a NoCloud seed image (`cidata`
+/// iso) is also made from those files and its path is returned so it can be
+/// attached to the VM.
+///
+/// ---
+pub fn write_vm_cloud_init_files(vm_name: &str) -> Result<Option<PathBuf>, Box<dyn Error>> {
+    let vm_data_dir = filesystem::get_vm_data_dir(vm_name)?;
+
+    fs::write(vm_data_dir.join("user-data"), render_vm_user_data(vm_name)?)?;
+    fs::write(vm_data_dir.join("meta-data"), render_vm_meta_data(vm_name))?;
+    fs::write(vm_data_dir.join("vendor-data"), "")?;
+
+    let datasource = filesystem::get_value_from_autovirt_json(&format!("vms.{}.datasource", vm_name))
+        .and_then(|v| v.as_str().map(String::from))
+        .unwrap_or_else(|| "imds".to_string());
+
+    if datasource != "seed" {
+        return Ok(None);
+    }
+
+    let seed_path = vm_data_dir.join("seed.iso");
+    make_seed_image(&vm_data_dir, &seed_path)?;
+    Ok(Some(seed_path))
+}
+
+/// Makes a NoCloud seed image from the cloud-init files in a directory using
+/// whichever of `cloud-localds`, `genisoimage`, `mkisofs` or `xorriso` is
+/// installed.
+///
+/// ---
+fn make_seed_image(files_dir: &Path, seed_path: &Path) -> Result<(), Box<dyn Error>> {
+    let user_data = files_dir.join("user-data");
+    let meta_data = files_dir.join("meta-data");
+    let vendor_data = files_dir.join("vendor-data");
+
+    let tool = seed_image_tool().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "None of cloud-localds, genisoimage, mkisofs or xorriso are installed (needed for the seed datasource)",
+        )
+    })?;
+
+    let mut cmd = if tool == "cloud-localds" {
+        let mut cmd = Command::new("cloud-localds");
+        cmd.arg(format!("--vendor-data={}", vendor_data.to_string_lossy()))
+            .arg(seed_path)
+            .arg(&user_data)
+            .arg(&meta_data);
+        cmd
+    } else {
+        let mut cmd = Command::new(tool);
+        if tool == "xorriso" {
+            cmd.arg("-as").arg("mkisofs");
+        }
+        cmd.arg("-output")
+            .arg(seed_path)
+            .arg("-volid")
+            .arg("cidata")
+            .arg("-joliet")
+            .arg("-rock")
+            .arg(&user_data)
+            .arg(&meta_data)
+            .arg(&vendor_data);
+        cmd
+    };
+
+    let output = cmd.output()?;
+    if !output.status.success() {
+        return Err(Box::new(io::Error::other(format!(
+            "Failed to make seed image -> {}",
+            String::from_utf8_lossy(&output.stderr)
+        ))));
+    }
+
+    Ok(())
+}
+
+/// Gets the first tool that can make seed images which is installed.
+fn seed_image_tool() -> Option<&'static str> {
+    ["cloud-localds", "genisoimage", "mkisofs", "xorriso"]
+        .into_iter()
+        .find(|tool| command_exists(tool))
+}
+
+/// Checks if a command is available in the user's $PATH.
+pub fn command_exists(cmd: &str) -> bool {
+    Command::new("sh")
+        .arg("-c")
+        .arg(format!("command -v {} >/dev/null 2>&1", cmd))
+        .status()
+        .map(|s| s.success())
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config(pass: &str) -> CloudConfig {
+        build_cloud_config("tester", pass, "ssh-ed25519 AAAA test", &UserDataOptions::default(), &catalog::OsFamily::default())
+            .unwrap()
+    }
+
+    fn parse_user_data(user_data: &str) -> YamlValue {
+        let yaml = user_data.strip_prefix("#cloud-config\n").expect("no #cloud-config header");
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn password_with_yaml_characters_round_trips() {
+        let pass = r#"p: a#s 's' "w"d: - [x] {y} & *z !"#;
+        let user_data = render_user_data(&test_config(pass), None).unwrap();
+
+        let config: CloudConfig = serde_yaml::from_value(parse_user_data(&user_data)).unwrap();
+        assert_eq!(config.users.len(), 1);
+        assert_eq!(config.users[0].name, "tester");
+        assert_eq!(config.users[0].plain_text_passwd, pass);
+        assert_eq!(config.users[0].ssh_authorized_keys, vec!["ssh-ed25519 AAAA test"]);
+    }
+
+    #[test]
+    fn merge_yaml_maps_and_sequences() {
+        let mut base: YamlValue = serde_yaml::from_str(
+            "packages: [curl]\nchpasswd: {expire: false}\nhostname: base\nwrite_files: [{path: /a, content: a}]",
+        )
+        .unwrap();
+        let overlay: YamlValue = serde_yaml::from_str(
+            "packages: [htop, curl]\nchpasswd: {list: [x]}\nhostname: [not, a, string]\nntp: {enabled: true}",
+        )
+        .unwrap();
+        merge_yaml(&mut base, overlay);
+
+        let expected: YamlValue = serde_yaml::from_str(
+            "packages: [curl, htop, curl]\nchpasswd: {expire: false, list: [x]}\nhostname: [not, a, string]\n\
+             write_files: [{path: /a, content: a}]\nntp: {enabled: true}",
+        )
+        .unwrap();
+        assert_eq!(base, expected);
+    }
+
+    #[test]
+    fn user_data_users_merge_by_name() {
+        let extra = "users:\n  - name: tester\n    groups: docker\n    ssh_authorized_keys: [ssh-rsa BBBB extra]\n  - name: other\n    shell: /bin/sh\n";
+        let user_data = parse_user_data(&render_user_data(&test_config("pw"), Some(extra)).unwrap());
+
+        let users = user_data["users"].as_sequence().unwrap();
+        assert_eq!(users.len(), 2, "{:?}", users);
+        // the VM's own user keeps its password and gets the extra bits
+        assert_eq!(users[0]["name"], "tester");
+        assert_eq!(users[0]["plain_text_passwd"], "pw");
+        assert_eq!(users[0]["groups"], "docker");
+        let keys: Vec<&str> = users[0]["ssh_authorized_keys"].as_sequence().unwrap().iter().map(|k| k.as_str().unwrap()).collect();
+        assert_eq!(keys, ["ssh-ed25519 AAAA test", "ssh-rsa BBBB extra"]);
+        assert_eq!(users[1]["name"], "other");
+        assert_eq!(users[1]["shell"], "/bin/sh");
+    }
+
+    #[test]
+    fn user_data_must_be_a_mapping() {
+        assert!(render_user_data(&test_config("pw"), Some("- just\n- a list\n")).is_err());
+        assert!(render_user_data(&test_config("pw"), Some("# only a comment\n")).is_ok());
+    }
+
+    #[test]
+    fn parse_mount_specs() {
+        assert_eq!(parse_mount("/dev/vdb1:/data").unwrap(), ["/dev/vdb1", "/data", "auto", "defaults,nofail", "0", "2"]);
+        assert_eq!(
+            parse_mount("LABEL=logs:/var/log/app:ext4:noatime").unwrap(),
+            ["LABEL=logs", "/var/log/app", "ext4", "noatime", "0", "2"]
+        );
+        // the device of an nfs mount has a `:` of its own
+        assert_eq!(
+            parse_mount("nas:/export/home:/home:nfs:rw,soft").unwrap(),
+            ["nas:/export/home", "/home", "nfs", "rw,soft", "0", "2"]
+        );
+        assert_eq!(parse_mount("[fd00::1]:/export:/mnt").unwrap()[..2], ["[fd00::1]:/export", "/mnt"]);
+
+        for bad in ["/dev/vdb1", ":/data", "/dev/vdb1:data", "/dev/vdb1:/data:ext4:defaults:extra", ""] {
+            assert!(parse_mount(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn vm_user_data_only_merges_recorded_extra() {
+        let _home = filesystem::test_home();
+        let vm_name = "cloudinitvm";
+        filesystem::insert_value_into_autovirt_json_object(
+            &format!("vms.{}", vm_name),
+            serde_json::json!({
+                "name": vm_name,
+                "cloud_config": serde_json::to_value(test_config("pw")).unwrap(),
+            }),
+        );
+        let vm_data_dir = filesystem::get_vm_data_dir(vm_name).unwrap();
+        fs::write(vm_data_dir.join("user-data-extra"), "packages: [stray]\n").unwrap();
+
+        let user_data = render_vm_user_data(vm_name).unwrap();
+        assert!(!user_data.contains("stray"), "{}", user_data);
+
+        filesystem::insert_value_into_autovirt_json_object(&format!("vms.{}.user_data_extra", vm_name), serde_json::json!(true));
+        let user_data = render_vm_user_data(vm_name).unwrap();
+        assert!(user_data.contains("stray"), "{}", user_data);
+    }
+}
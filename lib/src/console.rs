@@ -0,0 +1,457 @@
+//! This file contains things related to a VM's serial console.
+//!
+//! The serial port of every VM is a unix socket (`serial.sock` in its data
+//! directory) which a small logger process (`autovirt serial-logger`, started
+//! along with the VM) stays connected to. The logger writes everything the VM
+//! prints to `console.log` (with a timestamp per line, rotated once it gets
+//! big) and passes the console through to whoever is attached to
+//! `console.sock` with `autovirt console`.
+//!
+//! Foreground runs (create and `run` without `--detach`) just attach to the
+//! console once the VM is up so detaching from them leaves the VM running.
+//!
+//! ---
+
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::filesystem;
+
+/// Ctrl-] detaches from a console (like telnet).
+pub const DETACH_KEY: u8 = 0x1d;
+
+/// `console.log` is rotated once it's bigger than this.
+const MAX_LOG_BYTES: u64 = 10 * 1024 * 1024;
+
+/// The number of rotated logs kept (`console.log.1` is the newest).
+const KEPT_LOGS: usize = 5;
+
+fn serial_socket_path(vm_name: &str) -> io::Result<PathBuf> {
+    Ok(filesystem::get_vm_data_dir(vm_name)?.join("serial.sock"))
+}
+
+fn console_socket_path(vm_name: &str) -> io::Result<PathBuf> {
+    Ok(filesystem::get_vm_data_dir(vm_name)?.join("console.sock"))
+}
+
+fn console_log_path(vm_name: &str) -> io::Result<PathBuf> {
+    Ok(filesystem::get_vm_data_dir(vm_name)?.join("console.log"))
+}
+
+/// The qemu args for a VM's serial port.
+pub fn serial_args(vm_name: &str) -> io::Result<Vec<String>> {
+    let socket_path = filesystem::vm_data_dir_path(vm_name)?.join("serial.sock");
+    Ok(vec![
+        "-chardev".to_string(),
+        format!("socket,id=serial0,path={},server=on,wait=off", socket_path.to_string_lossy()),
+        "-serial".to_string(),
+        "chardev:serial0".to_string(),
+    ])
+}
+
+/// Removes a VM's serial socket left from when it last ran before it's
+/// started (a stale socket would make the logger connect to nothing).
+///
+/// ---
+pub fn remove_stale_serial_socket(vm_name: &str) -> io::Result<()> {
+    match fs::remove_file(serial_socket_path(vm_name)?) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Starts the serial logger for a VM that was just started (once qemu has made
+/// the serial socket).
+///
+/// ---
+pub fn spawn_serial_logger(vm_name: &str) -> Result<(), Box<dyn Error>> {
+    let socket_path = serial_socket_path(vm_name)?;
+    let started = Instant::now();
+    while !socket_path.exists() {
+        if started.elapsed() > Duration::from_secs(10) {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("qemu didn't make the serial socket {:?}", socket_path),
+            )));
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    // its own process group so it isn't killed with the terminal
+    Command::new(std::env::current_exe()?)
+        .arg("serial-logger")
+        .arg(vm_name)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .process_group(0)
+        .spawn()?;
+    Ok(())
+}
+
+/// Runs a VM's qemu command in the foreground with the console attached.
+/// Gives back the exit status of qemu or `None` if the user detached (the VM
+/// keeps running then).
+///
+/// ---
+pub fn run_attached(vm_name: &str, qemu_cmd: &mut Command) -> Result<Option<ExitStatus>, Box<dyn Error>> {
+    let mut qemu = qemu_cmd.process_group(0).spawn()?;
+    attach_child(vm_name, &mut qemu)
+}
+
+/// Attaches the console of a VM whose hypervisor was just started (once it
+/// has made the serial socket), see `run_attached`.
+///
+/// ---
+pub fn attach_child(vm_name: &str, hypervisor: &mut Child) -> Result<Option<ExitStatus>, Box<dyn Error>> {
+    // the hypervisor may fail before it gets as far as making the serial
+    // socket
+    let socket_path = serial_socket_path(vm_name)?;
+    while !socket_path.exists() {
+        if let Some(status) = hypervisor.try_wait()? {
+            return Ok(Some(status));
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    spawn_serial_logger(vm_name)?;
+
+    if attach_console(vm_name)? {
+        return Ok(None);
+    }
+    Ok(Some(hypervisor.wait()?))
+}
+
+/// Opens `console.log` for appending (rotating it first if it's too big).
+fn open_console_log(log_path: &Path) -> io::Result<File> {
+    if fs::metadata(log_path).map(|m| m.len() > MAX_LOG_BYTES).unwrap_or(false) {
+        for i in (1..KEPT_LOGS).rev() {
+            let _ = fs::rename(rotated_log_path(log_path, i), rotated_log_path(log_path, i + 1));
+        }
+        fs::rename(log_path, rotated_log_path(log_path, 1))?;
+    }
+    OpenOptions::new().create(true).append(true).open(log_path)
+}
+
+fn rotated_log_path(log_path: &Path, index: usize) -> PathBuf {
+    PathBuf::from(format!("{}.{}", log_path.to_string_lossy(), index))
+}
+
+/// Runs the serial logger of a VM (the hidden `serial-logger` command). This
+/// keeps going until the VM's serial socket closes (the VM stopped).
+///
+/// ---
+pub fn run_serial_logger(vm_name: &str) -> Result<(), Box<dyn Error>> {
+    let mut serial = UnixStream::connect(serial_socket_path(vm_name)?)?;
+    let log_path = console_log_path(vm_name)?;
+
+    let console_path = console_socket_path(vm_name)?;
+    let _ = fs::remove_file(&console_path);
+    let listener = UnixListener::bind(&console_path)?;
+
+    // only one console can be attached, attaching again takes it over
+    let attached: Arc<Mutex<Option<UnixStream>>> = Arc::new(Mutex::new(None));
+    {
+        let attached = Arc::clone(&attached);
+        let serial = serial.try_clone()?;
+        thread::spawn(move || {
+            for client in listener.incoming().flatten() {
+                let Ok(mut input) = client.try_clone() else { continue };
+                if let Some(old) = attached.lock().unwrap().replace(client) {
+                    let _ = old.shutdown(std::net::Shutdown::Both);
+                }
+                let Ok(mut serial) = serial.try_clone() else { continue };
+                thread::spawn(move || {
+                    let _ = io::copy(&mut input, &mut serial);
+                });
+            }
+        });
+    }
+
+    let mut log = open_console_log(&log_path)?;
+    let mut at_line_start = true;
+    let mut buffer = [0u8; 4096];
+    loop {
+        let read = match serial.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(read) => read,
+        };
+        let output = &buffer[..read];
+
+        let mut attached = attached.lock().unwrap();
+        if let Some(client) = attached.as_mut() {
+            if client.write_all(output).is_err() {
+                *attached = None;
+            }
+        }
+        drop(attached);
+
+        let mut line = Vec::with_capacity(output.len() + 32);
+        for &byte in output {
+            if at_line_start {
+                line.extend_from_slice(format!("[{}] ", timestamp()).as_bytes());
+                at_line_start = false;
+            }
+            match byte {
+                b'\r' => {}
+                b'\n' => {
+                    line.push(b'\n');
+                    at_line_start = true;
+                }
+                _ => line.push(byte),
+            }
+        }
+        log.write_all(&line)?;
+        if at_line_start && log.metadata().map(|m| m.len() > MAX_LOG_BYTES).unwrap_or(false) {
+            log = open_console_log(&log_path)?;
+        }
+    }
+
+    if let Some(client) = attached.lock().unwrap().take() {
+        let _ = client.shutdown(std::net::Shutdown::Both);
+    }
+    let _ = fs::remove_file(&console_path);
+    Ok(())
+}
+
+fn timestamp() -> String {
+    chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, false)
+}
+
+/// Puts the terminal in raw mode and gives back what to restore it with
+/// (`None` if stdin isn't a terminal).
+///
+/// ---
+fn terminal_raw_mode() -> Option<String> {
+    let saved = Command::new("stty").arg("-g").stdin(Stdio::inherit()).stderr(Stdio::null()).output().ok()?;
+    if !saved.status.success() {
+        return None;
+    }
+    Command::new("stty").args(["raw", "-echo"]).stdin(Stdio::inherit()).status().ok()?;
+    Some(String::from_utf8_lossy(&saved.stdout).trim().to_string())
+}
+
+fn restore_terminal(saved: &str) {
+    let _ = Command::new("stty").arg(saved).stdin(Stdio::inherit()).status();
+}
+
+/// Attaches the terminal to a running VM's console until Ctrl-] is pressed or
+/// the VM stops. Gives back if the user detached.
+///
+/// ---
+pub fn attach_console(vm_name: &str) -> Result<bool, Box<dyn Error>> {
+    let console_path = console_socket_path(vm_name)?;
+    let started = Instant::now();
+    let console = loop {
+        match UnixStream::connect(&console_path) {
+            Ok(console) => break console,
+            // the logger may still be starting
+            Err(_) if started.elapsed() < Duration::from_secs(5) => thread::sleep(Duration::from_millis(100)),
+            Err(e) => {
+                return Err(Box::new(io::Error::new(
+                    e.kind(),
+                    format!("Failed to attach to the console of VM '{}' (is it running?) -> {}", vm_name, e),
+                )))
+            }
+        }
+    };
+
+    println!("LOG:: Attached to the console of VM {} (Ctrl-] to detach)", vm_name);
+    let saved_terminal = terminal_raw_mode();
+
+    // the VM's output goes straight to the terminal
+    let mut output = console.try_clone()?;
+    let output_thread = thread::spawn(move || {
+        let mut stdout = io::stdout();
+        let mut buffer = [0u8; 4096];
+        while let Ok(read) = output.read(&mut buffer) {
+            if read == 0 || stdout.write_all(&buffer[..read]).and_then(|_| stdout.flush()).is_err() {
+                break;
+            }
+        }
+    });
+
+    // stdin is read on its own thread so the VM stopping ends this even if
+    // nothing is typed
+    let mut input = console.try_clone()?;
+    let (detach_tx, detach_rx) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let mut stdin = io::stdin();
+        let mut buffer = [0u8; 1024];
+        while let Ok(read) = stdin.read(&mut buffer) {
+            if read == 0 {
+                // no more input (not a terminal), keep showing the output
+                return;
+            }
+            let typed = &buffer[..read];
+            if let Some(position) = typed.iter().position(|&b| b == DETACH_KEY) {
+                let _ = input.write_all(&typed[..position]);
+                let _ = detach_tx.send(());
+                return;
+            }
+            if input.write_all(typed).is_err() {
+                return;
+            }
+        }
+    });
+
+    let detached = loop {
+        if detach_rx.try_recv().is_ok() {
+            break true;
+        }
+        if output_thread.is_finished() {
+            break false;
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    let _ = console.shutdown(std::net::Shutdown::Both);
+
+    if let Some(saved) = saved_terminal {
+        restore_terminal(&saved);
+    }
+    println!();
+    if detached {
+        println!("LOG:: Detached from the console of VM {} (it's still running)", vm_name);
+    } else {
+        println!("LOG:: The console of VM {} closed", vm_name);
+    }
+    Ok(detached)
+}
+
+/// Parses `--since` (an RFC3339 time or how long ago like 10m, 2h, 1d).
+fn parse_since(since: &str) -> Result<chrono::DateTime<chrono::FixedOffset>, Box<dyn Error>> {
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(since) {
+        return Ok(time);
+    }
+
+    let invalid = || -> Box<dyn Error> {
+        Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid --since '{}' (i.e. -> 30s, 10m, 2h, 1d or 2024-01-31T12:00:00+00:00)", since),
+        ))
+    };
+    let (amount, unit) = since.split_at(since.len().saturating_sub(1));
+    let amount: i64 = amount.parse().map_err(|_| invalid())?;
+    let seconds = match unit {
+        "s" => amount,
+        "m" => amount * 60,
+        "h" => amount * 60 * 60,
+        "d" => amount * 60 * 60 * 24,
+        _ => return Err(invalid()),
+    };
+    Ok((chrono::Local::now() - chrono::Duration::seconds(seconds)).fixed_offset())
+}
+
+/// Gets the time at the start of a `console.log` line.
+fn line_time(line: &str) -> Option<chrono::DateTime<chrono::FixedOffset>> {
+    let end = line.find("] ")?;
+    chrono::DateTime::parse_from_rfc3339(line.get(1..end)?).ok()
+}
+
+/// Reads the lines of a console log. The console gets whatever the VM writes
+/// so bytes that aren't UTF-8 are replaced instead of failing the read.
+///
+/// ---
+fn log_lines(mut reader: impl BufRead) -> impl Iterator<Item = io::Result<String>> {
+    std::iter::from_fn(move || {
+        let mut line = Vec::new();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) => None,
+            Ok(_) => Some(Ok(String::from_utf8_lossy(&line).trim_end_matches(['\n', '\r']).to_string())),
+            Err(e) => Some(Err(e)),
+        }
+    })
+}
+
+/// Finds the first console log line from `since` on that has `text` in it
+/// (the last rotated log is looked at too in case it was just rotated).
+///
+/// ---
+pub fn find_log_line(vm_name: &str, since: chrono::DateTime<chrono::FixedOffset>, text: &str) -> Option<String> {
+    let log_path = console_log_path(vm_name).ok()?;
+    [rotated_log_path(&log_path, 1), log_path]
+        .iter()
+        .filter_map(|path| File::open(path).ok())
+        .flat_map(|file| log_lines(BufReader::new(file)).map_while(Result::ok))
+        .find(|line| line.contains(text) && line_time(line).is_some_and(|time| time >= since))
+}
+
+/// Prints a VM's console log (the rotated ones too), optionally only from a
+/// point in time, and keeps printing new lines with `follow`.
+///
+/// ---
+pub fn show_logs(vm_name: &str, follow: bool, since: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let since = since.map(parse_since).transpose()?;
+    let log_path = console_log_path(vm_name)?;
+    if filesystem::get_value_from_autovirt_json(&format!("vms.{}", vm_name)).is_none() {
+        return Err(Box::new(io::Error::new(io::ErrorKind::NotFound, format!("VM '{}' not found", vm_name))));
+    }
+
+    // lines are only compared until one is new enough since the logs are in order
+    let mut printing = since.is_none();
+    let mut print_line = |line: &str| {
+        if !printing {
+            printing = line_time(line).zip(since).is_some_and(|(time, since)| time >= since);
+        }
+        if printing {
+            println!("{}", line);
+        }
+    };
+
+    for i in (1..=KEPT_LOGS).rev() {
+        if let Ok(file) = File::open(rotated_log_path(&log_path, i)) {
+            for line in log_lines(BufReader::new(file)) {
+                print_line(&line?);
+            }
+        }
+    }
+
+    let mut file = match File::open(&log_path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound && !follow => {
+            println!("INFO:: VM {} has no console log yet", vm_name);
+            return Ok(());
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            while !log_path.exists() {
+                thread::sleep(Duration::from_millis(500));
+            }
+            File::open(&log_path)?
+        }
+        Err(e) => return Err(Box::new(e)),
+    };
+
+    // a line being written when the end of the log is reached is kept until
+    // the rest of it is there
+    let mut partial = Vec::new();
+    loop {
+        let mut reader = BufReader::new(&file);
+        while reader.read_until(b'\n', &mut partial)? > 0 {
+            if partial.ends_with(b"\n") {
+                print_line(String::from_utf8_lossy(&partial).trim_end_matches(['\n', '\r']));
+                partial.clear();
+            }
+        }
+        if !follow {
+            if !partial.is_empty() {
+                print_line(&String::from_utf8_lossy(&partial));
+            }
+            return Ok(());
+        }
+
+        thread::sleep(Duration::from_millis(500));
+        // the log was rotated so the new one is read from the start
+        let rotated = fs::metadata(&log_path).map(|m| m.ino() != file.metadata().map(|f| f.ino()).unwrap_or(0));
+        if rotated.unwrap_or(false) {
+            file = File::open(&log_path)?;
+        }
+    }
+}
@@ -1,10 +1,21 @@
-use std::fmt::format;
 // use std::process::Command;
 use std::thread;
-use std::time;
 
+use crate::backend;
+use crate::catalog;
+use crate::cloudinit;
+use crate::blockdev;
+use crate::disk;
+use crate::diskspace;
 use crate::filesystem;
-use crate::initdata;
+use crate::hotplug;
+use crate::images;
+use crate::inventory;
+use crate::provision;
+use crate::qemucmd;
+use crate::selector;
+use crate::share;
+use crate::wait;
 
 /// The VM sizes (vcpus, ram, disk etc.)
 #[derive(Debug)]
@@ -28,9 +39,11 @@ pub struct _VMSizes {
 /// ```
 ///
 use std::fs;
+use std::path::Path;
 use std::process::Command;
 use std::time::Duration;
 
+#[allow(clippy::too_many_arguments)]
 pub fn create_new_vm(
     vm_name: &String,
     vm_dist: &String,
@@ -41,6 +54,17 @@ pub fn create_new_vm(
     vm_cpus: &String,
     vm_ssh_key: &String,
     vm_port_fwd: &String,
+    user_data_options: &cloudinit::UserDataOptions,
+    provision_options: &provision::ProvisionOptions,
+    vm_tags: &[String],
+    vm_groups: &[String],
+    vm_data_disks: &[String],
+    vm_disk_io: &blockdev::DiskIoOptions,
+    vm_shares: &[String],
+    vm_max_cpus: Option<u64>,
+    vm_max_memory_mb: Option<u64>,
+    vm_backend: &str,
+    wait: Option<&wait::WaitOptions>,
 ) {
     // Print debug info if the user has set the AUTOVIRT_DEBUG env var to 1
     if std::env::var("AUTOVIRT_DEBUG").is_ok() {
@@ -57,6 +81,7 @@ pub fn create_new_vm(
     println!("\x1b[0;32mMEMORY: \x1b[0m{}", vm_memory_mb);
     println!("\x1b[0;32mVCPUS: \x1b[0m{}", vm_cpus);
     println!("\x1b[0;32mSSH KEY: \x1b[0m{}", vm_ssh_key);
+    println!("\x1b[0;32mBACKEND: \x1b[0m{}", vm_backend);
     println!("\x1b[0;32m-----------------------\x1b[0m");
 
     // check if the vm name already exists in the config file
@@ -66,6 +91,97 @@ pub fn create_new_vm(
         std::process::exit(1);
     }
 
+    // tags/groups are checked up front since they're just a typo away from
+    // being invalid
+    let mut tags = serde_json::Map::new();
+    for tag in vm_tags {
+        let (key, value) = selector::parse_tag(tag).unwrap_or_else(|e| {
+            eprintln!("ERROR: {}", e);
+            std::process::exit(1);
+        });
+        tags.insert(key, serde_json::Value::String(value));
+    }
+
+    let mut groups = Vec::new();
+    for group in vm_groups {
+        groups.push(selector::parse_group(group).unwrap_or_else(|e| {
+            eprintln!("ERROR: {}", e);
+            std::process::exit(1);
+        }));
+    }
+
+    if let Err(e) = hotplug::check_sizes(vm_cpus, vm_memory_mb, vm_max_cpus, vm_max_memory_mb) {
+        eprintln!("ERROR: {}", e);
+        std::process::exit(1);
+    }
+
+    let vm_backend_impl = backend::backend(vm_backend).unwrap_or_else(|e| {
+        eprintln!("ERROR: {}", e);
+        std::process::exit(1);
+    });
+    // the microVM backends only get a root disk, a seed image and a tap
+    // device (see backend.rs)
+    if vm_backend != "qemu" {
+        let qemu_only = [
+            (!vm_shares.is_empty(), "Shares"),
+            (!vm_data_disks.is_empty(), "Data disks"),
+            (vm_max_cpus.is_some() || vm_max_memory_mb.is_some(), "CPU/memory hotplug"),
+        ];
+        if let Some((_, what)) = qemu_only.iter().find(|(used, _)| *used) {
+            eprintln!("ERROR: {} only work with the qemu backend", what);
+            std::process::exit(1);
+        }
+        if user_data_options.datasource != "seed" {
+            eprintln!("ERROR: The {} backend can't reach the imds server, it needs --datasource seed", vm_backend);
+            std::process::exit(1);
+        }
+    }
+
+    let mut data_disks = Vec::new();
+    for spec in vm_data_disks {
+        data_disks.push(disk::DataDiskOptions::parse(spec).unwrap_or_else(|e| {
+            eprintln!("ERROR: {}", e);
+            std::process::exit(1);
+        }));
+    }
+
+    let mut shares = Vec::new();
+    for spec in vm_shares {
+        shares.push(share::parse_share(spec).unwrap_or_else(|e| {
+            eprintln!("ERROR: {}", e);
+            std::process::exit(1);
+        }));
+    }
+
+    if let Err(e) = vm_disk_io.check() {
+        eprintln!("ERROR: {}", e);
+        std::process::exit(1);
+    }
+
+    // the key is stored as an absolute path (like the provisioner paths) so
+    // ssh finds its private key no matter where autovirt is run from later
+    let vm_ssh_key = match vm_ssh_key.as_str() {
+        "none" => vm_ssh_key.clone(),
+        key => fs::canonicalize(key).map(|path| path.to_string_lossy().to_string()).unwrap_or_else(|e| {
+            eprintln!("ERROR: Failed to find the ssh key '{}' -> {}", key, e);
+            std::process::exit(1);
+        }),
+    };
+
+    let image = catalog::find_image(vm_dist).unwrap_or_else(|| {
+        eprintln!("ERROR: Unknown distro '{}' (see `autovirt show --available`)", vm_dist);
+        std::process::exit(1);
+    });
+    let base_image_path = images::base_image_path(vm_dist, &image).expect("ERROR: COULD NOT FIND USER $HOME DIRECTORY");
+    if !base_image_path.is_file() {
+        eprintln!("ERROR: The image for {} isn't downloaded (run `autovirt download {}` first)", vm_dist, vm_dist);
+        std::process::exit(1);
+    }
+    let os_family = catalog::os_family(&image.os_family);
+    // the exact release/digest the VM is made from (the image may be updated
+    // later on)
+    let base_image = images::downloaded_image(vm_dist).unwrap_or_default();
+
     println!("Proceed? (yes please/N)");
     let mut user_input = String::new();
     std::io::stdin().read_line(&mut user_input).expect("Failed to read user input");
@@ -75,123 +191,250 @@ pub fn create_new_vm(
         std::process::exit(1);
     }
 
-    // Fetch the filename for the specified distro
-    let distro_filename = filesystem::get_value_from_autovirt_json(&format!("images.{}.filename", vm_dist))
-        .and_then(|v| v.as_str().map(String::from))
-        .expect("ERROR: Could not find the filename for the specified distro");
+    // Reading the contents of the ssh key file specified by the user (if the
+    // user gave one)
+    let ssh_key_content = if vm_ssh_key == "none" {
+        String::new()
+    } else {
+        fs::read_to_string(&vm_ssh_key).expect("ERROR: failed to read ssh key file contents")
+    };
+
+    // Building the cloud-init user-data before anything is copied so that bad
+    // --write-file/--user-data-file args don't leave a half created VM behind.
+    let cloud_config = cloudinit::build_cloud_config(vm_user, vm_pass, &ssh_key_content, user_data_options, &os_family)
+        .unwrap_or_else(|e| {
+            eprintln!("ERROR: Failed to build cloud-init user-data -> {}", e);
+            std::process::exit(1);
+        });
+
+    let extra_user_data = user_data_options.user_data_file.as_ref().map(|path| {
+        cloudinit::read_user_data_file(Path::new(path)).unwrap_or_else(|e| {
+            eprintln!("ERROR: Invalid user-data file -> {}", e);
+            std::process::exit(1);
+        })
+    });
+
+    if let Err(e) = cloudinit::render_user_data(&cloud_config, extra_user_data.as_deref())
+        .and_then(|_| cloudinit::check_user_data_options(user_data_options))
+    {
+        eprintln!("ERROR: Invalid cloud-init options -> {}", e);
+        std::process::exit(1);
+    }
+
+    let provisioners = provision::build_provisioners(provision_options).unwrap_or_else(|e| {
+        eprintln!("ERROR: Invalid provisioner -> {}", e);
+        std::process::exit(1);
+    });
 
     // Construct the full path for the VM image to be created in the _VMS directory
     let vms_dir = filesystem::get_autovirt_data_dir().unwrap().join("_VMS");
     fs::create_dir_all(&vms_dir).expect("ERROR: Could not create _VMS directory");
 
-    let vm_image_name = format!("{}-autovirt-{}", vm_name, distro_filename);
+    let vm_image_name = format!("{}-autovirt-{}", vm_name, image.filename);
     let vm_image_path = vms_dir.join(&vm_image_name);
 
     // Copy the base distro image to the _VMS directory with the new VM name
-    let base_image_path = filesystem::get_autovirt_data_dir().unwrap().join("_data/downloads").join(distro_filename);
-    fs::copy(&base_image_path, &vm_image_path).expect("ERROR: Failed to copy base image to _VMS directory");
+    // (converting it if the backend can only boot one format)
+    match vm_backend_impl.disk_format() {
+        Some(to_format) if image.format != to_format => {
+            println!("LOG:: Converting the {} image to {} for the {} backend...", image.format, to_format, vm_backend);
+            let status = Command::new("qemu-img")
+                .args(["convert", "-p", "-f", &image.format, "-O", to_format])
+                .arg(&base_image_path)
+                .arg(&vm_image_path)
+                .status()
+                .expect("ERROR: Failed to run qemu-img convert");
+            if !status.success() {
+                let _ = fs::remove_file(&vm_image_path);
+                eprintln!("ERROR: qemu-img convert failed with {}", status);
+                std::process::exit(1);
+            }
+        }
+        _ => {
+            fs::copy(&base_image_path, &vm_image_path).expect("ERROR: Failed to copy base image to _VMS directory");
+        }
+    }
 
     println!("LOG:: VM image copied to: {:?}", vm_image_path);
 
+    let vm_disk_format = disk::probe_image_format(&vm_image_path.to_string_lossy()).unwrap_or_else(|e| {
+        eprintln!("ERROR: Failed to find out the VM image format -> {}", e);
+        std::process::exit(1);
+    });
+    println!("LOG:: VM image format -> {}", vm_disk_format);
+
+    // Keeping the user's own cloud-config and the extra user-data parts in the
+    // VM's data directory so the user-data can be rendered again later on.
+    let vm_data_dir = filesystem::get_vm_data_dir(vm_name).expect("ERROR: Could not create VM data directory");
+    if let Some(extra) = &extra_user_data {
+        fs::write(vm_data_dir.join("user-data-extra"), extra)
+            .expect("ERROR: Failed to write user-data file");
+    }
+
+    let user_data_parts = cloudinit::collect_user_data_parts(&vm_data_dir, user_data_options)
+        .unwrap_or_else(|e| {
+            eprintln!("ERROR: Failed to copy user-data parts -> {}", e);
+            std::process::exit(1);
+        });
+
     // Add the VM details to the autovirt config, including the VM image path
     let vm_metadata = serde_json::json!({
         "name": vm_name,
         "distro": vm_dist,
+        "os_family": image.os_family,
+        "image_version": base_image.serial,
+        "image_digest": base_image.digest,
         "size": vm_size,
         "user": vm_user,
         "password": vm_pass,
         "memory_mb": vm_memory_mb,
         "cpus": vm_cpus,
+        "max_cpus": vm_max_cpus.map(|n| n.to_string()),
+        "max_memory_mb": vm_max_memory_mb.map(|n| n.to_string()),
         "image_path": vm_image_path.to_string_lossy(),
+        "disk_format": vm_disk_format,
+        "disk_io": serde_json::to_value(vm_disk_io).expect("ERROR: Failed to jsonify disk io options"),
+        "cloud_config": serde_json::to_value(&cloud_config).expect("ERROR: Failed to jsonify cloud config"),
+        "cloud_init_parts": serde_json::to_value(&user_data_parts).expect("ERROR: Failed to jsonify user-data parts"),
+        "user_data_extra": extra_user_data.is_some(),
+        "datasource": user_data_options.datasource,
+        "backend": vm_backend,
+        "ports": vm_port_fwd,
+        "ssh_key": vm_ssh_key,
+        "provisioners": serde_json::to_value(&provisioners).expect("ERROR: Failed to jsonify provisioners"),
+        "tags": tags,
+        "groups": groups,
     });
 
     filesystem::insert_value_into_autovirt_json_object(
         &format!("vms.{}", vm_name),
         vm_metadata,
     );
+    // only now that the VM is made from it (not when it's aborted or fails)
+    images::mark_used(vm_dist);
+    inventory::sync_managed_ssh_config_or_warn();
+
+    for data_disk in &data_disks {
+        if let Err(e) = disk::add_data_disk(vm_name, data_disk, false) {
+            eprintln!("ERROR: Failed to add data disk -> {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    if let Err(e) = share::add_shares(vm_name, &shares) {
+        eprintln!("ERROR: Failed to add shares -> {}", e);
+        std::process::exit(1);
+    }
 
     println!("LOG:: Executing VM startup process in 3 seconds...");
     thread::sleep(Duration::from_secs(3));
     println!("\x1b[0;32mLOG:: Creating VM...\x1b[0m");
 
-    // Reading the contents of the ssh key file specified by the user
-    let ssh_key_content = fs::read_to_string(vm_ssh_key).expect("ERROR: failed to read ssh key file contents");
-
-    // Doing string replaces for the user-data for the cloud init config file.
-    let final_user_data = initdata::CLOUD_INIT_USER_DATA
-        .replace("AUTOVIRT_USER", vm_user)
-        .replace("AUTOVIRT_PASS", vm_pass)
-        .replace("AUTOVIRT_SSH_KEY", &ssh_key_content);
-
-    let autovirt_data_dir = filesystem::get_autovirt_data_dir();
-    let autovirt_data_dir_cloud_init_user_data_file = autovirt_data_dir.unwrap().join("_data/conf/user-data");
-
+    // The cloud-init files are written to the VM's data directory which is
+    // served by the imds server (or packed into a seed image).
     println!("\x1b[0;32mLOG:: Writing to user-data (cloud-init) file...\x1b[0m");
-    std::fs::write(autovirt_data_dir_cloud_init_user_data_file, final_user_data)
-        .expect("ERROR: Failed to write user-data file");
+    let seed_image_path = cloudinit::write_vm_cloud_init_files(vm_name).unwrap_or_else(|e| {
+        eprintln!("ERROR: Failed to write cloud-init files -> {}", e);
+        std::process::exit(1);
+    });
     println!("\x1b[0;32mLOG:: User-data file written successfully\x1b[0m");
 
     // Resizing the VM disk to the specified size (in the cli args)
     let disk_size_amount = vm_size.parse::<u32>().unwrap();
-    let disk_resize_cmd = format!(
-        "qemu-img resize {:?} +{}G",
-        vm_image_path, disk_size_amount
-    );
 
     println!("\x1b[0;32mLOG:: Resizing disk to {}G...\x1b[0m", vm_size);
-    let disk_resize_output = Command::new("sh")
-        .arg("-c")
-        .arg(&disk_resize_cmd)
+    let disk_resize_output = Command::new("qemu-img")
+        .arg("resize")
+        .arg("-f")
+        .arg(&vm_disk_format)
+        .arg(&vm_image_path)
+        .arg(format!("+{}G", disk_size_amount))
         .output()
         .expect("ERROR: Failed to resize disk");
 
     if disk_resize_output.status.success() {
         println!("\x1b[0;32mLOG:: VM disk resized successfully to {}G\x1b[0m", vm_size);
+        if let Err(e) = diskspace::record_vm_disk_size(vm_name) {
+            eprintln!("ERROR: Failed to record the VM disk size -> {}", e);
+        }
     } else {
         eprintln!("\x1b[0;31mERROR:: FAILED TO RESIZE DISK\x1b[0m");
         eprintln!("ERROR:: Command exit code: {}", disk_resize_output.status);
     }
 
-    // Appendininning tho port forward strings to the network args of the
-    // command
-    let vm_network_args = format!("user,{}", vm_port_fwd);
-
-    // Checking the port forwaarding stirngs and splitting em into a vector
-    // let port_fwd_vec: Vec<&str> = vm_port_fwd.split(",").collect();
-    // _ = port_fwd_vec;
+    let mut vm_spec = backend::VmSpec::from_config(vm_name, vm_port_fwd, wait.is_some()).unwrap_or_else(|e| {
+        eprintln!("ERROR: Failed to get the VM settings -> {}", e);
+        std::process::exit(1);
+    });
+    // The cloud-init data comes from either the seed image or the imds server
+    vm_spec.seed_image = seed_image_path.clone();
 
     // Building command to create a VM
-    let mut create_vm_cmd = Command::new("qemu-system-x86_64");
-    create_vm_cmd
-        .arg("-net")
-        .arg("nic")
-        .arg("-net")
-        .arg(&vm_network_args) // full port forward args (raw str from user)
-        // .arg("user,hostfwd=tcp::2222-:22") // forwarding SSH to 2222 on host
-        .arg("-machine")
-        .arg("accel=kvm:tcg")
-        .arg("-m")
-        .arg(vm_memory_mb)
-        .arg("-nographic")
-        .arg("-hda")
-        .arg(&vm_image_path)
-        .arg("-smbios")
-        .arg(format!("type=1,serial=ds=nocloud;s=http://10.0.2.2:8000/"))
-        .arg("-serial")
-        .arg("pty")
-        .arg("-smp")
-        .arg(format!("cpus={}", vm_cpus));
+    let mut create_vm_cmd = vm_backend_impl.command(&vm_spec).unwrap_or_else(|e| {
+        eprintln!("ERROR: {}", e);
+        std::process::exit(1);
+    });
 
     println!("\nNote: Set AUTOVIRT_DEBUG=1 to see the command to be executed\nAlong with other debug info.\n");
 
     if std::env::var("AUTOVIRT_DEBUG").is_ok() {
         // Printing vm creation command if debug env var is present/is '1'
-        println!("INFO:: {:?}", create_vm_cmd);
+        println!("INFO:: {}", qemucmd::command_line(&create_vm_cmd));
     }
 
-    let status = create_vm_cmd
-        .status()
-        .expect("ERROR:: failed to exec VM creation command");
+    // With --wait the VM runs in the background and this returns once it's
+    // ready (the provisioners can just be run here then).
+    if let Some(wait) = wait {
+        let mut wait = wait.clone();
+        // the imds server goes away with this process so cloud-init has to
+        // be done with it first
+        if seed_image_path.is_none() && !wait.conditions.iter().any(|c| c == "cloud-init") {
+            println!("INFO:: Also waiting for cloud-init since the VM gets its data from this process");
+            wait.conditions.push("cloud-init".to_string());
+        }
+
+        let status = vm_backend_impl.start(&vm_spec, &mut create_vm_cmd).unwrap_or_else(|e| {
+            eprintln!("ERROR: Failed to start the VM -> {}", e);
+            std::process::exit(1);
+        });
+        if !status.is_some_and(|status| status.success()) {
+            eprintln!("ERROR:: Failed to start the VM\nAUTOVIRT_DEBUG=1 and re-run for more info");
+            std::process::exit(1);
+        }
+        println!("LOG:: VM started in the background -> {}", vm_name);
+
+        wait::wait_for_vm_or_exit(vm_name, &wait);
+        if !provisioners.is_empty()
+            && provision::provision_vm(vm_name, std::time::Duration::from_secs(provision::SSH_WAIT_TIMEOUT_SECS)).is_err()
+        {
+            std::process::exit(1);
+        }
+        println!("\nLOG:: AutoVirt VM creation success 👍");
+        return;
+    }
+
+    // Provisioners are run in the background once the VM can be reached over
+    // ssh since the VM itself runs in the foreground.
+    let provisioning = (!provisioners.is_empty()).then(|| {
+        println!("INFO:: {} provisioner(s) will run once the VM is reachable over ssh", provisioners.len());
+        provision::spawn_provisioning(vm_name)
+    });
+
+    let started = vm_backend_impl.start(&vm_spec, &mut create_vm_cmd).unwrap_or_else(|e| {
+        eprintln!("ERROR: Failed to start the VM -> {}", e);
+        std::process::exit(1);
+    });
+    let status = match started {
+        Some(status) => status,
+        None => {
+            println!("INFO:: Reattach with `autovirt console {}`, stop it with `autovirt stop {}`", vm_name, vm_name);
+            if let Some(provisioning) = provisioning {
+                println!("INFO:: Waiting for the provisioners to finish...");
+                let _ = provisioning.join();
+            }
+            return;
+        }
+    };
 
     if status.success() {
         println!("\nLOG:: AutoVirt VM creation success 👍");
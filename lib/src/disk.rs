@@ -0,0 +1,542 @@
+//! This file contains things related to VM disk images such as finding out
+//! what format an image is in and converting between formats.
+//!
+//! Image files can't be trusted to have the right extension (the ubuntu cloud
+//! images are qcow2 even though they're `.img` files) so the format is always
+//! probed with `qemu-img info` and recorded for downloaded images and VM disks
+//! (`disk_format`) so it can be given to qemu explicitly.
+//!
+//! VMs can also have extra data disks (`data_disks` in the VM's entry) which
+//! are attached every time the VM is run. Each one has a serial that never
+//! changes so it shows up at the same `/dev/disk/by-id/` path in the VM no
+//! matter what order the disks are attached in.
+//!
+//! ---
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::error::Error;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::SystemTime;
+
+use crate::blockdev;
+use crate::cloudinit;
+use crate::filesystem;
+use crate::run;
+use crate::snapshot;
+
+/// The disk formats autovirt can convert VM disks to (and make data disks in).
+pub const DISK_FORMATS: [&str; 2] = ["qcow2", "raw"];
+
+/// The buses data disks can be attached to.
+pub const DISK_BUSES: [&str; 3] = ["virtio", "scsi", "nvme"];
+
+/// A data disk as recorded in the VM's entry in the autovirt config file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DataDisk {
+    pub name: String,
+    pub path: String,
+    pub size: String,
+    pub format: String,
+    pub bus: String,
+    pub serial: String,
+    pub attached: bool,
+
+    /// Where the disk gets mounted on first boot (cloud-init)
+    #[serde(default)]
+    pub mountpoint: Option<String>,
+}
+
+/// Options for a new data disk (`autovirt disk add` / `create --data-disk`).
+#[derive(Debug, Clone)]
+pub struct DataDiskOptions {
+    pub name: Option<String>,
+    pub size: String,
+    pub format: String,
+    pub bus: String,
+    pub mountpoint: Option<String>,
+    pub filesystem: String,
+}
+
+impl DataDiskOptions {
+    /// Parses a `create --data-disk` arg (`size[:/mountpoint]`), everything
+    /// else is the default.
+    ///
+    /// ---
+    pub fn parse(spec: &str) -> Result<DataDiskOptions, Box<dyn Error>> {
+        let (size, mountpoint) = match spec.split_once(':') {
+            Some((size, mountpoint)) => (size, Some(mountpoint.to_string())),
+            None => (spec, None),
+        };
+        check_disk_size(size)?;
+        if mountpoint.as_ref().is_some_and(|m| !m.starts_with('/')) {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid --data-disk '{}' (expected size[:/mountpoint])", spec),
+            )));
+        }
+
+        Ok(DataDiskOptions {
+            name: None,
+            size: size.to_string(),
+            format: "qcow2".to_string(),
+            bus: "virtio".to_string(),
+            mountpoint,
+            filesystem: "ext4".to_string(),
+        })
+    }
+}
+
+/// Gets the `qemu-img info` json for an image (`-U` so this works on the disks
+/// of running VMs too).
+///
+/// ---
+pub fn image_info(image_path: &str) -> Result<Value, Box<dyn Error>> {
+    let output = Command::new("qemu-img")
+        .args(["info", "--output=json", "-U", image_path])
+        .output()?;
+    if !output.status.success() {
+        return Err(Box::new(io::Error::other(format!(
+            "qemu-img info failed for {} -> {}",
+            image_path,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))));
+    }
+    Ok(serde_json::from_slice(&output.stdout)?)
+}
+
+/// Finds out the format of an image (qcow2, raw etc.).
+pub fn probe_image_format(image_path: &str) -> Result<String, Box<dyn Error>> {
+    image_info(image_path)?
+        .get("format")
+        .and_then(|f| f.as_str())
+        .map(String::from)
+        .ok_or_else(|| io::Error::other(format!("qemu-img didn't give a format for {}", image_path)).into())
+}
+
+/// Gets the format of a VM's disk. VMs made before the format was recorded
+/// get their disk probed, nothing is written (see `record_vm_disk_format`)
+/// so building a VM's command doesn't change its entry.
+///
+/// ---
+pub fn vm_disk_format(vm_name: &str) -> Result<String, Box<dyn Error>> {
+    match recorded_vm_disk_format(vm_name) {
+        Some(format) => Ok(format),
+        None => probe_image_format(&vm_image_path(vm_name)?),
+    }
+}
+
+/// Records the format of a VM's disk for VMs made before it was recorded
+/// (done when they're run).
+///
+/// ---
+pub fn record_vm_disk_format(vm_name: &str) -> Result<String, Box<dyn Error>> {
+    if let Some(format) = recorded_vm_disk_format(vm_name) {
+        return Ok(format);
+    }
+
+    let format = probe_image_format(&vm_image_path(vm_name)?)?;
+    filesystem::insert_value_into_autovirt_json_object(
+        &format!("vms.{}.disk_format", vm_name),
+        Value::String(format.clone()),
+    );
+    println!("LOG:: Recorded the disk format of VM {} -> {}", vm_name, format);
+    Ok(format)
+}
+
+fn recorded_vm_disk_format(vm_name: &str) -> Option<String> {
+    filesystem::get_value_from_autovirt_json(&format!("vms.{}.disk_format", vm_name))
+        .and_then(|v| v.as_str().map(String::from))
+}
+
+/// Gets the path of a VM's disk image.
+pub fn vm_image_path(vm_name: &str) -> Result<String, Box<dyn Error>> {
+    filesystem::get_value_from_autovirt_json(&format!("vms.{}.image_path", vm_name))
+        .and_then(|v| v.as_str().map(String::from))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("VM '{}' not found", vm_name)).into())
+}
+
+/// Escapes a value for a qemu `-blockdev`/`-device` option string (commas
+/// have to be doubled).
+///
+/// ---
+pub fn escape_option_value(value: &str) -> String {
+    value.replace(',', ",,")
+}
+
+/// Converts a (stopped) VM's disk to another format. The converted image
+/// replaces the old one at the same path.
+///
+/// Internal snapshots aren't kept by `qemu-img convert` so the user is asked
+/// first if the disk has any.
+///
+/// ---
+pub fn convert_vm_disk(vm_name: &str, to_format: &str, compress: bool) -> Result<(), Box<dyn Error>> {
+    if !DISK_FORMATS.contains(&to_format) {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Can't convert to '{}' (supported: {})", to_format, DISK_FORMATS.join(", ")),
+        )));
+    }
+    if compress && to_format != "qcow2" {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Only qcow2 images can be compressed",
+        )));
+    }
+    if run::is_vm_running(vm_name) {
+        return Err(Box::new(io::Error::other(format!(
+            "VM '{}' is running, stop it before converting its disk",
+            vm_name
+        ))));
+    }
+
+    let image_path = vm_image_path(vm_name)?;
+    let from_format = vm_disk_format(vm_name)?;
+    if from_format == to_format && !compress {
+        println!("INFO:: VM {} disk is already {}", vm_name, to_format);
+        return Ok(());
+    }
+
+    let snapshots = snapshot::load_snapshots(vm_name);
+    if !snapshots.is_empty() {
+        println!(
+            "WARNING:: The disk has {} snapshot(s) which will be lost when converting it.",
+            snapshots.len()
+        );
+        println!("Proceed? (yes please/N)");
+        let mut user_input = String::new();
+        std::io::stdin().read_line(&mut user_input).expect("Failed to read user input");
+        if user_input.trim() != "yes please" {
+            println!("!!! ABORTING DISK CONVERSION !!!");
+            return Ok(());
+        }
+    }
+
+    let size_before = fs::metadata(&image_path)?.len();
+    let tmp_image_path = format!("{}.convert-tmp", image_path);
+
+    println!("LOG:: Converting VM {} disk from {} to {}...", vm_name, from_format, to_format);
+    let mut convert_cmd = Command::new("qemu-img");
+    convert_cmd.args(["convert", "-p", "-f", &from_format, "-O", to_format]);
+    if compress {
+        convert_cmd.arg("-c");
+    }
+    convert_cmd.arg(&image_path).arg(&tmp_image_path);
+
+    let status = convert_cmd.status()?;
+    if !status.success() {
+        let _ = fs::remove_file(&tmp_image_path);
+        return Err(Box::new(io::Error::other(format!("qemu-img convert failed with {}", status))));
+    }
+    fs::rename(&tmp_image_path, &image_path)?;
+
+    filesystem::insert_value_into_autovirt_json_object(
+        &format!("vms.{}.disk_format", vm_name),
+        Value::String(to_format.to_string()),
+    );
+    if !snapshots.is_empty() {
+        filesystem::insert_value_into_autovirt_json_object(&format!("vms.{}.snapshots", vm_name), Value::Array(vec![]));
+        filesystem::insert_value_into_autovirt_json_object(&format!("vms.{}.current_snapshot", vm_name), Value::Null);
+    }
+
+    let size_after = fs::metadata(&image_path)?.len();
+    println!(
+        "LOG:: VM disk converted to {} ({} MB -> {} MB)",
+        to_format,
+        size_before / 1024 / 1024,
+        size_after / 1024 / 1024
+    );
+    Ok(())
+}
+
+/// Gets the data disks recorded for a VM.
+pub fn load_data_disks(vm_name: &str) -> Vec<DataDisk> {
+    filesystem::get_value_from_autovirt_json(&format!("vms.{}.data_disks", vm_name))
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default()
+}
+
+fn save_data_disks(vm_name: &str, data_disks: &[DataDisk]) {
+    filesystem::insert_value_into_autovirt_json_object(
+        &format!("vms.{}.data_disks", vm_name),
+        serde_json::to_value(data_disks).expect("ERROR: Failed to jsonify data disks"),
+    );
+}
+
+pub fn find_data_disk(data_disks: &[DataDisk], disk_name: &str) -> Result<usize, Box<dyn Error>> {
+    data_disks.iter().position(|d| d.name == disk_name).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, format!("Data disk '{}' not found", disk_name)).into()
+    })
+}
+
+/// Makes a serial for a new data disk. This is also used as the label of the
+/// filesystem made on it so it has to fit in an xfs label (12 chars).
+///
+/// ---
+fn new_disk_serial(vm_name: &str, disk_name: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    vm_name.hash(&mut hasher);
+    disk_name.hash(&mut hasher);
+    SystemTime::now().hash(&mut hasher);
+    format!("av{:010x}", hasher.finish() & 0xff_ffff_ffff)
+}
+
+/// Gets the path a data disk shows up at in the VM (based on the serial).
+pub fn guest_device_path(data_disk: &DataDisk) -> String {
+    match data_disk.bus.as_str() {
+        "scsi" => format!("/dev/disk/by-id/scsi-0QEMU_QEMU_HARDDISK_{}", data_disk.serial),
+        "nvme" => format!("/dev/disk/by-id/nvme-QEMU_NVMe_Ctrl_{}", data_disk.serial),
+        _ => format!("/dev/disk/by-id/virtio-{}", data_disk.serial),
+    }
+}
+
+/// Checks the size of a new disk is something qemu-img understands (a number
+/// of bytes or a number with a K/M/G/T suffix).
+///
+/// ---
+fn check_disk_size(size: &str) -> Result<(), Box<dyn Error>> {
+    parse_disk_size(size).map(|_| ())
+}
+
+/// Gets the number of bytes in a disk size (same units as qemu-img, K/M/G/T
+/// are powers of 1024).
+///
+/// ---
+pub fn parse_disk_size(size: &str) -> Result<u64, Box<dyn Error>> {
+    let digits = size.trim_end_matches(['K', 'M', 'G', 'T', 'k']);
+    let invalid = || -> Box<dyn Error> {
+        Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid disk size '{}' (i.e. -> 50G, 512M)", size),
+        ))
+    };
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) || size.len() - digits.len() > 1 {
+        return Err(invalid());
+    }
+
+    let shift = match &size[digits.len()..] {
+        "K" | "k" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => 0,
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(invalid)
+}
+
+/// Makes a new data disk for a VM and records it (attached). If a mountpoint
+/// is given the disk is partitioned, formatted and mounted by cloud-init on
+/// the VM's first boot.
+///
+/// `write_cloud_init` rewrites the VM's cloud-init files straight away (create
+/// does that itself once everything is set up).
+///
+/// ---
+pub fn add_data_disk(
+    vm_name: &str,
+    options: &DataDiskOptions,
+    write_cloud_init: bool,
+) -> Result<DataDisk, Box<dyn Error>> {
+    let main_image_path = vm_image_path(vm_name)?;
+    let mut data_disks = load_data_disks(vm_name);
+
+    let disk_name = options.name.clone().unwrap_or_else(|| {
+        (1..).map(|n| format!("data{}", n)).find(|name| !data_disks.iter().any(|d| &d.name == name)).unwrap()
+    });
+    // the name ends up in qemu node names which can only be 31 chars long
+    if disk_name.is_empty()
+        || disk_name.len() > 16
+        || !disk_name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid disk name '{}' (up to 16 letters, numbers, - and _)", disk_name),
+        )));
+    }
+    if data_disks.iter().any(|d| d.name == disk_name) {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("VM '{}' already has a disk named '{}'", vm_name, disk_name),
+        )));
+    }
+    if !DISK_FORMATS.contains(&options.format.as_str()) || !DISK_BUSES.contains(&options.bus.as_str()) {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid disk format/bus '{}'/'{}'", options.format, options.bus),
+        )));
+    }
+    if options.mountpoint.as_ref().is_some_and(|m| !m.starts_with('/')) {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The mountpoint has to be an absolute path",
+        )));
+    }
+    check_disk_size(&options.size)?;
+
+    // data disks live next to the VM's main disk
+    let disk_path = PathBuf::from(&main_image_path)
+        .with_file_name(format!("{}-autovirt-disk-{}.{}", vm_name, disk_name, options.format));
+    if disk_path.exists() {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("Disk image already exists -> {:?}", disk_path),
+        )));
+    }
+
+    println!("LOG:: Creating {} {} data disk for VM {} -> {}", options.size, options.format, vm_name, disk_name);
+    let output = Command::new("qemu-img")
+        .args(["create", "-f", &options.format])
+        .arg(&disk_path)
+        .arg(&options.size)
+        .output()?;
+    if !output.status.success() {
+        return Err(Box::new(io::Error::other(format!(
+            "qemu-img create failed -> {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))));
+    }
+
+    let serial = new_disk_serial(vm_name, &disk_name);
+    let data_disk = DataDisk {
+        name: disk_name,
+        path: disk_path.to_string_lossy().to_string(),
+        size: options.size.clone(),
+        format: options.format.clone(),
+        bus: options.bus.clone(),
+        serial,
+        attached: true,
+        mountpoint: options.mountpoint.clone(),
+    };
+    data_disks.push(data_disk.clone());
+    save_data_disks(vm_name, &data_disks);
+
+    if let Some(mountpoint) = &data_disk.mountpoint {
+        let mut cloud_config = cloudinit::load_vm_cloud_config(vm_name)?;
+        cloudinit::add_data_disk_setup(
+            &mut cloud_config,
+            &guest_device_path(&data_disk),
+            &data_disk.serial,
+            &options.filesystem,
+            mountpoint,
+        );
+        cloudinit::save_vm_cloud_config(vm_name, &cloud_config);
+
+        if write_cloud_init {
+            cloudinit::write_vm_cloud_init_files(vm_name)?;
+            println!("INFO:: cloud-init only sets up disks on the VM's first boot, VMs that have");
+            println!("INFO:: already booted need the disk formatted and mounted by hand.");
+        }
+    }
+
+    if write_cloud_init && run::is_vm_running(vm_name) {
+        println!("INFO:: The disk will be attached the next time the VM is started");
+    }
+    println!("LOG:: Data disk added -> {} ({})", data_disk.name, data_disk.path);
+    Ok(data_disk)
+}
+
+/// Attaches/detaches a data disk (detached disks are kept but not given to
+/// the VM when it runs).
+///
+/// ---
+pub fn set_data_disk_attached(vm_name: &str, disk_name: &str, attached: bool) -> Result<(), Box<dyn Error>> {
+    if run::is_vm_running(vm_name) {
+        return Err(Box::new(io::Error::other(format!(
+            "VM '{}' is running, stop it first",
+            vm_name
+        ))));
+    }
+
+    let mut data_disks = load_data_disks(vm_name);
+    let index = find_data_disk(&data_disks, disk_name)?;
+    data_disks[index].attached = attached;
+    save_data_disks(vm_name, &data_disks);
+
+    println!(
+        "LOG:: Data disk {} -> {}",
+        if attached { "attached" } else { "detached" },
+        disk_name
+    );
+    Ok(())
+}
+
+/// Records the new size of a data disk (after it was resized).
+pub fn set_data_disk_size(vm_name: &str, disk_name: &str, size: &str) -> Result<(), Box<dyn Error>> {
+    let mut data_disks = load_data_disks(vm_name);
+    let index = find_data_disk(&data_disks, disk_name)?;
+    data_disks[index].size = size.to_string();
+    save_data_disks(vm_name, &data_disks);
+    Ok(())
+}
+
+/// Deletes a data disk (the image file and its entry). The disk has to be
+/// detached or the VM stopped.
+///
+/// ---
+pub fn remove_data_disk(vm_name: &str, disk_name: &str) -> Result<(), Box<dyn Error>> {
+    let mut data_disks = load_data_disks(vm_name);
+    let index = find_data_disk(&data_disks, disk_name)?;
+
+    if data_disks[index].attached && run::is_vm_running(vm_name) {
+        return Err(Box::new(io::Error::other(format!(
+            "Data disk '{}' is in use by the running VM '{}'",
+            disk_name, vm_name
+        ))));
+    }
+
+    println!("Are you sure you want to delete the data disk {} of VM {}? (yes please/N)", disk_name, vm_name);
+    let mut user_input = String::new();
+    std::io::stdin().read_line(&mut user_input).expect("Failed to read user input");
+    if user_input.trim() != "yes please" {
+        println!("!!! ABORTING DATA DISK DELETION !!!");
+        return Ok(());
+    }
+
+    let data_disk = data_disks.remove(index);
+    if PathBuf::from(&data_disk.path).exists() {
+        fs::remove_file(&data_disk.path)?;
+    }
+    save_data_disks(vm_name, &data_disks);
+
+    if data_disk.mountpoint.is_some() {
+        let mut cloud_config = cloudinit::load_vm_cloud_config(vm_name)?;
+        cloudinit::remove_data_disk_setup(&mut cloud_config, &guest_device_path(&data_disk), &data_disk.serial);
+        cloudinit::save_vm_cloud_config(vm_name, &cloud_config);
+        cloudinit::write_vm_cloud_init_files(vm_name)?;
+    }
+
+    println!("LOG:: Data disk deleted -> {}", data_disk.path);
+    Ok(())
+}
+
+/// Prints a VM's main disk and data disks.
+pub fn list_vm_disks(vm_name: &str) -> Result<(), Box<dyn Error>> {
+    let image_path = vm_image_path(vm_name)?;
+    let io_options = blockdev::load_disk_io_options(vm_name);
+    println!("main  {}  {}  {}  (boot disk)", vm_disk_format(vm_name)?, image_path, io_options.bus);
+
+    for data_disk in load_data_disks(vm_name) {
+        println!(
+            "{}  {}  {}  {}  size={}  serial={}  {}{}",
+            data_disk.name,
+            data_disk.format,
+            data_disk.path,
+            data_disk.bus,
+            data_disk.size,
+            data_disk.serial,
+            if data_disk.attached { "attached" } else { "detached" },
+            data_disk.mountpoint.map(|m| format!("  mount={}", m)).unwrap_or_default(),
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,478 @@
+//! This file contains things for seeing how much space VM disks really take up
+//! and getting space back from them (compacting and shrinking).
+//!
+//! The size in a VM's entry is only what the disk was resized to, the real
+//! numbers come from `qemu-img info` (the virtual size the VM sees and the
+//! bytes actually allocated on the host, for every image in the backing chain).
+//!
+//! ---
+
+use serde_json::Value;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::process::Command;
+
+use crate::cloudinit;
+use crate::disk;
+use crate::filesystem;
+use crate::run;
+
+/// The sectors read from the start of a disk to find its partitions (the MBR,
+/// the GPT header and 128 GPT entries).
+const PARTITION_TABLE_SECTORS: u64 = 34;
+
+/// Formats a number of bytes as something readable (1.5G, 300M etc.).
+pub fn human_size(bytes: u64) -> String {
+    let units = ["B", "K", "M", "G", "T"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}B", bytes)
+    } else {
+        format!("{:.1}{}", size, units[unit])
+    }
+}
+
+/// Gets the `qemu-img info` json for an image and every image under it in its
+/// backing chain (the image itself first).
+///
+/// ---
+pub fn image_chain_info(image_path: &str) -> Result<Vec<Value>, Box<dyn Error>> {
+    let output = Command::new("qemu-img")
+        .args(["info", "--output=json", "--backing-chain", "-U", image_path])
+        .output()?;
+    if !output.status.success() {
+        return Err(Box::new(io::Error::other(format!(
+            "qemu-img info failed for {} -> {}",
+            image_path,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))));
+    }
+
+    // qemu-img gives a single object when there's no backing file
+    match serde_json::from_slice(&output.stdout)? {
+        Value::Array(chain) => Ok(chain),
+        info => Ok(vec![info]),
+    }
+}
+
+fn info_u64(info: &Value, key: &str) -> u64 {
+    info.get(key).and_then(Value::as_u64).unwrap_or(0)
+}
+
+fn info_str<'a>(info: &'a Value, key: &str) -> &'a str {
+    info.get(key).and_then(Value::as_str).unwrap_or("?")
+}
+
+/// Prints an image and its backing chain. Gives back the bytes allocated by
+/// the image itself (backing files can be shared so they aren't counted).
+///
+/// ---
+fn print_image_usage(label: &str, image_path: &str) -> u64 {
+    let chain = match image_chain_info(image_path) {
+        Ok(chain) => chain,
+        Err(e) => {
+            println!("  {:<10} {}  (ERROR: {})", label, image_path, e);
+            return 0;
+        }
+    };
+
+    for (depth, info) in chain.iter().enumerate() {
+        let name = if depth == 0 {
+            format!("{:<10}", label)
+        } else {
+            format!("{:<10}", format!("{}└─ backing", "   ".repeat(depth - 1)))
+        };
+        println!(
+            "  {}  {:<6} virtual={:<8} allocated={:<8} {}",
+            name,
+            info_str(info, "format"),
+            human_size(info_u64(info, "virtual-size")),
+            human_size(info_u64(info, "actual-size")),
+            info_str(info, "filename"),
+        );
+    }
+    chain.first().map(|info| info_u64(info, "actual-size")).unwrap_or(0)
+}
+
+/// Prints the virtual and allocated sizes of VM disks (and the base images
+/// if no VMs are given).
+///
+/// ---
+pub fn show_disk_usage(vm_names: &[String]) -> Result<(), Box<dyn Error>> {
+    let all_vms = vm_names.is_empty();
+    let vm_names: Vec<String> = if all_vms {
+        filesystem::get_value_from_autovirt_json("vms")
+            .and_then(|v| v.as_object().map(|vms| vms.keys().cloned().collect()))
+            .unwrap_or_default()
+    } else {
+        vm_names.to_vec()
+    };
+
+    let mut total_allocated = 0;
+    for vm_name in &vm_names {
+        let image_path = disk::vm_image_path(vm_name)?;
+        println!("VM {}", vm_name);
+        total_allocated += print_image_usage("main", &image_path);
+        for data_disk in disk::load_data_disks(vm_name) {
+            total_allocated += print_image_usage(&data_disk.name, &data_disk.path);
+        }
+    }
+
+    if all_vms {
+        let downloads_dir = filesystem::get_autovirt_data_dir()
+            .ok_or_else(|| io::Error::other("Failed to find the autovirt data directory"))?
+            .join("_data/downloads");
+        let mut base_images: Vec<_> = fs::read_dir(&downloads_dir)
+            .map(|entries| entries.filter_map(|e| e.ok()).map(|e| e.path()).filter(|p| p.is_file()).collect())
+            .unwrap_or_default();
+        base_images.sort();
+
+        if !base_images.is_empty() {
+            println!("BASE IMAGES");
+        }
+        for base_image in base_images {
+            total_allocated += print_image_usage("image", &base_image.to_string_lossy());
+        }
+    }
+
+    println!("TOTAL ALLOCATED: {}", human_size(total_allocated));
+    Ok(())
+}
+
+/// Records the real size of a VM's disk (in GB, rounded up) in its entry.
+pub fn record_vm_disk_size(vm_name: &str) -> Result<(), Box<dyn Error>> {
+    let info = disk::image_info(&disk::vm_image_path(vm_name)?)?;
+    let size_gb = info_u64(&info, "virtual-size").div_ceil(1 << 30);
+    filesystem::insert_value_into_autovirt_json_object(
+        &format!("vms.{}.size", vm_name),
+        Value::String(size_gb.to_string()),
+    );
+    Ok(())
+}
+
+/// Gets the path and format of a (stopped) VM's main disk or one of its data
+/// disks.
+///
+/// ---
+fn stopped_vm_disk(vm_name: &str, disk_name: Option<&str>) -> Result<(String, String), Box<dyn Error>> {
+    let image_path = disk::vm_image_path(vm_name)?;
+    if run::is_vm_running(vm_name) {
+        return Err(Box::new(io::Error::other(format!(
+            "VM '{}' is running, stop it first",
+            vm_name
+        ))));
+    }
+
+    match disk_name {
+        None => Ok((image_path, disk::vm_disk_format(vm_name)?)),
+        Some(disk_name) => {
+            let data_disks = disk::load_data_disks(vm_name);
+            let data_disk = &data_disks[disk::find_data_disk(&data_disks, disk_name)?];
+            Ok((data_disk.path.clone(), data_disk.format.clone()))
+        }
+    }
+}
+
+fn has_internal_snapshots(info: &Value) -> bool {
+    info.get("snapshots").and_then(Value::as_array).is_some_and(|s| !s.is_empty())
+}
+
+/// Gets space back from a (stopped) VM's disk.
+///
+/// `virt-sparsify --in-place` (if it's installed) frees the blocks that are
+/// free in the guest's filesystems, then the image is rewritten with
+/// `qemu-img convert` which drops everything that's unallocated or zeroed.
+/// Images with internal snapshots aren't rewritten since convert would lose
+/// them.
+///
+/// ---
+pub fn compact_vm_disk(vm_name: &str, disk_name: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let (image_path, format) = stopped_vm_disk(vm_name, disk_name)?;
+    let info = disk::image_info(&image_path)?;
+    let allocated_before = info_u64(&info, "actual-size");
+
+    if cloudinit::command_exists("virt-sparsify") {
+        println!("LOG:: Freeing unused guest filesystem blocks in {}...", image_path);
+        let status = Command::new("virt-sparsify").args(["--in-place", &image_path]).status()?;
+        if !status.success() {
+            return Err(Box::new(io::Error::other(format!("virt-sparsify failed with {}", status))));
+        }
+    } else {
+        println!("INFO:: virt-sparsify (libguestfs) isn't installed so blocks freed inside the VM can't be found");
+        println!("INFO:: Running `sudo fstrim -av` in the VM (with disk discard on) frees them instead");
+    }
+
+    if has_internal_snapshots(&info) {
+        println!("INFO:: The image has internal snapshots so it isn't rewritten (that would lose them)");
+    } else {
+        println!("LOG:: Rewriting {} {} image...", image_path, format);
+        let tmp_image_path = format!("{}.compact-tmp", image_path);
+        let mut convert_cmd = Command::new("qemu-img");
+        convert_cmd.args(["convert", "-p", "-f", &format, "-O", &format]);
+        // keeping the image an overlay on the same backing file
+        if let Some(backing) = info.get("full-backing-filename").and_then(Value::as_str) {
+            let backing_format = disk::probe_image_format(backing)?;
+            convert_cmd.args(["-B", backing, "-F", &backing_format]);
+        }
+        convert_cmd.arg(&image_path).arg(&tmp_image_path);
+
+        let status = convert_cmd.status()?;
+        if !status.success() {
+            let _ = fs::remove_file(&tmp_image_path);
+            return Err(Box::new(io::Error::other(format!("qemu-img convert failed with {}", status))));
+        }
+        fs::rename(&tmp_image_path, &image_path)?;
+    }
+
+    let allocated_after = info_u64(&disk::image_info(&image_path)?, "actual-size");
+    println!(
+        "LOG:: Disk compacted ({} -> {}, {} reclaimed)",
+        human_size(allocated_before),
+        human_size(allocated_after),
+        human_size(allocated_before.saturating_sub(allocated_after))
+    );
+    Ok(())
+}
+
+/// Finds where the last partition on a disk image ends (in bytes) from its GPT
+/// or MBR partition table. `None` if the image has no partition table.
+///
+/// ---
+fn partitions_end(image_path: &str, format: &str) -> Result<Option<u64>, Box<dyn Error>> {
+    let tmp_path = std::env::temp_dir().join(format!("autovirt-parts-{}.raw", std::process::id()));
+    let output = Command::new("qemu-img")
+        .args(["dd", "-f", format, "-O", "raw", "bs=512"])
+        .arg(format!("count={}", PARTITION_TABLE_SECTORS))
+        .arg(format!("if={}", image_path))
+        .arg(format!("of={}", tmp_path.to_string_lossy()))
+        .output()?;
+    let sectors = fs::read(&tmp_path);
+    let _ = fs::remove_file(&tmp_path);
+    if !output.status.success() {
+        return Err(Box::new(io::Error::other(format!(
+            "Failed to read the partition table -> {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))));
+    }
+    let sectors = sectors?;
+
+    let u32_at = |offset: usize| u32::from_le_bytes(sectors[offset..offset + 4].try_into().unwrap()) as u64;
+    let u64_at = |offset: usize| u64::from_le_bytes(sectors[offset..offset + 8].try_into().unwrap());
+
+    if sectors.len() < 1024 || sectors[510..512] != [0x55, 0xaa] {
+        return Ok(None);
+    }
+
+    // a protective MBR partition means the real table is GPT
+    let mbr_entries: Vec<usize> = (0..4).map(|i| 446 + i * 16).collect();
+    if mbr_entries.iter().any(|&entry| sectors[entry + 4] == 0xee) {
+        if &sectors[512..520] != b"EFI PART" {
+            return Err(Box::new(io::Error::other("The GPT header is missing or broken")));
+        }
+        let entries_start = u64_at(512 + 72) as usize * 512;
+        let entry_count = u32_at(512 + 80) as usize;
+        let entry_size = u32_at(512 + 84) as usize;
+        if entry_size < 128 || entries_start + entry_count * entry_size > sectors.len() {
+            return Err(Box::new(io::Error::other("Unusual GPT layout, can't tell where the partitions end")));
+        }
+
+        // the backup GPT (33 sectors) has to fit after the last partition too
+        let last_lba = (0..entry_count)
+            .map(|i| entries_start + i * entry_size)
+            .filter(|&entry| sectors[entry..entry + 16].iter().any(|&b| b != 0))
+            .map(|entry| u64_at(entry + 40))
+            .max();
+        return Ok(last_lba.map(|lba| (lba + 1 + 33) * 512));
+    }
+
+    // logical partitions are all inside the extended one so that covers them
+    Ok(mbr_entries
+        .iter()
+        .filter(|&&entry| sectors[entry + 4] != 0)
+        .map(|&entry| (u32_at(entry + 8) + u32_at(entry + 12)) * 512)
+        .max())
+}
+
+/// Runs guestfish commands on a disk image (read only) and gets what they
+/// print.
+///
+/// ---
+fn guestfish(image_path: &str, format: &str, commands: &[&str]) -> Result<String, Box<dyn Error>> {
+    let mut guestfish_cmd = Command::new("guestfish");
+    guestfish_cmd.args(["--ro", &format!("--format={}", format), "-a", image_path, "run"]);
+    for command in commands {
+        guestfish_cmd.arg(":").args(command.split_whitespace());
+    }
+    let output = guestfish_cmd.output()?;
+    if !output.status.success() {
+        return Err(Box::new(io::Error::other(format!(
+            "guestfish failed -> {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Gets a number from a `key: value` line of guestfish output.
+fn guestfish_field(output: &str, key: &str) -> Option<u64> {
+    output.lines().find_map(|line| {
+        let (line_key, value) = line.trim().split_once(':')?;
+        (line_key.trim() == key).then(|| value.trim().parse().ok()).flatten()
+    })
+}
+
+/// Gets the size of an ext2/3/4 or xfs filesystem (in bytes) from its
+/// superblock. `None` for other filesystems.
+///
+/// ---
+fn filesystem_size(image_path: &str, format: &str, device: &str, vfs: &str) -> Result<Option<u64>, Box<dyn Error>> {
+    let (command, count_key, size_key) = match vfs {
+        "ext2" | "ext3" | "ext4" => ("tune2fs-l", "Block count", "Block size"),
+        "xfs" => ("xfs-info", "xfs_datablocks", "xfs_blocksize"),
+        _ => return Ok(None),
+    };
+    let output = guestfish(image_path, format, &[&format!("{} {}", command, device)])?;
+    match (guestfish_field(&output, count_key), guestfish_field(&output, size_key)) {
+        (Some(count), Some(size)) => Ok(Some(count * size)),
+        _ => Err(Box::new(io::Error::other(format!("Couldn't read the size of the {} filesystem on {}", vfs, device)))),
+    }
+}
+
+/// Finds where the partitions and the filesystems on a disk image end (in
+/// bytes), using guestfish (libguestfs) to look at the filesystems. Errors if
+/// that can't be told:
+/// - guestfish isn't installed
+/// - a filesystem fills the whole (unpartitioned) disk and isn't ext or xfs
+/// - there's neither a partition table nor a filesystem
+///
+/// Filesystems on partitions end where the partition does, or where the
+/// filesystem itself does for ext and xfs (which catches a partition that was
+/// shrunk without its filesystem).
+///
+/// ---
+fn used_end(image_path: &str, format: &str) -> Result<u64, Box<dyn Error>> {
+    let refuse = |reason: String| -> Box<dyn Error> {
+        Box::new(io::Error::other(format!("{}, refusing to shrink the disk", reason)))
+    };
+    if !cloudinit::command_exists("guestfish") {
+        return Err(refuse(
+            "guestfish (libguestfs) isn't installed so there's no telling whether the filesystems fit".to_string(),
+        ));
+    }
+
+    let partitioned_end = partitions_end(image_path, format)?;
+    let mut needed = partitioned_end;
+    let filesystems = guestfish(image_path, format, &["list-filesystems"])?;
+    let mut partitions: Option<String> = None;
+
+    // lines like `/dev/sda1: ext4` (the image is always /dev/sda)
+    for (device, vfs) in filesystems.lines().filter_map(|line| line.split_once(": ")) {
+        let device = device.trim();
+        let vfs = vfs.trim();
+        let end = if device == "/dev/sda" {
+            filesystem_size(image_path, format, device, vfs)?
+                .ok_or_else(|| refuse(format!("There's no telling where the {} filesystem on the disk ends", vfs)))?
+        } else if let Some(part_num) = device.strip_prefix("/dev/sda") {
+            if partitions.is_none() {
+                partitions = Some(guestfish(image_path, format, &["part-list /dev/sda"])?);
+            }
+            let part_list = partitions.as_deref().unwrap_or_default();
+            // `part-list` prints a block of `part_num`/`part_start`/`part_end`
+            // lines for each partition
+            let block = part_list
+                .split('}')
+                .find(|block| guestfish_field(block, "part_num") == part_num.parse().ok())
+                .ok_or_else(|| refuse(format!("Couldn't find partition {}", device)))?;
+            let start = guestfish_field(block, "part_start").unwrap_or_default();
+            match filesystem_size(image_path, format, device, vfs)? {
+                Some(size) => start + size,
+                None => guestfish_field(block, "part_end").unwrap_or_default() + 1,
+            }
+        } else if partitioned_end.is_some() {
+            // LVM/RAID volumes are inside the partitions
+            continue;
+        } else {
+            return Err(refuse(format!("There's no telling where {} is on the disk", device)));
+        };
+        needed = Some(needed.map_or(end, |needed| needed.max(end)));
+    }
+
+    needed.ok_or_else(|| refuse("The disk has no partition table or filesystem".to_string()))
+}
+
+/// Shrinks a (stopped) VM's disk. This refuses to cut off any partition or
+/// filesystem so they have to be shrunk in the VM first, and refuses when
+/// where they end can't be told (see `used_end`).
+///
+/// ---
+pub fn shrink_vm_disk(vm_name: &str, disk_name: Option<&str>, new_size: &str) -> Result<(), Box<dyn Error>> {
+    let (image_path, format) = stopped_vm_disk(vm_name, disk_name)?;
+    let new_size_bytes = disk::parse_disk_size(new_size)?;
+    let info = disk::image_info(&image_path)?;
+    let virtual_size = info_u64(&info, "virtual-size");
+
+    if new_size_bytes >= virtual_size {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "The disk is {} already, {} isn't smaller (use `autovirt resize` to grow it)",
+                human_size(virtual_size),
+                new_size
+            ),
+        )));
+    }
+    if has_internal_snapshots(&info) {
+        return Err(Box::new(io::Error::other(
+            "Disks with snapshots can't be shrunk, delete the snapshots first",
+        )));
+    }
+
+    let needed = used_end(&image_path, &format)?;
+    if needed > new_size_bytes {
+        return Err(Box::new(io::Error::other(format!(
+            "The partitions and filesystems on the disk need at least {} ({} bytes), shrink them in the VM first",
+            human_size(needed),
+            needed
+        ))));
+    }
+
+    println!(
+        "WARNING:: Shrinking {} from {} to {}. Anything past the last partition/filesystem is lost.",
+        image_path,
+        human_size(virtual_size),
+        human_size(new_size_bytes)
+    );
+    println!("Proceed? (yes please/N)");
+    let mut user_input = String::new();
+    std::io::stdin().read_line(&mut user_input).expect("Failed to read user input");
+    if user_input.trim() != "yes please" {
+        println!("!!! ABORTING DISK SHRINK !!!");
+        return Ok(());
+    }
+
+    let output = Command::new("qemu-img")
+        .args(["resize", "-f", &format, "--shrink", &image_path])
+        .arg(new_size_bytes.to_string())
+        .output()?;
+    if !output.status.success() {
+        return Err(Box::new(io::Error::other(format!(
+            "qemu-img resize failed -> {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))));
+    }
+
+    match disk_name {
+        None => record_vm_disk_size(vm_name)?,
+        Some(disk_name) => disk::set_data_disk_size(vm_name, disk_name, new_size)?,
+    }
+
+    println!("LOG:: Disk shrunk to {}", human_size(new_size_bytes));
+    println!("INFO:: GPT disks need their backup table moved in the VM (i.e. -> sudo sgdisk -e /dev/vda)");
+    Ok(())
+}
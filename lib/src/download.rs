@@ -14,39 +14,26 @@
 
 
 use reqwest::blocking::Client;
-use std::collections::HashMap;
 use std::error::Error;
-use std::fmt::format;
-use std::fs::File;
-use std::io::copy;
+use std::process::Command;
 
 use std::fs::{self};
-use std::io::{self};
-use std::path::PathBuf;
+use std::io::{self, Read};
+use std::path::Path;
 
+use crate::catalog;
+use crate::disk;
+use crate::downloadqueue::{Progress, ProgressReader};
+use crate::fetch;
 use crate::filesystem;
+use crate::imageindex;
+use crate::images;
+use crate::signature::{self, Verification};
+use crate::unpack;
 
-/// DATA DIRECTORY IS NOW IN THE FS.RS FILE AND IS AT ~/.autovirt
+// DATA DIRECTORY IS NOW IN THE FS.RS FILE AND IS AT ~/.autovirt
 // pub const AUTOVIRT_DATA_DIR: &str = "lib/_data/";
 
-/// Hashmap for all supported images
-static mut AVAILABLE_IMAGES: Option<HashMap<&'static str, &'static str>> = None;
-
-/// THIS FUNCTION IS NOT USED SINCE NOW ALL THE DATA IS STORED IN A JSON FILE.
-/// See filesystem.rs for more info
-/// INit available images hashmap
-pub fn init_available_images() {
-    unsafe {
-        if AVAILABLE_IMAGES.is_none() {
-            let mut m = HashMap::new();
-            m.insert("ubuntu1804", "https://cloud-images.ubuntu.com/releases/18.04/release/ubuntu-18.04-server-cloudimg-amd64.img");
-            m.insert("ubuntu2004", "https://cloud-images.ubuntu.com/releases/20.04/release/ubuntu-20.04-server-cloudimg-amd64.img");
-            m.insert("ubuntu2204", "https://cloud-images.ubuntu.com/releases/22.04/release/ubuntu-22.04-server-cloudimg-amd64.img");
-            AVAILABLE_IMAGES = Some(m);
-        }
-    }
-}
-
 /// Downloads the image for the specified OS/distro to the isos directory in
 /// this project. This is subject to change since there will be an option for
 /// the user to specify where to download the image or the download(s) will be
@@ -59,121 +46,400 @@ pub fn init_available_images() {
 /// If there is no match there will either be an error message and/or a list of
 /// all available images and will/or will default to the ubuntu 22.04 image.
 ///
-pub fn download_vm_image(distro: &String) -> Result<(), Box<dyn Error>> {
-    // Get the download link from the JSON config file
-    let distro_link = match filesystem::get_value_from_autovirt_json(&format!("images.{}.link", distro)) {
-        Some(link) => link.as_str().unwrap_or("").to_string(),
+pub fn download_vm_image(distro: &str, insecure: bool, progress: Option<&Progress>) -> Result<(), Box<dyn Error>> {
+    let image = match catalog::find_image(distro) {
+        Some(image) => image,
         None => {
             eprintln!("ERROR: Could not find a download link for distro -> {}", distro);
+            eprintln!("HINT: See `autovirt show --available` for the images that can be downloaded");
             return Err(Box::new(io::Error::new(io::ErrorKind::NotFound, "Distro link not found")));
         }
     };
+    // imported images (`autovirt image import`) come from a local file
+    if image.url.starts_with("file://") {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{} is an imported image, there is nothing to download ({})", distro, image.url),
+        )));
+    }
+    unpack::check_compression(&image.compression)?;
 
-    // Get the filename for the distro
-    let distro_filename = match filesystem::get_value_from_autovirt_json(&format!("images.{}.filename", distro)) {
-        Some(filename) => filename.as_str().unwrap_or("").to_string(),
-        None => {
-            eprintln!("ERROR: Could not find a filename for distro -> {}", distro);
-            return Err(Box::new(io::Error::new(io::ErrorKind::NotFound, "Distro filename not found")));
-        }
+    // Initialize HTTP client (proxy/CA settings) and make the request
+    let client = fetch::http_client()?;
+
+    // images with an index are downloaded by release so it's known which
+    // one a VM was made from, plain `latest` links still work without one
+    let release = match &image.index {
+        Some(_) => match imageindex::resolve_latest(&client, &image, insecure) {
+            Ok(release) => {
+                println!("INFO: Newest release of {} -> {}", distro, release.serial);
+                Some(release)
+            }
+            Err(e) if !image.url.contains("{serial}") => {
+                println!("WARNING:: Couldn't find the newest release of {} ({}), downloading {} instead", distro, e, image.url);
+                None
+            }
+            Err(e) => return Err(e),
+        },
+        None => None,
+    };
+
+    download_release(&client, distro, &image, release.as_ref(), insecure, progress)
+}
+
+/// The error for a download that can't be verified.
+fn refuse_unverified(distro: &str, reason: impl std::fmt::Display) -> Box<dyn Error> {
+    Box::new(io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("Refusing to download {}, it can't be verified -> {} (use --insecure to download it anyway)", distro, reason),
+    ))
+}
+
+/// Downloads a release of an image (or the image's plain url if there's no
+/// release) into `_data/downloads`, verifies it and records it as the image's
+/// current file.
+///
+/// The checksums have to be signed by the image's keyring (see signature.rs)
+/// unless `insecure`.
+///
+/// ---
+pub fn download_release(
+    client: &Client,
+    distro: &str,
+    image: &catalog::CatalogImage,
+    release: Option<&imageindex::ImageRelease>,
+    insecure: bool,
+    progress: Option<&Progress>,
+) -> Result<(), Box<dyn Error>> {
+    let serial = release.map(|r| r.serial.as_str());
+    let url = release.map(|r| r.url.clone()).unwrap_or_else(|| image.url.clone());
+    let filename = match serial {
+        Some(serial) => images::versioned_filename(&image.filename, serial),
+        None => image.filename.clone(),
     };
 
     // Construct the full file path
     let data_dir = filesystem::get_autovirt_data_dir().unwrap().join("_data/downloads/");
     fs::create_dir_all(&data_dir)?; // Ensure the download directory exists
-    let file_path = data_dir.join(distro_filename);
+    let file_path = data_dir.join(&filename);
 
-    // Initialize HTTP client and make the request
-    let client = Client::new();
-    println!("Downloading image for {} from {}...", distro, distro_link);
+    // a release that's already here just becomes the current one again
+    if let Some(serial) = serial {
+        let known = images::downloaded_image(distro).and_then(|d| d.versions.get(serial).cloned());
+        if let Some(version) = known.filter(|_| file_path.is_file()) {
+            println!("INFO: {} {} is already downloaded -> {}", distro, serial, file_path.to_string_lossy());
+            images::record_download(distro, Some(serial), version);
+            return Ok(());
+        }
+    }
+
+    // The checksum file is fetched first so a missing/broken one doesn't mean
+    // downloading the whole image for nothing (simplestreams has the sha256
+    // of every release already). Checksums are of the download unless the
+    // catalog says they're of the (unpacked) image.
+    let download_name = url_file_name(&url).to_string();
+    let mut verification = insecure.then(Verification::insecure);
+    let expected_checksum = match (release.and_then(|r| r.sha256.clone()), &image.checksum_url) {
+        (Some(sha256), _) => {
+            if !insecure {
+                verification = release.and_then(|r| r.verification.clone());
+                if verification.is_none() {
+                    return Err(refuse_unverified(distro, "its index isn't signed"));
+                }
+            }
+            Some(("sha256".to_string(), sha256, true))
+        }
+        (None, Some(checksum_url)) => {
+            let (listed_name, of_download) = checksum_entry(image, &download_name);
+            let checksum_url = imageindex::url_for_serial(checksum_url, serial.unwrap_or_default());
+            let checksum_urls = fetch::mirror_urls(&checksum_url, &image.mirrors);
+            let contents = fetch_checksum_file(client, &checksum_urls)?;
+            let contents = match insecure {
+                true => contents,
+                false => {
+                    let (contents, verified) = signature::verify_checksum_file(client, distro, image, serial, contents)
+                        .map_err(|e| refuse_unverified(distro, e))?;
+                    verification = Some(verified);
+                    contents
+                }
+            };
+            let checksum = parse_checksum_file(&contents, &listed_name, &image.checksum_type).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("No {} for {} in {}", image.checksum_type, listed_name, checksum_url),
+                )
+            })?;
+            Some((image.checksum_type.clone(), checksum, of_download))
+        }
+        (None, None) if insecure => {
+            println!("WARNING:: No checksum file for {}, the download can't be verified", distro);
+            None
+        }
+        (None, None) => return Err(refuse_unverified(distro, "it has no checksum file")),
+    };
+    if insecure {
+        println!("WARNING:: --insecure, the checksums of {} aren't signature verified", distro);
+    }
+
+    println!("Downloading image for {} from {}...", distro, url);
     println!("Downloading to -> {}", file_path.to_string_lossy());
     println!("INFO: This could take a while depending on your internet connection");
-    println!("HINT: Add an & at the end of the command to run it in the background");
+    println!("HINT: Several images can be downloaded at once (`autovirt download <image> <image>...`)");
+    if image.compression != "none" {
+        println!("INFO: The download is {} compressed, it's unpacked on the fly", image.compression);
+    }
     println!("INFO: Downloading...");
-    let mut response = client.get(&distro_link).send()?;
+    // the mirrors are tried in order if the origin doesn't work
+    let (response, url) = fetch::get(client, &fetch::mirror_urls(&url, &image.mirrors))?;
 
-    if response.status().is_success() {
-        let mut file = File::create(&file_path)?;
-        copy(&mut response, &mut file)?;
-        println!("Downloaded VM image to -> {}", file_path.to_string_lossy());
-    } else {
-        eprintln!("ERROR: Failed to download -> {}", response.status());
-        return Err(Box::new(io::Error::new(
-            io::ErrorKind::Other,
-            format!("HTTP error -> {}", response.status()),
-        )));
+    // downloaded next to the old one which is only replaced once the new
+    // one checks out
+    let part_path = data_dir.join(format!("{}.part", filename));
+    if let Some(progress) = progress {
+        progress.start(distro, response.content_length());
+    }
+    let response = ProgressReader::new(response, progress, distro);
+    let sha256 = unpack_and_check(response, distro, image, expected_checksum, &part_path)?;
+    fs::rename(&part_path, &file_path)?;
+    println!("Downloaded VM image to -> {}", file_path.to_string_lossy());
+
+    // the file extension doesn't say what format the image is really in
+    let format = disk::probe_image_format(&file_path.to_string_lossy())?;
+    println!("Image format -> {}", format);
+    if format != image.format {
+        println!("WARNING:: The catalog says {} is {} but it's {}", distro, image.format, format);
     }
 
+    // VMs record the digest of the image they were made from so it's always
+    // a sha256 (whatever the checksum file had)
+    let sha256 = match sha256 {
+        Some(sha256) => sha256,
+        None => file_checksum(&file_path.to_string_lossy(), "sha256")?,
+    };
+    images::record_download(
+        distro,
+        serial,
+        images::ImageVersion {
+            filename,
+            format,
+            digest: Some(format!("sha256:{}", sha256)),
+            url: Some(url),
+            downloaded_at: Some(chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true)),
+            last_used: None,
+            verification,
+        },
+    );
+    images::enforce_cache_budget(&file_path);
+
     Ok(())
 }
 
+/// Gets the name of the file the checksum file lists for a download and if
+/// that checksum is of the download itself (`checksum_of` isn't `image`).
+///
+/// ---
+fn checksum_entry(image: &catalog::CatalogImage, download_name: &str) -> (String, bool) {
+    match image.checksum_of != "image" {
+        true => (download_name.to_string(), true),
+        false => (unpack::unpacked_file_name(download_name, &image.compression, image.archive_member.as_deref()), false),
+    }
+}
+
+/// Unpacks a download into `part_path` and checks it against the expected
+/// `(checksum type, checksum, of the download)`, the download is hashed on
+/// the way through and the unpacked image afterwards. The part file is
+/// removed if anything is wrong.
+///
+/// Gives back the sha256 of the image if the checksums had it.
+///
+/// ---
+fn unpack_and_check<R: Read>(
+    download: R,
+    distro: &str,
+    image: &catalog::CatalogImage,
+    expected_checksum: Option<(String, String, bool)>,
+    part_path: &Path,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let download_checksum_type = match &expected_checksum {
+        Some((checksum_type, _, true)) => checksum_type.clone(),
+        _ => "sha256".to_string(),
+    };
+    let mut reader = unpack::HashingReader::new(download, &download_checksum_type)?;
+    let unpacked = unpack::unpack(&mut reader, &image.compression, image.archive_member.as_deref(), part_path)
+        .and_then(|_| reader.finish().map_err(|e| Box::new(e) as Box<dyn Error>));
+    let download_checksum = match unpacked {
+        Ok(checksum) => checksum,
+        Err(e) => {
+            let _ = fs::remove_file(part_path);
+            return Err(e);
+        }
+    };
+
+    let mut sha256 = None;
+    if let Some((checksum_type, expected_checksum, of_download)) = expected_checksum {
+        let checksum = match of_download {
+            true => download_checksum,
+            false => file_checksum(&part_path.to_string_lossy(), &checksum_type)?,
+        };
+        if !checksum.eq_ignore_ascii_case(&expected_checksum) {
+            fs::remove_file(part_path)?;
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} mismatch for {} (expected {}, got {}), the download was removed", checksum_type, distro, expected_checksum, checksum),
+            )));
+        }
+        println!("Checksum ({} of the {}) OK -> {}", checksum_type, if of_download { "download" } else { "image" }, checksum);
+        if checksum_type == "sha256" && (!of_download || image.compression == "none") {
+            sha256 = Some(checksum.to_lowercase());
+        }
+    }
+    Ok(sha256)
+}
+
+/// Gets the name of the file a url points at.
+fn url_file_name(url: &str) -> &str {
+    url.split(['?', '#']).next().unwrap_or(url).rsplit('/').next().unwrap_or(url)
+}
+
+/// Finds the checksum of a file in a checksum file.
+///
+/// This understands the usual layouts:
+/// - `<hash>  <file>` or `<hash> *<file>` (sha256sum and friends)
+/// - `SHA256 (<file>) = <hash>` (BSD style, used by Fedora/Rocky/Alma)
+/// - a file with just the hash in it (single image `.sha256` files)
+///
+/// ---
+fn parse_checksum_file(contents: &str, file_name: &str, checksum_type: &str) -> Option<String> {
+    let hash_len = match checksum_type {
+        "sha512" => 128,
+        _ => 64,
+    };
+    let is_hash = |s: &str| s.len() == hash_len && s.chars().all(|c| c.is_ascii_hexdigit());
+
+    let mut lone_hashes = Vec::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
 
+        if let Some((name, hash)) = line.split_once(") = ") {
+            let name = name.split_once(" (").map(|(_, name)| name).unwrap_or(name);
+            if name == file_name && is_hash(hash.trim()) {
+                return Some(hash.trim().to_lowercase());
+            }
+            continue;
+        }
 
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next()) {
+            (Some(hash), Some(name)) if is_hash(hash) && name.trim_start_matches('*') == file_name => {
+                return Some(hash.to_lowercase());
+            }
+            (Some(hash), None) if is_hash(hash) => lone_hashes.push(hash.to_lowercase()),
+            _ => {}
+        }
+    }
 
-// pub fn download_vm_image(distro: &String) -> Result<(), Box<dyn Error>> {
-//     init_available_images();
-
-//     let url;
-
-//     // Safely access the global hashmap
-//     unsafe {
-//         if let Some(images) = &AVAILABLE_IMAGES {
-//             if let Some(link) = images.get(distro.as_str()) {
-//                 println!("DOWNLOAD LINK FOR {}: {}", distro, link);
-//                 url = link;
-//             } else {
-//                 eprintln!("NO LINK FOR DISTRO FOUND: {}", distro);
-//                 return Err(Box::new(std::io::Error::new(
-//                     std::io::ErrorKind::NotFound,
-//                     "No downlead link found for the specified distro",
-//                 )));
-//             }
-//         } else {
-//             eprintln!("AVAILABLE_IMAGES not initialised");
-//             return Err(Box::new(std::io::Error::new(
-//                 std::io::ErrorKind::Other,
-//                 "AVAILABLE_IMAGES not initialised",
-//             )));
-//         }
-//     }
-
-//     // getting the user's home diretory
-
-//     let file_path = format!("{}-autovirt.img", distro);
-
-//     let client = Client::new();
-
-//     println!("Downloading image for {}...", distro);
-//     let mut response = match client.get(url.clone()).send() {
-//         Ok(resp) => resp,
-//         Err(e) => {
-//             eprintln!("ERROR: failed to send request -> {}", e);
-//             return Err(Box::new(e));
-//         }
-//     };
-
-//     if response.status().is_success() {
-//         let mut file = match File::create(&file_path) {
-//             Ok(f) => f,
-//             Err(e) => {
-//                 eprintln!("ERROR: failed to create file -> {}", e);
-//                 return Err(Box::new(e));
-//             }
-//         };
-
-//         if let Err(e) = copy(&mut response, &mut file) {
-//             eprintln!("ERROR: failed to copy data to file -> {}", e);
-//             return Err(Box::new(e));
-//         }
-
-//         println!("Downloaded vm file/image to -> {}", file_path);
-//     } else {
-//         eprintln!("ERROR: failed to download -> {}", response.status());
-//         return Err(Box::new(std::io::Error::new(
-//             std::io::ErrorKind::Other,
-//             format!("ERROR: some HTTP error -> {}", response.status()),
-//         )));
-//     }
-
-//     Ok(())
-// }
+    // a lone hash only counts if it's the only thing in the file
+    if lone_hashes.len() == 1 {
+        lone_hashes.pop()
+    } else {
+        None
+    }
+}
+
+/// Downloads an image's checksum file (from the first of its urls that
+/// works).
+///
+/// ---
+fn fetch_checksum_file(client: &Client, checksum_urls: &[String]) -> Result<String, Box<dyn Error>> {
+    println!("INFO: Fetching checksums from {}", checksum_urls.first().map(String::as_str).unwrap_or_default());
+    fetch::get_text(client, checksum_urls)
+        .map_err(|e| Box::new(io::Error::other(format!("Failed to fetch the checksum file -> {}", e))) as Box<dyn Error>)
+}
+
+/// Gets the checksum of a file with `sha256sum`/`sha512sum`.
+pub fn file_checksum(path: &str, checksum_type: &str) -> Result<String, Box<dyn Error>> {
+    let tool = match checksum_type {
+        "sha256" => "sha256sum",
+        "sha512" => "sha512sum",
+        other => {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown checksum type '{}' (sha256 or sha512)", other),
+            )))
+        }
+    };
+
+    let output = Command::new(tool).arg(path).output()?;
+    if !output.status.success() {
+        return Err(Box::new(io::Error::other(format!(
+            "{} failed -> {}",
+            tool,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(stdout.split_whitespace().next().unwrap_or_default().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use sha2::{Digest, Sha256};
+    use std::io::Write;
+
+    fn sha256_hex(data: &[u8]) -> String {
+        Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn gz_image(checksum_of: &str) -> catalog::CatalogImage {
+        serde_json::from_value(json!({
+            "url": "https://example.org/images/disk.img.gz",
+            "filename": "disk.img",
+            "compression": "gz",
+            "checksum_url": "https://example.org/images/SHA256SUMS",
+            "checksum_of": checksum_of,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn checksum_entry_is_the_download_or_the_image() {
+        assert_eq!(checksum_entry(&gz_image("download"), "disk.img.gz"), ("disk.img.gz".to_string(), true));
+        assert_eq!(checksum_entry(&gz_image("image"), "disk.img.gz"), ("disk.img".to_string(), false));
+    }
+
+    #[test]
+    fn checksum_is_of_the_download_or_the_image() {
+        let image: Vec<u8> = (0..100_000u32).map(|i| (i % 253) as u8).collect();
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&image).unwrap();
+        let download = encoder.finish().unwrap();
+        let (download_sha256, image_sha256) = (sha256_hex(&download), sha256_hex(&image));
+        let part_path = std::env::temp_dir().join(format!("autovirt-download-test-{}.part", std::process::id()));
+
+        let check = |checksum_of: &str, checksum: &str| {
+            let of_download = checksum_of != "image";
+            let expected = Some(("sha256".to_string(), checksum.to_string(), of_download));
+            unpack_and_check(&download[..], "test", &gz_image(checksum_of), expected, &part_path)
+        };
+
+        // the checksum of a compressed download doesn't say what the image's
+        // sha256 is
+        assert_eq!(check("download", &download_sha256).unwrap(), None);
+        assert_eq!(fs::read(&part_path).unwrap(), image);
+        assert!(check("download", &image_sha256).is_err());
+        assert!(!part_path.exists(), "a download that doesn't check out is removed");
+
+        assert_eq!(check("image", &image_sha256).unwrap(), Some(image_sha256.clone()));
+        assert_eq!(fs::read(&part_path).unwrap(), image);
+        assert!(check("image", &download_sha256).is_err());
+        assert!(!part_path.exists(), "a download that doesn't check out is removed");
+
+        // without checksums (--insecure and none in the catalog) it's just unpacked
+        assert_eq!(unpack_and_check(&download[..], "test", &gz_image("download"), None, &part_path).unwrap(), None);
+        fs::remove_file(&part_path).unwrap();
+    }
+}
@@ -0,0 +1,352 @@
+//! This file contains the download queue which fetches several images at once
+//! (`autovirt download ubuntu2204 debian12 fedora40` or `--all`).
+//!
+//! - the downloads run on the tokio runtime with at most `--jobs` (or
+//!   `download.jobs` in autovirt.json, 3 by default) at the same time
+//! - one progress line for all of them is printed every few seconds
+//! - failed downloads are retried `download.retries` times (2 by default)
+//!   with a growing wait in between, refused ones (unverified, unknown
+//!   images) aren't since they'd fail the same way again
+//! - every download holds a lockfile in `_data/downloads` so two autovirt
+//!   commands asking for the same image don't both download it, the second
+//!   one waits for the first and uses what it got
+//!
+//! ---
+
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+use crate::catalog;
+use crate::diskspace;
+use crate::download;
+use crate::filesystem;
+use crate::images;
+
+const DEFAULT_JOBS: usize = 3;
+const DEFAULT_RETRIES: u32 = 2;
+/// The first wait before a retry, it doubles every time (up to a minute)
+const RETRY_BACKOFF: Duration = Duration::from_secs(2);
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq)]
+enum DownloadState {
+    Queued,
+    /// Another autovirt is downloading it (its pid)
+    Waiting(u32),
+    Downloading,
+    /// Waiting to retry (the attempt that failed)
+    Retrying(u32),
+    Done,
+    Failed,
+}
+
+#[derive(Debug, Clone)]
+struct ImageProgress {
+    state: DownloadState,
+    bytes: u64,
+    total: Option<u64>,
+}
+
+/// The progress of every download in the queue.
+#[derive(Debug, Default)]
+pub struct Progress {
+    images: Mutex<BTreeMap<String, ImageProgress>>,
+}
+
+impl Progress {
+    fn update<F: FnOnce(&mut ImageProgress)>(&self, name: &str, f: F) {
+        let mut images = self.images.lock().unwrap_or_else(|e| e.into_inner());
+        let image = images.entry(name.to_string()).or_insert(ImageProgress {
+            state: DownloadState::Queued,
+            bytes: 0,
+            total: None,
+        });
+        f(image);
+    }
+
+    fn set_state(&self, name: &str, state: DownloadState) {
+        self.update(name, |image| image.state = state);
+    }
+
+    /// Marks the start of the transfer of an image (the size is from the
+    /// server, if it said).
+    ///
+    /// ---
+    pub fn start(&self, name: &str, total: Option<u64>) {
+        self.update(name, |image| {
+            image.state = DownloadState::Downloading;
+            image.bytes = 0;
+            image.total = total;
+        });
+    }
+
+    fn add_bytes(&self, name: &str, bytes: u64) {
+        self.update(name, |image| image.bytes += bytes);
+    }
+
+    /// The progress line (i.e. -> `ubuntu2204 45% (290M/650M), debian12 done`).
+    fn summary(&self) -> String {
+        let images = self.images.lock().unwrap_or_else(|e| e.into_inner());
+        images
+            .iter()
+            .map(|(name, image)| match &image.state {
+                DownloadState::Queued => format!("{} queued", name),
+                DownloadState::Waiting(pid) => format!("{} waiting for pid {}", name, pid),
+                DownloadState::Downloading => match image.total.filter(|total| *total > 0) {
+                    Some(total) => format!(
+                        "{} {}% ({}/{})",
+                        name,
+                        image.bytes * 100 / total,
+                        diskspace::human_size(image.bytes),
+                        diskspace::human_size(total)
+                    ),
+                    None => format!("{} {}", name, diskspace::human_size(image.bytes)),
+                },
+                DownloadState::Retrying(attempt) => format!("{} retrying (attempt {} failed)", name, attempt),
+                DownloadState::Done => format!("{} done", name),
+                DownloadState::Failed => format!("{} FAILED", name),
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// A reader that counts what goes through it into the queue's progress (it
+/// just passes things through without a queue).
+///
+/// ---
+pub struct ProgressReader<'a, R: Read> {
+    inner: R,
+    progress: Option<&'a Progress>,
+    name: &'a str,
+}
+
+impl<'a, R: Read> ProgressReader<'a, R> {
+    pub fn new(inner: R, progress: Option<&'a Progress>, name: &'a str) -> ProgressReader<'a, R> {
+        ProgressReader { inner, progress, name }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if let Some(progress) = self.progress {
+            progress.add_bytes(self.name, read as u64);
+        }
+        Ok(read)
+    }
+}
+
+/// The lockfile of an image's download, removed when it's dropped.
+struct DownloadLock {
+    path: PathBuf,
+}
+
+impl Drop for DownloadLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Takes the download lock of an image, or gives back the pid of the autovirt
+/// that has it.
+///
+/// Locks of processes that are gone (killed mid download) are taken over.
+///
+/// ---
+fn try_lock(downloads_dir: &Path, name: &str) -> io::Result<Result<DownloadLock, u32>> {
+    let path = downloads_dir.join(format!(".{}.lock", name));
+    for _ in 0..2 {
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                write!(file, "{}", std::process::id())?;
+                return Ok(Ok(DownloadLock { path }));
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                let holder = fs::read_to_string(&path).ok().and_then(|pid| pid.trim().parse::<u32>().ok());
+                match holder {
+                    Some(pid) if Path::new(&format!("/proc/{}", pid)).exists() => return Ok(Err(pid)),
+                    _ => {
+                        println!("INFO: Removing the stale download lock of {}", name);
+                        fs::remove_file(&path)?;
+                    }
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::WouldBlock, format!("Couldn't take the download lock of {}", name)))
+}
+
+/// Errors that would be the same on a retry (refused or unknown images).
+fn is_retryable(e: &(dyn Error + 'static)) -> bool {
+    !matches!(
+        e.downcast_ref::<io::Error>().map(io::Error::kind),
+        Some(io::ErrorKind::PermissionDenied | io::ErrorKind::NotFound | io::ErrorKind::InvalidInput | io::ErrorKind::Unsupported)
+    )
+}
+
+/// When an image's current file was downloaded (to tell if someone else got
+/// it while we were waiting).
+///
+/// ---
+fn downloaded_at(name: &str) -> Option<String> {
+    images::downloaded_image(name).and_then(|d| d.downloaded_at)
+}
+
+/// Downloads one image for the queue (waiting for its lock and retrying).
+fn download_one(name: &str, insecure: bool, retries: u32, progress: &Progress) -> Result<(), Box<dyn Error>> {
+    let downloads_dir = images::downloads_dir()?;
+    fs::create_dir_all(&downloads_dir)?;
+
+    let before = downloaded_at(name);
+    let mut waited = false;
+    let _lock = loop {
+        match try_lock(&downloads_dir, name)? {
+            Ok(lock) => break lock,
+            Err(pid) => {
+                if !waited {
+                    println!("INFO: {} is being downloaded by another autovirt (pid {}), waiting for it", name, pid);
+                    progress.set_state(name, DownloadState::Waiting(pid));
+                    waited = true;
+                }
+                std::thread::sleep(Duration::from_secs(1));
+            }
+        }
+    };
+    if waited && downloaded_at(name) != before {
+        println!("LOG:: {} was downloaded by the other autovirt", name);
+        return Ok(());
+    }
+
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        progress.set_state(name, DownloadState::Downloading);
+        match download::download_vm_image(name, insecure, Some(progress)) {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt <= retries && is_retryable(e.as_ref()) => {
+                let wait = (RETRY_BACKOFF * 2u32.pow(attempt - 1)).min(Duration::from_secs(60));
+                println!("WARNING:: Downloading {} failed ({}), retrying in {}s", name, e, wait.as_secs());
+                progress.set_state(name, DownloadState::Retrying(attempt));
+                std::thread::sleep(wait);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Gets a number from the `download` settings in autovirt.json.
+fn download_setting_u64(key: &str) -> Option<u64> {
+    filesystem::get_value_from_autovirt_json(&format!("download.{}", key)).and_then(|v| match v {
+        Value::Number(n) => n.as_u64(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    })
+}
+
+/// Downloads some images (every catalog image with `all`) at the same time,
+/// at most `jobs` at once.
+///
+/// ---
+pub async fn download_images(names: &[String], all: bool, jobs: Option<usize>, insecure: bool) -> Result<(), Box<dyn Error>> {
+    let mut names: Vec<String> = match all {
+        // imported images have nothing to download
+        true => catalog::all_images()
+            .into_iter()
+            .filter(|(_, image)| !image.url.starts_with("file://"))
+            .map(|(name, _)| name)
+            .collect(),
+        false => names.to_vec(),
+    };
+    let mut seen = std::collections::HashSet::new();
+    names.retain(|name| seen.insert(name.clone()));
+    if names.is_empty() {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "No images to download (give their names or --all)",
+        )));
+    }
+
+    let jobs = jobs
+        .or_else(|| download_setting_u64("jobs").map(|jobs| jobs as usize))
+        .unwrap_or(DEFAULT_JOBS)
+        .max(1);
+    let retries = download_setting_u64("retries").map(|r| r as u32).unwrap_or(DEFAULT_RETRIES);
+    if names.len() > 1 {
+        println!("INFO: Downloading {} images, {} at a time -> {}", names.len(), jobs.min(names.len()), names.join(", "));
+    }
+
+    let progress = Arc::new(Progress::default());
+    for name in &names {
+        progress.set_state(name, DownloadState::Queued);
+    }
+    let finished = Arc::new(AtomicBool::new(false));
+    let reporter = {
+        let progress = progress.clone();
+        let finished = finished.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PROGRESS_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if finished.load(Ordering::Relaxed) {
+                    break;
+                }
+                println!("LOG:: Progress -> {}", progress.summary());
+            }
+        })
+    };
+
+    let workers = Arc::new(Semaphore::new(jobs));
+    let mut handles = Vec::new();
+    for name in names.clone() {
+        let workers = workers.clone();
+        let progress = progress.clone();
+        handles.push(tokio::spawn(async move {
+            let _permit = workers.acquire_owned().await.expect("ERROR: The download queue was closed");
+            let worker_progress = progress.clone();
+            let worker_name = name.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                download_one(&worker_name, insecure, retries, &worker_progress).map_err(|e| e.to_string())
+            })
+            .await
+            .unwrap_or_else(|e| Err(format!("the download crashed -> {}", e)));
+            match &result {
+                Ok(()) => progress.set_state(&name, DownloadState::Done),
+                Err(e) => {
+                    eprintln!("ERROR: Failed to download {} -> {}", name, e);
+                    progress.set_state(&name, DownloadState::Failed);
+                }
+            }
+            (name, result.is_ok())
+        }));
+    }
+
+    let mut failed = Vec::new();
+    for handle in handles {
+        match handle.await {
+            Ok((_, true)) => {}
+            Ok((name, false)) => failed.push(name),
+            Err(e) => failed.push(format!("? ({})", e)),
+        }
+    }
+    finished.store(true, Ordering::Relaxed);
+    reporter.abort();
+
+    if names.len() > 1 {
+        println!("LOG:: Downloads -> {}", progress.summary());
+    }
+    if !failed.is_empty() {
+        return Err(Box::new(io::Error::other(format!("Failed to download -> {}", failed.join(", ")))));
+    }
+    Ok(())
+}
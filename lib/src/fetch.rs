@@ -0,0 +1,107 @@
+//! This file contains the http side of downloads (images, checksum files and
+//! indexes) so they all go through the same proxy/CA settings and mirrors.
+//!
+//! The settings are under `download` in autovirt.json:
+//! - `proxy` -> the proxy for http and https (HTTP_PROXY/HTTPS_PROXY/ALL_PROXY
+//!   are used if it's not set)
+//! - `no_proxy` -> hosts that don't go through the proxy (NO_PROXY otherwise)
+//! - `ca_bundle` -> a pem file with extra CA certificates to trust (for
+//!   proxies and mirrors with an in-house CA)
+//!
+//! Images can have `mirrors`, servers with the same layout as the one the
+//! image urls point at. They are tried in order when the origin doesn't work.
+//!
+//! ---
+
+use reqwest::blocking::{Client, Response};
+use reqwest::{Certificate, Proxy};
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::time::Duration;
+
+use crate::filesystem;
+
+/// Gets a `download.<key>` setting from autovirt.json.
+fn download_setting(key: &str) -> Option<String> {
+    filesystem::get_value_from_autovirt_json(&format!("download.{}", key))
+        .and_then(|v| v.as_str().map(String::from))
+        .filter(|v| !v.is_empty())
+}
+
+/// Makes the http client for downloads (with the proxy and CA settings).
+pub fn http_client() -> Result<Client, Box<dyn Error>> {
+    let mut builder = Client::builder().connect_timeout(Duration::from_secs(30)).timeout(None);
+
+    if let Some(proxy_url) = download_setting("proxy") {
+        let no_proxy = download_setting("no_proxy").and_then(|n| reqwest::NoProxy::from_string(&n));
+        builder = builder.proxy(Proxy::all(&proxy_url)?.no_proxy(no_proxy));
+        println!("INFO: Using the proxy {}", proxy_url);
+    }
+
+    if let Some(ca_bundle) = download_setting("ca_bundle") {
+        let pem = fs::read(&ca_bundle)
+            .map_err(|e| io::Error::new(e.kind(), format!("Failed to read the CA bundle {} -> {}", ca_bundle, e)))?;
+        let certificates = Certificate::from_pem_bundle(&pem)?;
+        if certificates.is_empty() {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("No certificates in the CA bundle {}", ca_bundle),
+            )));
+        }
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+
+    Ok(builder.build()?)
+}
+
+/// Gets the urls to try for something, the url itself and then the same path
+/// on every mirror (i.e. -> `https://cloud.debian.org/images/x` on the mirror
+/// `http://mirror.lab/debian` is `http://mirror.lab/debian/images/x`).
+///
+/// ---
+pub fn mirror_urls(url: &str, mirrors: &[String]) -> Vec<String> {
+    let mut urls = vec![url.to_string()];
+    let path = url
+        .split_once("://")
+        .filter(|(scheme, _)| *scheme != "file")
+        .and_then(|(_, rest)| rest.find('/').map(|position| &rest[position..]));
+    if let Some(path) = path {
+        urls.extend(mirrors.iter().map(|mirror| format!("{}{}", mirror.trim_end_matches('/'), path)));
+    }
+    urls
+}
+
+/// Gets the first of some urls that works (the others are fallbacks), with
+/// the url it came from.
+///
+/// ---
+pub fn get(client: &Client, urls: &[String]) -> Result<(Response, String), Box<dyn Error>> {
+    let mut errors = Vec::new();
+    for url in urls {
+        if !errors.is_empty() {
+            println!("INFO: Trying {}", url);
+        }
+        match client.get(url).send() {
+            Ok(response) if response.status().is_success() => return Ok((response, url.clone())),
+            Ok(response) => errors.push(format!("{} -> HTTP {}", url, response.status())),
+            Err(e) => errors.push(format!("{} -> {}", url, e)),
+        }
+        println!("WARNING:: Failed to fetch {}", errors.last().map(String::as_str).unwrap_or_default());
+    }
+    Err(Box::new(io::Error::other(format!("Failed to fetch from any url ({})", errors.join("; ")))))
+}
+
+/// Gets the contents of the first of some urls that works (http(s) or
+/// `file://`).
+///
+/// ---
+pub fn get_text(client: &Client, urls: &[String]) -> Result<String, Box<dyn Error>> {
+    if let Some(path) = urls.first().and_then(|url| url.strip_prefix("file://")) {
+        return fs::read_to_string(path)
+            .map_err(|e| Box::new(io::Error::new(e.kind(), format!("Failed to read {} -> {}", path, e))) as Box<dyn Error>);
+    }
+    Ok(get(client, urls)?.0.text()?)
+}
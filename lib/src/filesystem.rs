@@ -1,36 +1,24 @@
-use serde_json::{Result, Value};
+use serde_json::Value;
 use std::env;
 use std::fs;
 use std::fs::OpenOptions;
 use std::io::{self, Write};
 use std::path::PathBuf;
+use std::sync::Mutex;
 use serde_json::json;
 
 use crate::initdata;
 
 
+/// Lock so that threads in the same process don't overwrite each other's
+/// changes to the autovirt config file.
+static AUTOVIRT_JSON_LOCK: Mutex<()> = Mutex::new(());
+
 const DEFAULT_AUTOVIRT_CONFIG_DATA: &str = r#"
 {
     "something": "autovirt",
     "version": "0.0.1",
-    "images": {
-        "ubuntu1804": {
-            "link": "https://cloud-images.ubuntu.com/releases/18.04/release/ubuntu-18.04-server-cloudimg-amd64.img",
-            "filename": "ubuntu-18.04-autovirt-server-cloudimg-amd64.img"
-        },
-        "ubuntu2004": {
-            "link": "https://cloud-images.ubuntu.com/releases/20.04/release/ubuntu-20.04-server-cloudimg-amd64.img",
-            "filename": "ubuntu-20.04-autovirt-server-cloudimg-amd64.img"
-        },
-        "ubuntu2204": {
-            "link": "https://cloud-images.ubuntu.com/releases/22.04/release/ubuntu-22.04-server-cloudimg-amd64.img",
-            "filename": "ubuntu-22.04-autovirt-server-cloudimg-amd64.img"
-        },
-        "ubuntu2404": {
-            "link": "https://cloud-images.ubuntu.com/releases/24.04/release/ubuntu-24.04-server-cloudimg-amd64.img",
-            "filename": "ubuntu-24.04-autovirt-server-cloudimg-amd64.img"
-        }
-    },
+    "images": {},
     "downloaded_images": {},
     "vms": {}
 }
@@ -55,6 +43,30 @@ pub fn get_autovirt_data_dir() -> Option<PathBuf> {
     }
 }
 
+/// Function that gets the data directory for a single VM (by name) which is
+/// where the generated cloud-init files and other per VM things are kept.
+///
+/// The directory is at `~/.autovirt/_data/vms/<vm name>/` and is created if it
+/// isn't there already.
+///
+/// ---
+pub fn get_vm_data_dir(vm_name: &str) -> io::Result<PathBuf> {
+    let vm_data_dir = vm_data_dir_path(vm_name)?;
+    fs::create_dir_all(&vm_data_dir)?;
+    Ok(vm_data_dir)
+}
+
+/// Gets the path of a VM's data directory without creating it (for paths
+/// that only go into commands).
+///
+/// ---
+pub fn vm_data_dir_path(vm_name: &str) -> io::Result<PathBuf> {
+    let autovirt_dir = get_autovirt_data_dir().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, "ERROR: COULD NOT FIND USER $HOME DIRECTORY")
+    })?;
+    Ok(autovirt_dir.join("_data/vms").join(vm_name))
+}
+
 /// Function that creates the autovirt data directory with all the required
 /// files.
 ///
@@ -104,9 +116,9 @@ pub fn create_autovirt_data_dir() -> io::Result<()> {
 /// List of things that are added/returned by this to the config file:
 ///
 /// - version               `# autovirt version`
-/// - images                `# all the images available for autovirt`
-///   - ubuntu2204: link    `# link to distro download`
-///   - ubuntu2204: link    `# link to distro download`
+/// - images                `# changes to/extra images on top of the catalog (see catalog.rs)`
+///   - mydistro: url       `# link to distro download`
+///   - mydistro: filename  `# name of the download`
 /// - other metadata
 ///
 /// Some things may change here and alot of other things will be added in the
@@ -195,10 +207,10 @@ pub fn get_autovirt_json_path() -> String {
 pub fn get_value_from_autovirt_json(key: &str) -> Option<Value> {
     let file_path = get_autovirt_json_path();
     let file_content = fs::read_to_string(file_path).unwrap_or_else(|_| "{}".to_string());
-    let v: Value = serde_json::from_str(&file_content).unwrap_or_else(|_| Value::Null);
+    let v: Value = serde_json::from_str(&file_content).unwrap_or(Value::Null);
 
     key.split('.')
-        .fold(Some(&v), |acc, part| acc?.get(part))
+        .try_fold(&v, |acc, part| acc.get(part))
         .cloned()
 }
 
@@ -208,6 +220,7 @@ pub fn get_value_from_autovirt_json(key: &str) -> Option<Value> {
 /// directory at `$HOME/.autovirt/autovirt.json`
 ///
 /// ---
+#[allow(dead_code)]
 pub fn insert_value_into_autovirt_json(key: &str, value: &str) {
     let file_path = get_autovirt_json_path();
 
@@ -240,6 +253,7 @@ pub fn insert_value_into_autovirt_json(key: &str, value: &str) {
 /// ---
 pub fn insert_value_into_autovirt_json_object(key: &str, value: Value) {
     let file_path = get_autovirt_json_path();
+    let _lock = AUTOVIRT_JSON_LOCK.lock().unwrap_or_else(|e| e.into_inner());
 
     let file_content = fs::read_to_string(&file_path).unwrap_or_else(|_| "{}".to_string());
     let mut v: Value = serde_json::from_str(&file_content).unwrap_or_else(|_| json!({}));
@@ -256,6 +270,69 @@ pub fn insert_value_into_autovirt_json_object(key: &str, value: Value) {
 
     current[keys[keys.len() - 1]] = value;
 
-    fs::write(&file_path, serde_json::to_string_pretty(&v).unwrap()).expect("Failed to write to file");
+    write_autovirt_json_file(&file_path, &v).expect("Failed to write to file");
 }
 
+/// Removes a key (and everything under it) from the autovirt config file.
+/// Gives back if there was anything to remove.
+///
+/// ---
+pub fn remove_value_from_autovirt_json(key: &str) -> bool {
+    let file_path = get_autovirt_json_path();
+    let _lock = AUTOVIRT_JSON_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let file_content = fs::read_to_string(&file_path).unwrap_or_else(|_| "{}".to_string());
+    let mut v: Value = serde_json::from_str(&file_content).unwrap_or_else(|_| json!({}));
+
+    let (parent_key, last_key) = key.rsplit_once('.').unwrap_or(("", key));
+    let parent = if parent_key.is_empty() {
+        Some(&mut v)
+    } else {
+        parent_key.split('.').try_fold(&mut v, |acc, part| acc.get_mut(part))
+    };
+    let removed = parent
+        .and_then(|parent| parent.as_object_mut())
+        .and_then(|parent| parent.remove(last_key))
+        .is_some();
+
+    if removed {
+        write_autovirt_json_file(&file_path, &v).expect("Failed to write to file");
+    }
+    removed
+}
+
+/// Writes the whole autovirt config file by writing to a temp file first and
+/// then renaming it over the real one so the config file never ends up half
+/// written (i.e. when autovirt exits while something in the background is
+/// updating a VM's entry).
+///
+/// ---
+pub fn write_autovirt_json_file(file_path: &str, v: &Value) -> io::Result<()> {
+    let tmp_file_path = format!("{}.tmp-{}", file_path, std::process::id());
+    let contents = serde_json::to_string_pretty(v).map_err(io::Error::other)?;
+    fs::write(&tmp_file_path, contents)?;
+    fs::rename(&tmp_file_path, file_path)
+}
+
+
+/// Points `$HOME` at a temporary directory with an empty autovirt config file
+/// for the tests that need one. It's set up once and shared so the tests hold
+/// the guard while they use it.
+///
+/// ---
+#[cfg(test)]
+pub fn test_home() -> std::sync::MutexGuard<'static, ()> {
+    static TEST_HOME_LOCK: Mutex<()> = Mutex::new(());
+    static SETUP: std::sync::Once = std::sync::Once::new();
+
+    let guard = TEST_HOME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    SETUP.call_once(|| {
+        let home = env::temp_dir().join(format!("autovirt-test-home-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&home);
+        fs::create_dir_all(home.join(".autovirt/_data/vms")).expect("Failed to create the test home");
+        fs::write(home.join(".autovirt/autovirt.json"), DEFAULT_AUTOVIRT_CONFIG_DATA)
+            .expect("Failed to write the test config file");
+        env::set_var("HOME", &home);
+    });
+    guard
+}
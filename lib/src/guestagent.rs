@@ -0,0 +1,318 @@
+//! This file contains a small client for qemu-guest-agent which runs inside
+//! VMs and lets autovirt do things in them without SSH (or port forwards).
+//!
+//! Every VM gets a virtio-serial channel for the agent (`qga.sock` in its data
+//! directory, see `run::runtime_args`) and the agent is installed by the
+//! generated cloud-init config. The protocol is the same json as QMP but the
+//! agent only answers once it's running in the VM so every connection starts
+//! with a `guest-sync` to throw away anything stale on the channel.
+//!
+//! ---
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde_json::{json, Value};
+use std::error::Error;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::filesystem;
+use crate::run;
+
+/// The name of the virtio-serial port the agent looks for.
+pub const GUEST_AGENT_PORT: &str = "org.qemu.guest_agent.0";
+
+/// A connection to a VM's guest agent.
+pub struct GuestAgentClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl GuestAgentClient {
+    /// Connects to a VM's guest agent socket and syncs with the agent.
+    /// `timeout` is how long to wait for the agent to answer.
+    ///
+    /// ---
+    pub fn connect(vm_name: &str, timeout: Duration) -> Result<GuestAgentClient, Box<dyn Error>> {
+        if !run::is_vm_running(vm_name) {
+            return Err(Box::new(io::Error::other(format!("VM '{}' is not running", vm_name))));
+        }
+
+        let socket_path = guest_agent_socket_path(vm_name)?;
+        let stream = UnixStream::connect(&socket_path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to connect to {:?} (VM started before autovirt added the agent channel?) -> {}", socket_path, e),
+            )
+        })?;
+        stream.set_read_timeout(Some(timeout))?;
+
+        let mut client = GuestAgentClient {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        };
+
+        let sync_id = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as u64 & 0xffff_ffff;
+        client.send("guest-sync", Some(json!({ "id": sync_id })))?;
+        loop {
+            let message = client.read_message().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("The guest agent in VM '{}' isn't answering (is qemu-guest-agent running in it?)", vm_name),
+                )
+            })?;
+            if message.get("return").and_then(Value::as_u64) == Some(sync_id) {
+                break;
+            }
+        }
+
+        // commands like guest-exec-status can take a while to come back
+        client.reader.get_ref().set_read_timeout(Some(Duration::from_secs(60)))?;
+        Ok(client)
+    }
+
+    fn send(&mut self, command: &str, arguments: Option<Value>) -> Result<(), Box<dyn Error>> {
+        let mut request = json!({ "execute": command });
+        if let Some(arguments) = arguments {
+            request["arguments"] = arguments;
+        }
+        self.writer.write_all(format!("{}\n", request).as_bytes())?;
+        Ok(())
+    }
+
+    /// Reads a json message (anything that isn't json, like leftovers from an
+    /// earlier connection, is skipped).
+    fn read_message(&mut self) -> Result<Value, Box<dyn Error>> {
+        loop {
+            let mut line = Vec::new();
+            if self.reader.read_until(b'\n', &mut line)? == 0 {
+                return Err(Box::new(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Guest agent connection closed",
+                )));
+            }
+            if let Ok(message) = serde_json::from_slice(&line) {
+                return Ok(message);
+            }
+        }
+    }
+
+    /// Runs a guest agent command and returns whatever is in `return`.
+    pub fn execute(&mut self, command: &str, arguments: Option<Value>) -> Result<Value, Box<dyn Error>> {
+        self.send(command, arguments)?;
+
+        let message = self.read_message()?;
+        if let Some(ret) = message.get("return") {
+            return Ok(ret.clone());
+        }
+        let desc = message
+            .get("error")
+            .and_then(|e| e.get("desc"))
+            .and_then(Value::as_str)
+            .unwrap_or("unknown error");
+        Err(Box::new(io::Error::other(format!("Guest agent {} failed -> {}", command, desc))))
+    }
+
+    /// Runs a command that the agent doesn't answer when it works (like
+    /// `guest-shutdown`).
+    pub fn execute_no_reply(&mut self, command: &str, arguments: Option<Value>) -> Result<(), Box<dyn Error>> {
+        self.send(command, arguments)
+    }
+}
+
+/// Gets the path of a VM's guest agent socket.
+pub fn guest_agent_socket_path(vm_name: &str) -> io::Result<PathBuf> {
+    Ok(filesystem::get_vm_data_dir(vm_name)?.join("qga.sock"))
+}
+
+/// The qemu args for the guest agent channel.
+pub fn guest_agent_args(vm_name: &str) -> io::Result<Vec<String>> {
+    let socket_path = filesystem::vm_data_dir_path(vm_name)?.join("qga.sock");
+    Ok(vec![
+        "-chardev".to_string(),
+        format!("socket,path={},server=on,wait=off,id=qga0", socket_path.to_string_lossy()),
+        "-device".to_string(),
+        "virtio-serial-pci,id=virtio-serial0".to_string(),
+        "-device".to_string(),
+        format!("virtserialport,chardev=qga0,name={}", GUEST_AGENT_PORT),
+    ])
+}
+
+/// Prints the network interfaces (and IPs) of a running VM.
+pub fn show_vm_ips(vm_name: &str) -> Result<(), Box<dyn Error>> {
+    let mut client = GuestAgentClient::connect(vm_name, Duration::from_secs(5))?;
+    let interfaces = client.execute("guest-network-get-interfaces", None)?;
+
+    for interface in interfaces.as_array().cloned().unwrap_or_default() {
+        let name = interface.get("name").and_then(Value::as_str).unwrap_or("?");
+        let mac = interface.get("hardware-address").and_then(Value::as_str).unwrap_or("-");
+        let addresses: Vec<String> = interface
+            .get("ip-addresses")
+            .and_then(Value::as_array)
+            .map(|addresses| {
+                addresses
+                    .iter()
+                    .filter_map(|a| {
+                        let ip = a.get("ip-address").and_then(Value::as_str)?;
+                        let prefix = a.get("prefix").and_then(Value::as_u64)?;
+                        Some(format!("{}/{}", ip, prefix))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        println!(
+            "{}  {}  {}",
+            name,
+            mac,
+            if addresses.is_empty() { "-".to_string() } else { addresses.join(", ") }
+        );
+    }
+    Ok(())
+}
+
+/// Freezes the filesystems in a running VM (for a consistent snapshot).
+/// Gives back if they were frozen, a VM without a working agent just gets a
+/// note since the snapshot still works without it.
+///
+/// ---
+pub fn try_freeze_filesystems(vm_name: &str) -> bool {
+    let frozen = GuestAgentClient::connect(vm_name, Duration::from_secs(3))
+        .and_then(|mut client| client.execute("guest-fsfreeze-freeze", None));
+    match frozen {
+        Ok(count) => {
+            println!("LOG:: Froze {} filesystem(s) in VM {}", count, vm_name);
+            true
+        }
+        Err(e) => {
+            println!("INFO:: Not freezing the VM's filesystems -> {}", e);
+            false
+        }
+    }
+}
+
+/// Thaws the filesystems in a running VM (after `try_freeze_filesystems` or
+/// after loading a snapshot that was made while they were frozen).
+///
+/// ---
+pub fn try_thaw_filesystems(vm_name: &str) {
+    let thawed = GuestAgentClient::connect(vm_name, Duration::from_secs(3))
+        .and_then(|mut client| client.execute("guest-fsfreeze-thaw", None));
+    match thawed {
+        Ok(count) if count.as_u64().unwrap_or(0) > 0 => println!("LOG:: Thawed {} filesystem(s) in VM {}", count, vm_name),
+        Ok(_) => {}
+        Err(e) => eprintln!("ERROR: Failed to thaw the VM's filesystems -> {}", e),
+    }
+}
+
+/// Asks the guest agent to shut the VM down. Errors if the agent isn't there.
+pub fn shutdown(vm_name: &str) -> Result<(), Box<dyn Error>> {
+    let mut client = GuestAgentClient::connect(vm_name, Duration::from_secs(3))?;
+    client.execute_no_reply("guest-shutdown", Some(json!({ "mode": "powerdown" })))
+}
+
+/// Reads a (small) file in a running VM, `None` if it isn't there.
+pub fn read_file(vm_name: &str, path: &str, timeout: Duration) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let mut client = GuestAgentClient::connect(vm_name, timeout)?;
+    let handle = match client.execute("guest-file-open", Some(json!({ "path": path, "mode": "r" }))) {
+        Ok(handle) => handle,
+        Err(_) => return Ok(None),
+    };
+
+    let mut contents = Vec::new();
+    let read = loop {
+        let chunk = match client.execute("guest-file-read", Some(json!({ "handle": handle, "count": 65536 }))) {
+            Ok(chunk) => chunk,
+            Err(e) => break Err(e),
+        };
+        if let Some(data) = chunk.get("buf-b64").and_then(Value::as_str) {
+            contents.extend(BASE64.decode(data)?);
+        }
+        if chunk.get("eof").and_then(Value::as_bool).unwrap_or(true) {
+            break Ok(());
+        }
+    };
+    let _ = client.execute("guest-file-close", Some(json!({ "handle": handle })));
+    read?;
+    Ok(Some(contents))
+}
+
+/// Sets the password of a user in a running VM (and records it if it's the
+/// VM's user).
+///
+/// ---
+pub fn set_user_password(vm_name: &str, user: &str, password: &str) -> Result<(), Box<dyn Error>> {
+    let mut client = GuestAgentClient::connect(vm_name, Duration::from_secs(5))?;
+    client.execute(
+        "guest-set-user-password",
+        Some(json!({ "username": user, "password": BASE64.encode(password), "crypted": false })),
+    )?;
+
+    let vm_user = filesystem::get_value_from_autovirt_json(&format!("vms.{}.user", vm_name))
+        .and_then(|v| v.as_str().map(String::from));
+    if vm_user.as_deref() == Some(user) {
+        filesystem::insert_value_into_autovirt_json_object(
+            &format!("vms.{}.password", vm_name),
+            Value::String(password.to_string()),
+        );
+    }
+
+    println!("LOG:: Password set for user {} in VM {}", user, vm_name);
+    Ok(())
+}
+
+/// Runs a command in a running VM, prints its output and gives back its exit
+/// code.
+///
+/// ---
+pub fn exec(vm_name: &str, command: &[String], timeout: Duration) -> Result<i32, Box<dyn Error>> {
+    let (path, args) = command.split_first().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "No command given (i.e. -> autovirt guest-exec vm -- uname -a)")
+    })?;
+
+    let mut client = GuestAgentClient::connect(vm_name, Duration::from_secs(5))?;
+    let started = client.execute(
+        "guest-exec",
+        Some(json!({ "path": path, "arg": args, "capture-output": true })),
+    )?;
+    let pid = started
+        .get("pid")
+        .and_then(Value::as_i64)
+        .ok_or_else(|| io::Error::other("The guest agent didn't give a pid for the command"))?;
+
+    let start = Instant::now();
+    loop {
+        let status = client.execute("guest-exec-status", Some(json!({ "pid": pid })))?;
+        if status.get("exited").and_then(Value::as_bool).unwrap_or(false) {
+            for (key, truncated_key, stderr) in [("out-data", "out-truncated", false), ("err-data", "err-truncated", true)] {
+                let data = status.get(key).and_then(Value::as_str).map(|d| BASE64.decode(d)).transpose()?;
+                if let Some(data) = data {
+                    if stderr {
+                        io::stderr().write_all(&data)?;
+                    } else {
+                        io::stdout().write_all(&data)?;
+                    }
+                }
+                if status.get(truncated_key).and_then(Value::as_bool).unwrap_or(false) {
+                    eprintln!("INFO:: The command's {} was cut short by the guest agent", if stderr { "stderr" } else { "stdout" });
+                }
+            }
+            // killed by a signal -> 128 + signal like a shell
+            return Ok(status
+                .get("exitcode")
+                .and_then(Value::as_i64)
+                .or_else(|| status.get("signal").and_then(Value::as_i64).map(|s| 128 + s))
+                .unwrap_or(1) as i32);
+        }
+
+        if start.elapsed() > timeout {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("The command didn't finish in {}s (it's still running in the VM as pid {})", timeout.as_secs(), pid),
+            )));
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+}
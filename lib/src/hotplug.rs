@@ -0,0 +1,283 @@
+//! This file contains things for changing the CPUs and memory of running VMs.
+//!
+//! VMs are started with room to grow (`-smp maxcpus=`, `-m slots=,maxmem=`)
+//! and a virtio-balloon device. Resizing a running VM plugs/unplugs vCPUs,
+//! hot-adds memory (a `pc-dimm`) when it grows and inflates the balloon when
+//! it shrinks, all over QMP. Anything that doesn't fit in the room the VM was
+//! started with is only used on the next boot.
+//!
+//! The limits a VM was started with are kept in `machine.json` in its data
+//! directory (the config can change while it runs).
+//!
+//! ---
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::error::Error;
+use std::fs;
+use std::io;
+
+use crate::disk;
+use crate::filesystem;
+use crate::qmp;
+use crate::share;
+
+/// The number of memory slots VMs get for hot-added memory.
+const MEMORY_SLOTS: u64 = 8;
+
+/// Hot-added memory is added in multiples of this (MB).
+const DIMM_ALIGN_MB: u64 = 128;
+
+/// The limits a running VM was started with.
+#[derive(Debug, Serialize, Deserialize)]
+struct MachineLimits {
+    max_cpus: u64,
+    max_memory_mb: u64,
+    memory_slots: u64,
+    /// The backend hot-added memory has to use (memfd if the memory is shared
+    /// with virtiofsd)
+    memory_backend: String,
+}
+
+/// Gets a number from a VM's entry (stored as a string or a number).
+fn vm_number(vm_name: &str, key: &str) -> Option<u64> {
+    filesystem::get_value_from_autovirt_json(&format!("vms.{}.{}", vm_name, key)).and_then(|v| match v {
+        Value::String(s) => s.trim().parse().ok(),
+        v => v.as_u64(),
+    })
+}
+
+/// The max vCPUs if none is set (the host's CPU count).
+fn default_max_cpus(cpus: u64) -> u64 {
+    let host_cpus = std::thread::available_parallelism().map(|n| n.get() as u64).unwrap_or(1);
+    cpus.max(host_cpus)
+}
+
+/// The max memory if none is set (4x the memory).
+fn default_max_memory_mb(memory_mb: u64) -> u64 {
+    memory_mb * 4
+}
+
+/// Gets the CPUs, max CPUs, memory and max memory of a VM.
+fn vm_sizes(vm_name: &str) -> (u64, u64, u64, u64) {
+    let cpus = vm_number(vm_name, "cpus").unwrap_or(1);
+    let memory_mb = vm_number(vm_name, "memory_mb").unwrap_or(512);
+    let max_cpus = vm_number(vm_name, "max_cpus").unwrap_or_else(|| default_max_cpus(cpus)).max(cpus);
+    let max_memory_mb = vm_number(vm_name, "max_memory_mb")
+        .unwrap_or_else(|| default_max_memory_mb(memory_mb))
+        .max(memory_mb);
+    (cpus, max_cpus, memory_mb, max_memory_mb)
+}
+
+/// Checks the CPU/memory sizes given to create/resize make sense together.
+pub fn check_sizes(
+    cpus: &str,
+    memory_mb: &str,
+    max_cpus: Option<u64>,
+    max_memory_mb: Option<u64>,
+) -> Result<(), Box<dyn Error>> {
+    let invalid = |msg: String| -> Box<dyn Error> { Box::new(io::Error::new(io::ErrorKind::InvalidInput, msg)) };
+
+    let cpus: u64 = cpus.parse().map_err(|_| invalid(format!("Invalid CPU count '{}'", cpus)))?;
+    let memory_mb: u64 = memory_mb.parse().map_err(|_| invalid(format!("Invalid memory size '{}'", memory_mb)))?;
+    if cpus == 0 || memory_mb == 0 {
+        return Err(invalid("The CPU count and memory have to be more than 0".to_string()));
+    }
+    if max_cpus.is_some_and(|max| max < cpus) {
+        return Err(invalid(format!("--max-cpus can't be less than the CPU count ({})", cpus)));
+    }
+    if max_memory_mb.is_some_and(|max| max < memory_mb) {
+        return Err(invalid(format!("--max-memory can't be less than the memory ({}MB)", memory_mb)));
+    }
+    Ok(())
+}
+
+/// Gets the limits a VM is started with.
+fn machine_limits(vm_name: &str) -> MachineLimits {
+    let (_, max_cpus, memory_mb, max_memory_mb) = vm_sizes(vm_name);
+    let shared_memory = share::load_shares(vm_name).iter().any(|s| s.transport == "virtiofs");
+    MachineLimits {
+        max_cpus,
+        max_memory_mb,
+        memory_slots: if max_memory_mb > memory_mb { MEMORY_SLOTS } else { 0 },
+        memory_backend: if shared_memory { "memory-backend-memfd" } else { "memory-backend-ram" }.to_string(),
+    }
+}
+
+/// Records the limits a VM is started with for resizing it live (done right
+/// before it's started).
+///
+/// ---
+pub fn write_machine_limits(vm_name: &str) -> Result<(), Box<dyn Error>> {
+    fs::write(
+        filesystem::get_vm_data_dir(vm_name)?.join("machine.json"),
+        serde_json::to_string_pretty(&machine_limits(vm_name))?,
+    )?;
+    Ok(())
+}
+
+/// Gets the qemu args for a VM's CPUs, memory and balloon.
+pub fn machine_args(vm_name: &str) -> Vec<String> {
+    let (cpus, max_cpus, memory_mb, max_memory_mb) = vm_sizes(vm_name);
+    let limits = machine_limits(vm_name);
+
+    let memory_arg = if limits.memory_slots > 0 {
+        format!("size={}M,slots={},maxmem={}M", memory_mb, limits.memory_slots, max_memory_mb)
+    } else {
+        format!("size={}M", memory_mb)
+    };
+
+    vec![
+        "-m".to_string(),
+        memory_arg,
+        "-smp".to_string(),
+        format!("cpus={},maxcpus={}", cpus, max_cpus),
+        "-device".to_string(),
+        "virtio-balloon-pci,id=balloon0,deflate-on-oom=on".to_string(),
+    ]
+}
+
+fn load_machine_limits(vm_name: &str) -> Result<MachineLimits, Box<dyn Error>> {
+    let path = filesystem::get_vm_data_dir(vm_name)?.join("machine.json");
+    let contents = fs::read_to_string(&path)
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to read {:?} -> {}", path, e)))?;
+    Ok(serde_json::from_str(&contents)?)
+}
+
+/// Plugs/unplugs vCPUs so the VM has `cpus` of them.
+fn set_live_cpus(client: &mut qmp::QmpClient, limits: &MachineLimits, cpus: u64) -> Result<(), Box<dyn Error>> {
+    let slots = client.execute("query-hotpluggable-cpus", None)?;
+    let slots = slots.as_array().cloned().unwrap_or_default();
+    let plugged = slots.iter().filter(|s| s.get("qom-path").is_some()).count() as u64;
+
+    if cpus == plugged {
+        println!("LOG:: CPUs -> {} (unchanged)", cpus);
+        return Ok(());
+    }
+    if cpus > limits.max_cpus {
+        println!(
+            "INFO:: CPUs -> {} on the next boot (the VM was started with room for {})",
+            cpus, limits.max_cpus
+        );
+        return Ok(());
+    }
+
+    if cpus > plugged {
+        for slot in slots.iter().filter(|s| s.get("qom-path").is_none()).take((cpus - plugged) as usize) {
+            let props = slot.get("props").and_then(Value::as_object).cloned().unwrap_or_default();
+            let id = format!(
+                "cpu-{}",
+                props.values().map(|v| v.to_string()).collect::<Vec<_>>().join("-")
+            );
+            let mut arguments = json!({ "driver": slot.get("type").cloned().unwrap_or_default(), "id": id });
+            for (key, value) in props {
+                arguments[key] = value;
+            }
+            client.execute("device_add", Some(arguments))?;
+        }
+        println!("LOG:: CPUs {} -> {} (live)", plugged, cpus);
+        println!("INFO:: Most distros online new CPUs by themselves (check with `nproc` in the VM)");
+        return Ok(());
+    }
+
+    // only the CPUs that were hot-plugged can be unplugged (newest first)
+    let mut removable: Vec<&str> = slots
+        .iter()
+        .filter_map(|s| s.get("qom-path").and_then(Value::as_str))
+        .filter_map(|path| path.strip_prefix("/machine/peripheral/"))
+        .collect();
+    removable.sort_by_key(|id| (id.len(), *id));
+    let unplug_count = ((plugged - cpus) as usize).min(removable.len());
+    for id in removable.iter().rev().take(unplug_count) {
+        client.execute("device_del", Some(json!({ "id": id })))?;
+    }
+
+    let live_cpus = plugged - unplug_count as u64;
+    println!("LOG:: CPUs {} -> {} (live, the VM has to let go of them)", plugged, live_cpus);
+    if live_cpus != cpus {
+        println!(
+            "INFO:: CPUs -> {} on the next boot (CPUs the VM was started with can't be unplugged)",
+            cpus
+        );
+    }
+    Ok(())
+}
+
+/// Hot-adds memory or moves the balloon so the VM has `memory_mb`.
+fn set_live_memory(client: &mut qmp::QmpClient, limits: &MachineLimits, memory_mb: u64) -> Result<(), Box<dyn Error>> {
+    const MB: u64 = 1024 * 1024;
+
+    let summary = client.execute("query-memory-size-summary", None)?;
+    let plugged_mb = (summary.get("base-memory").and_then(Value::as_u64).unwrap_or(0)
+        + summary.get("plugged-memory").and_then(Value::as_u64).unwrap_or(0))
+        / MB;
+
+    // shrinking (or growing back) within what's plugged is the balloon's job
+    if memory_mb <= plugged_mb {
+        client.execute("balloon", Some(json!({ "value": memory_mb * MB })))?;
+        println!("LOG:: Memory -> {}MB (live, balloon target, {}MB plugged)", memory_mb, plugged_mb);
+        return Ok(());
+    }
+
+    let dimms = client.execute("query-memory-devices", None)?.as_array().map(|d| d.len()).unwrap_or(0) as u64;
+    let add_mb = (memory_mb - plugged_mb).div_ceil(DIMM_ALIGN_MB) * DIMM_ALIGN_MB;
+    if plugged_mb + add_mb > limits.max_memory_mb || dimms >= limits.memory_slots {
+        // the balloon may be inflated so at least give back all that's plugged
+        client.execute("balloon", Some(json!({ "value": plugged_mb * MB })))?;
+        println!(
+            "INFO:: Memory -> {}MB on the next boot (the VM was started with room for {}MB, {} of {} memory slots used)",
+            memory_mb, limits.max_memory_mb, dimms, limits.memory_slots
+        );
+        return Ok(());
+    }
+
+    let mut backend = json!({
+        "qom-type": limits.memory_backend,
+        "id": format!("mem-hp{}", dimms),
+        "size": add_mb * MB,
+    });
+    if limits.memory_backend == "memory-backend-memfd" {
+        backend["share"] = json!(true);
+    }
+    client.execute("object-add", Some(backend))?;
+    client.execute(
+        "device_add",
+        Some(json!({ "driver": "pc-dimm", "id": format!("dimm-hp{}", dimms), "memdev": format!("mem-hp{}", dimms) })),
+    )?;
+    client.execute("balloon", Some(json!({ "value": (plugged_mb + add_mb) * MB })))?;
+
+    println!("LOG:: Memory {}MB -> {}MB (live, hot-added {}MB)", plugged_mb, plugged_mb + add_mb, add_mb);
+    println!("INFO:: Most distros online hot-added memory by themselves (check with `free -m` in the VM)");
+    Ok(())
+}
+
+/// Applies new CPU/memory sizes to a running VM as far as it can be done live
+/// (the sizes are already recorded for the next boot).
+///
+/// ---
+pub fn resize_running_vm(vm_name: &str, cpus: Option<u64>, memory_mb: Option<u64>) -> Result<(), Box<dyn Error>> {
+    let limits = load_machine_limits(vm_name)?;
+    let mut client = qmp::connect_vm(vm_name)?;
+
+    if let Some(cpus) = cpus {
+        set_live_cpus(&mut client, &limits, cpus)?;
+    }
+    if let Some(memory_mb) = memory_mb {
+        set_live_memory(&mut client, &limits, memory_mb)?;
+    }
+    Ok(())
+}
+
+/// Grows a running VM's main disk by `add_gb` GB (`block_resize`, the image
+/// is locked by qemu so `qemu-img resize` can't be used).
+///
+/// ---
+pub fn grow_running_disk(vm_name: &str, add_gb: u64) -> Result<(), Box<dyn Error>> {
+    let info = disk::image_info(&disk::vm_image_path(vm_name)?)?;
+    let size = info.get("virtual-size").and_then(Value::as_u64).unwrap_or(0) + (add_gb << 30);
+
+    let mut client = qmp::connect_vm(vm_name)?;
+    client.execute("block_resize", Some(json!({ "node-name": "main-fmt", "size": size })))?;
+    println!("LOG:: Disk grown by {}G (live, the VM's partition/filesystem still have to be grown in it)", add_gb);
+    Ok(())
+}
@@ -0,0 +1,414 @@
+//! This file contains things for finding the newest release (serial) of an
+//! image from its index, so autovirt knows exactly which build a VM was made
+//! from instead of just whatever a `latest/` link pointed at that day.
+//!
+//! Two kinds of index are understood:
+//! - `simplestreams` -> Ubuntu's simplestreams json (a `products:1.0` file or
+//!   the `index:1.0` file pointing at one), which has the path and sha256 of
+//!   every serial of every product
+//! - `directory` -> a plain html directory listing (Debian, Rocky, Alma, Arch
+//!   etc.) where the serials are found with a pattern like `{serial}/` and put
+//!   into the image's `url`/`checksum_url` in place of `{serial}`
+//!
+//! Simplestreams indexes of images with a `signature` are read from their
+//! clearsigned `.sjson` twin and checked with the image's keyring (the sha256
+//! of a release is only worth something if the index is the distro's).
+//!
+//! Index urls can be `file://` urls too (for mirrors on disk and for trying
+//! the parsing out on saved index files).
+//!
+//! ---
+
+use reqwest::blocking::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::error::Error;
+use std::io;
+
+use crate::catalog::CatalogImage;
+use crate::fetch;
+use crate::signature::{self, Verification};
+
+/// Where the releases of an image are listed (`index` in the catalog).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageIndex {
+    /// simplestreams or directory
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub url: String,
+    /// The simplestreams product (i.e. -> com.ubuntu.cloud:server:22.04:amd64)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub product: Option<String>,
+    /// The simplestreams item type to download
+    #[serde(default = "default_ftype")]
+    pub ftype: String,
+    /// The directory listing entries to look for, `{serial}` marks the serial
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+}
+
+fn default_ftype() -> String {
+    "disk1.img".to_string()
+}
+
+/// One release of an image.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageRelease {
+    pub serial: String,
+    pub url: String,
+    /// The sha256 of the download (simplestreams has it, directories don't)
+    pub sha256: Option<String>,
+    pub size: Option<u64>,
+    /// How the index the sha256 came from was verified
+    pub verification: Option<Verification>,
+}
+
+/// Compares two serials the way people would (digit runs as numbers) so
+/// `9.10` comes after `9.9` and `20240701` after `20240115`.
+///
+/// ---
+pub fn compare_serials(a: &str, b: &str) -> Ordering {
+    let mut a_chars = a.chars().peekable();
+    let mut b_chars = b.chars().peekable();
+    loop {
+        match (a_chars.peek().copied(), b_chars.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let take_number = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+                    let mut number = String::new();
+                    while let Some(c) = chars.peek().copied().filter(char::is_ascii_digit) {
+                        number.push(c);
+                        chars.next();
+                    }
+                    number.trim_start_matches('0').to_string()
+                };
+                let (x, y) = (take_number(&mut a_chars), take_number(&mut b_chars));
+                let ordering = x.len().cmp(&y.len()).then_with(|| x.cmp(&y));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(&y);
+                }
+                a_chars.next();
+                b_chars.next();
+            }
+        }
+    }
+}
+
+/// Gets the newest of some releases.
+pub fn newest_release(releases: Vec<ImageRelease>) -> Option<ImageRelease> {
+    releases.into_iter().max_by(|a, b| compare_serials(&a.serial, &b.serial))
+}
+
+/// Gets the base url simplestreams paths are relative to (the part before
+/// `streams/v1/`).
+///
+/// ---
+pub fn simplestreams_mirror(index_url: &str) -> String {
+    match index_url.find("streams/v1/") {
+        Some(position) => index_url[..position].to_string(),
+        None => index_url.rsplit_once('/').map(|(base, _)| format!("{}/", base)).unwrap_or_default(),
+    }
+}
+
+/// Finds the products file for a product in a simplestreams `index:1.0` file
+/// (the path is relative to the mirror).
+///
+/// ---
+pub fn parse_simplestreams_index(contents: &str, product: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let index: Value = serde_json::from_str(contents)?;
+    let entries = index.get("index").and_then(Value::as_object).cloned().unwrap_or_default();
+    Ok(entries.values().find_map(|entry| {
+        let has_product = entry
+            .get("products")
+            .and_then(Value::as_array)
+            .is_some_and(|products| products.iter().any(|p| p.as_str() == Some(product)));
+        let is_download = entry.get("datatype").and_then(Value::as_str).unwrap_or("image-downloads") == "image-downloads";
+        if has_product && is_download {
+            entry.get("path").and_then(Value::as_str).map(String::from)
+        } else {
+            None
+        }
+    }))
+}
+
+/// Gets the releases of a product from a simplestreams `products:1.0` file.
+///
+/// Every version with an item of the wanted type is a release (the item key
+/// or its `ftype` can match), its url is the item's path on the mirror.
+///
+/// ---
+pub fn parse_simplestreams(contents: &str, product: &str, ftype: &str, mirror: &str) -> Result<Vec<ImageRelease>, Box<dyn Error>> {
+    let stream: Value = serde_json::from_str(contents)?;
+    let format = stream.get("format").and_then(Value::as_str).unwrap_or_default();
+    if format != "products:1.0" {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Not a simplestreams products file (format '{}')", format),
+        )));
+    }
+
+    let versions = stream
+        .pointer(&format!("/products/{}/versions", product.replace('~', "~0").replace('/', "~1")))
+        .and_then(Value::as_object)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No product '{}' in the stream", product)))?;
+
+    let mirror = if mirror.is_empty() || mirror.ends_with('/') { mirror.to_string() } else { format!("{}/", mirror) };
+    let mut releases = Vec::new();
+    for (serial, version) in versions {
+        let items = version.get("items").and_then(Value::as_object).cloned().unwrap_or_default();
+        let item = items
+            .iter()
+            .find(|(name, item)| *name == ftype || item.get("ftype").and_then(Value::as_str) == Some(ftype))
+            .map(|(_, item)| item);
+        let Some(path) = item.and_then(|i| i.get("path")).and_then(Value::as_str) else {
+            continue;
+        };
+        releases.push(ImageRelease {
+            serial: serial.clone(),
+            url: format!("{}{}", mirror, path.trim_start_matches('/')),
+            sha256: item.and_then(|i| i.get("sha256")).and_then(Value::as_str).map(str::to_lowercase),
+            size: item.and_then(|i| i.get("size")).and_then(Value::as_u64),
+            verification: None,
+        });
+    }
+    Ok(releases)
+}
+
+/// Gets the serials from an html directory listing, from the links that
+/// match a pattern like `{serial}/` or `Rocky-9-GenericCloud-Base-{serial}.x86_64.qcow2`.
+///
+/// Serials have to start with a digit so `latest/` and friends don't count.
+///
+/// ---
+pub fn parse_directory_index(contents: &str, pattern: &str) -> Vec<String> {
+    let (prefix, suffix) = pattern.split_once("{serial}").unwrap_or((pattern, ""));
+
+    let mut serials: Vec<String> = contents
+        .split("href=")
+        .skip(1)
+        .filter_map(|rest| {
+            let quote = rest.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+            let link = rest[1..].split(quote).next()?;
+            // only the last part of the link counts (listings link to
+            // `sub/`, `./sub/` or `/full/path/sub/`)
+            let name = link.trim_end_matches('/').rsplit('/').next()?;
+            let name = if link.ends_with('/') { format!("{}/", name) } else { name.to_string() };
+            let serial = name.strip_prefix(prefix)?.strip_suffix(suffix)?;
+            let valid = serial.starts_with(|c: char| c.is_ascii_digit()) && !serial.contains(['/', '?', '#']);
+            valid.then(|| serial.to_string())
+        })
+        .collect();
+    serials.sort_by(|a, b| compare_serials(a, b));
+    serials.dedup();
+    serials
+}
+
+/// Fills in the `{serial}` of an image url/checksum url.
+pub fn url_for_serial(url: &str, serial: &str) -> String {
+    url.replace("{serial}", serial)
+}
+
+/// Gets the url of the clearsigned version of a simplestreams file
+/// (`download.json` -> `download.sjson`).
+///
+/// ---
+pub fn signed_stream_url(url: &str) -> String {
+    match url.strip_suffix(".json") {
+        Some(stem) => format!("{}.sjson", stem),
+        None => url.to_string(),
+    }
+}
+
+/// Gets a simplestreams file, verified with the image's keyring if it has a
+/// signature (and `--insecure` wasn't given).
+///
+/// ---
+fn get_stream(client: &Client, image: &CatalogImage, url: &str, insecure: bool) -> Result<(String, Option<Verification>), Box<dyn Error>> {
+    match image.signature.as_ref().filter(|_| !insecure) {
+        Some(spec) => {
+            let signed = fetch::get_text(client, &fetch::mirror_urls(&signed_stream_url(url), &image.mirrors))?;
+            let (contents, verification) = signature::verify_clearsigned(signed.as_bytes(), &spec.keyring)
+                .map_err(|e| io::Error::new(io::ErrorKind::PermissionDenied, format!("The index {} isn't verified -> {}", url, e)))?;
+            println!("INFO: Index signed by {}", verification.signer.as_deref().unwrap_or("-"));
+            Ok((contents, Some(verification)))
+        }
+        None => Ok((fetch::get_text(client, &fetch::mirror_urls(url, &image.mirrors))?, None)),
+    }
+}
+
+/// Gets all the releases of an image from its index (oldest first).
+pub fn list_releases(client: &Client, image: &CatalogImage, insecure: bool) -> Result<Vec<ImageRelease>, Box<dyn Error>> {
+    let index = image
+        .index
+        .as_ref()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "The image has no index"))?;
+
+    let mut releases = match index.kind.as_str() {
+        "simplestreams" => {
+            let product = index
+                .product
+                .as_deref()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "A simplestreams index needs a product"))?;
+            let mirror = simplestreams_mirror(&index.url);
+            let (mut contents, mut verification) = get_stream(client, image, &index.url, insecure)?;
+            // the top level index just says which file has the product
+            if contents.contains("\"index:1.0\"") {
+                let path = parse_simplestreams_index(&contents, product)?.ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, format!("No product '{}' in {}", product, index.url))
+                })?;
+                (contents, verification) = get_stream(client, image, &format!("{}{}", mirror, path), insecure)?;
+            }
+            let mut releases = parse_simplestreams(&contents, product, &index.ftype, &mirror)?;
+            for release in &mut releases {
+                release.verification = verification.clone();
+            }
+            releases
+        }
+        "directory" => {
+            let pattern = index.pattern.as_deref().unwrap_or("{serial}/");
+            parse_directory_index(&fetch::get_text(client, &fetch::mirror_urls(&index.url, &image.mirrors))?, pattern)
+                .into_iter()
+                .map(|serial| ImageRelease {
+                    url: url_for_serial(&image.url, &serial),
+                    serial,
+                    sha256: None,
+                    size: None,
+                    verification: None,
+                })
+                .collect()
+        }
+        other => {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown index type '{}' (simplestreams or directory)", other),
+            )))
+        }
+    };
+    releases.sort_by(|a, b| compare_serials(&a.serial, &b.serial));
+    Ok(releases)
+}
+
+/// Gets the newest release of an image from its index.
+pub fn resolve_latest(client: &Client, image: &CatalogImage, insecure: bool) -> Result<ImageRelease, Box<dyn Error>> {
+    let index_url = image.index.as_ref().map(|i| i.url.clone()).unwrap_or_default();
+    newest_release(list_releases(client, image, insecure)?).ok_or_else(|| {
+        Box::new(io::Error::new(io::ErrorKind::NotFound, format!("No releases found in {}", index_url))) as Box<dyn Error>
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn fixture_path(path: &str) -> String {
+        format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), path)
+    }
+
+    fn fixture_url(path: &str) -> String {
+        format!("file://{}", fixture_path(path))
+    }
+
+    fn image_with_index(url: &str, index: Value) -> CatalogImage {
+        serde_json::from_value(json!({ "url": url, "filename": "test.qcow2", "index": index })).unwrap()
+    }
+
+    #[test]
+    fn serials_compare_digit_runs_as_numbers() {
+        assert_eq!(compare_serials("9.10", "9.9"), Ordering::Greater);
+        assert_eq!(compare_serials("20240710.1", "20240710"), Ordering::Greater);
+        assert_eq!(compare_serials("20240102-1614", "20240701-1795"), Ordering::Less);
+        assert_eq!(compare_serials("007", "7"), Ordering::Equal);
+    }
+
+    #[test]
+    fn simplestreams_newest_release_is_picked() {
+        let image = image_with_index(
+            "https://cloud-images.ubuntu.com/releases/jammy/release/ubuntu-22.04-server-cloudimg-amd64.img",
+            json!({
+                "type": "simplestreams",
+                "url": fixture_url("simplestreams/streams/v1/index.json"),
+                "product": "com.ubuntu.cloud:server:22.04:amd64",
+            }),
+        );
+        let releases = list_releases(&Client::new(), &image, false).unwrap();
+
+        // 20240716 has no disk1.img and the arm64 product doesn't count
+        let serials: Vec<&str> = releases.iter().map(|r| r.serial.as_str()).collect();
+        assert_eq!(serials, ["20240612", "20240710", "20240710.1"]);
+
+        let newest = newest_release(releases).unwrap();
+        assert_eq!(newest.serial, "20240710.1");
+        assert_eq!(
+            newest.url,
+            fixture_url("simplestreams/server/releases/jammy/release-20240710.1/ubuntu-22.04-server-cloudimg-amd64.img")
+        );
+        assert_eq!(newest.sha256.as_deref(), Some("8f3e2d1c0b9a8f7e6d5c4b3a2f1e0d9c8b7a6f5e4d3c2b1a0f9e8d7c6b5a4f3e"));
+        assert_eq!(newest.size, Some(646774784));
+    }
+
+    #[test]
+    fn simplestreams_sha256_is_lowercased() {
+        let contents =
+            std::fs::read_to_string(fixture_path("simplestreams/streams/v1/com.ubuntu.cloud:released:download.json"))
+                .unwrap();
+        let releases =
+            parse_simplestreams(&contents, "com.ubuntu.cloud:server:22.04:amd64", "disk1.img", "https://mirror").unwrap();
+        let release = releases.iter().find(|r| r.serial == "20240612").unwrap();
+        assert_eq!(
+            release.sha256.as_deref(),
+            Some("5c6a1b3e4f2d9c8b7a6e5d4c3b2a1f0e9d8c7b6a5f4e3d2c1b0a9f8e7d6c5b4a")
+        );
+        assert!(release.url.starts_with("https://mirror/server/releases/"));
+    }
+
+    #[test]
+    fn directory_listing_newest_release_is_picked() {
+        let image = image_with_index(
+            "https://cloud.debian.org/images/cloud/bookworm/{serial}/debian-12-genericcloud-amd64-{serial}.qcow2",
+            json!({ "type": "directory", "url": fixture_url("directory-listing.html") }),
+        );
+        let releases = list_releases(&Client::new(), &image, false).unwrap();
+
+        // `latest/`, `daily/`, the parent and the sort links aren't serials
+        let serials: Vec<&str> = releases.iter().map(|r| r.serial.as_str()).collect();
+        assert_eq!(serials, ["20240102-1614", "20240211-1654", "20240701-1795", "20240717-1811"]);
+
+        let newest = newest_release(releases).unwrap();
+        assert_eq!(newest.serial, "20240717-1811");
+        assert_eq!(
+            newest.url,
+            "https://cloud.debian.org/images/cloud/bookworm/20240717-1811/debian-12-genericcloud-amd64-20240717-1811.qcow2"
+        );
+        assert_eq!(newest.sha256, None);
+    }
+
+    #[test]
+    fn directory_listing_file_pattern() {
+        let contents = r#"<a href="Rocky-9-GenericCloud-Base-9.3-20231113.0.x86_64.qcow2">x</a>
+            <a href="Rocky-9-GenericCloud-Base-9.4-20240509.0.x86_64.qcow2">x</a>
+            <a href="Rocky-9-GenericCloud-Base-9.4-20240509.0.x86_64.qcow2.CHECKSUM">x</a>
+            <a href="Rocky-9-GenericCloud-Base.latest.x86_64.qcow2">x</a>"#;
+        assert_eq!(
+            parse_directory_index(contents, "Rocky-9-GenericCloud-Base-{serial}.x86_64.qcow2"),
+            ["9.3-20231113.0", "9.4-20240509.0"]
+        );
+    }
+
+    #[test]
+    fn url_for_serial_fills_in_every_serial() {
+        assert_eq!(
+            url_for_serial("https://example.org/{serial}/image-{serial}.qcow2", "20240717-1811"),
+            "https://example.org/20240717-1811/image-20240717-1811.qcow2"
+        );
+        assert_eq!(url_for_serial("https://example.org/SHA512SUMS", "1"), "https://example.org/SHA512SUMS");
+    }
+}
@@ -0,0 +1,899 @@
+//! This file contains things for managing the base images VMs are made from
+//! (the ones in `_data/downloads`), whether they came from the catalog or were
+//! imported from a local file (like a golden image built in-house).
+//!
+//! Imported images get an entry under `images` in autovirt.json so they show
+//! up with the catalog images and work with `create --dist`. Every image on
+//! disk has an entry under `downloaded_images` with its format and digest.
+//!
+//! Images with an index (see imageindex.rs) are kept per release (serial),
+//! the entry says which one is current (the one new VMs are made from) and
+//! lists all of them under `versions`. `autovirt image update` gets newer
+//! releases and removes the old ones no VM was made from.
+//!
+//! `autovirt image prune` removes the images no VM uses and with
+//! `image_cache.max_size` (i.e. -> "20G") in autovirt.json the least recently
+//! used of those are removed after downloads until the images fit in it.
+//!
+//! ---
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::catalog;
+use crate::diskspace;
+use crate::download;
+use crate::fetch;
+use crate::filesystem;
+use crate::imageindex;
+use crate::signature::Verification;
+
+/// One release of a downloaded image (`downloaded_images.<name>.versions`).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ImageVersion {
+    pub filename: String,
+    #[serde(default)]
+    pub format: String,
+    /// `<type>:<hash>` of the image file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub downloaded_at: Option<String>,
+    /// When a VM was last made from it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used: Option<String>,
+    /// How the download's checksums were verified (see signature.rs)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verification: Option<Verification>,
+}
+
+/// What's on disk for an image (`downloaded_images.<name>`), the top level
+/// fields are the current file.
+///
+/// ---
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DownloadedImage {
+    pub filename: String,
+    #[serde(default)]
+    pub format: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub downloaded_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verification: Option<Verification>,
+    /// The local file an imported image came from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub versions: BTreeMap<String, ImageVersion>,
+}
+
+/// Gets the directory the base images are kept in.
+pub fn downloads_dir() -> io::Result<PathBuf> {
+    filesystem::get_autovirt_data_dir()
+        .map(|dir| dir.join("_data/downloads"))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "ERROR: COULD NOT FIND USER $HOME DIRECTORY"))
+}
+
+/// Gets the `downloaded_images` entry of an image.
+pub fn downloaded_image(name: &str) -> Option<DownloadedImage> {
+    filesystem::get_value_from_autovirt_json(&format!("downloaded_images.{}", name))
+        .and_then(|v| serde_json::from_value(v).ok())
+}
+
+fn save_downloaded_image(name: &str, downloaded: &DownloadedImage) {
+    filesystem::insert_value_into_autovirt_json_object(
+        &format!("downloaded_images.{}", name),
+        serde_json::to_value(downloaded).expect("ERROR: Failed to jsonify downloaded image"),
+    );
+}
+
+/// Records a downloaded file of an image as its current one (and as one of
+/// its versions if it's a release from an index).
+///
+/// ---
+pub fn record_download(name: &str, serial: Option<&str>, version: ImageVersion) {
+    let mut downloaded = downloaded_image(name).unwrap_or_default();
+    downloaded.filename = version.filename.clone();
+    downloaded.format = version.format.clone();
+    downloaded.serial = serial.map(String::from);
+    downloaded.digest = version.digest.clone();
+    downloaded.url = version.url.clone();
+    downloaded.downloaded_at = version.downloaded_at.clone();
+    downloaded.last_used = version.last_used.clone();
+    downloaded.verification = version.verification.clone();
+    downloaded.source = None;
+    if let Some(serial) = serial {
+        downloaded.versions.insert(serial.to_string(), version);
+    }
+    save_downloaded_image(name, &downloaded);
+}
+
+/// Records that a VM was just made from the current file of an image (for
+/// the least recently used eviction).
+///
+/// ---
+pub fn mark_used(name: &str) {
+    let Some(mut downloaded) = downloaded_image(name) else {
+        return;
+    };
+    let now = chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+    downloaded.last_used = Some(now.clone());
+    if let Some(version) = downloaded.serial.clone().and_then(|serial| downloaded.versions.get_mut(&serial)) {
+        version.last_used = Some(now);
+    }
+    save_downloaded_image(name, &downloaded);
+}
+
+/// The file name a release of an image is saved as (the serial goes before
+/// the extension, i.e. -> `debian-12-autovirt-genericcloud-amd64-20240717-1811.qcow2`).
+///
+/// ---
+pub fn versioned_filename(filename: &str, serial: &str) -> String {
+    match filename.rsplit_once('.') {
+        Some((stem, extension)) => format!("{}-{}.{}", stem, serial, extension),
+        None => format!("{}-{}", filename, serial),
+    }
+}
+
+/// Gets the path of the file new VMs of an image are made from (it may not
+/// be downloaded).
+///
+/// ---
+pub fn base_image_path(name: &str, image: &catalog::CatalogImage) -> io::Result<PathBuf> {
+    let filename = downloaded_image(name).map(|d| d.filename).unwrap_or_else(|| image.filename.clone());
+    Ok(downloads_dir()?.join(filename))
+}
+
+/// Image names end up as keys in autovirt.json (which uses `.` as the key
+/// separator) and in file names so only simple names are allowed.
+///
+/// ---
+fn check_image_name(name: &str) -> Result<(), Box<dyn Error>> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid image name '{}' (only letters, numbers, - and _)", name),
+        )));
+    }
+    Ok(())
+}
+
+/// Gets the names of the VMs made from an image (from one release of it if
+/// a serial is given).
+///
+/// ---
+fn vms_using_image(name: &str, serial: Option<&str>) -> Vec<String> {
+    filesystem::get_value_from_autovirt_json("vms")
+        .and_then(|vms| vms.as_object().cloned())
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|(_, vm)| vm.get("distro").and_then(Value::as_str) == Some(name))
+        .filter(|(_, vm)| serial.is_none() || vm.get("image_version").and_then(Value::as_str) == serial)
+        .map(|(vm_name, _)| vm_name)
+        .collect()
+}
+
+/// Gets the names of the VMs that have an image file in the backing chain of
+/// one of their disks (those would break if it was removed).
+///
+/// ---
+fn vms_backed_by(image_path: &Path) -> Vec<String> {
+    let image_path = fs::canonicalize(image_path).unwrap_or_else(|_| image_path.to_path_buf());
+    let vms = filesystem::get_value_from_autovirt_json("vms")
+        .and_then(|vms| vms.as_object().cloned())
+        .unwrap_or_default();
+
+    vms.into_iter()
+        .filter(|(_, vm)| {
+            let mut disks: Vec<String> = vm.get("image_path").and_then(Value::as_str).map(String::from).into_iter().collect();
+            disks.extend(
+                vm.get("data_disks")
+                    .and_then(Value::as_array)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|d| d.get("path").and_then(Value::as_str).map(String::from)),
+            );
+            disks.iter().any(|disk| {
+                diskspace::image_chain_info(disk)
+                    .unwrap_or_default()
+                    .iter()
+                    .skip(1)
+                    .filter_map(|info| info.get("filename").and_then(Value::as_str))
+                    .any(|backing| fs::canonicalize(backing).is_ok_and(|p| p == image_path))
+            })
+        })
+        .map(|(vm_name, _)| vm_name)
+        .collect()
+}
+
+/// Imports a local image file as a base image called `name` (hard-linked
+/// into `_data/downloads` when it's on the same filesystem, copied if not or
+/// if `copy` is set).
+///
+/// ---
+pub fn import_image(
+    source: &str,
+    name: &str,
+    format: Option<&str>,
+    os_family: &str,
+    copy: bool,
+) -> Result<(), Box<dyn Error>> {
+    check_image_name(name)?;
+    if catalog::find_image(name).is_some() {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("There is already an image called '{}' (see `autovirt show --available`)", name),
+        )));
+    }
+    if !catalog::os_family_names().iter().any(|f| f == os_family) {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unknown OS family '{}' (one of -> {})", os_family, catalog::os_family_names().join(", ")),
+        )));
+    }
+
+    let source_path = fs::canonicalize(source)
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to find '{}' -> {}", source, e)))?;
+    if !source_path.is_file() {
+        return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, format!("'{}' isn't a file", source))));
+    }
+
+    // the format is checked since booting a raw image as qcow2 (or the other
+    // way around) just gives a VM that doesn't boot
+    let probed_format = crate::disk::probe_image_format(&source_path.to_string_lossy())?;
+    if let Some(format) = format.filter(|f| *f != probed_format) {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("'{}' is a {} image, not {}", source, probed_format, format),
+        )));
+    }
+
+    let downloads_dir = downloads_dir()?;
+    fs::create_dir_all(&downloads_dir)?;
+    let filename = format!("{}-autovirt-imported.{}", name, probed_format);
+    let image_path = downloads_dir.join(&filename);
+    if image_path.exists() {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{:?} already exists (remove it with `autovirt image remove {}`)", image_path, name),
+        )));
+    }
+
+    let linked = !copy && fs::hard_link(&source_path, &image_path).is_ok();
+    if linked {
+        println!("LOG:: Hard-linked {:?} -> {:?}", source_path, image_path);
+    } else {
+        println!("LOG:: Copying {:?} -> {:?}...", source_path, image_path);
+        fs::copy(&source_path, &image_path)?;
+    }
+
+    let checksum = download::file_checksum(&image_path.to_string_lossy(), "sha256")?;
+    println!("LOG:: Checksum (sha256) -> {}", checksum);
+
+    filesystem::insert_value_into_autovirt_json_object(
+        &format!("images.{}", name),
+        json!({
+            "description": format!("Imported from {}", source_path.to_string_lossy()),
+            "os_family": os_family,
+            "url": format!("file://{}", source_path.to_string_lossy()),
+            "filename": filename,
+            "format": probed_format,
+            "imported": true,
+        }),
+    );
+    save_downloaded_image(
+        name,
+        &DownloadedImage {
+            filename,
+            format: probed_format,
+            digest: Some(format!("sha256:{}", checksum)),
+            downloaded_at: Some(chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true)),
+            source: Some(source_path.to_string_lossy().to_string()),
+            ..Default::default()
+        },
+    );
+
+    println!("LOG:: Imported image '{}', make VMs from it with `autovirt create --dist {}`", name, name);
+    Ok(())
+}
+
+/// Gets all the files of an image in `_data/downloads` (the current one and
+/// every release).
+///
+/// ---
+fn image_files(name: &str, image: &catalog::CatalogImage) -> io::Result<Vec<PathBuf>> {
+    let downloads_dir = downloads_dir()?;
+    let mut files = vec![base_image_path(name, image)?];
+    if let Some(downloaded) = downloaded_image(name) {
+        files.extend(downloaded.versions.values().map(|v| downloads_dir.join(&v.filename)));
+    }
+    files.sort();
+    files.dedup();
+    Ok(files.into_iter().filter(|f| f.is_file()).collect())
+}
+
+/// Removes a base image (every file and its entries). Images that VM disks
+/// are backed by can't be removed.
+///
+/// ---
+pub fn remove_image(name: &str) -> Result<(), Box<dyn Error>> {
+    let image = catalog::find_image(name)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Image '{}' not found", name)))?;
+    let imported = filesystem::get_value_from_autovirt_json(&format!("images.{}.imported", name))
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+
+    let files = image_files(name, &image)?;
+    if files.is_empty() && !imported {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Image '{}' isn't downloaded", name),
+        )));
+    }
+
+    let backed: Vec<String> = files.iter().flat_map(|file| vms_backed_by(file)).collect();
+    if !backed.is_empty() {
+        return Err(Box::new(io::Error::other(format!(
+            "The disks of these VMs are backed by the image -> {}",
+            backed.join(", ")
+        ))));
+    }
+
+    // VMs get their own copy of the image so they keep working without it
+    let using = vms_using_image(name, None);
+    if !using.is_empty() {
+        println!("INFO:: VMs made from this image (they keep working) -> {}", using.join(", "));
+    }
+
+    println!("Are you sure you want to remove the image {}? (yes please/N)", name);
+    let mut user_input = String::new();
+    std::io::stdin().read_line(&mut user_input).expect("Failed to read user input");
+    if user_input.trim() != "yes please" {
+        println!("!!! ABORTING IMAGE REMOVAL !!!");
+        return Ok(());
+    }
+
+    for file in &files {
+        fs::remove_file(file)?;
+        println!("LOG:: Image file deleted -> {:?}", file);
+    }
+    filesystem::remove_value_from_autovirt_json(&format!("downloaded_images.{}", name));
+    if imported {
+        filesystem::remove_value_from_autovirt_json(&format!("images.{}", name));
+        println!("LOG:: Imported image entry removed -> {}", name);
+    }
+    Ok(())
+}
+
+/// Removes the old releases of an image that no VM was made from (and no VM
+/// disk is backed by).
+///
+/// ---
+fn prune_old_versions(name: &str) -> Result<(), Box<dyn Error>> {
+    let Some(mut downloaded) = downloaded_image(name) else {
+        return Ok(());
+    };
+    let downloads_dir = downloads_dir()?;
+
+    let old: Vec<(String, ImageVersion)> = downloaded
+        .versions
+        .iter()
+        .filter(|(serial, _)| downloaded.serial.as_ref() != Some(*serial))
+        .map(|(serial, version)| (serial.clone(), version.clone()))
+        .collect();
+    for (serial, version) in old {
+        let path = downloads_dir.join(&version.filename);
+        let mut users = vms_using_image(name, Some(&serial));
+        if path.is_file() {
+            users.extend(vms_backed_by(&path));
+        }
+        if !users.is_empty() {
+            println!("INFO:: Keeping {} {} (used by -> {})", name, serial, users.join(", "));
+            continue;
+        }
+
+        if path.is_file() && version.filename != downloaded.filename {
+            fs::remove_file(&path)?;
+        }
+        downloaded.versions.remove(&serial);
+        println!("LOG:: Removed old release {} {}", name, serial);
+    }
+    save_downloaded_image(name, &downloaded);
+    Ok(())
+}
+
+/// Gets the newest release of the given images (all downloaded images with
+/// an index if none are given), keeping the old releases VMs were made from.
+///
+/// ---
+pub fn update_images(names: &[String], insecure: bool) -> Result<(), Box<dyn Error>> {
+    let images = catalog::all_images();
+    let names: Vec<String> = if names.is_empty() {
+        images
+            .iter()
+            .filter(|(name, image)| image.index.is_some() && downloaded_image(name).is_some())
+            .map(|(name, _)| name.clone())
+            .collect()
+    } else {
+        names.to_vec()
+    };
+    if names.is_empty() {
+        println!("INFO:: No downloaded images with an index to update");
+        return Ok(());
+    }
+
+    let client = fetch::http_client()?;
+    let mut failed = Vec::new();
+    for name in &names {
+        let Some(image) = images.get(name) else {
+            eprintln!("ERROR: Image '{}' not found", name);
+            failed.push(name.clone());
+            continue;
+        };
+        if image.index.is_none() {
+            println!("INFO:: {} has no index (re-download it with `autovirt download {}`)", name, name);
+            continue;
+        }
+
+        let result = imageindex::resolve_latest(&client, image, insecure).and_then(|latest| {
+            let current = downloaded_image(name).and_then(|d| d.serial);
+            if current.as_deref() == Some(latest.serial.as_str()) {
+                println!("LOG:: {} is up to date ({})", name, latest.serial);
+            } else {
+                println!("LOG:: Updating {} {} -> {}", name, current.as_deref().unwrap_or("-"), latest.serial);
+                download::download_release(&client, name, image, Some(&latest), insecure, None)?;
+            }
+            prune_old_versions(name)
+        });
+        if let Err(e) = result {
+            eprintln!("ERROR: Failed to update {} -> {}", name, e);
+            failed.push(name.clone());
+        }
+    }
+
+    if !failed.is_empty() {
+        return Err(Box::new(io::Error::other(format!("Failed to update -> {}", failed.join(", ")))));
+    }
+    Ok(())
+}
+
+/// Prints everything known about an image (catalog entry, downloads, VMs).
+pub fn show_image_info(name: &str) -> Result<(), Box<dyn Error>> {
+    let image = catalog::find_image(name)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Image '{}' not found", name)))?;
+    let downloaded = downloaded_image(name);
+    let image_path = base_image_path(name, &image)?;
+
+    println!("Name: {}", name);
+    println!("Description: {}", image.description);
+    println!("OS family: {}", image.os_family);
+    println!("Url: {}", image.url);
+    if let Some(index) = &image.index {
+        println!("Index: {} ({})", index.url, index.kind);
+    }
+    println!("Format: {}", image.format);
+    if image.compression != "none" {
+        println!("Compression: {}", image.compression);
+    }
+    if let Some(checksum_url) = &image.checksum_url {
+        println!("Checksums: {} ({})", checksum_url, image.checksum_type);
+    }
+    match &image.signature {
+        Some(signature) => println!("Signature: {} (keyring {})", signature.url.as_deref().unwrap_or(&signature.kind), signature.keyring),
+        None => println!("Signature: - (downloads need --insecure)"),
+    }
+    println!("Default user: {}", image.default_user.as_deref().unwrap_or("-"));
+    if let Some(datasource) = &image.datasource {
+        println!("Datasource: {}", datasource);
+    }
+
+    match downloaded.filter(|_| image_path.is_file()) {
+        Some(downloaded) => {
+            let size = fs::metadata(&image_path).map(|m| m.len()).unwrap_or(0);
+            println!("Downloaded: yes -> {} ({})", image_path.to_string_lossy(), diskspace::human_size(size));
+            if let Some(serial) = &downloaded.serial {
+                println!("Release: {}", serial);
+            }
+            if let Some(digest) = &downloaded.digest {
+                println!("Digest: {}", digest);
+            }
+            match &downloaded.verification {
+                Some(v) if v.status == "verified" => println!(
+                    "Verified: yes -> signed by {} (keyring {})",
+                    v.signer.as_deref().unwrap_or("-"),
+                    v.keyring.as_deref().unwrap_or("-")
+                ),
+                Some(v) => println!("Verified: no ({})", v.status),
+                None => println!("Verified: -"),
+            }
+            match &downloaded.source {
+                Some(source) => println!("Imported from: {} (at {})", source, downloaded.downloaded_at.as_deref().unwrap_or("-")),
+                None => println!("Downloaded at: {}", downloaded.downloaded_at.as_deref().unwrap_or("-")),
+            }
+            if !downloaded.versions.is_empty() {
+                println!("Releases:");
+                for (serial, version) in &downloaded.versions {
+                    let using = vms_using_image(name, Some(serial));
+                    println!(
+                        "  - {}{} -> {} (VMs: {})",
+                        serial,
+                        if downloaded.serial.as_ref() == Some(serial) { " (current)" } else { "" },
+                        version.filename,
+                        if using.is_empty() { "-".to_string() } else { using.join(", ") }
+                    );
+                }
+            }
+        }
+        None if image_path.is_file() => println!("Downloaded: yes -> {}", image_path.to_string_lossy()),
+        None => println!("Downloaded: no (`autovirt download {}`)", name),
+    }
+
+    let using = vms_using_image(name, None);
+    println!("VMs: {}", if using.is_empty() { "-".to_string() } else { using.join(", ") });
+    Ok(())
+}
+
+/// A file in `_data/downloads` (one release of an image, the only file of an
+/// image without releases or a file autovirt doesn't know about).
+///
+/// ---
+struct CachedImage {
+    /// None for untracked files
+    name: Option<String>,
+    serial: Option<String>,
+    path: PathBuf,
+    size: u64,
+    digest: Option<String>,
+    downloaded_at: Option<String>,
+    last_used: Option<String>,
+    /// The VMs made from it or with a disk backed by it
+    vms: Vec<String>,
+}
+
+impl CachedImage {
+    /// When it was last used (made into a VM or downloaded) as a unix time,
+    /// the file's mtime if there's nothing recorded.
+    ///
+    /// ---
+    fn used_at(&self) -> i64 {
+        self.last_used
+            .as_deref()
+            .or(self.downloaded_at.as_deref())
+            .and_then(|t| chrono::DateTime::parse_from_rfc3339(t).ok())
+            .map(|t| t.timestamp())
+            .or_else(|| {
+                fs::metadata(&self.path)
+                    .and_then(|m| m.modified())
+                    .ok()
+                    .map(|t| chrono::DateTime::<chrono::Utc>::from(t).timestamp())
+            })
+            .unwrap_or(0)
+    }
+
+    fn label(&self) -> String {
+        match (&self.name, &self.serial) {
+            (Some(name), Some(serial)) => format!("{} {}", name, serial),
+            (Some(name), None) => name.clone(),
+            (None, _) => self.path.file_name().unwrap_or_default().to_string_lossy().to_string(),
+        }
+    }
+}
+
+/// Gets everything in `_data/downloads` (leftover `.part` files aside).
+fn cached_images() -> io::Result<Vec<CachedImage>> {
+    let downloads_dir = downloads_dir()?;
+    let size_of = |path: &Path| fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    let vms_of = |name: &str, serial: Option<&str>, path: &Path| {
+        let mut vms = vms_using_image(name, serial);
+        vms.extend(vms_backed_by(path));
+        vms.sort();
+        vms.dedup();
+        vms
+    };
+
+    let downloaded: BTreeMap<String, DownloadedImage> = filesystem::get_value_from_autovirt_json("downloaded_images")
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default();
+
+    let mut cached = Vec::new();
+    for (name, image) in &downloaded {
+        let mut files: Vec<(Option<String>, ImageVersion)> =
+            image.versions.iter().map(|(serial, version)| (Some(serial.clone()), version.clone())).collect();
+        if !image.versions.values().any(|v| v.filename == image.filename) {
+            files.push((
+                image.serial.clone(),
+                ImageVersion {
+                    filename: image.filename.clone(),
+                    format: image.format.clone(),
+                    digest: image.digest.clone(),
+                    url: image.url.clone(),
+                    downloaded_at: image.downloaded_at.clone(),
+                    last_used: image.last_used.clone(),
+                    verification: image.verification.clone(),
+                },
+            ));
+        }
+
+        for (serial, version) in files {
+            let path = downloads_dir.join(&version.filename);
+            if !path.is_file() {
+                continue;
+            }
+            cached.push(CachedImage {
+                name: Some(name.clone()),
+                vms: vms_of(name, serial.as_deref(), &path),
+                serial,
+                size: size_of(&path),
+                digest: version.digest,
+                downloaded_at: version.downloaded_at,
+                last_used: version.last_used,
+                path,
+            });
+        }
+    }
+
+    // images downloaded before there were `downloaded_images` entries
+    for (name, image) in catalog::all_images() {
+        let path = downloads_dir.join(&image.filename);
+        if downloaded.contains_key(&name) || !path.is_file() {
+            continue;
+        }
+        cached.push(CachedImage {
+            vms: vms_of(&name, None, &path),
+            name: Some(name),
+            serial: None,
+            size: size_of(&path),
+            digest: None,
+            downloaded_at: None,
+            last_used: None,
+            path,
+        });
+    }
+
+    let known: Vec<PathBuf> = cached.iter().map(|c| c.path.clone()).collect();
+    let mut untracked: Vec<PathBuf> = fs::read_dir(&downloads_dir)
+        .map(|entries| entries.filter_map(|e| e.ok()).map(|e| e.path()).collect())
+        .unwrap_or_default();
+    untracked.retain(|p| p.is_file() && !known.contains(p) && p.extension().is_none_or(|e| e != "part"));
+    untracked.sort();
+    for path in untracked {
+        cached.push(CachedImage {
+            name: None,
+            serial: None,
+            size: size_of(&path),
+            digest: None,
+            downloaded_at: None,
+            last_used: None,
+            vms: vms_backed_by(&path),
+            path,
+        });
+    }
+
+    Ok(cached)
+}
+
+/// Lists the images in `_data/downloads` with their size, digest, download
+/// date and the VMs that use them.
+///
+/// ---
+pub fn list_images() -> Result<(), Box<dyn Error>> {
+    let cached = cached_images()?;
+    if cached.is_empty() {
+        println!("No images downloaded.");
+        return Ok(());
+    }
+
+    println!(
+        "{:<16} {:<16} {:>8}  {:<19}  {:<20}  {:<20}  VMS",
+        "NAME", "RELEASE", "SIZE", "DIGEST", "DOWNLOADED", "LAST USED"
+    );
+    for image in &cached {
+        let digest = image
+            .digest
+            .as_deref()
+            .map(|d| d.chars().take(19).collect::<String>())
+            .unwrap_or_else(|| "-".to_string());
+        println!(
+            "{:<16} {:<16} {:>8}  {:<19}  {:<20}  {:<20}  {}",
+            image.name.as_deref().unwrap_or("(untracked)"),
+            image.serial.as_deref().unwrap_or("-"),
+            diskspace::human_size(image.size),
+            digest,
+            image.downloaded_at.as_deref().unwrap_or("-"),
+            image.last_used.as_deref().unwrap_or("-"),
+            if image.vms.is_empty() { "0".to_string() } else { format!("{} ({})", image.vms.len(), image.vms.join(", ")) },
+        );
+        if image.name.is_none() {
+            println!("  └─ {}", image.path.to_string_lossy());
+        }
+    }
+
+    let total: u64 = cached.iter().map(|c| c.size).sum();
+    match cache_budget() {
+        Some(budget) => println!("TOTAL: {} (budget {})", diskspace::human_size(total), diskspace::human_size(budget)),
+        None => println!("TOTAL: {}", diskspace::human_size(total)),
+    }
+    Ok(())
+}
+
+/// Gets the image cache size budget (`image_cache.max_size`) if there is one.
+fn cache_budget() -> Option<u64> {
+    let max_size = filesystem::get_value_from_autovirt_json("image_cache.max_size")?;
+    let max_size = max_size.as_str().map(String::from).unwrap_or_else(|| max_size.to_string());
+    match crate::disk::parse_disk_size(&max_size) {
+        Ok(budget) => Some(budget),
+        Err(e) => {
+            eprintln!("WARNING:: Ignoring image_cache.max_size -> {}", e);
+            None
+        }
+    }
+}
+
+/// Removes a file from the image cache (and its release/entry), the newest
+/// release left becomes the current one if the current one is removed.
+///
+/// ---
+fn remove_cached_image(image: &CachedImage) -> Result<(), Box<dyn Error>> {
+    fs::remove_file(&image.path)?;
+    println!("LOG:: Removed {} ({}) -> {:?}", image.label(), diskspace::human_size(image.size), image.path);
+
+    let Some(name) = &image.name else {
+        return Ok(());
+    };
+    let Some(mut downloaded) = downloaded_image(name) else {
+        return Ok(());
+    };
+    if let Some(serial) = &image.serial {
+        downloaded.versions.remove(serial);
+    }
+
+    let removed_current = downloads_dir()?.join(&downloaded.filename) == image.path;
+    let newest = downloaded
+        .versions
+        .iter()
+        .max_by(|a, b| imageindex::compare_serials(a.0, b.0))
+        .map(|(serial, version)| (serial.clone(), version.clone()));
+    match (removed_current, newest) {
+        (false, _) => save_downloaded_image(name, &downloaded),
+        (true, Some((serial, version))) => record_download(name, Some(&serial), version),
+        (true, None) => {
+            filesystem::remove_value_from_autovirt_json(&format!("downloaded_images.{}", name));
+            // an imported image is gone for good once its file is
+            if filesystem::get_value_from_autovirt_json(&format!("images.{}.imported", name)).and_then(|v| v.as_bool()) == Some(true) {
+                filesystem::remove_value_from_autovirt_json(&format!("images.{}", name));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Picks the unused images to remove, all of them or (with a budget) the
+/// least recently used ones until the rest fits in it. Files in `keep` are
+/// never picked.
+///
+/// ---
+fn pick_evictions(cached: Vec<CachedImage>, budget: Option<u64>, keep: &[PathBuf]) -> Vec<CachedImage> {
+    let mut total: u64 = cached.iter().map(|c| c.size).sum();
+    // untracked files are left alone, they may be anything
+    let mut unused: Vec<CachedImage> = cached
+        .into_iter()
+        .filter(|c| c.vms.is_empty() && c.name.is_some() && !keep.contains(&c.path))
+        .collect();
+    unused.sort_by_key(|c| c.used_at());
+
+    let Some(budget) = budget else {
+        return unused;
+    };
+    let mut picked = Vec::new();
+    for image in unused {
+        if total <= budget {
+            break;
+        }
+        total = total.saturating_sub(image.size);
+        picked.push(image);
+    }
+    if total > budget {
+        println!("WARNING:: The images in use still take {} (over the {} budget)", diskspace::human_size(total), diskspace::human_size(budget));
+    }
+    picked
+}
+
+/// Removes leftover `.part` files of downloads that didn't finish (the ones
+/// that haven't been written to for an hour, so running downloads are safe).
+///
+/// ---
+fn remove_stale_part_files(dry_run: bool) -> io::Result<()> {
+    let stale = |path: &Path| {
+        fs::metadata(path)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.elapsed().ok())
+            .is_some_and(|age| age.as_secs() > 3600)
+    };
+    for entry in fs::read_dir(downloads_dir()?)?.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if path.extension().is_some_and(|e| e == "part") && stale(&path) {
+            if dry_run {
+                println!("Would remove the unfinished download {:?}", path);
+            } else {
+                fs::remove_file(&path)?;
+                println!("LOG:: Removed the unfinished download {:?}", path);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Removes the downloaded images no VM uses (made from or backed by), or
+/// with `max_size` only the least recently used of them until the images fit
+/// in it.
+///
+/// ---
+pub fn prune_images(max_size: Option<&str>, dry_run: bool) -> Result<(), Box<dyn Error>> {
+    let budget = max_size.map(crate::disk::parse_disk_size).transpose()?;
+    let picked = pick_evictions(cached_images()?, budget, &[]);
+    remove_stale_part_files(dry_run)?;
+    if picked.is_empty() {
+        println!("INFO:: Nothing to prune");
+        return Ok(());
+    }
+
+    let freed: u64 = picked.iter().map(|c| c.size).sum();
+    for image in &picked {
+        println!("- {} ({}) -> {}", image.label(), diskspace::human_size(image.size), image.path.to_string_lossy());
+    }
+    if dry_run {
+        println!("INFO:: Would free {}", diskspace::human_size(freed));
+        return Ok(());
+    }
+
+    println!("Are you sure you want to remove these {} images? (yes please/N)", picked.len());
+    let mut user_input = String::new();
+    std::io::stdin().read_line(&mut user_input).expect("Failed to read user input");
+    if user_input.trim() != "yes please" {
+        println!("!!! ABORTING IMAGE PRUNE !!!");
+        return Ok(());
+    }
+
+    for image in &picked {
+        remove_cached_image(image)?;
+    }
+    println!("LOG:: Freed {}", diskspace::human_size(freed));
+    Ok(())
+}
+
+/// Evicts the least recently used unused images if the images are over the
+/// `image_cache.max_size` budget (run after downloads, `just_downloaded` is
+/// never evicted).
+///
+/// ---
+pub fn enforce_cache_budget(just_downloaded: &Path) {
+    let Some(budget) = cache_budget() else {
+        return;
+    };
+    let result = cached_images().map_err(|e| Box::new(e) as Box<dyn Error>).and_then(|cached| {
+        for image in pick_evictions(cached, Some(budget), &[just_downloaded.to_path_buf()]) {
+            println!("INFO:: Over the image cache budget ({}), evicting {}", diskspace::human_size(budget), image.label());
+            remove_cached_image(&image)?;
+        }
+        Ok(())
+    });
+    if let Err(e) = result {
+        eprintln!("WARNING:: Failed to keep the images in their budget -> {}", e);
+    }
+}
@@ -5,6 +5,10 @@ use std::path::Path;
 
 use crate::filesystem;
 
+/// The only files of a VM's data directory the server gives out, the rest
+/// (logs, extra user-data parts etc.) can have passwords in them.
+const VM_CLOUD_INIT_FILES: [&str; 3] = ["user-data", "meta-data", "vendor-data"];
+
 /// Function to start the IDMS server (Instance Metadata Service) for the
 /// metadata for a VM.
 ///
@@ -14,18 +18,36 @@ use crate::filesystem;
 /// The cofig directory has cloud-init config files (user-data, meta-data etc.)
 /// which may have dynamic parameters based on user specified value.
 ///
+/// Each VM gets its own cloud-init files under `/<vm name>/` which are served
+/// from the VM's data directory (`~/.autovirt/_data/vms/<vm name>/`), only
+/// `user-data`, `meta-data` and `vendor-data` though. Anything else is served
+/// from `~/.autovirt/_data/conf` like before.
+///
 pub fn start_idms_server() {
     let server = Server::new(move |request, mut response| {
         let path = request.uri().path().trim_start_matches('/');
         let path = if path.is_empty() { "index.html" } else { path };
 
+        // not letting anything get out of the autovirt data directory
+        if path.split('/').any(|part| part == "..") {
+            return Ok(response.status(404).body("404 🫡".as_bytes().to_vec())?);
+        }
+
         // Getting the path of the autovirt data directory where the cloud init
         // config files are stored and then serving them
 
-        let autovirt_data_dir = filesystem::get_autovirt_data_dir();
-        let autovirt_cloud_init_dir = autovirt_data_dir.unwrap().join("_data/conf");
+        let autovirt_data_dir = filesystem::get_autovirt_data_dir().unwrap();
+        let autovirt_cloud_init_dir = autovirt_data_dir.join("_data/conf");
 
-        let file_path = Path::new(&autovirt_cloud_init_dir).join(path);
+        let file_path = match path.split_once('/') {
+            Some((vm_name, file)) if autovirt_data_dir.join("_data/vms").join(vm_name).is_dir() => {
+                if !VM_CLOUD_INIT_FILES.contains(&file) {
+                    return Ok(response.status(404).body("404 🫡".as_bytes().to_vec())?);
+                }
+                autovirt_data_dir.join("_data/vms").join(vm_name).join(file)
+            }
+            _ => Path::new(&autovirt_cloud_init_dir).join(path),
+        };
         println!("Starting imds server with path -> {:?}", file_path);
 
         if file_path.is_file() {
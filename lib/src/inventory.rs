@@ -0,0 +1,313 @@
+//! This file contains things for generating ssh config `Host` blocks and
+//! ansible inventories from the VMs in the autovirt config file.
+//!
+//! The ssh config can be managed by autovirt in which case it's written to
+//! `~/.autovirt/ssh_config` (which is `Include`d from `~/.ssh/config`) and is
+//! kept in sync when VMs are created, cloned or deleted.
+//!
+//! ---
+
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::filesystem;
+use crate::ssh;
+
+/// The line added to `~/.ssh/config` to include the autovirt ssh config.
+const SSH_CONFIG_INCLUDE_LINE: &str = "Include ~/.autovirt/ssh_config";
+
+/// Gets all the VMs from the autovirt config file (name -> VM entry).
+fn all_vms() -> Map<String, Value> {
+    filesystem::get_value_from_autovirt_json("vms")
+        .and_then(|v| v.as_object().cloned())
+        .unwrap_or_default()
+}
+
+/// Gets the VMs to use out of all of them. All the VMs are used if no names
+/// are given.
+///
+/// ---
+fn selected_vms(vm_names: &[String]) -> Result<Vec<String>, Box<dyn Error>> {
+    let vms = all_vms();
+    if vm_names.is_empty() {
+        return Ok(vms.keys().cloned().collect());
+    }
+
+    for vm_name in vm_names {
+        if !vms.contains_key(vm_name) {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("VM '{}' not found", vm_name),
+            )));
+        }
+    }
+    Ok(vm_names.to_vec())
+}
+
+/// Renders ssh config `Host` blocks for the VMs (all VMs if no names are
+/// given). VMs without a port forward to ssh are left out with a comment.
+///
+/// ---
+pub fn render_ssh_config(vm_names: &[String]) -> Result<String, Box<dyn Error>> {
+    let mut ssh_config = String::from("# Generated by autovirt (autovirt ssh-config)\n");
+
+    for vm_name in selected_vms(vm_names)? {
+        let target = match ssh::ssh_target_for_vm(&vm_name) {
+            Ok(target) => target,
+            Err(_) => {
+                ssh_config.push_str(&format!("\n# {}: no port forward to ssh\n", vm_name));
+                continue;
+            }
+        };
+
+        ssh_config.push_str(&format!("\nHost {}\n", vm_name));
+        ssh_config.push_str(&format!("    HostName {}\n", target.host));
+        ssh_config.push_str(&format!("    Port {}\n", target.port));
+        ssh_config.push_str(&format!("    User {}\n", target.user));
+        if let Some(identity_file) = &target.identity_file {
+            ssh_config.push_str(&format!("    IdentityFile {}\n", identity_file));
+            ssh_config.push_str("    IdentitiesOnly yes\n");
+        }
+        // every VM gets new host keys and forwarded ports get reused
+        ssh_config.push_str("    StrictHostKeyChecking no\n");
+        ssh_config.push_str("    UserKnownHostsFile /dev/null\n");
+        ssh_config.push_str("    LogLevel ERROR\n");
+    }
+
+    Ok(ssh_config)
+}
+
+fn managed_ssh_config_path() -> Option<PathBuf> {
+    filesystem::get_autovirt_data_dir().map(|dir| dir.join("ssh_config"))
+}
+
+fn user_ssh_config_path() -> Option<PathBuf> {
+    std::env::var("HOME").ok().map(|home| PathBuf::from(home).join(".ssh/config"))
+}
+
+/// Writes the managed ssh config (`~/.autovirt/ssh_config`) with all the VMs
+/// and adds an `Include` for it at the top of `~/.ssh/config`.
+///
+/// ---
+pub fn install_managed_ssh_config() -> Result<(), Box<dyn Error>> {
+    filesystem::insert_value_into_autovirt_json_object("settings.ssh_config_managed", Value::Bool(true));
+    sync_managed_ssh_config()?;
+
+    let user_ssh_config = user_ssh_config_path()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Could not find user $HOME directory"))?;
+    let existing = fs::read_to_string(&user_ssh_config).unwrap_or_default();
+
+    if existing.lines().any(|line| line.trim() == SSH_CONFIG_INCLUDE_LINE) {
+        println!("INFO:: {:?} already includes the autovirt ssh config", user_ssh_config);
+        return Ok(());
+    }
+
+    // Include has to come before any Host blocks to apply to every host
+    if let Some(ssh_dir) = user_ssh_config.parent() {
+        fs::create_dir_all(ssh_dir)?;
+    }
+    fs::write(&user_ssh_config, format!("{}\n\n{}", SSH_CONFIG_INCLUDE_LINE, existing))?;
+    println!("LOG:: Added '{}' to {:?}", SSH_CONFIG_INCLUDE_LINE, user_ssh_config);
+
+    Ok(())
+}
+
+/// Removes the managed ssh config and the `Include` for it.
+///
+/// ---
+pub fn uninstall_managed_ssh_config() -> Result<(), Box<dyn Error>> {
+    filesystem::insert_value_into_autovirt_json_object("settings.ssh_config_managed", Value::Bool(false));
+
+    if let Some(path) = managed_ssh_config_path().filter(|p| p.exists()) {
+        fs::remove_file(&path)?;
+        println!("LOG:: Removed {:?}", path);
+    }
+
+    if let Some(user_ssh_config) = user_ssh_config_path().filter(|p| p.exists()) {
+        let existing = fs::read_to_string(&user_ssh_config)?;
+        if existing.lines().any(|line| line.trim() == SSH_CONFIG_INCLUDE_LINE) {
+            let updated: String = existing
+                .lines()
+                .filter(|line| line.trim() != SSH_CONFIG_INCLUDE_LINE)
+                .map(|line| format!("{}\n", line))
+                .collect();
+            fs::write(&user_ssh_config, updated.trim_start())?;
+            println!("LOG:: Removed '{}' from {:?}", SSH_CONFIG_INCLUDE_LINE, user_ssh_config);
+        }
+    }
+
+    Ok(())
+}
+
+/// Rewrites the managed ssh config with all the VMs if the user has turned it
+/// on (`autovirt ssh-config --install`). Does nothing otherwise.
+///
+/// This is called whenever VMs are created/deleted/cloned etc.
+///
+/// ---
+pub fn sync_managed_ssh_config() -> Result<(), Box<dyn Error>> {
+    let managed = filesystem::get_value_from_autovirt_json("settings.ssh_config_managed")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    if !managed {
+        return Ok(());
+    }
+
+    let path = managed_ssh_config_path()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Could not find user $HOME directory"))?;
+    fs::write(&path, render_ssh_config(&[])?)?;
+    Ok(())
+}
+
+/// Same as `sync_managed_ssh_config` but only prints an error since the ssh
+/// config being out of date shouldn't stop anything else.
+///
+/// ---
+pub fn sync_managed_ssh_config_or_warn() {
+    if let Err(e) = sync_managed_ssh_config() {
+        eprintln!("ERROR: Failed to update the autovirt ssh config -> {}", e);
+    }
+}
+
+/// Turns a tag/group/distro into a valid ansible group name.
+fn ansible_group_name(name: &str) -> String {
+    let group: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' })
+        .collect();
+
+    if group.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("_{}", group)
+    } else {
+        group
+    }
+}
+
+/// Gets the ansible host vars for a VM (connection details + some autovirt
+/// metadata).
+///
+/// ---
+fn host_vars(vm_name: &str, vm_data: &Value) -> Option<Value> {
+    let target = ssh::ssh_target_for_vm(vm_name).ok()?;
+
+    let mut vars = json!({
+        "ansible_host": target.host,
+        "ansible_port": target.port,
+        "ansible_user": target.user,
+        "ansible_ssh_common_args": "-o StrictHostKeyChecking=no -o UserKnownHostsFile=/dev/null",
+        "autovirt_distro": vm_data.get("distro").cloned().unwrap_or(Value::Null),
+        "autovirt_tags": vm_data.get("tags").cloned().unwrap_or_else(|| json!({})),
+    });
+
+    if let Some(identity_file) = target.identity_file {
+        vars["ansible_ssh_private_key_file"] = Value::String(identity_file);
+    }
+
+    Some(vars)
+}
+
+/// Gets the ansible groups a VM is in (from its tags, groups and distro).
+///
+/// Tags become `<key>_<value>` groups.
+///
+/// ---
+fn vm_groups(vm_data: &Value) -> Vec<String> {
+    let mut groups = vec!["autovirt".to_string()];
+
+    if let Some(distro) = vm_data.get("distro").and_then(|v| v.as_str()) {
+        groups.push(ansible_group_name(&format!("distro_{}", distro)));
+    }
+
+    if let Some(tags) = vm_data.get("tags").and_then(|v| v.as_object()) {
+        for (key, value) in tags {
+            let value = value.as_str().map(String::from).unwrap_or_else(|| value.to_string());
+            groups.push(ansible_group_name(&format!("{}_{}", key, value)));
+        }
+    }
+
+    if let Some(vm_groups) = vm_data.get("groups").and_then(|v| v.as_array()) {
+        for group in vm_groups.iter().filter_map(|g| g.as_str()) {
+            groups.push(ansible_group_name(group));
+        }
+    }
+
+    groups
+}
+
+/// Gets all the VMs that can be reached over ssh along with their host vars
+/// and groups.
+///
+/// ---
+fn inventory_hosts() -> Vec<(String, Value, Vec<String>)> {
+    all_vms()
+        .into_iter()
+        .filter_map(|(vm_name, vm_data)| {
+            let vars = host_vars(&vm_name, &vm_data)?;
+            let groups = vm_groups(&vm_data);
+            Some((vm_name, vars, groups))
+        })
+        .collect()
+}
+
+/// Renders the inventory in the format ansible wants from dynamic inventory
+/// scripts (`--list`).
+///
+/// ---
+pub fn render_dynamic_inventory() -> Value {
+    let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let mut hostvars = Map::new();
+
+    for (vm_name, vars, vm_groups) in inventory_hosts() {
+        for group in vm_groups {
+            groups.entry(group).or_default().push(vm_name.clone());
+        }
+        hostvars.insert(vm_name, vars);
+    }
+
+    let mut inventory = Map::new();
+    inventory.insert(
+        "all".to_string(),
+        json!({ "children": groups.keys().cloned().collect::<Vec<String>>() }),
+    );
+    for (group, hosts) in groups {
+        inventory.insert(group, json!({ "hosts": hosts }));
+    }
+    inventory.insert("_meta".to_string(), json!({ "hostvars": hostvars }));
+
+    Value::Object(inventory)
+}
+
+/// Renders the host vars for a single VM (`--host <vm>`).
+pub fn render_dynamic_inventory_host(vm_name: &str) -> Value {
+    all_vms()
+        .get(vm_name)
+        .and_then(|vm_data| host_vars(vm_name, vm_data))
+        .unwrap_or_else(|| json!({}))
+}
+
+/// Renders the inventory as a static inventory file (the yaml inventory format
+/// written as json, which ansible reads fine since json is yaml).
+///
+/// ---
+pub fn render_static_inventory() -> Value {
+    let mut all_hosts = Map::new();
+    let mut children: BTreeMap<String, Map<String, Value>> = BTreeMap::new();
+
+    for (vm_name, vars, vm_groups) in inventory_hosts() {
+        for group in vm_groups {
+            children.entry(group).or_default().insert(vm_name.clone(), json!({}));
+        }
+        all_hosts.insert(vm_name, vars);
+    }
+
+    let children: Map<String, Value> = children
+        .into_iter()
+        .map(|(group, hosts)| (group, json!({ "hosts": hosts })))
+        .collect();
+
+    json!({ "all": { "hosts": all_hosts, "children": children } })
+}
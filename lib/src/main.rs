@@ -1,8 +1,7 @@
 // Rust imports
 
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser};
 use clap::Subcommand;
-use tokio;
 // use tokio::task;
 // use tokio::runtime::Runtime;
 // use std::process::Command;
@@ -16,6 +15,31 @@ mod imds;
 mod filesystem;
 mod vmutils;
 mod initdata;
+mod cloudinit;
+mod ssh;
+mod provision;
+mod inventory;
+mod qmp;
+mod selector;
+mod snapshot;
+mod disk;
+mod blockdev;
+mod share;
+mod diskspace;
+mod hotplug;
+mod guestagent;
+mod console;
+mod catalog;
+mod wait;
+mod images;
+mod imageindex;
+mod unpack;
+mod fetch;
+mod bundle;
+mod signature;
+mod downloadqueue;
+mod backend;
+mod qemucmd;
 
 #[derive(Parser)]
 #[command(name = "AutoVirt", about = "AutoVirt VM Automation CLI", long_about = None)]
@@ -28,6 +52,7 @@ struct Cli {
 }
 
 #[derive(Subcommand)]
+#[allow(clippy::large_enum_variant)]
 enum VMCommands {
     /// Creates config files and data directory(s) for autovirt.
     Install { },
@@ -47,7 +72,11 @@ enum VMCommands {
 
     },
     /// Lists all currently installed VMs with some metadata.
-    List { },
+    List {
+        /// Only list the VMs matching a selector
+        #[arg(short, long, help = "Only list VMs matching a selector (i.e. -> 'env=ci,group=web')")]
+        selector: Option<String>,
+    },
     /// Create a new VM based on a given distro, user/pass and name
     /// (cloud-init/qemu)
     Create {
@@ -60,7 +89,7 @@ enum VMCommands {
             short,
             long,
             required=true,
-            help = "Distro of the VM to create (see options with \n`autovirt show available`)",
+            help = "Distro of the VM to create (see options with \n`autovirt show --available`)",
             default_value = "ubuntu2204"
         )]
         dist: String,
@@ -74,7 +103,7 @@ enum VMCommands {
         user: String,
 
         /// The password for the VM (non-root)
-        #[arg(short, long, required=true, help = "The Password for the VM", default_value = "123456")]
+        #[arg(short = 'P', long, required=true, help = "The Password for the VM", default_value = "123456")]
         pass: String,
 
         /// The amount of memory in MB (Example: 512 or 1024)
@@ -85,6 +114,14 @@ enum VMCommands {
         #[arg(short, long, required=true, help = "The amount of vCPU's for the vm", default_value = "1")]
         cpus: String,
 
+        /// The most vCPUs the VM can be given while it runs
+        #[arg(long, help = "The most vCPUs the VM can have while running (default: the host's CPU count)")]
+        max_cpus: Option<u64>,
+
+        /// The most memory the VM can be given while it runs
+        #[arg(long = "max-memory", help = "The most memory in MB the VM can have while running (default: 4x --mem)")]
+        max_memory_mb: Option<u64>,
+
         /// The path to an ssh key to add to the user
         #[arg(short, long, required=true, help = "Path to an ssh key to add to the user", default_value = "none")]
         key: String,
@@ -93,6 +130,128 @@ enum VMCommands {
         #[arg(short, long, help = "Port forward args (i.e. -> 'hostfwd=tcp::2244-:22' )", default_value = "")]
         ports: String,
 
+        /// Packages to install on first boot (cloud-init)
+        #[arg(long = "package", help = "Package to install on first boot (can be used multiple times)")]
+        packages: Vec<String>,
+
+        /// Commands to run on first boot (cloud-init)
+        #[arg(long = "runcmd", help = "Command to run on first boot (can be used multiple times)")]
+        runcmd: Vec<String>,
+
+        /// Local files to write into the VM on first boot (cloud-init)
+        #[arg(long = "write-file", help = "Local file to write into the VM as src:/dst (can be used multiple times)")]
+        write_files: Vec<String>,
+
+        /// Extra mounts for the VM (cloud-init)
+        #[arg(long = "mount", help = "Mount entry as device:/mountpoint[:fstype[:options]] (can be used multiple times)")]
+        mounts: Vec<String>,
+
+        /// The timezone for the VM
+        #[arg(long, help = "Timezone for the VM (i.e. -> 'Europe/London')")]
+        timezone: Option<String>,
+
+        /// The hostname for the VM
+        #[arg(long, help = "Hostname for the VM")]
+        hostname: Option<String>,
+
+        /// A cloud-config file to merge on top of the generated user-data
+        #[arg(long, help = "Path to a cloud-config yaml file to merge on top of the generated user-data")]
+        user_data_file: Option<String>,
+
+        /// Shell scripts to run on first boot (cloud-init x-shellscript part)
+        #[arg(long = "script", help = "Shell script to run on first boot (can be used multiple times)")]
+        scripts: Vec<String>,
+
+        /// Boothooks to run early on every boot (cloud-init cloud-boothook part)
+        #[arg(long = "boothook", help = "Boothook script to run early on boot (can be used multiple times)")]
+        boothooks: Vec<String>,
+
+        /// Custom cloud-init part handlers (cloud-init part-handler part)
+        #[arg(long = "part-handler", help = "Python cloud-init part handler file (can be used multiple times)")]
+        part_handlers: Vec<String>,
+
+        /// Urls for cloud-init to include (cloud-init #include part)
+        #[arg(long = "include-url", help = "Url for cloud-init to fetch and include (can be used multiple times)")]
+        include_urls: Vec<String>,
+
+        /// The hypervisor the VM runs on
+        #[arg(long, value_parser = backend::BACKENDS, default_value = "qemu", help = "The hypervisor the VM runs on")]
+        backend: String,
+
+        /// Where the VM gets its cloud-init data from
+        #[arg(
+            long,
+            help = "Where the VM gets its cloud-init data from (imds server or a seed image, default: imds unless the image or backend needs a seed)",
+            value_parser = ["imds", "seed"]
+        )]
+        datasource: Option<String>,
+
+        /// Shell commands to run over ssh once the VM is up
+        #[arg(long = "provision-shell", help = "Shell command to run over ssh once the VM is up (can be used multiple times)")]
+        provision_shell: Vec<String>,
+
+        /// Local scripts to run over ssh once the VM is up
+        #[arg(long = "provision-script", help = "Local script to run over ssh once the VM is up (can be used multiple times)")]
+        provision_scripts: Vec<String>,
+
+        /// Local files to upload over ssh once the VM is up
+        #[arg(long = "provision-file", help = "Local file to upload once the VM is up as src:dst (can be used multiple times)")]
+        provision_files: Vec<String>,
+
+        /// Tags for the VM
+        #[arg(long = "tag", help = "Tag for the VM as key=value (can be used multiple times)")]
+        tags: Vec<String>,
+
+        /// Groups for the VM
+        #[arg(long = "group", help = "Group to add the VM to (can be used multiple times)")]
+        groups: Vec<String>,
+
+        /// Extra data disks for the VM
+        #[arg(long = "data-disk", help = "Data disk as size[:/mountpoint] (i.e. -> '50G:/srv/data', can be used multiple times)")]
+        data_disks: Vec<String>,
+
+        /// The bus for the main disk
+        #[arg(long, value_parser = blockdev::MAIN_DISK_BUSES, default_value = "virtio", help = "Bus for the main disk")]
+        disk_bus: String,
+
+        /// The disk cache mode
+        #[arg(long, value_parser = blockdev::CACHE_MODES, default_value = "writeback", help = "Cache mode for the disks")]
+        disk_cache: String,
+
+        /// The disk aio backend
+        #[arg(long, value_parser = blockdev::AIO_BACKENDS, default_value = "threads", help = "Aio backend for the disks (native needs --disk-cache none/directsync)")]
+        disk_aio: String,
+
+        /// Don't pass discard/TRIM through to the image files
+        #[arg(long, help = "Don't pass discard/TRIM from the VM through to the disk images")]
+        no_discard: bool,
+
+        /// IOPS limit for each disk
+        #[arg(long, help = "IO operations per second limit for each disk")]
+        disk_iops: Option<u64>,
+
+        /// Bytes per second limit for each disk
+        #[arg(long, help = "Bytes per second limit for each disk")]
+        disk_bps: Option<u64>,
+
+        /// Host directories to share with the VM
+        #[arg(long = "share", help = "Host directory to share as host_path:tag[:ro][:/guest/path] (can be used multiple times)")]
+        shares: Vec<String>,
+
+        /// Wait until the VM is ready and return (the VM keeps running in the background)
+        #[arg(
+            long,
+            value_parser = wait::CONDITIONS,
+            num_args = 0..=1,
+            default_missing_value = "ssh",
+            help = "Return once the VM is ready (ssh by default), the VM keeps running in the background"
+        )]
+        wait: Option<String>,
+
+        /// How long --wait waits
+        #[arg(long, default_value_t = 300, help = "Seconds to --wait before giving up")]
+        wait_timeout: u64,
+
         // /// The path to an already existing image (.img cloud init file)
         // #[arg(short, long, help = "Path to existing cloud init .img file", default_value = "1")]
         // path: String,
@@ -106,15 +265,126 @@ enum VMCommands {
         /// String for port forwarding arguments
         #[arg(short, long, help = "Port forward args (i.e. -> 'hostfwd=tcp::2244-:22' )", default_value = "")]
         ports: String,
+
+        /// Run the VM's provisioners once it is up
+        #[arg(long, help = "Run the VM's provisioners over ssh once it is up")]
+        provision: bool,
+
+        /// Shell commands to run over ssh once the VM is up
+        #[arg(long = "provision-shell", help = "Shell command to run over ssh once the VM is up (can be used multiple times)")]
+        provision_shell: Vec<String>,
+
+        /// Local scripts to run over ssh once the VM is up
+        #[arg(long = "provision-script", help = "Local script to run over ssh once the VM is up (can be used multiple times)")]
+        provision_scripts: Vec<String>,
+
+        /// Local files to upload over ssh once the VM is up
+        #[arg(long = "provision-file", help = "Local file to upload once the VM is up as src:dst (can be used multiple times)")]
+        provision_files: Vec<String>,
+
+        /// Run the VM in the background
+        #[arg(long, help = "Run the VM in the background (stop it with `autovirt stop`)")]
+        detach: bool,
+
+        /// Host directories to share with the VM
+        #[arg(long = "share", help = "Host directory to share as host_path:tag[:ro][:/guest/path] (can be used multiple times)")]
+        shares: Vec<String>,
+
+        /// Wait until the VM is ready and return (the VM keeps running in the background)
+        #[arg(
+            long,
+            value_parser = wait::CONDITIONS,
+            num_args = 0..=1,
+            default_missing_value = "ssh",
+            help = "Return once the VM is ready (ssh by default), the VM keeps running in the background"
+        )]
+        wait: Option<String>,
+
+        /// How long --wait waits
+        #[arg(long, default_value_t = 300, help = "Seconds to --wait before giving up")]
+        wait_timeout: u64,
+
+        /// Print the hypervisor command instead of running it
+        #[arg(long, conflicts_with = "shares", help = "Print the (shell quoted) hypervisor command the VM would be run with and exit")]
+        dry_run: bool,
+    },
+    /// Waits until a VM is ready (exit codes: 0 ready, 1 error, 2 timed out, 3 not running, 4 cloud-init failed).
+    Wait {
+        #[arg(required=true, help = "Name of the VM")]
+        name: String,
+
+        #[arg(long = "for", value_parser = wait::CONDITIONS, default_value = "ssh", help = "What to wait for, checked in order (can be used multiple times)")]
+        conditions: Vec<String>,
+
+        #[arg(long, default_value_t = 22, help = "Guest port to wait for with --for port")]
+        port: u16,
+
+        #[arg(long, default_value_t = 300, help = "Seconds to wait before giving up")]
+        timeout: u64,
+    },
+    /// Starts VMs in the background (by name or selector).
+    Start {
+        /// The names of the VMs to start
+        #[arg(help = "Names of the VMs to start")]
+        names: Vec<String>,
+
+        /// Start the VMs matching a selector
+        #[arg(short, long, help = "Start VMs matching a selector (i.e. -> 'env=ci,group=web')")]
+        selector: Option<String>,
+    },
+    /// Stops running VMs (by name or selector).
+    Stop {
+        /// The names of the VMs to stop
+        #[arg(help = "Names of the VMs to stop")]
+        names: Vec<String>,
+
+        /// Stop the VMs matching a selector
+        #[arg(short, long, help = "Stop VMs matching a selector (i.e. -> 'env=ci,group=web')")]
+        selector: Option<String>,
+
+        /// Kill VMs that don't shut down in time
+        #[arg(short, long, help = "Kill VMs that don't shut down before the timeout")]
+        force: bool,
+
+        /// How long to wait for VMs to shut down
+        #[arg(long, help = "Seconds to wait for each VM to shut down", default_value_t = 60)]
+        timeout: u64,
+    },
+    /// Adds/removes tags (key=value) on a VM.
+    Tag {
+        /// The name of the VM
+        #[arg(required=true, help = "Name of the VM")]
+        name: String,
+
+        #[command(subcommand)]
+        command: TagCommands,
+    },
+    /// Adds/removes a VM to/from groups.
+    Group {
+        /// The name of the VM
+        #[arg(required=true, help = "Name of the VM")]
+        name: String,
+
+        #[command(subcommand)]
+        command: GroupCommands,
     },
     /// Downloads a cloud-init compatible image for the specified distro.
     Download {
-        /// The distro (linux distribution) of the image to download
+        /// The distros (linux distributions) of the images to download
         #[arg(
-            help = "The VM Image (cloud init/qemu) to download.\nSee `autovirt show available` for a full list\nof available images to download.",
-            required=true,
+            help = "The VM Images (cloud init/qemu) to download.\nSee `autovirt show --available` for a full list\nof available images to download.",
+            required_unless_present = "all",
         )]
-        dist: String,
+        dists: Vec<String>,
+
+        #[arg(long, conflicts_with = "dists", help = "Download every image in the catalog")]
+        all: bool,
+
+        #[arg(short, long, help = "How many images to download at once (download.jobs in autovirt.json, 3 by default)")]
+        jobs: Option<usize>,
+
+        #[arg(long, help = "Download even if the image's checksums can't be signature verified")]
+        insecure: bool,
     },
     /// Show various things such as available images to download
     Show {
@@ -139,6 +409,14 @@ enum VMCommands {
         /// New amount of CPUs
         #[arg(short, long, required=true,  help = "The new amount of CPUs for the VM (1, 2, 4 etc.")]
         cpus: String,
+
+        /// New max amount of CPUs
+        #[arg(long, help = "The most vCPUs the VM can have while running (used from the next boot)")]
+        max_cpus: Option<u64>,
+
+        /// New max memory
+        #[arg(long = "max-memory", help = "The most memory in MB the VM can have while running (used from the next boot)")]
+        max_memory_mb: Option<u64>,
     },
     /// Clone a specified VM by name to a new VM with a new name.
     Clone {
@@ -153,8 +431,12 @@ enum VMCommands {
     /// Deletes specified VM (by name) along with associated files & relevant
     /// configs.
     Delete {
-        #[arg(required=true, help = "Name of the VM to delete")]
-        name: String,
+        #[arg(help = "Names of the VMs to delete")]
+        names: Vec<String>,
+
+        /// Delete the VMs matching a selector
+        #[arg(short, long, help = "Delete VMs matching a selector (i.e. -> 'env=ci,group=web')")]
+        selector: Option<String>,
     },
     /// Gets the checksum of a specified file/image.
     Checksum {
@@ -163,12 +445,443 @@ enum VMCommands {
         file: String,
     },
     /// Checks autovirt config file for errors & downloaded vm checksums
-    Health { }
+    Health { },
+    /// Runs the provisioners of a (running) VM over ssh again.
+    Provision {
+        /// The name of the VM to provision
+        #[arg(required=true, help = "Name of the VM to provision")]
+        name: String,
+
+        /// How long to wait for the VM to be reachable over ssh
+        #[arg(long, help = "Seconds to wait for the VM to be reachable over ssh", default_value_t = provision::SSH_WAIT_TIMEOUT_SECS)]
+        timeout: u64,
+    },
+    /// Prints ssh config Host blocks for VMs (or keeps an Included ssh config
+    /// file in sync with the VMs).
+    SshConfig {
+        /// The VMs to print Host blocks for (all VMs if none are given)
+        #[arg(help = "Names of the VMs (all VMs if none are given)")]
+        names: Vec<String>,
+
+        /// Keep ~/.autovirt/ssh_config in sync and Include it in ~/.ssh/config
+        #[arg(long, help = "Keep ~/.autovirt/ssh_config in sync with all VMs and Include it in ~/.ssh/config")]
+        install: bool,
+
+        /// Stop managing the Included ssh config file
+        #[arg(long, conflicts_with = "install", help = "Remove the managed ssh config and its Include")]
+        uninstall: bool,
+    },
+    /// Prints an ansible inventory (json) of the VMs. This can also be used as
+    /// a dynamic inventory script (`--list`/`--host`).
+    Inventory {
+        /// List all hosts and groups (dynamic inventory format)
+        #[arg(long, help = "List all groups and hosts (dynamic inventory script format)")]
+        list: bool,
+
+        /// Get the vars of a single host (dynamic inventory format)
+        #[arg(long, conflicts_with = "list", help = "Get the vars of a single VM (dynamic inventory script format)")]
+        host: Option<String>,
+
+        /// Print a static inventory instead
+        #[arg(long = "static", conflicts_with_all = ["list", "host"], help = "Print a static inventory (yaml inventory format as json)")]
+        static_inventory: bool,
+    },
+    /// Attaches to the serial console of a running VM (Ctrl-] to detach).
+    Console {
+        #[arg(required=true, help = "Name of the VM")]
+        name: String,
+    },
+    /// Shows the serial console log of a VM.
+    Logs {
+        #[arg(required=true, help = "Name of the VM")]
+        name: String,
+
+        #[arg(short, long, help = "Keep printing new lines as they come")]
+        follow: bool,
+
+        #[arg(long, help = "Only show lines from this time on (i.e. -> 10m, 2h, 1d or 2024-01-31T12:00:00+00:00)")]
+        since: Option<String>,
+    },
+    /// Keeps a running VM's console log (started with the VM).
+    #[command(name = "serial-logger", hide = true)]
+    SerialLogger {
+        name: String,
+    },
+    /// Shows the network interfaces/IPs of a running VM (guest agent).
+    Ip {
+        #[arg(required=true, help = "Name of the VM")]
+        name: String,
+    },
+    /// Runs a command in a running VM through the guest agent (no SSH needed).
+    #[command(name = "guest-exec")]
+    GuestExec {
+        #[arg(required=true, help = "Name of the VM")]
+        name: String,
+
+        #[arg(long, default_value = "300", help = "Seconds to wait for the command to finish")]
+        timeout: u64,
+
+        #[arg(last = true, required = true, help = "The command to run (after --)")]
+        command: Vec<String>,
+    },
+    /// Sets the password of a user in a running VM (guest agent).
+    #[command(name = "set-password")]
+    SetPassword {
+        #[arg(required=true, help = "Name of the VM")]
+        name: String,
+
+        #[arg(required=true, help = "The user to set the password of")]
+        user: String,
+
+        #[arg(long, help = "The new password (asked for if not given)")]
+        password: Option<String>,
+    },
+    /// Creates, lists, reverts and deletes VM disk snapshots.
+    Snapshot {
+        #[command(subcommand)]
+        command: SnapshotCommands,
+    },
+    /// Things related to VM disks (formats etc.).
+    Disk {
+        #[command(subcommand)]
+        command: DiskCommands,
+    },
+    /// Lists/removes the host directories shared with a VM.
+    Share {
+        #[command(subcommand)]
+        command: ShareCommands,
+    },
+    /// Imports, removes and shows the base images VMs are made from.
+    Image {
+        #[command(subcommand)]
+        command: ImageCommands,
+    },
+    /// Things related to the cloud-init data given to VMs.
+    CloudInit {
+        #[command(subcommand)]
+        command: CloudInitCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum ImageCommands {
+    /// Imports a local image file as a base image (usable with `create --dist <name>`).
+    Import {
+        #[arg(required=true, help = "Path of the image file")]
+        path: String,
+
+        #[arg(long, required=true, help = "Name to give the image")]
+        name: String,
+
+        #[arg(long, value_parser = disk::DISK_FORMATS, help = "Format of the image (checked against the file)")]
+        format: Option<String>,
+
+        #[arg(long = "os-family", default_value = "debian", help = "OS family of the image (debian, rhel, alpine...)")]
+        os_family: String,
+
+        #[arg(long, help = "Copy the file even if it could be hard-linked")]
+        copy: bool,
+    },
+    /// Removes a base image (refused if VM disks are backed by it).
+    Remove {
+        #[arg(required=true, help = "Name of the image")]
+        name: String,
+    },
+    /// Gets the newest release of images with an index (keeps old releases VMs were made from).
+    Update {
+        #[arg(help = "Names of the images (all downloaded images with an index if none)")]
+        names: Vec<String>,
+
+        #[arg(long, help = "Download even if the checksums can't be signature verified")]
+        insecure: bool,
+    },
+    /// Lists the downloaded images with their size, digest and the VMs using them.
+    List,
+    /// Removes the downloaded images no VM uses.
+    Prune {
+        #[arg(long = "max-size", help = "Only remove the least recently used ones until the images fit in this (i.e. -> 20G)")]
+        max_size: Option<String>,
+
+        #[arg(long = "dry-run", help = "Only show what would be removed")]
+        dry_run: bool,
+    },
+    /// Makes a bundle (tarball) of downloaded images to seed another host with.
+    Export {
+        #[arg(help = "Names of the images")]
+        names: Vec<String>,
+
+        #[arg(long, conflicts_with = "names", help = "Export all the downloaded images")]
+        all: bool,
+
+        #[arg(short, long, required = true, help = "The bundle file to write (i.e. -> images.tar)")]
+        output: String,
+    },
+    /// Adds the images in a bundle made with `autovirt image export`.
+    ImportBundle {
+        #[arg(required = true, help = "Path of the bundle")]
+        path: String,
+    },
+    /// Shows everything known about a base image.
+    Info {
+        #[arg(required=true, help = "Name of the image")]
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum CloudInitCommands {
+    /// Prints the exact user-data payload a VM will receive.
+    Render {
+        /// The name of the VM to render the user-data of
+        #[arg(required=true, help = "Name of the VM")]
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum SnapshotCommands {
+    /// Snapshots a VM (includes the memory state if the VM is running).
+    Create {
+        #[arg(required=true, help = "Name of the VM")]
+        name: String,
+
+        #[arg(help = "Name of the snapshot (defaults to snap-<date>-<time>)")]
+        snapshot: Option<String>,
+
+        #[arg(short, long, help = "Description of the snapshot")]
+        description: Option<String>,
+    },
+    /// Lists the snapshots of a VM.
+    List {
+        #[arg(required=true, help = "Name of the VM")]
+        name: String,
+    },
+    /// Reverts a VM to a snapshot.
+    Revert {
+        #[arg(required=true, help = "Name of the VM")]
+        name: String,
+
+        #[arg(required=true, help = "Name of the snapshot")]
+        snapshot: String,
+    },
+    /// Deletes a snapshot of a VM.
+    Delete {
+        #[arg(required=true, help = "Name of the VM")]
+        name: String,
+
+        #[arg(required=true, help = "Name of the snapshot")]
+        snapshot: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum DiskCommands {
+    /// Adds a new data disk to a VM (attached every time the VM is run).
+    Add {
+        #[arg(required=true, help = "Name of the VM")]
+        name: String,
+
+        #[arg(long, required=true, help = "Size of the disk (i.e. -> 50G, 512M)")]
+        size: String,
+
+        #[arg(long = "disk-name", help = "Name of the disk (defaults to data<N>)")]
+        disk_name: Option<String>,
+
+        #[arg(long, value_parser = disk::DISK_FORMATS, default_value = "qcow2", help = "Format of the disk image")]
+        format: String,
+
+        #[arg(long, value_parser = disk::DISK_BUSES, default_value = "virtio", help = "Bus to attach the disk to")]
+        bus: String,
+
+        #[arg(long, help = "Partition, format and mount the disk here on first boot (cloud-init)")]
+        mount: Option<String>,
+
+        #[arg(long, default_value = "ext4", help = "Filesystem to format the disk with (with --mount)")]
+        filesystem: String,
+    },
+    /// Lists the disks of a VM.
+    List {
+        #[arg(required=true, help = "Name of the VM")]
+        name: String,
+    },
+    /// Detaches a data disk from a (stopped) VM without deleting it.
+    Detach {
+        #[arg(required=true, help = "Name of the VM")]
+        name: String,
+
+        #[arg(required=true, help = "Name of the disk")]
+        disk_name: String,
+    },
+    /// Attaches a detached data disk to a (stopped) VM again.
+    Attach {
+        #[arg(required=true, help = "Name of the VM")]
+        name: String,
+
+        #[arg(required=true, help = "Name of the disk")]
+        disk_name: String,
+    },
+    /// Changes the disk IO settings of a VM (used the next time it starts).
+    Set {
+        #[arg(required=true, help = "Name of the VM")]
+        name: String,
+
+        #[arg(long, value_parser = blockdev::MAIN_DISK_BUSES, help = "Bus for the main disk")]
+        bus: Option<String>,
+
+        #[arg(long, value_parser = blockdev::CACHE_MODES, help = "Cache mode for the disks")]
+        cache: Option<String>,
+
+        #[arg(long, value_parser = blockdev::AIO_BACKENDS, help = "Aio backend for the disks")]
+        aio: Option<String>,
+
+        #[arg(long, value_parser = ["on", "off"], help = "Pass discard/TRIM through to the disk images")]
+        discard: Option<String>,
+
+        #[arg(long, help = "IO operations per second limit for each disk (0 for no limit)")]
+        iops: Option<u64>,
+
+        #[arg(long, help = "Bytes per second limit for each disk (0 for no limit)")]
+        bps: Option<u64>,
+    },
+    /// Deletes a data disk of a VM.
+    Remove {
+        #[arg(required=true, help = "Name of the VM")]
+        name: String,
+
+        #[arg(required=true, help = "Name of the disk")]
+        disk_name: String,
+    },
+    /// Converts a (stopped) VM's disk to another format.
+    Convert {
+        #[arg(required=true, help = "Name of the VM")]
+        name: String,
+
+        #[arg(long, required=true, value_parser = disk::DISK_FORMATS, help = "The format to convert the disk to")]
+        to: String,
+
+        #[arg(long, help = "Compress the disk (qcow2 only)")]
+        compress: bool,
+    },
+    /// Shows the virtual and allocated sizes of VM disks (and base images).
+    Usage {
+        #[arg(help = "Names of the VMs (all VMs and the base images if none are given)")]
+        names: Vec<String>,
+    },
+    /// Gets unused space back from a (stopped) VM's disk.
+    Compact {
+        #[arg(required=true, help = "Name of the VM")]
+        name: String,
+
+        #[arg(long = "disk", help = "Name of a data disk to compact instead of the main disk")]
+        disk_name: Option<String>,
+    },
+    /// Shrinks a (stopped) VM's disk if its partitions and filesystems fit in the new size.
+    Shrink {
+        #[arg(required=true, help = "Name of the VM")]
+        name: String,
+
+        #[arg(long, required=true, help = "The new size of the disk (i.e. -> 20G)")]
+        size: String,
+
+        #[arg(long = "disk", help = "Name of a data disk to shrink instead of the main disk")]
+        disk_name: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ShareCommands {
+    /// Lists the shares of a VM.
+    List {
+        #[arg(required=true, help = "Name of the VM")]
+        name: String,
+    },
+    /// Removes a share from a VM (by tag).
+    Remove {
+        #[arg(required=true, help = "Name of the VM")]
+        name: String,
+
+        #[arg(required=true, help = "Tag of the share")]
+        tag: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum TagCommands {
+    /// Adds tags to the VM (replacing the value of existing keys).
+    Add {
+        #[arg(required=true, help = "Tags as key=value")]
+        tags: Vec<String>,
+    },
+    /// Removes tags from the VM.
+    Remove {
+        #[arg(required=true, help = "Keys of the tags to remove")]
+        keys: Vec<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum GroupCommands {
+    /// Adds the VM to groups.
+    Add {
+        #[arg(required=true, help = "Names of the groups")]
+        groups: Vec<String>,
+    },
+    /// Removes the VM from groups.
+    Remove {
+        #[arg(required=true, help = "Names of the groups")]
+        groups: Vec<String>,
+    },
+}
+
+/// Gets the VMs for a bulk command (names or selector) exiting if that fails.
+fn resolve_vm_names_or_exit(names: &[String], selector: Option<&String>) -> Vec<String> {
+    let vm_names = selector::resolve_vm_names(names, selector).unwrap_or_else(|e| {
+        eprintln!("ERROR: {}", e);
+        std::process::exit(1);
+    });
+    if vm_names.is_empty() {
+        println!("INFO:: No VMs match the selector");
+    }
+    vm_names
+}
+
+/// Gets the provisioner args of a create/run command in the order they were
+/// given (clap keeps every kind of them in its own list).
+///
+/// ---
+fn provision_options(matches: &clap::ArgMatches) -> provision::ProvisionOptions {
+    let mut args = Vec::new();
+    if let Some((_, matches)) = matches.subcommand() {
+        for id in ["provision_shell", "provision_scripts", "provision_files"] {
+            if let (Some(indices), Some(values)) = (matches.indices_of(id), matches.get_many::<String>(id)) {
+                args.extend(indices.zip(values).map(|(index, value)| {
+                    let arg = match id {
+                        "provision_shell" => provision::ProvisionArg::Shell(value.clone()),
+                        "provision_scripts" => provision::ProvisionArg::Script(value.clone()),
+                        _ => provision::ProvisionArg::File(value.clone()),
+                    };
+                    (index, arg)
+                }));
+            }
+        }
+    }
+    args.sort_by_key(|(index, _)| *index);
+    provision::ProvisionOptions { args: args.into_iter().map(|(_, arg)| arg).collect() }
+}
+
+/// Makes the wait options for `--wait` on create/run.
+fn wait_options(wait: &Option<String>, timeout_secs: u64) -> Option<wait::WaitOptions> {
+    wait.as_ref().map(|condition| wait::WaitOptions {
+        conditions: vec![condition.clone()],
+        guest_port: 22,
+        timeout: std::time::Duration::from_secs(timeout_secs),
+    })
 }
 
 #[tokio::main]
 async fn main() {
-    let cli_arguments = Cli::parse();
+    let matches = Cli::command().get_matches();
+    let cli_arguments = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
 
     // Setting imds http server params (used for cloud-init/vm config files).
     // This includes user data, metadata and other shit.
@@ -176,9 +889,6 @@ async fn main() {
     let _imds_listen_port = "8000";
     let _imds_data_dir = "./lib/src/conf/";
 
-    // Init the distro image list
-    download::init_available_images();
-
     // File server is run in the create command section.
 
     match &cli_arguments.command {
@@ -188,7 +898,8 @@ async fn main() {
             println!("WARNING:: THE EXISTING DATA DIRECTORY AND WILL CAUSE DATA LOSS.");
             println!("Proceed with installation? [yes/No] ");
             let mut proceed_prompt = String::new();
-            if { std::io::stdin().read_line(&mut proceed_prompt).unwrap(); proceed_prompt.trim().eq_ignore_ascii_case("yes") } {
+            std::io::stdin().read_line(&mut proceed_prompt).unwrap();
+            if proceed_prompt.trim().eq_ignore_ascii_case("yes") {
                 println!("INFO:: installing autovirt...");
                 println!("INFO:: Creating data directories for autovirt...");
                 match filesystem::create_autovirt_data_dir() {
@@ -212,7 +923,8 @@ async fn main() {
             println!("WARNING:: and other important data.\n");
             println!("Proceed with initialisation? [yes/No] ");
             let mut proceed_prompt = String::new();
-            if { std::io::stdin().read_line(&mut proceed_prompt).unwrap(); proceed_prompt.trim().eq_ignore_ascii_case("yes") } {
+            std::io::stdin().read_line(&mut proceed_prompt).unwrap();
+            if proceed_prompt.trim().eq_ignore_ascii_case("yes") {
                 println!("INFO:: Initialising autovirt...");
                 println!("INFO:: Creating config file for autovirt...");
                 match filesystem::insert_autovirt_config_data() {
@@ -229,9 +941,15 @@ async fn main() {
         VMCommands::Info { name, raw } => {
             vmutils::get_vm_info_by_name(name, *raw);
         }
-        VMCommands::List { } => {
+        VMCommands::List { selector } => {
+            let selector = selector.as_ref().map(|expression| {
+                selector::Selector::parse(expression).unwrap_or_else(|e| {
+                    eprintln!("ERROR: {}", e);
+                    std::process::exit(1);
+                })
+            });
             println!("\n------ All Installed VMs ------\n");
-            vmutils::list_vms();
+            vmutils::list_vms(selector.as_ref());
         }
         VMCommands::Create {
             name,
@@ -241,8 +959,37 @@ async fn main() {
             pass,
             mem,
             cpus,
+            max_cpus,
+            max_memory_mb,
             key,
             ports,
+            packages,
+            runcmd,
+            write_files,
+            mounts,
+            timezone,
+            hostname,
+            user_data_file,
+            scripts,
+            boothooks,
+            part_handlers,
+            include_urls,
+            backend,
+            datasource,
+            tags,
+            groups,
+            data_disks,
+            disk_bus,
+            disk_cache,
+            disk_aio,
+            no_discard,
+            disk_iops,
+            disk_bps,
+            shares,
+            wait,
+            wait_timeout,
+            // the provisioner args are read in order from the matches
+            ..
         } => {
             // File server is currently run with hardcoded values since the
             // compiler keeps yapping.
@@ -254,39 +1001,129 @@ async fn main() {
                 imds::start_idms_server();
             });
 
+            let user_data_options = cloudinit::UserDataOptions {
+                packages: packages.clone(),
+                runcmd: runcmd.clone(),
+                write_files: write_files.clone(),
+                mounts: mounts.clone(),
+                timezone: timezone.clone(),
+                hostname: hostname.clone(),
+                user_data_file: user_data_file.clone(),
+                scripts: scripts.clone(),
+                boothooks: boothooks.clone(),
+                part_handlers: part_handlers.clone(),
+                include_urls: include_urls.clone(),
+                // some images can only use one datasource (see the catalog)
+                // microVMs can't reach the imds server (no user networking)
+                datasource: datasource
+                    .clone()
+                    .or_else(|| catalog::find_image(dist).and_then(|image| image.datasource))
+                    .or_else(|| (backend != "qemu").then(|| "seed".to_string()))
+                    .unwrap_or_else(|| "imds".to_string()),
+            };
+
+            let provision_options = provision_options(&matches);
+
+            let disk_io = blockdev::DiskIoOptions {
+                bus: disk_bus.clone(),
+                cache: disk_cache.clone(),
+                aio: disk_aio.clone(),
+                discard: !no_discard,
+                iops: *disk_iops,
+                bps: *disk_bps,
+            };
+
             // imds::run_file_server(imds_addr, imds_data_dir).await;
-            create::create_new_vm(name, dist, size, user, pass, mem, cpus, key, ports);
+            create::create_new_vm(
+                name, dist, size, user, pass, mem, cpus, key, ports, &user_data_options, &provision_options, tags, groups,
+                data_disks, &disk_io, shares, *max_cpus, *max_memory_mb, backend, wait_options(wait, *wait_timeout).as_ref(),
+            );
             // exit everythnig
             std::process::exit(0);
         }
-        VMCommands::Run { name, ports } => {
-            run::run_vm(name, ports);
+        VMCommands::Run {
+            name,
+            ports,
+            provision,
+            detach,
+            shares,
+            wait,
+            wait_timeout,
+            dry_run,
+            // the provisioner args are read in order from the matches
+            ..
+        } => {
+            let provision_options = provision_options(&matches);
+            let wait_options = wait_options(wait, *wait_timeout);
+            run::run_vm(name, ports, &provision_options, *provision, *detach, shares, wait_options.as_ref(), *dry_run);
         }
-        VMCommands::Download { dist } =>  {
-            let distro_link = filesystem::get_value_from_autovirt_json(&format!("images.{}.link", dist));
-            match distro_link {
-                Some(link) => println!("Fetched download link for {}: -> {}", dist, link.as_str().unwrap_or("Invalid link")),
-                None => println!("ERROR: Could not find a download link for istro -> {}", dist),
+        VMCommands::Wait { name, conditions, port, timeout } => {
+            let options = wait::WaitOptions {
+                conditions: conditions.clone(),
+                guest_port: *port,
+                timeout: std::time::Duration::from_secs(*timeout),
+            };
+            wait::wait_for_vm_or_exit(name, &options);
+        }
+        VMCommands::Start { names, selector } => {
+            run::start_vms(&resolve_vm_names_or_exit(names, selector.as_ref()));
+        }
+        VMCommands::Stop { names, selector, force, timeout } => {
+            let mut failed = false;
+            for vm_name in resolve_vm_names_or_exit(names, selector.as_ref()) {
+                if let Err(e) = run::stop_vm(&vm_name, *force, std::time::Duration::from_secs(*timeout)) {
+                    eprintln!("ERROR: Failed to stop VM {} -> {}", vm_name, e);
+                    failed = true;
+                }
+            }
+            if failed {
+                std::process::exit(1);
+            }
+        }
+        VMCommands::Tag { name, command } => {
+            let result = match command {
+                TagCommands::Add { tags } => vmutils::add_vm_tags(name, tags),
+                TagCommands::Remove { keys } => vmutils::remove_vm_tags(name, keys),
+            };
+            if let Err(e) = result {
+                eprintln!("ERROR: Failed to update tags -> {}", e);
+                std::process::exit(1);
+            }
+        }
+        VMCommands::Group { name, command } => {
+            let result = match command {
+                GroupCommands::Add { groups } => vmutils::add_vm_groups(name, groups),
+                GroupCommands::Remove { groups } => vmutils::remove_vm_groups(name, groups),
+            };
+            if let Err(e) = result {
+                eprintln!("ERROR: Failed to update groups -> {}", e);
+                std::process::exit(1);
+            }
+        }
+        VMCommands::Download { dists, all, jobs, insecure } =>  {
+            // the downloads run on the runtime's blocking threads (see
+            // downloadqueue.rs)
+            if let Err(e) = downloadqueue::download_images(dists, *all, *jobs, *insecure).await {
+                eprintln!("ERROR: Download failed -> {}", e);
+                std::process::exit(1);
             }
-
-            let _ = download::download_vm_image(dist);
-
-            // let _ = download::download_vm_image(&dist.to_string());
         },
         VMCommands::Show { available  } => {
             _ = available; // this is meant to be unused
             vmutils::show_available_images();
         }
-        VMCommands::Resize { name, disk, memory, cpus } => {
-            vmutils::resize_vm(name, disk, memory, cpus);
+        VMCommands::Resize { name, disk, memory, cpus, max_cpus, max_memory_mb } => {
+            vmutils::resize_vm(name, disk, memory, cpus, *max_cpus, *max_memory_mb);
         },
         VMCommands::Clone { name, new_name } => {
             vmutils::clone_vm(name, new_name);
         },
-        VMCommands::Delete { name } => {
-            println!("Deleting VM (name): {}", name);
-            vmutils::delete_vm(name);
-            print!("VM Deleted (or not idk bruh)");
+        VMCommands::Delete { names, selector } => {
+            let vm_names = resolve_vm_names_or_exit(names, selector.as_ref());
+            if !vm_names.is_empty() {
+                vmutils::delete_vms(&vm_names);
+                print!("VM Deleted (or not idk bruh)");
+            }
         },
         VMCommands::Checksum { file } => {
             vmutils::get_image_checksum(file);
@@ -294,6 +1131,215 @@ async fn main() {
         VMCommands::Health {  } => {
             println!("Checking autovirt data and config file for errors and checksums for vms...");
         }
+        VMCommands::Provision { name, timeout } => {
+            if provision::provision_vm(name, std::time::Duration::from_secs(*timeout)).is_err() {
+                std::process::exit(1);
+            }
+        }
+        VMCommands::SshConfig { names, install, uninstall } => {
+            let result = if *install {
+                inventory::install_managed_ssh_config()
+            } else if *uninstall {
+                inventory::uninstall_managed_ssh_config()
+            } else {
+                inventory::render_ssh_config(names).map(|ssh_config| print!("{}", ssh_config))
+            };
+
+            if let Err(e) = result {
+                eprintln!("ERROR: Failed to generate ssh config -> {}", e);
+                std::process::exit(1);
+            }
+        }
+        VMCommands::Inventory { list, host, static_inventory } => {
+            _ = list; // listing everything is what happens by default
+            let inventory = match (host, static_inventory) {
+                (Some(host), _) => inventory::render_dynamic_inventory_host(host),
+                (None, true) => inventory::render_static_inventory(),
+                (None, false) => inventory::render_dynamic_inventory(),
+            };
+            println!("{}", serde_json::to_string_pretty(&inventory).expect("ERROR: Failed to jsonify inventory"));
+        }
+        VMCommands::Console { name } => {
+            if !run::is_vm_running(name) {
+                eprintln!("ERROR: VM '{}' is not running", name);
+                std::process::exit(1);
+            }
+            if let Err(e) = backend::for_vm(name).console(name) {
+                eprintln!("ERROR: {}", e);
+                std::process::exit(1);
+            }
+        }
+        VMCommands::Logs { name, follow, since } => {
+            if let Err(e) = console::show_logs(name, *follow, since.as_deref()) {
+                eprintln!("ERROR: Failed to show the console log -> {}", e);
+                std::process::exit(1);
+            }
+        }
+        VMCommands::SerialLogger { name } => {
+            if let Err(e) = console::run_serial_logger(name) {
+                eprintln!("ERROR: Serial logger failed -> {}", e);
+                std::process::exit(1);
+            }
+        }
+        VMCommands::Ip { name } => {
+            if let Err(e) = guestagent::show_vm_ips(name) {
+                eprintln!("ERROR: Failed to get the VM's IPs -> {}", e);
+                std::process::exit(1);
+            }
+        }
+        VMCommands::GuestExec { name, timeout, command } => {
+            match guestagent::exec(name, command, std::time::Duration::from_secs(*timeout)) {
+                Ok(code) => std::process::exit(code),
+                Err(e) => {
+                    eprintln!("ERROR: guest-exec failed -> {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        VMCommands::SetPassword { name, user, password } => {
+            let password = password.clone().unwrap_or_else(|| {
+                println!("New password for {}:", user);
+                let mut user_input = String::new();
+                std::io::stdin().read_line(&mut user_input).expect("Failed to read user input");
+                user_input.trim_end_matches(['\r', '\n']).to_string()
+            });
+            if let Err(e) = guestagent::set_user_password(name, user, &password) {
+                eprintln!("ERROR: Failed to set the password -> {}", e);
+                std::process::exit(1);
+            }
+        }
+        VMCommands::Snapshot { command } => {
+            let result = match command {
+                SnapshotCommands::Create { name, snapshot, description } => {
+                    snapshot::create_snapshot(name, snapshot.as_deref(), description.as_deref()).map(|_| ())
+                }
+                SnapshotCommands::List { name } => snapshot::list_snapshots(name),
+                SnapshotCommands::Revert { name, snapshot } => snapshot::revert_snapshot(name, snapshot),
+                SnapshotCommands::Delete { name, snapshot } => snapshot::delete_snapshot(name, snapshot),
+            };
+            if let Err(e) = result {
+                eprintln!("ERROR: Snapshot command failed -> {}", e);
+                std::process::exit(1);
+            }
+        }
+        VMCommands::Disk { command } => {
+            let result = match command {
+                DiskCommands::Add { name, size, disk_name, format, bus, mount, filesystem } => {
+                    let options = disk::DataDiskOptions {
+                        name: disk_name.clone(),
+                        size: size.clone(),
+                        format: format.clone(),
+                        bus: bus.clone(),
+                        mountpoint: mount.clone(),
+                        filesystem: filesystem.clone(),
+                    };
+                    disk::add_data_disk(name, &options, true).map(|_| ())
+                }
+                DiskCommands::List { name } => disk::list_vm_disks(name),
+                DiskCommands::Detach { name, disk_name } => disk::set_data_disk_attached(name, disk_name, false),
+                DiskCommands::Attach { name, disk_name } => disk::set_data_disk_attached(name, disk_name, true),
+                DiskCommands::Remove { name, disk_name } => disk::remove_data_disk(name, disk_name),
+                DiskCommands::Set { name, bus, cache, aio, discard, iops, bps } => {
+                    let mut options = blockdev::load_disk_io_options(name);
+                    options.bus = bus.clone().unwrap_or(options.bus);
+                    options.cache = cache.clone().unwrap_or(options.cache);
+                    options.aio = aio.clone().unwrap_or(options.aio);
+                    options.discard = discard.as_ref().map(|d| d == "on").unwrap_or(options.discard);
+                    options.iops = iops.map(|iops| Some(iops).filter(|&i| i > 0)).unwrap_or(options.iops);
+                    options.bps = bps.map(|bps| Some(bps).filter(|&b| b > 0)).unwrap_or(options.bps);
+                    blockdev::set_disk_io_options(name, &options)
+                }
+                DiskCommands::Convert { name, to, compress } => disk::convert_vm_disk(name, to, *compress),
+                DiskCommands::Usage { names } => diskspace::show_disk_usage(names),
+                DiskCommands::Compact { name, disk_name } => diskspace::compact_vm_disk(name, disk_name.as_deref()),
+                DiskCommands::Shrink { name, size, disk_name } => {
+                    diskspace::shrink_vm_disk(name, disk_name.as_deref(), size)
+                }
+            };
+            if let Err(e) = result {
+                eprintln!("ERROR: Disk command failed -> {}", e);
+                std::process::exit(1);
+            }
+        }
+        VMCommands::Image { command } => {
+            let result = match command {
+                ImageCommands::Import { path, name, format, os_family, copy } => {
+                    images::import_image(path, name, format.as_deref(), os_family, *copy)
+                }
+                ImageCommands::Remove { name } => images::remove_image(name),
+                ImageCommands::Update { names, insecure } => tokio::task::block_in_place(|| images::update_images(names, *insecure)),
+                ImageCommands::List => images::list_images(),
+                ImageCommands::Prune { max_size, dry_run } => images::prune_images(max_size.as_deref(), *dry_run),
+                ImageCommands::Export { names, all, output } => bundle::export_images(names, *all, output),
+                ImageCommands::ImportBundle { path } => bundle::import_bundle(path),
+                ImageCommands::Info { name } => images::show_image_info(name),
+            };
+            if let Err(e) = result {
+                eprintln!("ERROR: Image command failed -> {}", e);
+                std::process::exit(1);
+            }
+        }
+        VMCommands::Share { command } => {
+            let result = match command {
+                ShareCommands::List { name } => share::list_shares(name),
+                ShareCommands::Remove { name, tag } => share::remove_share(name, tag),
+            };
+            if let Err(e) = result {
+                eprintln!("ERROR: Share command failed -> {}", e);
+                std::process::exit(1);
+            }
+        }
+        VMCommands::CloudInit { command } => match command {
+            CloudInitCommands::Render { name } => match cloudinit::render_vm_user_data(name) {
+                Ok(user_data) => print!("{}", user_data),
+                Err(e) => {
+                    eprintln!("ERROR: Failed to render user-data for VM '{}' -> {}", name, e);
+                    std::process::exit(1);
+                }
+            },
+        },
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cli_flags_dont_clash() {
+        // clap only finds two args with the same short flag when it's run
+        Cli::command().debug_assert();
+
+        // -p is --ports for both create and run
+        let create = [
+            "create", "-n", "vm", "-d", "debian12", "-s", "10", "-u", "u", "-P", "pw", "-m", "512", "-c", "1", "-k", "none",
+        ];
+        for args in [&create[..], &["run", "vm"]] {
+            let matches = Cli::command()
+                .try_get_matches_from(["autovirt"].iter().chain(args).chain(&["-p", "hostfwd=tcp::2244-:22"]))
+                .unwrap();
+            let (command, sub_matches) = matches.subcommand().unwrap();
+            assert_eq!(sub_matches.get_one::<String>("ports").unwrap(), "hostfwd=tcp::2244-:22", "{}", command);
+        }
+    }
+
+    #[test]
+    fn provisioners_keep_the_cli_order_and_drop_duplicates() {
+        let matches = Cli::command()
+            .try_get_matches_from([
+                "autovirt", "run", "vm", "--provision-shell", "echo 1", "--provision-file", "/tmp:/srv",
+                "--provision-shell", "echo 2", "--provision-shell", "echo 1",
+            ])
+            .unwrap();
+        let provisioners = provision::build_provisioners(&provision_options(&matches)).unwrap();
+        assert_eq!(
+            provisioners,
+            vec![
+                provision::Provisioner::Shell { inline: "echo 1".to_string() },
+                provision::Provisioner::File { source: "/tmp".to_string(), destination: "/srv".to_string() },
+                provision::Provisioner::Shell { inline: "echo 2".to_string() },
+            ]
+        );
+    }
+}
@@ -0,0 +1,302 @@
+//! This file contains things related to provisioning VMs over ssh once they
+//! are up (after cloud-init has done its thing).
+//!
+//! Provisioners are stored in the VM's entry in the autovirt.json config file
+//! so they can be run again with `autovirt provision <vm>`. They are run in the
+//! order they were given on the command line, provisioners given to `run` go
+//! after the stored ones (unless the same one is stored already).
+//!
+//! The output of the provisioners is shown in the terminal and appended to
+//! `provision.log` in the VM's data directory.
+//!
+//! ---
+
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::filesystem;
+use crate::ssh;
+
+/// How long to wait for the VM to be reachable over ssh before giving up.
+pub const SSH_WAIT_TIMEOUT_SECS: u64 = 600;
+
+/// A single provisioning step.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Provisioner {
+    /// An inline shell command (run with `sh -s` in the VM).
+    Shell { inline: String },
+    /// A local script file which is piped to `sh -s` in the VM.
+    Script { path: String },
+    /// A local file/directory uploaded to the VM with scp.
+    File { source: String, destination: String },
+}
+
+/// A provisioner arg from the cli.
+#[derive(Debug, Clone)]
+pub enum ProvisionArg {
+    /// `--provision-shell`
+    Shell(String),
+    /// `--provision-script`
+    Script(String),
+    /// `--provision-file` (src:dst)
+    File(String),
+}
+
+/// Provisioner args from the cli (create/run commands) in the order they were
+/// given.
+///
+/// ---
+#[derive(Debug, Default, Clone)]
+pub struct ProvisionOptions {
+    pub args: Vec<ProvisionArg>,
+}
+
+impl ProvisionOptions {
+    pub fn is_empty(&self) -> bool {
+        self.args.is_empty()
+    }
+}
+
+/// Builds the provisioners from the cli args making sure the local files are
+/// there. Local paths are stored as absolute paths so the provisioners still
+/// work when `autovirt provision` is run from somewhere else.
+///
+/// The same provisioner given twice is only kept once.
+///
+/// ---
+pub fn build_provisioners(options: &ProvisionOptions) -> Result<Vec<Provisioner>, Box<dyn Error>> {
+    let mut provisioners = Vec::new();
+
+    for arg in &options.args {
+        let provisioner = match arg {
+            ProvisionArg::Shell(inline) => Provisioner::Shell { inline: inline.clone() },
+            ProvisionArg::Script(path) => Provisioner::Script { path: absolute_path(path)? },
+            ProvisionArg::File(spec) => {
+                let (source, destination) = match spec.split_once(':') {
+                    Some((source, destination)) if !source.is_empty() && !destination.is_empty() => {
+                        (source, destination)
+                    }
+                    _ => {
+                        return Err(Box::new(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("Invalid --provision-file '{}' (expected src:dst)", spec),
+                        )));
+                    }
+                };
+                Provisioner::File { source: absolute_path(source)?, destination: destination.to_string() }
+            }
+        };
+        if !provisioners.contains(&provisioner) {
+            provisioners.push(provisioner);
+        }
+    }
+
+    Ok(provisioners)
+}
+
+fn absolute_path(path: &str) -> Result<String, Box<dyn Error>> {
+    let path = fs::canonicalize(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to find '{}' -> {}", path, e)))?;
+    Ok(path.to_string_lossy().to_string())
+}
+
+/// Gets the provisioners stored for a VM.
+pub fn load_provisioners(vm_name: &str) -> Vec<Provisioner> {
+    filesystem::get_value_from_autovirt_json(&format!("vms.{}.provisioners", vm_name))
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default()
+}
+
+/// Adds provisioners to the end of the ones stored for a VM (skipping the
+/// ones it already has).
+///
+/// ---
+pub fn add_provisioners(vm_name: &str, provisioners: &[Provisioner]) {
+    let mut all_provisioners = load_provisioners(vm_name);
+    for provisioner in provisioners {
+        if !all_provisioners.contains(provisioner) {
+            all_provisioners.push(provisioner.clone());
+        }
+    }
+
+    filesystem::insert_value_into_autovirt_json_object(
+        &format!("vms.{}.provisioners", vm_name),
+        serde_json::to_value(all_provisioners).expect("ERROR: Failed to jsonify provisioners"),
+    );
+}
+
+/// Sets the status of a VM in the autovirt config file.
+fn set_vm_status(vm_name: &str, status: &str) {
+    filesystem::insert_value_into_autovirt_json_object(
+        &format!("vms.{}.status", vm_name),
+        serde_json::Value::String(status.to_string()),
+    );
+}
+
+/// Runs all the provisioners for a VM once it can be reached over ssh.
+///
+/// The VM's status is set to `provisioned` if everything worked and
+/// `provision_failed` (along with the error in `provision_error`) if not.
+///
+/// ---
+pub fn provision_vm(vm_name: &str, ssh_timeout: Duration) -> Result<(), Box<dyn Error>> {
+    let provisioners = load_provisioners(vm_name);
+    if provisioners.is_empty() {
+        println!("INFO:: No provisioners for VM -> {}", vm_name);
+        return Ok(());
+    }
+
+    let log_path = filesystem::get_vm_data_dir(vm_name)?.join("provision.log");
+    let log_file = Arc::new(Mutex::new(OpenOptions::new().create(true).append(true).open(&log_path)?));
+
+    let started_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    writeln!(log_file.lock().unwrap(), "==== provisioning {} (unix time {}) ====", vm_name, started_at)?;
+
+    set_vm_status(vm_name, "provisioning");
+    let result = run_provisioners(vm_name, &provisioners, ssh_timeout, &log_file);
+
+    match &result {
+        Ok(()) => {
+            set_vm_status(vm_name, "provisioned");
+            filesystem::insert_value_into_autovirt_json_object(
+                &format!("vms.{}.provision_error", vm_name),
+                serde_json::Value::Null,
+            );
+            writeln!(log_file.lock().unwrap(), "==== provisioning finished ====")?;
+            println!("LOG:: VM provisioned successfully -> {}", vm_name);
+        }
+        Err(e) => {
+            set_vm_status(vm_name, "provision_failed");
+            filesystem::insert_value_into_autovirt_json_object(
+                &format!("vms.{}.provision_error", vm_name),
+                serde_json::Value::String(e.to_string()),
+            );
+            writeln!(log_file.lock().unwrap(), "==== provisioning failed: {} ====", e)?;
+            eprintln!("ERROR: Provisioning failed for VM {} -> {}", vm_name, e);
+            eprintln!("INFO:: See the provisioning log -> {:?}", log_path);
+        }
+    }
+
+    result
+}
+
+fn run_provisioners(
+    vm_name: &str,
+    provisioners: &[Provisioner],
+    ssh_timeout: Duration,
+    log_file: &Arc<Mutex<File>>,
+) -> Result<(), Box<dyn Error>> {
+    let target = ssh::ssh_target_for_vm(vm_name)?;
+    ssh::check_batch_login(vm_name)?;
+
+    println!(
+        "LOG:: Waiting for VM {} to be reachable over ssh ({}@{}:{})...",
+        vm_name, target.user, target.host, target.port
+    );
+    ssh::wait_for_ssh(&target, ssh_timeout)?;
+
+    for (index, provisioner) in provisioners.iter().enumerate() {
+        let step = format!("{}/{}", index + 1, provisioners.len());
+
+        // shell commands and scripts are fed to `sh -s` in the VM so nothing
+        // has to be quoted
+        let (description, mut cmd, script) = match provisioner {
+            Provisioner::Shell { inline } => {
+                let mut cmd = ssh::ssh_command(&target);
+                cmd.arg("sh -s");
+                (format!("shell: {}", inline), cmd, Some(format!("{}\n", inline).into_bytes()))
+            }
+            Provisioner::Script { path } => {
+                let mut cmd = ssh::ssh_command(&target);
+                cmd.arg("sh -s");
+                let script = fs::read(Path::new(path))
+                    .map_err(|e| io::Error::new(e.kind(), format!("Failed to read '{}' -> {}", path, e)))?;
+                (format!("script: {}", path), cmd, Some(script))
+            }
+            Provisioner::File { source, destination } => {
+                let cmd = ssh::scp_upload_command(&target, source, destination);
+                (format!("file: {} -> {}", source, destination), cmd, None)
+            }
+        };
+
+        println!("LOG:: [{}] Running provisioner ({})", step, description);
+        writeln!(log_file.lock().unwrap(), "---- [{}] {} ----", step, description)?;
+
+        cmd.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped());
+        let mut child = cmd.spawn()?;
+
+        let mut stdin = child.stdin.take().expect("ERROR: Failed to open provisioner stdin");
+        if let Some(script) = script {
+            stdin.write_all(&script)?;
+        }
+        drop(stdin);
+
+        let stdout_thread = stream_output(child.stdout.take(), vm_name, Arc::clone(log_file));
+        let stderr_thread = stream_output(child.stderr.take(), vm_name, Arc::clone(log_file));
+
+        let status = child.wait()?;
+        let _ = stdout_thread.join();
+        let _ = stderr_thread.join();
+
+        if !status.success() {
+            return Err(Box::new(io::Error::other(format!(
+                "Provisioner {} ({}) failed with {}",
+                step, description, status
+            ))));
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies the output of a provisioner to the terminal and the log file line by
+/// line as it comes in.
+///
+/// ---
+fn stream_output<R: Read + Send + 'static>(
+    output: Option<R>,
+    vm_name: &str,
+    log_file: Arc<Mutex<File>>,
+) -> thread::JoinHandle<()> {
+    let prefix = format!("[{}]", vm_name);
+    thread::spawn(move || {
+        let output = match output {
+            Some(output) => output,
+            None => return,
+        };
+
+        for line in BufReader::new(output).lines().map_while(Result::ok) {
+            eprintln!("{} {}", prefix, line);
+            let _ = writeln!(log_file.lock().unwrap(), "{}", line);
+        }
+    })
+}
+
+/// Starts provisioning a VM in the background (used by create/run where the
+/// VM itself is running in the foreground).
+///
+/// ---
+pub fn spawn_provisioning(vm_name: &str) -> thread::JoinHandle<()> {
+    let vm_name = vm_name.to_string();
+    thread::spawn(move || {
+        let _ = provision_vm(&vm_name, Duration::from_secs(SSH_WAIT_TIMEOUT_SECS));
+    })
+}
+
+/// Makes a provisioner list from the cli args and stores it for the VM (used
+/// by the run command).
+///
+/// ---
+pub fn add_provisioners_from_options(vm_name: &str, options: &ProvisionOptions) -> Result<(), Box<dyn Error>> {
+    let provisioners = build_provisioners(options)?;
+    add_provisioners(vm_name, &provisioners);
+    Ok(())
+}
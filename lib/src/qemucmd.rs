@@ -0,0 +1,488 @@
+//! This file contains the QEMU command builder, the one place a VM's
+//! `qemu-system-x86_64` invocation is put together (create and run both get
+//! it through the qemu backend, see backend.rs).
+//!
+//! The invocation is built from the VM's entry in autovirt.json in parts (the
+//! network, the machine, the console, the disks, the shares, the management
+//! sockets and where the cloud-init data comes from) which always go in the
+//! same order. Anything autovirt doesn't have an option for can be added with
+//! `qemu_extra_args` in the VM's entry, they go at the very end:
+//! - `"qemu_extra_args": ["-device", "virtio-rng-pci"]`
+//! - `"qemu_extra_args": "-device virtio-rng-pci"` (split like a shell would,
+//!   quotes and backslashes work)
+//!
+//! Building the invocation doesn't change anything on disk, what has to be
+//! done before qemu runs (starting virtiofsd etc.) is in `prepare` which the
+//! qemu backend calls when it starts the VM.
+//!
+//! `autovirt run --dry-run` prints the command shell quoted instead of
+//! running it.
+//!
+//! ---
+
+use serde_json::Value;
+use std::error::Error;
+use std::io;
+use std::path::PathBuf;
+use std::process::Command;
+
+use crate::backend::VmSpec;
+use crate::blockdev;
+use crate::console;
+use crate::filesystem;
+use crate::hotplug;
+use crate::run;
+use crate::share;
+
+/// Where the VM gets its cloud-init data from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CloudInitSource {
+    /// A seed image attached as a cdrom
+    Seed(PathBuf),
+    /// The imds server (its url goes in the smbios serial)
+    Imds(String),
+}
+
+/// Everything in a VM's qemu invocation, in the order it's passed.
+#[derive(Debug, Clone)]
+pub struct QemuInvocation {
+    pub binary: String,
+    /// The user networking port forwards (raw `hostfwd=...` str from the user)
+    pub port_forwards: String,
+    /// `-m`/`-smp` and the balloon (see hotplug.rs)
+    pub machine: Vec<String>,
+    /// The serial console socket (see console.rs)
+    pub serial: Vec<String>,
+    /// The VM disk and its data disks (see blockdev.rs)
+    pub disks: Vec<String>,
+    /// The shared folders (see share.rs)
+    pub shares: Vec<String>,
+    /// The pid file, QMP and guest agent sockets (see run.rs)
+    pub runtime: Vec<String>,
+    pub cloud_init: CloudInitSource,
+    pub daemonize: bool,
+    /// `qemu_extra_args` from the VM's entry
+    pub extra_args: Vec<String>,
+}
+
+impl QemuInvocation {
+    /// Builds the invocation of a VM from its spec and its entry in the
+    /// autovirt config file.
+    ///
+    /// ---
+    pub fn from_spec(spec: &VmSpec, binary: String) -> Result<QemuInvocation, Box<dyn Error>> {
+        let vm_name = spec.name.as_str();
+        let disks = blockdev::vm_disk_args(vm_name)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid VM disk settings -> {}", e)))?;
+        let shares = share::share_args(vm_name, &spec.memory_mb)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid shared folders -> {}", e)))?;
+
+        let cloud_init = match &spec.seed_image {
+            Some(seed_path) => CloudInitSource::Seed(seed_path.clone()),
+            None => CloudInitSource::Imds(format!("http://10.0.2.2:8000/{}/", vm_name)),
+        };
+
+        Ok(QemuInvocation {
+            binary,
+            port_forwards: spec.port_forwards.clone(),
+            machine: hotplug::machine_args(vm_name),
+            serial: console::serial_args(vm_name)?,
+            disks,
+            shares,
+            runtime: run::runtime_args(vm_name)?,
+            cloud_init,
+            daemonize: spec.detach,
+            extra_args: extra_args(vm_name)?,
+        })
+    }
+
+    /// The args qemu is run with (without the binary).
+    pub fn args(&self) -> Vec<String> {
+        let mut args = vec![
+            "-net".to_string(),
+            "nic".to_string(),
+            "-net".to_string(),
+            format!("user,{}", self.port_forwards),
+            "-machine".to_string(),
+            "accel=kvm:tcg".to_string(),
+        ];
+        args.extend(self.machine.iter().cloned());
+        args.extend(self.serial.iter().cloned());
+        args.extend(self.disks.iter().cloned());
+        args.extend(self.shares.iter().cloned());
+        args.extend(self.runtime.iter().cloned());
+        // the console is the serial socket (see console.rs) either way
+        args.extend(["-display".to_string(), "none".to_string()]);
+        if self.daemonize {
+            args.push("-daemonize".to_string());
+        }
+        match &self.cloud_init {
+            CloudInitSource::Seed(seed_path) => args.extend(blockdev::seed_cdrom_args(seed_path)),
+            CloudInitSource::Imds(url) => {
+                args.push("-smbios".to_string());
+                args.push(format!("type=1,serial=ds=nocloud;s={}", url));
+            }
+        }
+        args.extend(self.extra_args.iter().cloned());
+        args
+    }
+
+    /// The command that runs qemu.
+    pub fn command(&self) -> Command {
+        let mut command = Command::new(&self.binary);
+        command.args(self.args());
+        command
+    }
+}
+
+/// Gets a VM ready for qemu to run: its data directory, the limits for
+/// resizing it live, no stale serial socket and virtiofsd for its shares.
+///
+/// ---
+pub fn prepare(vm_name: &str) -> Result<(), Box<dyn Error>> {
+    filesystem::get_vm_data_dir(vm_name)?;
+    hotplug::write_machine_limits(vm_name)
+        .map_err(|e| io::Error::other(format!("Failed to set up the VM CPUs/memory -> {}", e)))?;
+    console::remove_stale_serial_socket(vm_name)?;
+    share::start_share_daemons(vm_name)
+        .map_err(|e| io::Error::other(format!("Failed to set up shared folders -> {}", e)))?;
+    Ok(())
+}
+
+/// Gets the `qemu_extra_args` of a VM (a list of args or a string of them).
+pub fn extra_args(vm_name: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let invalid = || -> Box<dyn Error> {
+        Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("vms.{}.qemu_extra_args has to be a list of strings or a string", vm_name),
+        ))
+    };
+    match filesystem::get_value_from_autovirt_json(&format!("vms.{}.qemu_extra_args", vm_name)) {
+        None | Some(Value::Null) => Ok(Vec::new()),
+        Some(Value::String(args)) => shell_split(&args).map_err(|e| {
+            Box::new(io::Error::new(io::ErrorKind::InvalidInput, format!("vms.{}.qemu_extra_args -> {}", vm_name, e)))
+                as Box<dyn Error>
+        }),
+        Some(Value::Array(args)) => args
+            .iter()
+            .map(|arg| arg.as_str().map(String::from).ok_or_else(invalid))
+            .collect(),
+        Some(_) => Err(invalid()),
+    }
+}
+
+/// Splits a string into args like a POSIX shell would (without expanding
+/// anything): everything in single quotes is kept as is, in double quotes a
+/// backslash only escapes `"`, `\`, `$` and a backtick, and outside quotes
+/// it escapes any character.
+///
+/// ---
+pub fn shell_split(line: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut arg: Option<String> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => args.extend(arg.take()),
+            '\'' => {
+                let current = arg.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => current.push(c),
+                        None => return Err("unterminated single quote".to_string()),
+                    }
+                }
+            }
+            '"' => {
+                let current = arg.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) if "\"\\$`".contains(c) => current.push(c),
+                            Some(c) => {
+                                current.push('\\');
+                                current.push(c);
+                            }
+                            None => return Err("unterminated double quote".to_string()),
+                        },
+                        Some(c) => current.push(c),
+                        None => return Err("unterminated double quote".to_string()),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some(c) => arg.get_or_insert_with(String::new).push(c),
+                None => return Err("nothing after the trailing backslash".to_string()),
+            },
+            c => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    args.extend(arg);
+    Ok(args)
+}
+
+/// Quotes an arg for a POSIX shell (only if it has to be).
+pub fn shell_quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_=+:,./@%^".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        return arg.to_string();
+    }
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+/// The shell quoted command line of a command (what `--dry-run` prints).
+pub fn command_line(command: &Command) -> String {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| shell_quote(&arg.to_string_lossy()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::fs;
+
+    fn to_strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    /// Makes a VM entry with every option group in use and gives back its
+    /// spec and data directory path.
+    ///
+    /// ---
+    fn golden_vm(vm_name: &str, entry: Value) -> (VmSpec, String) {
+        let mut vm = json!({
+            "name": vm_name,
+            "memory_mb": "1024",
+            "cpus": "2",
+            "max_cpus": 4,
+            "max_memory_mb": 2048,
+            "image_path": "/images/golden,1.qcow2",
+            "disk_format": "qcow2",
+            "datasource": "imds",
+            "disk_io": { "bus": "virtio", "cache": "none", "aio": "native", "discard": true, "iops": 500 },
+            "data_disks": [
+                {
+                    "name": "logs", "path": "/images/logs.qcow2", "size": "5G", "format": "qcow2",
+                    "bus": "nvme", "serial": "avlogs", "attached": true,
+                },
+                {
+                    "name": "old", "path": "/images/old.raw", "size": "1G", "format": "raw",
+                    "bus": "virtio", "serial": "avold", "attached": false,
+                },
+            ],
+            "shares": [
+                { "host_path": "/srv/data", "tag": "data", "read_only": true, "guest_path": "/mnt/data", "transport": "9p" },
+                { "host_path": "/srv/code", "tag": "code", "read_only": false, "guest_path": "/mnt/code", "transport": "virtiofs" },
+            ],
+        });
+        if let (Some(vm), Some(entry)) = (vm.as_object_mut(), entry.as_object()) {
+            vm.extend(entry.clone());
+        }
+        filesystem::insert_value_into_autovirt_json_object(&format!("vms.{}", vm_name), vm);
+
+        let data_dir = filesystem::vm_data_dir_path(vm_name).unwrap();
+        let _ = fs::remove_dir_all(&data_dir);
+        let spec = VmSpec {
+            name: vm_name.to_string(),
+            memory_mb: "1024".to_string(),
+            cpus: "2".to_string(),
+            image_path: "/images/golden,1.qcow2".to_string(),
+            disk_format: "qcow2".to_string(),
+            port_forwards: "hostfwd=tcp::2222-:22".to_string(),
+            seed_image: None,
+            detach: false,
+        };
+        (spec, data_dir.to_string_lossy().to_string())
+    }
+
+    #[test]
+    fn option_groups() {
+        let _home = filesystem::test_home();
+        let (spec, dir) = golden_vm("golden", json!({}));
+        let invocation = QemuInvocation::from_spec(&spec, "qemu-system-x86_64".to_string()).unwrap();
+
+        assert_eq!(
+            invocation.machine,
+            to_strings(&[
+                "-m",
+                "size=1024M,slots=8,maxmem=2048M",
+                "-smp",
+                "cpus=2,maxcpus=4",
+                "-device",
+                "virtio-balloon-pci,id=balloon0,deflate-on-oom=on",
+            ])
+        );
+        assert_eq!(
+            invocation.serial,
+            [
+                "-chardev".to_string(),
+                format!("socket,id=serial0,path={}/serial.sock,server=on,wait=off", dir),
+                "-serial".to_string(),
+                "chardev:serial0".to_string(),
+            ]
+        );
+        assert_eq!(
+            invocation.disks,
+            to_strings(&[
+                "-blockdev",
+                "driver=file,node-name=main-file,filename=/images/golden,,1.qcow2,aio=native,cache.direct=on,cache.no-flush=off,discard=unmap",
+                "-blockdev",
+                "driver=qcow2,node-name=main-fmt,file=main-file,cache.direct=on,cache.no-flush=off,discard=unmap",
+                "-object",
+                "throttle-group,id=main-limits,limits.iops-total=500",
+                "-blockdev",
+                "driver=throttle,node-name=main-throttle,throttle-group=main-limits,file=main-fmt",
+                "-device",
+                "virtio-blk-pci,drive=main-throttle,id=disk-main,write-cache=on,bootindex=0",
+                "-blockdev",
+                "driver=file,node-name=data-logs-file,filename=/images/logs.qcow2,aio=native,cache.direct=on,cache.no-flush=off,discard=unmap",
+                "-blockdev",
+                "driver=qcow2,node-name=data-logs-fmt,file=data-logs-file,cache.direct=on,cache.no-flush=off,discard=unmap",
+                "-object",
+                "throttle-group,id=data-logs-limits,limits.iops-total=500",
+                "-blockdev",
+                "driver=throttle,node-name=data-logs-throttle,throttle-group=data-logs-limits,file=data-logs-fmt",
+                "-device",
+                "nvme,drive=data-logs-throttle,id=disk-logs,serial=avlogs",
+            ])
+        );
+        assert_eq!(
+            invocation.shares,
+            [
+                "-object".to_string(),
+                "memory-backend-memfd,id=mem,size=1024M,share=on".to_string(),
+                "-numa".to_string(),
+                "node,memdev=mem".to_string(),
+                "-virtfs".to_string(),
+                "local,path=/srv/data,mount_tag=data,security_model=mapped-xattr,id=fs-data,readonly=on".to_string(),
+                "-chardev".to_string(),
+                format!("socket,id=vfs-code,path={}/virtiofs-code.sock", dir),
+                "-device".to_string(),
+                "vhost-user-fs-pci,chardev=vfs-code,tag=code".to_string(),
+            ]
+        );
+        assert_eq!(
+            invocation.runtime,
+            [
+                "-pidfile".to_string(),
+                format!("{}/qemu.pid", dir),
+                "-qmp".to_string(),
+                format!("unix:{}/qmp.sock,server=on,wait=off", dir),
+                "-chardev".to_string(),
+                format!("socket,path={}/qga.sock,server=on,wait=off,id=qga0", dir),
+                "-device".to_string(),
+                "virtio-serial-pci,id=virtio-serial0".to_string(),
+                "-device".to_string(),
+                "virtserialport,chardev=qga0,name=org.qemu.guest_agent.0".to_string(),
+            ]
+        );
+
+        // the groups go in this order with the network first
+        let mut expected = to_strings(&["-net", "nic", "-net", "user,hostfwd=tcp::2222-:22", "-machine", "accel=kvm:tcg"]);
+        for group in [&invocation.machine, &invocation.serial, &invocation.disks, &invocation.shares, &invocation.runtime] {
+            expected.extend(group.iter().cloned());
+        }
+        expected.extend(to_strings(&["-display", "none", "-smbios", "type=1,serial=ds=nocloud;s=http://10.0.2.2:8000/golden/"]));
+        assert_eq!(invocation.args(), expected);
+        assert_eq!(invocation.command().get_program(), "qemu-system-x86_64");
+    }
+
+    #[test]
+    fn seed_image_and_daemonize() {
+        let _home = filesystem::test_home();
+        let (mut spec, _) = golden_vm("goldenseed", json!({ "shares": [], "data_disks": [] }));
+        spec.seed_image = Some(PathBuf::from("/vms/seed.iso"));
+        spec.detach = true;
+        let args = QemuInvocation::from_spec(&spec, "qemu".to_string()).unwrap().args();
+
+        let display = args.iter().position(|arg| arg == "-display").unwrap();
+        assert_eq!(
+            args[display..],
+            to_strings(&[
+                "-display",
+                "none",
+                "-daemonize",
+                "-blockdev",
+                "driver=file,node-name=seed-file,filename=/vms/seed.iso,read-only=on",
+                "-blockdev",
+                "driver=raw,node-name=seed-fmt,file=seed-file,read-only=on",
+                "-device",
+                "ide-cd,drive=seed-fmt,bus=ide.1,id=seed",
+            ])
+        );
+        assert!(!args.iter().any(|arg| arg == "-smbios"));
+    }
+
+    #[test]
+    fn extra_args_go_last_in_order() {
+        let _home = filesystem::test_home();
+        let extra = ["-device", "virtio-rng-pci", "-fw_cfg", "name=opt/x,string=a b", "-d", "guest_errors"];
+        let (spec, _) = golden_vm("goldenextra", json!({ "qemu_extra_args": extra }));
+        let args = QemuInvocation::from_spec(&spec, "qemu".to_string()).unwrap().args();
+        assert_eq!(args[args.len() - extra.len()..], to_strings(&extra));
+        assert!(args[..args.len() - extra.len()].last().unwrap().starts_with("type=1,serial=ds=nocloud"));
+
+        // the string form is split like a shell would
+        let (spec, _) = golden_vm(
+            "goldenextra",
+            json!({ "qemu_extra_args": r#"-device virtio-rng-pci  -fw_cfg 'name=opt/x,string=a b' -d "guest_errors""# }),
+        );
+        let args = QemuInvocation::from_spec(&spec, "qemu".to_string()).unwrap().args();
+        assert_eq!(args[args.len() - extra.len()..], to_strings(&extra));
+
+        for invalid in [json!("-device 'unterminated"), json!(["-m", 512]), json!({ "-m": "512" })] {
+            let (spec, _) = golden_vm("goldenextra", json!({ "qemu_extra_args": invalid }));
+            let error = QemuInvocation::from_spec(&spec, "qemu".to_string()).unwrap_err();
+            assert!(error.to_string().contains("qemu_extra_args"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn building_changes_nothing_on_disk() {
+        let _home = filesystem::test_home();
+        let (spec, dir) = golden_vm("goldenpure", json!({}));
+        QemuInvocation::from_spec(&spec, "qemu".to_string()).unwrap();
+        assert!(!PathBuf::from(&dir).exists(), "{} was created", dir);
+
+        // a VM that ran before keeps its files until it's started again
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(format!("{}/serial.sock", dir), "stale").unwrap();
+        QemuInvocation::from_spec(&spec, "qemu".to_string()).unwrap();
+        assert!(PathBuf::from(format!("{}/serial.sock", dir)).exists());
+        assert!(!PathBuf::from(format!("{}/machine.json", dir)).exists());
+    }
+
+    #[test]
+    fn shell_quote_only_quotes_when_needed() {
+        assert_eq!(shell_quote("-device"), "-device");
+        assert_eq!(shell_quote("user,hostfwd=tcp::2222-:22"), "user,hostfwd=tcp::2222-:22");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("type=1,serial=ds=nocloud;s=http://x/"), "'type=1,serial=ds=nocloud;s=http://x/'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote(r#"say "hi""#), r#"'say "hi"'"#);
+
+        let mut command = Command::new("qemu-system-x86_64");
+        command.args(["-name", "my vm", "-append", ""]);
+        assert_eq!(command_line(&command), "qemu-system-x86_64 -name 'my vm' -append ''");
+    }
+
+    #[test]
+    fn shell_split_undoes_shell_quote() {
+        assert_eq!(shell_split("  -m   512 ").unwrap(), to_strings(&["-m", "512"]));
+        assert_eq!(shell_split(r#"a\ b 'c d' "e \"f\" \g" '' h"i"j"#).unwrap(), to_strings(&["a b", "c d", r#"e "f" \g"#, "", "hij"]));
+        assert!(shell_split("'open").is_err());
+        assert!(shell_split(r#""open"#).is_err());
+        assert!(shell_split("trailing\\").is_err());
+
+        let args = to_strings(&["plain", "", "a b", "it's", r#"say "hi""#, "$HOME", "back\\slash"]);
+        let line = args.iter().map(|arg| shell_quote(arg)).collect::<Vec<_>>().join(" ");
+        assert_eq!(shell_split(&line).unwrap(), args);
+    }
+}
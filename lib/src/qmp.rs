@@ -0,0 +1,124 @@
+//! This file contains a (very) small client for QMP (the QEMU Machine
+//! Protocol) which is used to talk to running VMs.
+//!
+//! Every VM is started with a QMP socket at `qmp.sock` in its data directory
+//! (see `run::runtime_args`) so things like stopping VMs can be done from
+//! another autovirt command.
+//!
+//! ---
+
+use serde_json::{json, Value};
+use std::error::Error;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::filesystem;
+
+/// A connection to a VM's QMP socket.
+pub struct QmpClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl QmpClient {
+    /// Connects to a QMP socket and does the capabilities handshake.
+    pub fn connect(socket_path: &Path) -> Result<QmpClient, Box<dyn Error>> {
+        let stream = UnixStream::connect(socket_path)
+            .map_err(|e| io::Error::new(e.kind(), format!("Failed to connect to {:?} -> {}", socket_path, e)))?;
+        stream.set_read_timeout(Some(Duration::from_secs(60)))?;
+
+        let mut client = QmpClient {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        };
+
+        // the server sends a greeting first and then nothing can be done until
+        // capabilities negotiation is done
+        let greeting = client.read_message()?;
+        if greeting.get("QMP").is_none() {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unexpected QMP greeting -> {}", greeting),
+            )));
+        }
+        client.execute("qmp_capabilities", None)?;
+
+        Ok(client)
+    }
+
+    fn read_message(&mut self) -> Result<Value, Box<dyn Error>> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "QMP connection closed",
+            )));
+        }
+        Ok(serde_json::from_str(&line)?)
+    }
+
+    /// Runs a QMP command and returns whatever is in `return`. Events that
+    /// come in while waiting for the reply are skipped.
+    ///
+    /// ---
+    pub fn execute(&mut self, command: &str, arguments: Option<Value>) -> Result<Value, Box<dyn Error>> {
+        let mut request = json!({ "execute": command });
+        if let Some(arguments) = arguments {
+            request["arguments"] = arguments;
+        }
+        self.writer.write_all(format!("{}\n", request).as_bytes())?;
+
+        loop {
+            let message = self.read_message()?;
+            if let Some(ret) = message.get("return") {
+                return Ok(ret.clone());
+            }
+            if let Some(error) = message.get("error") {
+                let desc = error.get("desc").and_then(|d| d.as_str()).unwrap_or("unknown error");
+                return Err(Box::new(io::Error::other(format!("QMP {} failed -> {}", command, desc))));
+            }
+            // anything else is an event
+        }
+    }
+
+    /// Runs a QMP command that starts a background job (i.e. `snapshot-save`)
+    /// and waits for the job to finish.
+    ///
+    /// ---
+    pub fn execute_job(&mut self, command: &str, job_id: &str, mut arguments: Value) -> Result<(), Box<dyn Error>> {
+        arguments["job-id"] = Value::String(job_id.to_string());
+        self.execute(command, Some(arguments))?;
+
+        loop {
+            let jobs = self.execute("query-jobs", None)?;
+            let job = jobs
+                .as_array()
+                .and_then(|jobs| jobs.iter().find(|j| j.get("id").and_then(|id| id.as_str()) == Some(job_id)))
+                .cloned()
+                .ok_or_else(|| io::Error::other(format!("QMP job {} disappeared", job_id)))?;
+
+            if job.get("status").and_then(|s| s.as_str()) == Some("concluded") {
+                self.execute("job-dismiss", Some(json!({ "id": job_id })))?;
+                if let Some(error) = job.get("error").and_then(|e| e.as_str()) {
+                    return Err(Box::new(io::Error::other(format!("QMP {} failed -> {}", command, error))));
+                }
+                return Ok(());
+            }
+
+            std::thread::sleep(Duration::from_millis(200));
+        }
+    }
+
+}
+
+/// Gets the path of a VM's QMP socket.
+pub fn qmp_socket_path(vm_name: &str) -> io::Result<PathBuf> {
+    Ok(filesystem::get_vm_data_dir(vm_name)?.join("qmp.sock"))
+}
+
+/// Connects to a running VM's QMP socket.
+pub fn connect_vm(vm_name: &str) -> Result<QmpClient, Box<dyn Error>> {
+    QmpClient::connect(&qmp_socket_path(vm_name)?)
+}
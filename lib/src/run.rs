@@ -2,29 +2,165 @@
 //!
 //! Most things in here interact with the autovirt.json config file and perform
 //! actions based on that.
+//!
+//! Every VM is started with a pid file (and a QMP socket on qemu) in its data
+//! directory so that running VMs can be found and stopped from other autovirt
+//! commands, the hypervisor specific parts are in backend.rs.
 
+use crate::backend;
+use crate::cloudinit;
+use crate::disk;
 use crate::filesystem;
-use std::process::Command;
+use crate::guestagent;
+use crate::inventory;
+use crate::provision;
+use crate::qemucmd;
+use crate::share;
+use crate::wait;
+use std::error::Error;
+use std::io;
 use std::thread;
 use std::time;
 
-pub fn run_vm(vm_name: &String, vm_port_fwd: &String) {
-    println!("LOG:: Executing VM startup process in 3 seconds...");
-    let startup_wait = time::Duration::from_secs(3);
-    thread::sleep(startup_wait);
+/// The args every VM is started with so that it can be managed while it's
+/// running (pid file + QMP socket in the VM's data directory).
+///
+/// ---
+pub fn runtime_args(vm_name: &str) -> io::Result<Vec<String>> {
+    let vm_data_dir = filesystem::vm_data_dir_path(vm_name)?;
 
-    println!("\x1b[0;32mLOG:: Starting VM...\x1b[0m");
+    let mut args = vec![
+        "-pidfile".to_string(),
+        vm_data_dir.join("qemu.pid").to_string_lossy().to_string(),
+        "-qmp".to_string(),
+        format!("unix:{},server=on,wait=off", vm_data_dir.join("qmp.sock").to_string_lossy()),
+    ];
+    args.extend(guestagent::guest_agent_args(vm_name)?);
+    Ok(args)
+}
 
-    // ======== Getting the VM details from the autovirt config file ========
-    let vm_memory_mb_json =
-        filesystem::get_value_from_autovirt_json(&format!("vms.{}.memory_mb", vm_name))
+/// Gets the pid of a VM's hypervisor process if the VM is running (see
+/// backend.rs).
+///
+/// ---
+pub fn vm_pid(vm_name: &str) -> Option<u32> {
+    backend::for_vm(vm_name).pid(vm_name)
+}
+
+/// Checks if a VM is running.
+pub fn is_vm_running(vm_name: &str) -> bool {
+    vm_pid(vm_name).is_some()
+}
+
+/// Runs a VM based on its entry in the autovirt config file.
+///
+/// If no port forward args are given then the ones the VM was created with are
+/// used. The VM's provisioners are run once it's reachable over ssh when
+/// `run_provisioners` is set or when new provisioners are given.
+///
+/// The VM runs in the foreground (in the terminal) unless `detach` is set in
+/// which case the hypervisor runs in the background and this returns once the VM has started (or
+/// once it's ready with `wait`, which also runs it in the background).
+///
+/// With `dry_run` the hypervisor command is only printed (shell quoted) and
+/// nothing about the VM is changed.
+///
+/// ---
+#[allow(clippy::too_many_arguments)]
+pub fn run_vm(
+    vm_name: &String,
+    vm_port_fwd: &str,
+    provision_options: &provision::ProvisionOptions,
+    run_provisioners: bool,
+    detach: bool,
+    vm_shares: &[String],
+    wait: Option<&wait::WaitOptions>,
+    dry_run: bool,
+) {
+    let detach = detach || wait.is_some();
+
+    if filesystem::get_value_from_autovirt_json(&format!("vms.{}", vm_name)).is_none() {
+        eprintln!("ERROR: VM with the name '{}' does not exist", vm_name);
+        std::process::exit(1);
+    }
+
+    if is_vm_running(vm_name) {
+        eprintln!("ERROR: VM '{}' is already running", vm_name);
+        std::process::exit(1);
+    }
+
+    if dry_run {
+        let vm_port_fwd = match vm_port_fwd.is_empty() {
+            true => filesystem::get_value_from_autovirt_json(&format!("vms.{}.ports", vm_name))
+                .and_then(|v| v.as_str().map(String::from))
+                .unwrap_or_default(),
+            false => vm_port_fwd.to_string(),
+        };
+        let command = backend::VmSpec::from_config(vm_name, &vm_port_fwd, detach)
+            .and_then(|spec| backend::for_vm(vm_name).command(&spec))
+            .unwrap_or_else(|e| {
+                eprintln!("ERROR: {}", e);
+                std::process::exit(1);
+            });
+        println!("{}", qemucmd::command_line(&command));
+        return;
+    }
+
+    if let Err(e) = provision::add_provisioners_from_options(vm_name, provision_options) {
+        eprintln!("ERROR: Invalid provisioner -> {}", e);
+        std::process::exit(1);
+    }
+
+    // New shares are kept for the next runs too but cloud-init only mounts
+    // things on the first boot so they have to be mounted by hand this time.
+    let mut shares = Vec::new();
+    for spec in vm_shares {
+        shares.push(share::parse_share(spec).unwrap_or_else(|e| {
+            eprintln!("ERROR: {}", e);
+            std::process::exit(1);
+        }));
+    }
+    match share::add_shares(vm_name, &shares) {
+        Ok(true) => {
+            if let Err(e) = cloudinit::write_vm_cloud_init_files(vm_name) {
+                eprintln!("ERROR: Failed to write cloud-init files -> {}", e);
+            }
+            println!("INFO:: Mount the new share(s) in the VM with:");
+            for share in &shares {
+                println!("INFO::   {}", share::manual_mount_command(share));
+            }
+        }
+        Ok(false) => {}
+        Err(e) => {
+            eprintln!("ERROR: Failed to add shares -> {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    // Using the port forwards the VM was created with if none are given (and
+    // keeping the new ones if they are since ssh goes through them).
+    let vm_port_fwd = if vm_port_fwd.is_empty() {
+        filesystem::get_value_from_autovirt_json(&format!("vms.{}.ports", vm_name))
             .and_then(|v| v.as_str().map(String::from))
-            .unwrap_or_else(|| "512".to_string()); // 512 mb if shit goes down
+            .unwrap_or_default()
+    } else {
+        filesystem::insert_value_into_autovirt_json_object(
+            &format!("vms.{}.ports", vm_name),
+            serde_json::Value::String(vm_port_fwd.to_string()),
+        );
+        inventory::sync_managed_ssh_config_or_warn();
+        vm_port_fwd.to_string()
+    };
 
-    let vm_cpus_json = filesystem::get_value_from_autovirt_json(&format!("vms.{}.cpus", vm_name))
-        .and_then(|v| v.as_str().map(String::from))
-        .unwrap_or_else(|| "1".to_string()); // default 1 cpu if not found
+    if !detach {
+        println!("LOG:: Executing VM startup process in 3 seconds...");
+        let startup_wait = time::Duration::from_secs(3);
+        thread::sleep(startup_wait);
+    }
 
+    println!("\x1b[0;32mLOG:: Starting VM...\x1b[0m");
+
+    // ======== Getting the VM details from the autovirt config file ========
     let vm_distro_json =
         filesystem::get_value_from_autovirt_json(&format!("vms.{}.distro", vm_name))
             .and_then(|v| v.as_str().map(String::from))
@@ -32,56 +168,74 @@ pub fn run_vm(vm_name: &String, vm_port_fwd: &String) {
                                                           // but that doesn't matter
                                                           // since its just a label
 
-    let vm_image_path_json = filesystem::get_value_from_autovirt_json(&format!("vms.{}.image_path", vm_name))
-        .and_then(|v| v.as_str().map(String::from))
-        .unwrap_or_else(|| "".to_string()); // default 1 cpu if not found
+    // VMs made before the disk format was recorded get it recorded now
+    // (`--dry-run` only probes it)
+    if let Err(e) = disk::record_vm_disk_format(vm_name) {
+        eprintln!("ERROR: Failed to find out the VM disk format -> {}", e);
+        std::process::exit(1);
+    }
+
+    let spec = backend::VmSpec::from_config(vm_name, &vm_port_fwd, detach).unwrap_or_else(|e| {
+        eprintln!("ERROR: Failed to get the VM settings -> {}", e);
+        std::process::exit(1);
+    });
+    let backend = backend::for_vm(vm_name);
 
     println!("-------- VM Details --------");
     println!("NAME: {}", vm_name);
     println!("DISTRO: {}", vm_distro_json);
-    println!("MEMORY: {}MB", vm_memory_mb_json);
-    println!("CPUS: {}", vm_cpus_json);
-    println!("PATH: {}", vm_image_path_json);
+    println!("MEMORY: {}MB", spec.memory_mb);
+    println!("CPUS: {}", spec.cpus);
+    println!("PATH: {}", spec.image_path);
+    println!("FORMAT: {}", spec.disk_format);
+    println!("BACKEND: {}", backend.name());
     println!("-----------------------------");
 
-
-    // vm args for networking (port forwarding string/args given by the user)
-    let vm_network_args = format!("user,{}", vm_port_fwd);
-
-    _ = vm_network_args;
     // Building cmd to run the VM
-    let mut run_vm_cmd = Command::new("qemu-system-x86_64");
-    run_vm_cmd
-        .arg("-net")
-        .arg("nic")
-        .arg("-net")
-        .arg(&vm_network_args) // full raw port fwd str args from the user
-        // .arg("user,")
-        // .arg("user,hostfwd=tcp::2244-:22") // forwarding SSH to 2222 on host
-        .arg("-machine")
-        .arg("accel=kvm:tcg")
-        .arg("-m")
-        .arg(&vm_memory_mb_json)
-        .arg("-nographic")
-        .arg("-hda")
-        .arg(&vm_image_path_json)
-        .arg("-smbios")
-        .arg("type=1,serial=ds=nocloud;s=http://10.0.2.2:8000/")
-        .arg("-serial")
-        .arg("pty")
-        .arg("-smp")
-        .arg(&format!("cpus={}", vm_cpus_json));
+    let mut run_vm_cmd = backend.command(&spec).unwrap_or_else(|e| {
+        eprintln!("ERROR: {}", e);
+        std::process::exit(1);
+    });
 
     if std::env::var("AUTOVIRT_DEBUG").is_ok() {
-        println!("DEBUG:: run command -> {:?}", run_vm_cmd);
+        println!("DEBUG:: run command -> {}", qemucmd::command_line(&run_vm_cmd));
     }
 
-    let status = run_vm_cmd
-        .status()
-        .expect("ERROR:: Failed to exec run VM command");
+    let provision = run_provisioners || !provision_options.is_empty();
+    let provisioning = (provision && !detach).then(|| provision::spawn_provisioning(vm_name));
+
+    let started = backend.start(&spec, &mut run_vm_cmd).unwrap_or_else(|e| {
+        eprintln!("ERROR: Failed to start the VM -> {}", e);
+        std::process::exit(1);
+    });
+    let status = match started {
+        Some(status) => status,
+        None => {
+            println!("INFO:: Reattach with `autovirt console {}`, stop it with `autovirt stop {}`", vm_name, vm_name);
+            if let Some(provisioning) = provisioning {
+                println!("INFO:: Waiting for the provisioners to finish...");
+                let _ = provisioning.join();
+            }
+            return;
+        }
+    };
 
     if status.success() {
-        println!("\nLOG:: AutoVirt run success 👍");
+        if detach {
+            println!("LOG:: VM started in the background -> {}", vm_name);
+            if let Some(wait) = wait {
+                wait::wait_for_vm_or_exit(vm_name, wait);
+            }
+            // nothing else is running in the foreground so the provisioners
+            // can just be run here
+            if provision
+                && provision::provision_vm(vm_name, time::Duration::from_secs(provision::SSH_WAIT_TIMEOUT_SECS)).is_err()
+            {
+                std::process::exit(1);
+            }
+        } else {
+            println!("\nLOG:: AutoVirt run success 👍");
+        }
     } else {
         eprintln!(
             "ERROR:: Something went wrong or something failed to do something with
@@ -89,3 +243,28 @@ pub fn run_vm(vm_name: &String, vm_port_fwd: &String) {
         );
     }
 }
+
+/// Starts VMs in the background (skipping the ones that are already running).
+///
+/// ---
+pub fn start_vms(vm_names: &[String]) {
+    for vm_name in vm_names {
+        if is_vm_running(vm_name) {
+            println!("INFO:: VM is already running -> {}", vm_name);
+            continue;
+        }
+        run_vm(vm_name, "", &provision::ProvisionOptions::default(), false, true, &[], None, false);
+    }
+}
+
+/// Stops a running VM (see `backend::stop`).
+///
+/// The VM is asked to shut down (the guest agent or the ACPI power button) and
+/// then this waits for it to go away. If it's still running after the timeout
+/// then the hypervisor is killed if `force` is set or an error is returned if
+/// it isn't.
+///
+/// ---
+pub fn stop_vm(vm_name: &str, force: bool, timeout: time::Duration) -> Result<(), Box<dyn Error>> {
+    backend::stop(backend::for_vm(vm_name).as_ref(), vm_name, force, timeout)
+}
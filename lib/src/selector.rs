@@ -0,0 +1,259 @@
+//! This file contains the selector expressions used to pick VMs by their tags
+//! and groups for bulk operations (i.e. `autovirt stop --selector env=ci`).
+//!
+//! A selector is a comma separated list of terms which all have to match:
+//!
+//! - `key=value`   the VM has the tag with that value
+//! - `key!=value`  the VM doesn't have the tag with that value
+//! - `key`         the VM has the tag (any value)
+//! - `!key`        the VM doesn't have the tag
+//! - `group=name`  the VM is in the group (`group!=name` for not in it)
+//!
+//! ---
+
+use serde_json::Value;
+use std::error::Error;
+use std::io;
+
+use crate::filesystem;
+
+#[derive(Debug, Clone)]
+enum Term {
+    Equals(String, String),
+    NotEquals(String, String),
+    Exists(String),
+    NotExists(String),
+    InGroup(String),
+    NotInGroup(String),
+}
+
+/// A parsed selector expression.
+#[derive(Debug, Clone)]
+pub struct Selector {
+    terms: Vec<Term>,
+}
+
+impl Selector {
+    /// Parses a selector expression (see the top of this file for the syntax).
+    pub fn parse(expression: &str) -> Result<Selector, Box<dyn Error>> {
+        let mut terms = Vec::new();
+
+        for term in expression.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            let parsed = if let Some((key, value)) = term.split_once("!=") {
+                match key.trim() {
+                    "group" => Term::NotInGroup(value.trim().to_string()),
+                    key => Term::NotEquals(key.to_string(), value.trim().to_string()),
+                }
+            } else if let Some((key, value)) = term.split_once('=') {
+                match key.trim() {
+                    "group" => Term::InGroup(value.trim().to_string()),
+                    key => Term::Equals(key.to_string(), value.trim().to_string()),
+                }
+            } else if let Some(key) = term.strip_prefix('!') {
+                Term::NotExists(key.trim().to_string())
+            } else {
+                Term::Exists(term.to_string())
+            };
+
+            // keys can't be what parse_tag doesn't allow (i.e. `!env=prod` or
+            // `group` without a name), nothing would ever match them
+            let is_invalid = match &parsed {
+                Term::Equals(key, _) | Term::NotEquals(key, _) | Term::Exists(key) | Term::NotExists(key) => {
+                    key.is_empty() || key == "group" || key.contains(['!', '='])
+                }
+                Term::InGroup(group) | Term::NotInGroup(group) => group.is_empty(),
+            };
+            if is_invalid {
+                return Err(Box::new(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid selector term '{}'", term),
+                )));
+            }
+
+            terms.push(parsed);
+        }
+
+        if terms.is_empty() {
+            return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "Empty selector")));
+        }
+
+        Ok(Selector { terms })
+    }
+
+    /// Checks if a VM (its entry in the autovirt config file) matches.
+    pub fn matches(&self, vm_data: &Value) -> bool {
+        let tag = |key: &str| vm_data.get("tags").and_then(|t| t.get(key)).and_then(|v| v.as_str());
+        let in_group = |group: &str| {
+            vm_data
+                .get("groups")
+                .and_then(|g| g.as_array())
+                .map(|groups| groups.iter().any(|g| g.as_str() == Some(group)))
+                .unwrap_or(false)
+        };
+
+        self.terms.iter().all(|term| match term {
+            Term::Equals(key, value) => tag(key) == Some(value.as_str()),
+            Term::NotEquals(key, value) => tag(key) != Some(value.as_str()),
+            Term::Exists(key) => tag(key).is_some(),
+            Term::NotExists(key) => tag(key).is_none(),
+            Term::InGroup(group) => in_group(group),
+            Term::NotInGroup(group) => !in_group(group),
+        })
+    }
+}
+
+/// Gets the names of the VMs matching a selector.
+pub fn select_vms(selector: &Selector) -> Vec<String> {
+    filesystem::get_value_from_autovirt_json("vms")
+        .and_then(|v| v.as_object().cloned())
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|(_, vm_data)| selector.matches(vm_data))
+        .map(|(vm_name, _)| vm_name)
+        .collect()
+}
+
+/// Gets the VMs for a bulk command from either the names given or a selector
+/// expression (one of them has to be given).
+///
+/// ---
+pub fn resolve_vm_names(names: &[String], selector: Option<&String>) -> Result<Vec<String>, Box<dyn Error>> {
+    match (names.is_empty(), selector) {
+        (true, Some(expression)) => Ok(select_vms(&Selector::parse(expression)?)),
+        (false, None) => {
+            for vm_name in names {
+                if filesystem::get_value_from_autovirt_json(&format!("vms.{}", vm_name)).is_none() {
+                    return Err(Box::new(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("VM '{}' not found", vm_name),
+                    )));
+                }
+            }
+            Ok(names.to_vec())
+        }
+        (false, Some(_)) => Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Give either VM names or a selector, not both",
+        ))),
+        (true, None) => Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Give at least one VM name or a selector (--selector)",
+        ))),
+    }
+}
+
+/// Parses `key=value` tags (used by create and `autovirt tag`).
+///
+/// `group` can't be used as a tag key since selectors use it for groups.
+///
+/// ---
+pub fn parse_tag(tag: &str) -> Result<(String, String), Box<dyn Error>> {
+    match tag.split_once('=') {
+        Some((key, value))
+            if !key.trim().is_empty()
+                && key.trim() != "group"
+                && !key.contains([',', '!', '.'])
+                && !value.contains(',') =>
+        {
+            Ok((key.trim().to_string(), value.trim().to_string()))
+        }
+        _ => Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid tag '{}' (expected key=value, 'group' can't be used as a key)", tag),
+        ))),
+    }
+}
+
+/// Checks a group name can be used in selectors.
+///
+/// ---
+pub fn parse_group(group: &str) -> Result<String, Box<dyn Error>> {
+    let group = group.trim();
+    if group.is_empty() || group.contains([',', '=', '!']) {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid group name '{}'", group),
+        )));
+    }
+    Ok(group.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn vm(tags: Value, groups: Value) -> Value {
+        json!({ "name": "vm", "tags": tags, "groups": groups })
+    }
+
+    fn matches(expression: &str, vm_data: &Value) -> bool {
+        Selector::parse(expression).unwrap().matches(vm_data)
+    }
+
+    #[test]
+    fn tag_terms() {
+        let ci = vm(json!({ "env": "ci", "team": "infra" }), json!([]));
+        let bare = vm(json!({}), json!([]));
+
+        assert!(matches("env=ci", &ci));
+        assert!(!matches("env=prod", &ci));
+        assert!(matches("env!=prod", &ci));
+        assert!(!matches("env!=ci", &ci));
+        // a VM without the tag doesn't have it with any value either
+        assert!(matches("env!=ci", &bare));
+        assert!(!matches("env=ci", &bare));
+
+        assert!(matches("team", &ci));
+        assert!(!matches("team", &bare));
+        assert!(matches("!team", &bare));
+        assert!(!matches("!team", &ci));
+    }
+
+    #[test]
+    fn group_terms() {
+        let web = vm(json!({}), json!(["web", "eu"]));
+        let no_groups = json!({ "name": "old" });
+
+        assert!(matches("group=web", &web));
+        assert!(!matches("group=db", &web));
+        assert!(matches("group!=db", &web));
+        assert!(!matches("group!=eu", &web));
+        // VMs from before groups existed are in none
+        assert!(!matches("group=web", &no_groups));
+        assert!(matches("group!=web", &no_groups));
+    }
+
+    #[test]
+    fn every_term_has_to_match() {
+        let vm_data = vm(json!({ "env": "ci", "os": "debian" }), json!(["web"]));
+
+        assert!(matches("env=ci, os=debian,group=web", &vm_data));
+        assert!(matches(" env = ci , !gpu ", &vm_data));
+        assert!(!matches("env=ci,os=fedora", &vm_data));
+        assert!(!matches("env=ci,group=db", &vm_data));
+        // empty terms are skipped
+        assert!(matches("env=ci,,", &vm_data));
+    }
+
+    #[test]
+    fn invalid_selectors() {
+        for bad in ["", " , ", "=ci", "!=ci", "!", "group=", "group!=", "group", "!group", "!env=ci"] {
+            assert!(Selector::parse(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn tags_and_groups() {
+        assert_eq!(parse_tag(" env = ci ").unwrap(), ("env".to_string(), "ci".to_string()));
+        assert_eq!(parse_tag("note=").unwrap(), ("note".to_string(), String::new()));
+        for bad in ["env", "=ci", "group=web", "a.b=c", "!env=ci", "env=a,b"] {
+            assert!(parse_tag(bad).is_err(), "{:?}", bad);
+        }
+
+        assert_eq!(parse_group(" web ").unwrap(), "web");
+        for bad in ["", "a,b", "a=b", "!a"] {
+            assert!(parse_group(bad).is_err(), "{:?}", bad);
+        }
+    }
+}
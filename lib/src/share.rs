@@ -0,0 +1,332 @@
+//! This file contains things for sharing host directories with VMs.
+//!
+//! Shares are recorded per VM (`shares` in the VM's entry) and are set up every
+//! time the VM runs. virtiofs is used if `virtiofsd` is installed when the
+//! share is added (it's a lot faster) and 9p (`-virtfs`) otherwise since that
+//! works with a stock qemu. The transport is recorded with the share since the
+//! mount entry in the VM's cloud-init config depends on it.
+//!
+//! virtiofsd can be pointed at with `AUTOVIRT_VIRTIOFSD` if it isn't in the
+//! usual places.
+//!
+//! ---
+
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::cloudinit;
+use crate::disk;
+use crate::filesystem;
+
+/// Where virtiofsd usually lives (it's not in $PATH on most distros).
+const VIRTIOFSD_PATHS: [&str; 3] = ["/usr/libexec/virtiofsd", "/usr/lib/qemu/virtiofsd", "/usr/lib/virtiofsd"];
+
+/// A host directory shared with a VM.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Share {
+    pub host_path: String,
+    pub tag: String,
+    pub read_only: bool,
+    pub guest_path: String,
+    /// `virtiofs` or `9p`
+    pub transport: String,
+}
+
+/// Parses a `--share` arg (`host_path:tag[:ro][:/guest/path]`). The share is
+/// mounted at `/mnt/<tag>` in the VM if no guest path is given.
+///
+/// ---
+pub fn parse_share(spec: &str) -> Result<Share, Box<dyn Error>> {
+    let invalid = || -> Box<dyn Error> {
+        Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid --share '{}' (expected host_path:tag[:ro][:/guest/path])", spec),
+        ))
+    };
+
+    let mut parts = spec.split(':');
+    let host_path = parts.next().filter(|p| !p.is_empty()).ok_or_else(invalid)?;
+    let tag = parts.next().ok_or_else(invalid)?;
+
+    let mut read_only = false;
+    let mut guest_path = None;
+    for part in parts {
+        match part {
+            "ro" if !read_only && guest_path.is_none() => read_only = true,
+            path if path.starts_with('/') && guest_path.is_none() => guest_path = Some(path.to_string()),
+            _ => return Err(invalid()),
+        }
+    }
+
+    // 9p mount tags can only be 31 chars long
+    if tag.is_empty() || tag.len() > 31 || !tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid share tag '{}' (up to 31 letters, numbers, - and _)", tag),
+        )));
+    }
+
+    let host_path = fs::canonicalize(host_path)
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to find '{}' -> {}", host_path, e)))?;
+    if !host_path.is_dir() {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{:?} is not a directory", host_path),
+        )));
+    }
+
+    Ok(Share {
+        host_path: host_path.to_string_lossy().to_string(),
+        tag: tag.to_string(),
+        read_only,
+        guest_path: guest_path.unwrap_or_else(|| format!("/mnt/{}", tag)),
+        transport: if find_virtiofsd().is_some() { "virtiofs" } else { "9p" }.to_string(),
+    })
+}
+
+/// Finds virtiofsd (`AUTOVIRT_VIRTIOFSD`, the usual places or $PATH).
+fn find_virtiofsd() -> Option<PathBuf> {
+    if let Ok(path) = std::env::var("AUTOVIRT_VIRTIOFSD") {
+        return Some(PathBuf::from(path)).filter(|p| p.is_file());
+    }
+
+    VIRTIOFSD_PATHS
+        .iter()
+        .map(PathBuf::from)
+        .find(|p| p.is_file())
+        .or_else(|| cloudinit::command_exists("virtiofsd").then(|| PathBuf::from("virtiofsd")))
+}
+
+/// Gets the shares of a VM.
+pub fn load_shares(vm_name: &str) -> Vec<Share> {
+    filesystem::get_value_from_autovirt_json(&format!("vms.{}.shares", vm_name))
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default()
+}
+
+fn save_shares(vm_name: &str, shares: &[Share]) {
+    filesystem::insert_value_into_autovirt_json_object(
+        &format!("vms.{}.shares", vm_name),
+        serde_json::to_value(shares).expect("ERROR: Failed to jsonify shares"),
+    );
+}
+
+/// The cloud-init `mounts` entry for a share.
+fn mount_entry(share: &Share) -> Vec<String> {
+    let (fstype, options) = match share.transport.as_str() {
+        "virtiofs" => ("virtiofs", "defaults,nofail"),
+        _ => ("9p", "trans=virtio,version=9p2000.L,msize=262144,nofail"),
+    };
+    let options = if share.read_only { format!("{},ro", options) } else { options.to_string() };
+
+    vec![
+        share.tag.clone(),
+        share.guest_path.clone(),
+        fstype.to_string(),
+        options,
+        "0".to_string(),
+        "0".to_string(),
+    ]
+}
+
+/// Adds shares to a VM along with the cloud-init mount entries for them.
+/// Shares with a tag the VM already has are only allowed if they're the same.
+///
+/// Returns if anything was added.
+///
+/// ---
+pub fn add_shares(vm_name: &str, new_shares: &[Share]) -> Result<bool, Box<dyn Error>> {
+    let mut shares = load_shares(vm_name);
+    let mut cloud_config = cloudinit::load_vm_cloud_config(vm_name)?;
+    let mut added = false;
+
+    for new_share in new_shares {
+        if let Some(existing) = shares.iter().find(|s| s.tag == new_share.tag) {
+            if existing.host_path == new_share.host_path
+                && existing.read_only == new_share.read_only
+                && existing.guest_path == new_share.guest_path
+            {
+                continue;
+            }
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("VM '{}' already has a different share with the tag '{}'", vm_name, new_share.tag),
+            )));
+        }
+
+        cloud_config.mounts.push(mount_entry(new_share));
+        shares.push(new_share.clone());
+        added = true;
+        println!(
+            "LOG:: Sharing {} with VM {} at {} ({}{})",
+            new_share.host_path,
+            vm_name,
+            new_share.guest_path,
+            new_share.transport,
+            if new_share.read_only { ", read only" } else { "" }
+        );
+    }
+
+    save_shares(vm_name, &shares);
+    cloudinit::save_vm_cloud_config(vm_name, &cloud_config);
+    Ok(added)
+}
+
+/// Removes a share (by tag) from a VM.
+pub fn remove_share(vm_name: &str, tag: &str) -> Result<(), Box<dyn Error>> {
+    let mut shares = load_shares(vm_name);
+    let index = shares.iter().position(|s| s.tag == tag).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, format!("VM '{}' has no share with the tag '{}'", vm_name, tag))
+    })?;
+
+    let share = shares.remove(index);
+    save_shares(vm_name, &shares);
+
+    let mut cloud_config = cloudinit::load_vm_cloud_config(vm_name)?;
+    let entry = mount_entry(&share);
+    cloud_config.mounts.retain(|mount| mount != &entry);
+    cloudinit::save_vm_cloud_config(vm_name, &cloud_config);
+    cloudinit::write_vm_cloud_init_files(vm_name)?;
+
+    println!("LOG:: Share removed from VM {} -> {}", vm_name, tag);
+    Ok(())
+}
+
+/// Prints the shares of a VM.
+pub fn list_shares(vm_name: &str) -> Result<(), Box<dyn Error>> {
+    disk::vm_image_path(vm_name)?;
+
+    let shares = load_shares(vm_name);
+    if shares.is_empty() {
+        println!("INFO:: VM {} has no shares", vm_name);
+    }
+    for share in shares {
+        println!(
+            "{}  {} -> {}  {}{}",
+            share.tag,
+            share.host_path,
+            share.guest_path,
+            share.transport,
+            if share.read_only { "  ro" } else { "" }
+        );
+    }
+    Ok(())
+}
+
+/// The command to mount a share by hand in the VM (cloud-init only mounts
+/// things on the first boot).
+///
+/// ---
+pub fn manual_mount_command(share: &Share) -> String {
+    let entry = mount_entry(share);
+    format!(
+        "sudo mkdir -p {} && sudo mount -t {} -o {} {} {}",
+        share.guest_path,
+        entry[2],
+        entry[3].replace(",nofail", ""),
+        share.tag,
+        share.guest_path
+    )
+}
+
+/// Starts virtiofsd for a share and waits for its socket to show up.
+fn start_virtiofsd(share: &Share, socket_path: &Path) -> Result<(), Box<dyn Error>> {
+    let virtiofsd = find_virtiofsd().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Share '{}' uses virtiofs but virtiofsd isn't installed (or set AUTOVIRT_VIRTIOFSD)", share.tag),
+        )
+    })?;
+
+    let _ = fs::remove_file(socket_path);
+    let mut cmd = Command::new(virtiofsd);
+    cmd.arg(format!("--socket-path={}", socket_path.to_string_lossy()))
+        .arg(format!("--shared-dir={}", share.host_path))
+        .arg("--cache=auto")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null());
+    if share.read_only {
+        cmd.arg("--readonly");
+    }
+    // virtiofsd exits by itself when qemu disconnects from it
+    cmd.spawn()?;
+
+    let started = Instant::now();
+    while !socket_path.exists() {
+        if started.elapsed() > Duration::from_secs(5) {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("virtiofsd didn't start for share '{}'", share.tag),
+            )));
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    Ok(())
+}
+
+/// The socket qemu talks to a share's virtiofsd on.
+fn virtiofs_socket_path(vm_name: &str, tag: &str) -> io::Result<PathBuf> {
+    Ok(filesystem::vm_data_dir_path(vm_name)?.join(format!("virtiofs-{}.sock", tag)))
+}
+
+/// Starts virtiofsd for a VM's virtiofs shares (done right before the VM is
+/// started).
+///
+/// ---
+pub fn start_share_daemons(vm_name: &str) -> Result<(), Box<dyn Error>> {
+    for share in load_shares(vm_name).iter().filter(|s| s.transport == "virtiofs") {
+        filesystem::get_vm_data_dir(vm_name)?;
+        start_virtiofsd(share, &virtiofs_socket_path(vm_name, &share.tag)?)?;
+    }
+    Ok(())
+}
+
+/// Gets the qemu args for a VM's shares (virtiofsd is started for the
+/// virtiofs ones by `start_share_daemons`).
+///
+/// virtiofs needs the VM's memory to be shared with virtiofsd so the memory is
+/// given as a memfd backend in that case.
+///
+/// ---
+pub fn share_args(vm_name: &str, memory_mb: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let shares = load_shares(vm_name);
+    let mut args = Vec::new();
+
+    if shares.iter().any(|s| s.transport == "virtiofs") {
+        args.push("-object".to_string());
+        args.push(format!("memory-backend-memfd,id=mem,size={}M,share=on", memory_mb));
+        args.push("-numa".to_string());
+        args.push("node,memdev=mem".to_string());
+    }
+
+    for share in &shares {
+        if share.transport == "virtiofs" {
+            let socket_path = virtiofs_socket_path(vm_name, &share.tag)?;
+            args.push("-chardev".to_string());
+            args.push(format!(
+                "socket,id=vfs-{},path={}",
+                share.tag,
+                disk::escape_option_value(&socket_path.to_string_lossy())
+            ));
+            args.push("-device".to_string());
+            args.push(format!("vhost-user-fs-pci,chardev=vfs-{},tag={}", share.tag, share.tag));
+        } else {
+            args.push("-virtfs".to_string());
+            args.push(format!(
+                "local,path={},mount_tag={},security_model=mapped-xattr,id=fs-{}{}",
+                disk::escape_option_value(&share.host_path),
+                share.tag,
+                share.tag,
+                if share.read_only { ",readonly=on" } else { "" }
+            ));
+        }
+    }
+
+    Ok(args)
+}
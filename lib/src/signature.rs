@@ -0,0 +1,411 @@
+//! This file contains the OpenPGP verification of image checksum files (and
+//! simplestreams indexes), a checksum file from the same server as the image
+//! only proves the download isn't broken, the signature proves it's the
+//! distro's.
+//!
+//! Images say how their checksums are signed with `signature` in the catalog:
+//! - `{"type": "detached", "url": ".../SHA256SUMS.gpg", "keyring": "ubuntu"}`
+//! - `{"type": "clearsigned", "keyring": "fedora41"}` (the checksum file is
+//!   signed itself, only the signed part of it is used)
+//!
+//! Keyrings are looked up by name in this order:
+//! - `keyrings.<name>` in autovirt.json (a path)
+//! - `~/.autovirt/keyrings/<name>.gpg` or `<name>.asc`
+//! - the system paths for it in the catalog's `keyrings` (the distro keyring
+//!   packages, i.e. -> ubuntu-cloudimage-keyring)
+//!
+//! The checking itself is done by `gpgv` (armored keys are dearmored first
+//! since gpgv only reads binary keyrings). Images that can't be verified are
+//! refused unless `--insecure` is given.
+//!
+//! ---
+
+use base64::Engine;
+use reqwest::blocking::Client;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::catalog::{self, CatalogImage};
+use crate::fetch;
+use crate::filesystem;
+use crate::imageindex;
+
+/// How an image's checksums are signed (`signature` in the catalog).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignatureSpec {
+    /// detached or clearsigned
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// The url of the detached signature (`{serial}` works like in `url`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    pub keyring: String,
+}
+
+/// How a download was verified (recorded in `downloaded_images`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Verification {
+    /// verified or insecure
+    pub status: String,
+    /// The fingerprint (and user id) of the key that signed the checksums
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signer: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keyring: Option<String>,
+}
+
+impl Verification {
+    /// For downloads made with `--insecure`.
+    pub fn insecure() -> Verification {
+        Verification { status: "insecure".to_string(), signer: None, keyring: None }
+    }
+
+    fn verified(signer: String, keyring: &str) -> Verification {
+        Verification { status: "verified".to_string(), signer: Some(signer), keyring: Some(keyring.to_string()) }
+    }
+}
+
+/// A directory for the files gpgv needs, removed when it's dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new() -> io::Result<TempDir> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "autovirt-gpgv-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&dir)?;
+        Ok(TempDir(dir))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn unverified(message: String) -> Box<dyn Error> {
+    Box::new(io::Error::new(io::ErrorKind::PermissionDenied, message))
+}
+
+/// Gets the signer from gpgv's `--status-fd` output, or why the signature
+/// isn't good.
+///
+/// Only a `VALIDSIG` counts and any bad/expired/revoked signature in there
+/// makes the whole thing bad. Signatures that can't be checked (`ERRSIG`, i.e.
+/// by another of the distro's keys that isn't in the keyring) don't matter
+/// when there's a valid one.
+///
+/// ---
+pub fn parse_gpg_status(status: &str) -> Result<String, String> {
+    let mut fingerprint = None;
+    let mut user_id = None;
+    let mut unchecked = None;
+    for line in status.lines().filter_map(|l| l.strip_prefix("[GNUPG:] ")) {
+        let mut fields = line.splitn(3, ' ');
+        let keyword = fields.next().unwrap_or_default();
+        match keyword {
+            "VALIDSIG" => fingerprint = fields.next().map(String::from),
+            "GOODSIG" => user_id = fields.nth(1).map(String::from),
+            "BADSIG" => return Err("BAD signature".to_string()),
+            "EXPSIG" | "EXPKEYSIG" => return Err("the signature or its key has expired".to_string()),
+            "REVKEYSIG" => return Err("the signing key has been revoked".to_string()),
+            "ERRSIG" => unchecked = Some("the signature couldn't be checked (key not in the keyring?)"),
+            "NODATA" => unchecked = Some("no signature found"),
+            _ => {}
+        }
+    }
+    match (fingerprint, user_id) {
+        (Some(fingerprint), Some(user_id)) => Ok(format!("{} ({})", fingerprint, user_id)),
+        (Some(fingerprint), None) => Ok(fingerprint),
+        (None, _) => Err(unchecked.unwrap_or("no valid signature").to_string()),
+    }
+}
+
+/// Turns an armored (`-----BEGIN PGP PUBLIC KEY BLOCK-----`) key into the
+/// binary form gpgv reads, several blocks in a row are joined.
+///
+/// ---
+pub fn dearmor(text: &str) -> Option<Vec<u8>> {
+    let mut keys = Vec::new();
+    let mut lines = text.lines().map(str::trim);
+    while lines.by_ref().any(|l| l.starts_with("-----BEGIN PGP PUBLIC KEY BLOCK")) {
+        // the headers (Version: etc.) end at the first empty line
+        let mut body = String::new();
+        let mut in_headers = true;
+        for line in lines.by_ref() {
+            if line.starts_with("-----END PGP") {
+                break;
+            }
+            if in_headers {
+                if line.is_empty() {
+                    in_headers = false;
+                } else if !line.contains(':') {
+                    in_headers = false;
+                    body.push_str(line);
+                }
+                continue;
+            }
+            // `=XXXX` is the checksum of the block
+            if !line.starts_with('=') {
+                body.push_str(line);
+            }
+        }
+        keys.extend(base64::engine::general_purpose::STANDARD.decode(body).ok()?);
+    }
+    (!keys.is_empty()).then_some(keys)
+}
+
+/// Finds a keyring by name (see the top of the file for where).
+pub fn find_keyring(name: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let configured = filesystem::get_value_from_autovirt_json(&format!("keyrings.{}", name))
+        .and_then(|v| v.as_str().map(PathBuf::from));
+    let user_dir = filesystem::get_autovirt_data_dir().map(|dir| dir.join("keyrings"));
+    let mut candidates: Vec<PathBuf> = configured.into_iter().collect();
+    if let Some(user_dir) = user_dir {
+        candidates.push(user_dir.join(format!("{}.gpg", name)));
+        candidates.push(user_dir.join(format!("{}.asc", name)));
+    }
+    candidates.extend(catalog::keyring_paths(name).into_iter().map(PathBuf::from));
+
+    candidates.into_iter().find(|path| path.is_file()).ok_or_else(|| {
+        unverified(format!(
+            "keyring '{}' not found (put it in ~/.autovirt/keyrings/{}.gpg or set keyrings.{} in autovirt.json)",
+            name, name, name
+        ))
+    })
+}
+
+/// Runs gpgv with a keyring (dearmored into `temp` if it needs to be),
+/// giving back the signer.
+///
+/// ---
+fn gpgv(
+    keyring: &Path,
+    keyring_name: &str,
+    temp: &TempDir,
+    args: &[&Path],
+    output: Option<&Path>,
+) -> Result<Verification, Box<dyn Error>> {
+    let mut keyring = fs::canonicalize(keyring)?;
+    let contents = fs::read(&keyring)?;
+    if contents.starts_with(b"-----BEGIN") {
+        let binary = dearmor(&String::from_utf8_lossy(&contents))
+            .ok_or_else(|| unverified(format!("keyring {:?} isn't a valid armored key", keyring)))?;
+        keyring = temp.0.join("keyring.gpg");
+        fs::write(&keyring, binary)?;
+    }
+
+    let mut command = Command::new("gpgv");
+    command.arg("--status-fd").arg("2").arg("--keyring").arg(&keyring);
+    if let Some(output) = output {
+        command.arg("--output").arg(output);
+    }
+    let result = command.args(args).output().map_err(|e| {
+        io::Error::new(e.kind(), format!("Failed to run gpgv (is gnupg installed?) -> {}", e))
+    })?;
+
+    let status = String::from_utf8_lossy(&result.stderr);
+    // gpgv exits with 2 when any signature can't be checked, which is fine
+    // if another one is valid (see parse_gpg_status)
+    let only_unchecked = result.status.code() == Some(2) && status.contains("[GNUPG:] ERRSIG ");
+    match parse_gpg_status(&status) {
+        Ok(signer) if result.status.success() || only_unchecked => Ok(Verification::verified(signer, keyring_name)),
+        Ok(_) => Err(unverified("gpgv failed".to_string())),
+        Err(reason) => Err(unverified(reason)),
+    }
+}
+
+/// Verifies a detached signature of some data.
+pub fn verify_detached(data: &[u8], signature: &[u8], keyring_name: &str) -> Result<Verification, Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let data_path = temp.0.join("data");
+    let signature_path = temp.0.join("data.sig");
+    fs::write(&data_path, data)?;
+    fs::write(&signature_path, signature)?;
+    gpgv(&find_keyring(keyring_name)?, keyring_name, &temp, &[&signature_path, &data_path], None)
+}
+
+/// Verifies a clearsigned file, giving back only the part that's signed.
+pub fn verify_clearsigned(signed: &[u8], keyring_name: &str) -> Result<(String, Verification), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let signed_path = temp.0.join("signed.asc");
+    let output_path = temp.0.join("output");
+    fs::write(&signed_path, signed)?;
+    let verification = gpgv(&find_keyring(keyring_name)?, keyring_name, &temp, &[&signed_path], Some(&output_path))?;
+    Ok((fs::read_to_string(&output_path)?, verification))
+}
+
+/// Verifies an image's checksum file with its signature, giving back the
+/// signed contents to get the checksums from.
+///
+/// ---
+pub fn verify_checksum_file(
+    client: &Client,
+    name: &str,
+    image: &CatalogImage,
+    serial: Option<&str>,
+    contents: String,
+) -> Result<(String, Verification), Box<dyn Error>> {
+    let spec = image
+        .signature
+        .as_ref()
+        .ok_or_else(|| unverified(format!("there's no signature for the checksums of {} in the catalog", name)))?;
+
+    let (contents, verification) = match spec.kind.as_str() {
+        "clearsigned" => verify_clearsigned(contents.as_bytes(), &spec.keyring)?,
+        "detached" => {
+            let url = spec
+                .url
+                .as_deref()
+                .map(|url| imageindex::url_for_serial(url, serial.unwrap_or_default()))
+                .ok_or_else(|| unverified(format!("the detached signature of {} has no url", name)))?;
+            println!("INFO: Fetching the signature from {}", url);
+            let (response, _) = fetch::get(client, &fetch::mirror_urls(&url, &image.mirrors))?;
+            let verification = verify_detached(contents.as_bytes(), &response.bytes()?, &spec.keyring)?;
+            (contents, verification)
+        }
+        other => return Err(unverified(format!("unknown signature type '{}' (detached or clearsigned)", other))),
+    };
+    println!("INFO: Checksums signed by {}", verification.signer.as_deref().unwrap_or("-"));
+    Ok((contents, verification))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cloudinit;
+
+    const FINGERPRINT: &str = "1144AAEF3226A67D6CA8A50A84DAB363467F0736";
+
+    fn validsig() -> String {
+        format!("[GNUPG:] VALIDSIG {} 2024-07-17 1721207323 0 4 0 22 8 00 {}\n", FINGERPRINT, FINGERPRINT)
+    }
+
+    #[test]
+    fn gpg_status_validsig_only() {
+        assert_eq!(parse_gpg_status(&validsig()), Ok(FINGERPRINT.to_string()));
+
+        let with_user = format!("[GNUPG:] GOODSIG 84DAB363467F0736 Test Key <test@example.org>\n{}", validsig());
+        assert_eq!(parse_gpg_status(&with_user), Ok(format!("{} (Test Key <test@example.org>)", FINGERPRINT)));
+    }
+
+    #[test]
+    fn gpg_status_badsig() {
+        let status = format!("[GNUPG:] BADSIG 84DAB363467F0736 Test Key <test@example.org>\n{}", validsig());
+        assert_eq!(parse_gpg_status(&status), Err("BAD signature".to_string()));
+    }
+
+    #[test]
+    fn gpg_status_expkeysig() {
+        let status = "[GNUPG:] EXPKEYSIG 84DAB363467F0736 Test Key <test@example.org>\n";
+        assert!(parse_gpg_status(status).unwrap_err().contains("expired"));
+    }
+
+    #[test]
+    fn gpg_status_errsig() {
+        let errsig = "[GNUPG:] ERRSIG E2836F4957EDD12D 22 8 00 1721207323 9 605133C32A9A205E773BC127E2836F4957EDD12D\n\
+                      [GNUPG:] NO_PUBKEY E2836F4957EDD12D\n";
+        assert!(parse_gpg_status(errsig).unwrap_err().contains("couldn't be checked"));
+
+        // signed by two keys and only one of them is in the keyring
+        let status = format!("[GNUPG:] NEWSIG\n{}[GNUPG:] NEWSIG\n{}", validsig(), errsig);
+        assert_eq!(parse_gpg_status(&status), Ok(FINGERPRINT.to_string()));
+    }
+
+    #[test]
+    fn gpg_status_nodata() {
+        assert_eq!(parse_gpg_status("[GNUPG:] NODATA 1\n"), Err("no signature found".to_string()));
+        assert_eq!(parse_gpg_status(""), Err("no valid signature".to_string()));
+    }
+
+    #[test]
+    fn dearmor_joins_blocks() {
+        let armored = "Some text before the keys\n\
+                       -----BEGIN PGP PUBLIC KEY BLOCK-----\n\
+                       Comment: first key\n\
+                       \n\
+                       Zmlyc3Qga2V5\n\
+                       =AbCd\n\
+                       -----END PGP PUBLIC KEY BLOCK-----\n\
+                       -----BEGIN PGP PUBLIC KEY BLOCK-----\n\
+                       \n\
+                       c2Vjb25k\n\
+                       IGtleQ==\n\
+                       =EfGh\n\
+                       -----END PGP PUBLIC KEY BLOCK-----\n";
+        assert_eq!(dearmor(armored), Some(b"first keysecond key".to_vec()));
+        assert_eq!(dearmor("no keys in here"), None);
+    }
+
+    /// Runs gpg in its own home directory.
+    fn gpg(home: &Path, args: &[&str]) -> Vec<u8> {
+        let output = Command::new("gpg")
+            .env("GNUPGHOME", home)
+            .args(["--batch", "--pinentry-mode", "loopback", "--passphrase", ""])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "gpg {:?} failed -> {}", args, String::from_utf8_lossy(&output.stderr));
+        output.stdout
+    }
+
+    #[test]
+    fn gpgv_round_trip() {
+        if !cloudinit::command_exists("gpg") || !cloudinit::command_exists("gpgv") {
+            eprintln!("gpg/gpgv isn't installed, skipping");
+            return;
+        }
+        let temp = TempDir::new().unwrap();
+        let home = temp.0.join("gnupg");
+        fs::create_dir(&home).unwrap();
+        fs::set_permissions(&home, std::os::unix::fs::PermissionsExt::from_mode(0o700)).unwrap();
+        for user in ["a@example.org", "b@example.org"] {
+            gpg(&home, &["--quick-gen-key", user, "ed25519", "sign", "never"]);
+        }
+        let keyring = temp.0.join("a.asc");
+        fs::write(&keyring, gpg(&home, &["--armor", "--export", "a@example.org"])).unwrap();
+
+        let data_path = temp.0.join("SHA256SUMS");
+        fs::write(&data_path, "0123abcd  image.qcow2\n").unwrap();
+        let data = data_path.to_str().unwrap();
+        let verify_detached = |signature: &[u8], data: &[u8]| {
+            let temp = TempDir::new().unwrap();
+            let (data_path, signature_path) = (temp.0.join("data"), temp.0.join("data.sig"));
+            fs::write(&data_path, data).unwrap();
+            fs::write(&signature_path, signature).unwrap();
+            gpgv(&keyring, "test", &temp, &[&signature_path, &data_path], None)
+        };
+
+        let signature = gpg(&home, &["-u", "a@example.org", "--detach-sign", "-o", "-", data]);
+        let verification = verify_detached(&signature, b"0123abcd  image.qcow2\n").unwrap();
+        assert_eq!(verification.status, "verified");
+        assert!(verification.signer.unwrap().contains("a@example.org"));
+        assert!(verify_detached(&signature, b"4567ef01  image.qcow2\n").is_err());
+
+        // one of the two signers is in the keyring
+        let both = gpg(&home, &["-u", "a@example.org", "-u", "b@example.org", "--detach-sign", "-o", "-", data]);
+        assert!(verify_detached(&both, b"0123abcd  image.qcow2\n").is_ok());
+
+        // none of them is
+        let other = gpg(&home, &["-u", "b@example.org", "--detach-sign", "-o", "-", data]);
+        assert!(verify_detached(&other, b"0123abcd  image.qcow2\n").is_err());
+
+        // a clearsigned file gives back only the signed part
+        let signed_path = temp.0.join("SHA256SUMS.asc");
+        fs::write(&signed_path, gpg(&home, &["-u", "a@example.org", "--clearsign", "-o", "-", data])).unwrap();
+        let output_path = temp.0.join("output");
+        gpgv(&keyring, "test", &temp, &[&signed_path], Some(&output_path)).unwrap();
+        assert_eq!(fs::read_to_string(&output_path).unwrap(), "0123abcd  image.qcow2\n");
+
+        let _ = Command::new("gpgconf").env("GNUPGHOME", &home).args(["--kill", "gpg-agent"]).status();
+    }
+}
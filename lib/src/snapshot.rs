@@ -0,0 +1,396 @@
+//! This file contains things related to VM disk snapshots.
+//!
+//! Snapshots are qcow2 internal snapshots of the VM disk and its attached
+//! qcow2 data disks (raw data disks can't have them and are left out, with a
+//! warning). The same disks are used whether the VM is running or not.
+//! `qemu-img snapshot` is used when the VM is stopped and the QMP `snapshot-save`/`snapshot-load`/`snapshot-delete`
+//! jobs are used when it's running (which also saves the VM's memory/device
+//! state so reverting puts the running VM back exactly where it was).
+//!
+//! Snapshots are recorded in the VM's entry in the autovirt config file
+//! (`snapshots`) with when they were made, a description and their parent
+//! (the snapshot the VM was at when they were made, `current_snapshot`).
+//!
+//! The VM's filesystems are frozen with the guest agent (if it's there) while
+//! a running VM is snapshotted.
+//!
+//! ---
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::error::Error;
+use std::io;
+use std::process::Command;
+
+use crate::disk;
+use crate::filesystem;
+use crate::guestagent;
+use crate::qmp;
+use crate::run;
+
+/// A snapshot as recorded in the VM's entry in the autovirt config file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub name: String,
+    pub created_at: String,
+    pub description: Option<String>,
+    pub parent: Option<String>,
+    /// Made while the VM was running (has the memory/device state too)
+    #[serde(default)]
+    pub live: bool,
+    /// The VM's filesystems were frozen by the guest agent while it was made
+    #[serde(default)]
+    pub frozen: bool,
+}
+
+/// Gets the snapshots recorded for a VM.
+pub fn load_snapshots(vm_name: &str) -> Vec<Snapshot> {
+    filesystem::get_value_from_autovirt_json(&format!("vms.{}.snapshots", vm_name))
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default()
+}
+
+fn save_snapshots(vm_name: &str, snapshots: &[Snapshot]) {
+    filesystem::insert_value_into_autovirt_json_object(
+        &format!("vms.{}.snapshots", vm_name),
+        serde_json::to_value(snapshots).expect("ERROR: Failed to jsonify snapshots"),
+    );
+}
+
+fn current_snapshot(vm_name: &str) -> Option<String> {
+    filesystem::get_value_from_autovirt_json(&format!("vms.{}.current_snapshot", vm_name))
+        .and_then(|v| v.as_str().map(String::from))
+}
+
+fn set_current_snapshot(vm_name: &str, snapshot_name: Option<&str>) {
+    filesystem::insert_value_into_autovirt_json_object(
+        &format!("vms.{}.current_snapshot", vm_name),
+        snapshot_name.map(|name| Value::String(name.to_string())).unwrap_or(Value::Null),
+    );
+}
+
+/// A disk that's part of a VM's snapshots.
+struct SnapshotDisk {
+    /// `main` or the data disk name (for messages)
+    name: String,
+    image_path: String,
+    /// The qcow2 node of the disk in the running VM (see blockdev.rs)
+    node: String,
+}
+
+/// Gets the disks a VM's snapshots are made of: the VM disk (which has to be
+/// qcow2) and its attached qcow2 data disks.
+///
+/// ---
+fn snapshot_disks(vm_name: &str) -> Result<Vec<SnapshotDisk>, Box<dyn Error>> {
+    let format = disk::vm_disk_format(vm_name)?;
+    if format != "qcow2" {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Snapshots need a qcow2 disk but VM '{}' has a {} disk (see `autovirt disk convert {} --to qcow2`)",
+                vm_name, format, vm_name
+            ),
+        )));
+    }
+    let mut disks = vec![SnapshotDisk {
+        name: "main".to_string(),
+        image_path: disk::vm_image_path(vm_name)?,
+        node: "main-fmt".to_string(),
+    }];
+
+    for data_disk in disk::load_data_disks(vm_name).into_iter().filter(|d| d.attached) {
+        if data_disk.format != "qcow2" {
+            println!(
+                "WARNING:: Data disk '{}' is {}, it isn't part of the snapshot",
+                data_disk.name, data_disk.format
+            );
+            continue;
+        }
+        disks.push(SnapshotDisk {
+            node: format!("data-{}-fmt", data_disk.name),
+            name: data_disk.name,
+            image_path: data_disk.path,
+        });
+    }
+    Ok(disks)
+}
+
+/// Gets the node names of disks (the `devices` of the QMP snapshot jobs).
+fn snapshot_nodes(disks: &[&SnapshotDisk]) -> Vec<String> {
+    disks.iter().map(|disk| disk.node.clone()).collect()
+}
+
+/// Gets the names of the snapshots that are actually in a disk image.
+fn disk_snapshot_names(image_path: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    Ok(disk::image_info(image_path)?
+        .get("snapshots")
+        .and_then(|s| s.as_array())
+        .map(|snapshots| {
+            snapshots
+                .iter()
+                .filter_map(|s| s.get("name").and_then(|n| n.as_str()).map(String::from))
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+/// Runs `qemu-img snapshot <flag> <name> <image>` (stopped VMs only).
+fn qemu_img_snapshot(flag: &str, snapshot_name: &str, image_path: &str) -> Result<(), Box<dyn Error>> {
+    let output = Command::new("qemu-img")
+        .args(["snapshot", flag, snapshot_name, image_path])
+        .output()?;
+    if !output.status.success() {
+        return Err(Box::new(io::Error::other(format!(
+            "qemu-img snapshot {} failed -> {}",
+            flag,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))));
+    }
+    Ok(())
+}
+
+fn find_snapshot(snapshots: &[Snapshot], snapshot_name: &str) -> Result<usize, Box<dyn Error>> {
+    snapshots.iter().position(|s| s.name == snapshot_name).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, format!("Snapshot '{}' not found", snapshot_name)).into()
+    })
+}
+
+fn confirm(prompt: &str) -> bool {
+    println!("{} (yes please/N)", prompt);
+    let mut user_input = String::new();
+    std::io::stdin().read_line(&mut user_input).expect("Failed to read user input");
+    user_input.trim() == "yes please"
+}
+
+/// Makes a snapshot of a VM's disk (and memory if the VM is running). A name
+/// based on the current time is used if none is given.
+///
+/// ---
+pub fn create_snapshot(
+    vm_name: &str,
+    snapshot_name: Option<&str>,
+    description: Option<&str>,
+) -> Result<String, Box<dyn Error>> {
+    let disks = snapshot_disks(vm_name)?;
+    let now = chrono::Local::now();
+    let snapshot_name = snapshot_name
+        .map(String::from)
+        .unwrap_or_else(|| format!("snap-{}", now.format("%Y%m%d-%H%M%S")));
+
+    let mut snapshots = load_snapshots(vm_name);
+    if snapshots.iter().any(|s| s.name == snapshot_name) {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("Snapshot '{}' already exists", snapshot_name),
+        )));
+    }
+
+    let live = run::is_vm_running(vm_name);
+    let mut frozen = false;
+    if live {
+        println!("LOG:: Saving snapshot of running VM {} -> {}", vm_name, snapshot_name);
+        let mut client = qmp::connect_vm(vm_name)?;
+        let nodes = snapshot_nodes(&disks.iter().collect::<Vec<_>>());
+        frozen = guestagent::try_freeze_filesystems(vm_name);
+        let saved = client.execute_job(
+            "snapshot-save",
+            &format!("autovirt-save-{}", snapshot_name),
+            json!({ "tag": snapshot_name, "vmstate": nodes[0], "devices": nodes }),
+        );
+        if frozen {
+            guestagent::try_thaw_filesystems(vm_name);
+        }
+        saved?;
+    } else {
+        println!("LOG:: Creating snapshot of VM {} -> {}", vm_name, snapshot_name);
+        for disk in &disks {
+            qemu_img_snapshot("-c", &snapshot_name, &disk.image_path)?;
+        }
+    }
+
+    snapshots.push(Snapshot {
+        name: snapshot_name.clone(),
+        created_at: now.to_rfc3339_opts(chrono::SecondsFormat::Secs, false),
+        description: description.map(String::from),
+        parent: current_snapshot(vm_name),
+        live,
+        frozen,
+    });
+    save_snapshots(vm_name, &snapshots);
+    set_current_snapshot(vm_name, Some(&snapshot_name));
+
+    println!("LOG:: Snapshot created -> {}", snapshot_name);
+    Ok(snapshot_name)
+}
+
+/// Prints the snapshots of a VM (snapshots recorded for the VM that aren't in
+/// the disk image anymore are marked).
+///
+/// ---
+pub fn list_snapshots(vm_name: &str) -> Result<(), Box<dyn Error>> {
+    let disks = snapshot_disks(vm_name)?;
+    let mut on_disks = Vec::new();
+    for disk in &disks {
+        on_disks.push(disk_snapshot_names(&disk.image_path)?);
+    }
+    let on_disk = &on_disks[0];
+    let current = current_snapshot(vm_name);
+    let snapshots = load_snapshots(vm_name);
+
+    if snapshots.is_empty() {
+        println!("INFO:: VM {} has no snapshots", vm_name);
+    }
+
+    for snapshot in &snapshots {
+        let mut flags = Vec::new();
+        if current.as_deref() == Some(snapshot.name.as_str()) {
+            flags.push("current");
+        }
+        if snapshot.live {
+            flags.push("live");
+        }
+        let missing: Vec<&str> = disks
+            .iter()
+            .zip(&on_disks)
+            .filter(|(_, names)| !names.contains(&snapshot.name))
+            .map(|(disk, _)| disk.name.as_str())
+            .collect();
+        let missing_flag = format!("MISSING FROM DISK {}", missing.join(", "));
+        if !missing.is_empty() {
+            flags.push(&missing_flag);
+        }
+
+        let mut line = format!(
+            "{}  {}  parent={}",
+            snapshot.name,
+            snapshot.created_at,
+            snapshot.parent.as_deref().unwrap_or("-")
+        );
+        if let Some(description) = &snapshot.description {
+            line.push_str(&format!("  \"{}\"", description));
+        }
+        if !flags.is_empty() {
+            line.push_str(&format!("  [{}]", flags.join(", ")));
+        }
+        println!("{}", line);
+    }
+
+    for name in on_disk.iter().filter(|name| !snapshots.iter().any(|s| &s.name == *name)) {
+        println!("{}  (not made by autovirt)", name);
+    }
+
+    Ok(())
+}
+
+/// Reverts a VM to a snapshot. Running VMs can only be reverted to live
+/// snapshots since the memory state is needed.
+///
+/// ---
+pub fn revert_snapshot(vm_name: &str, snapshot_name: &str) -> Result<(), Box<dyn Error>> {
+    let disks = snapshot_disks(vm_name)?;
+    let snapshots = load_snapshots(vm_name);
+    let snapshot = &snapshots[find_snapshot(&snapshots, snapshot_name)?];
+
+    let running = run::is_vm_running(vm_name);
+    if running && !snapshot.live {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Snapshot '{}' was made while the VM was stopped, stop the VM to revert to it", snapshot_name),
+        )));
+    }
+
+    // a disk added after the snapshot was made can't be reverted with the rest
+    for disk in &disks {
+        if !disk_snapshot_names(&disk.image_path)?.iter().any(|name| name == snapshot_name) {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Snapshot '{}' isn't on disk '{}' of VM {}", snapshot_name, disk.name, vm_name),
+            )));
+        }
+    }
+
+    if !confirm(&format!(
+        "Revert VM {} to snapshot {}? Changes since then will be lost.",
+        vm_name, snapshot_name
+    )) {
+        println!("!!! ABORTING SNAPSHOT REVERT !!!");
+        return Ok(());
+    }
+
+    if running {
+        let mut client = qmp::connect_vm(vm_name)?;
+        let nodes = snapshot_nodes(&disks.iter().collect::<Vec<_>>());
+        client.execute_job(
+            "snapshot-load",
+            &format!("autovirt-load-{}", snapshot_name),
+            json!({ "tag": snapshot_name, "vmstate": nodes[0], "devices": nodes }),
+        )?;
+        // the VM comes back with its filesystems frozen like they were when
+        // the snapshot was made
+        if snapshot.frozen {
+            guestagent::try_thaw_filesystems(vm_name);
+        }
+    } else {
+        for disk in &disks {
+            qemu_img_snapshot("-a", snapshot_name, &disk.image_path)?;
+        }
+    }
+
+    set_current_snapshot(vm_name, Some(snapshot_name));
+    println!("LOG:: VM {} reverted to snapshot -> {}", vm_name, snapshot_name);
+    Ok(())
+}
+
+/// Deletes a snapshot. Snapshots made on top of it get its parent as their
+/// parent.
+///
+/// ---
+pub fn delete_snapshot(vm_name: &str, snapshot_name: &str) -> Result<(), Box<dyn Error>> {
+    let disks = snapshot_disks(vm_name)?;
+    let mut snapshots = load_snapshots(vm_name);
+    let index = find_snapshot(&snapshots, snapshot_name)?;
+
+    if !confirm(&format!("Delete snapshot {} of VM {}?", snapshot_name, vm_name)) {
+        println!("!!! ABORTING SNAPSHOT DELETION !!!");
+        return Ok(());
+    }
+
+    // the snapshot may have been removed from (some of) the disks by hand
+    // already
+    let mut with_snapshot = Vec::new();
+    for disk in &disks {
+        if disk_snapshot_names(&disk.image_path)?.iter().any(|name| name == snapshot_name) {
+            with_snapshot.push(disk);
+        }
+    }
+    if !with_snapshot.is_empty() {
+        if run::is_vm_running(vm_name) {
+            let mut client = qmp::connect_vm(vm_name)?;
+            client.execute_job(
+                "snapshot-delete",
+                &format!("autovirt-delete-{}", snapshot_name),
+                json!({ "tag": snapshot_name, "devices": snapshot_nodes(&with_snapshot) }),
+            )?;
+        } else {
+            for disk in &with_snapshot {
+                qemu_img_snapshot("-d", snapshot_name, &disk.image_path)?;
+            }
+        }
+    }
+
+    let removed = snapshots.remove(index);
+    for snapshot in snapshots.iter_mut() {
+        if snapshot.parent.as_deref() == Some(snapshot_name) {
+            snapshot.parent = removed.parent.clone();
+        }
+    }
+    save_snapshots(vm_name, &snapshots);
+
+    if current_snapshot(vm_name).as_deref() == Some(snapshot_name) {
+        set_current_snapshot(vm_name, removed.parent.as_deref());
+    }
+
+    println!("LOG:: Snapshot deleted -> {}", snapshot_name);
+    Ok(())
+}
@@ -0,0 +1,228 @@
+//! This file contains things related to connecting to VMs over ssh.
+//!
+//! VMs use qemu's user networking so ssh goes through the host port that is
+//! forwarded to port 22 in the guest (the `hostfwd=tcp::2244-:22` port forward
+//! args given to create/run which are stored in the VM's entry).
+//!
+//! ---
+
+use std::error::Error;
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::filesystem;
+
+/// Everything needed to ssh into a VM.
+#[derive(Debug, Clone)]
+pub struct SshTarget {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    /// The private key to use (if the VM was created with a public key).
+    pub identity_file: Option<String>,
+}
+
+/// Gets the host address and port that forwards to a guest port from the
+/// port forward args (i.e. `hostfwd=tcp::2244-:22,hostfwd=tcp::8080-:80`).
+///
+/// The host address defaults to `127.0.0.1` when it isn't in the args.
+///
+/// ---
+pub fn find_host_forward(port_fwd_args: &str, guest_port: u16) -> Option<(String, u16)> {
+    for fwd in port_fwd_args.split(',') {
+        let fwd = match fwd.trim().strip_prefix("hostfwd=") {
+            Some(fwd) => fwd,
+            None => continue,
+        };
+
+        // hostfwd=[tcp|udp]:[hostaddr]:hostport-[guestaddr]:guestport
+        let (host_part, guest_part) = match fwd.split_once('-') {
+            Some(parts) => parts,
+            None => continue,
+        };
+
+        let host_fields: Vec<&str> = host_part.split(':').collect();
+        let (protocol, host_addr, host_port) = match host_fields.as_slice() {
+            [protocol, host_addr, host_port] => (*protocol, *host_addr, *host_port),
+            [host_addr, host_port] => ("tcp", *host_addr, *host_port),
+            _ => continue,
+        };
+
+        let fwd_guest_port = guest_part.rsplit(':').next().and_then(|p| p.parse::<u16>().ok());
+        if protocol != "udp" && fwd_guest_port == Some(guest_port) {
+            let host_addr = if host_addr.is_empty() { "127.0.0.1" } else { host_addr };
+            if let Ok(host_port) = host_port.parse::<u16>() {
+                return Some((host_addr.to_string(), host_port));
+            }
+        }
+    }
+
+    None
+}
+
+/// Gets the private key path for a public key path (`~/.ssh/id_ed25519.pub`
+/// -> `~/.ssh/id_ed25519`) if it exists.
+///
+/// ---
+pub fn private_key_for(public_key_path: &str) -> Option<String> {
+    if public_key_path.is_empty() || public_key_path == "none" {
+        return None;
+    }
+
+    let private_key_path = public_key_path.strip_suffix(".pub").unwrap_or(public_key_path);
+    if Path::new(private_key_path).is_file() {
+        Some(private_key_path.to_string())
+    } else {
+        None
+    }
+}
+
+/// Gets the ssh details for a VM from its entry in the autovirt config file.
+///
+/// ---
+pub fn ssh_target_for_vm(vm_name: &str) -> Result<SshTarget, Box<dyn Error>> {
+    let vm_data = filesystem::get_value_from_autovirt_json(&format!("vms.{}", vm_name))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("VM '{}' not found", vm_name)))?;
+
+    let ports = vm_data.get("ports").and_then(|v| v.as_str()).unwrap_or("");
+    let (host, port) = find_host_forward(ports, 22).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("VM '{}' has no port forward to ssh (i.e. -> 'hostfwd=tcp::2244-:22')", vm_name),
+        )
+    })?;
+
+    let user = vm_data.get("user").and_then(|v| v.as_str()).unwrap_or("root").to_string();
+    let identity_file = vm_data
+        .get("ssh_key")
+        .and_then(|v| v.as_str())
+        .and_then(private_key_for);
+
+    Ok(SshTarget { host, port, user, identity_file })
+}
+
+/// Checks that ssh can log into a VM without asking for a password, ssh runs
+/// with `BatchMode=yes` so a VM made without a key (`ssh_key: "none"`) can
+/// only be logged into with a key from the ssh agent.
+///
+/// This fails right away instead of waiting on logins that can't work.
+///
+/// ---
+pub fn check_batch_login(vm_name: &str) -> Result<(), Box<dyn Error>> {
+    let ssh_key = filesystem::get_value_from_autovirt_json(&format!("vms.{}.ssh_key", vm_name))
+        .and_then(|v| v.as_str().map(String::from))
+        .unwrap_or_default();
+
+    if ssh_key == "none" && std::env::var_os("SSH_AUTH_SOCK").is_none() {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "VM '{}' was created without an ssh key and there's no ssh agent (SSH_AUTH_SOCK) to log in with, \
+                 recreate it with --key or start an agent with the key the VM accepts",
+                vm_name
+            ),
+        )));
+    }
+    Ok(())
+}
+
+/// The common options for the ssh and scp commands.
+///
+/// Host key checking is turned off since every VM gets new host keys and the
+/// forwarded ports get reused all the time.
+///
+/// ---
+fn common_ssh_args(target: &SshTarget) -> Vec<String> {
+    let mut args = vec![
+        "-o".to_string(),
+        "StrictHostKeyChecking=no".to_string(),
+        "-o".to_string(),
+        "UserKnownHostsFile=/dev/null".to_string(),
+        "-o".to_string(),
+        "LogLevel=ERROR".to_string(),
+        "-o".to_string(),
+        "BatchMode=yes".to_string(),
+        "-o".to_string(),
+        "ConnectTimeout=5".to_string(),
+    ];
+
+    if let Some(identity_file) = &target.identity_file {
+        args.push("-i".to_string());
+        args.push(identity_file.clone());
+    }
+
+    args
+}
+
+/// Builds an ssh command to the VM (the remote command still has to be added).
+pub fn ssh_command(target: &SshTarget) -> Command {
+    let mut cmd = Command::new("ssh");
+    cmd.args(common_ssh_args(target))
+        .arg("-p")
+        .arg(target.port.to_string())
+        .arg(format!("{}@{}", target.user, target.host));
+    cmd
+}
+
+/// Builds an scp command that uploads a local file/directory to the VM.
+pub fn scp_upload_command(target: &SshTarget, source: &str, destination: &str) -> Command {
+    let mut cmd = Command::new("scp");
+    cmd.args(common_ssh_args(target))
+        .arg("-r")
+        .arg("-P")
+        .arg(target.port.to_string())
+        .arg(source)
+        .arg(format!("{}@{}:{}", target.user, target.host, destination));
+    cmd
+}
+
+/// Checks if something is accepting tcp connections on the host/port.
+pub fn port_is_open(host: &str, port: u16) -> bool {
+    let addrs = match (host, port).to_socket_addrs() {
+        Ok(addrs) => addrs,
+        Err(_) => return false,
+    };
+
+    addrs.into_iter().any(|addr| TcpStream::connect_timeout(&addr, Duration::from_secs(2)).is_ok())
+}
+
+/// Waits until the VM can actually be logged into over ssh (the port being
+/// open isn't enough since sshd in the guest comes up before cloud-init has
+/// added the user).
+///
+/// ---
+pub fn wait_for_ssh(target: &SshTarget, timeout: Duration) -> Result<(), Box<dyn Error>> {
+    let started = Instant::now();
+
+    while started.elapsed() < timeout {
+        if port_is_open(&target.host, target.port) {
+            let logged_in = ssh_command(target)
+                .arg("true")
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .map(|s| s.success())
+                .unwrap_or(false);
+
+            if logged_in {
+                return Ok(());
+            }
+        }
+        thread::sleep(Duration::from_secs(3));
+    }
+
+    Err(Box::new(io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!(
+            "Timed out after {}s waiting for ssh on {}:{}",
+            timeout.as_secs(),
+            target.host,
+            target.port
+        ),
+    )))
+}
@@ -0,0 +1,323 @@
+//! This file contains things for unpacking compressed cloud images while
+//! they are downloaded (`.qcow2.xz`, `.raw.xz`, `.img.gz`, `.tar.gz` etc.) so
+//! only the image itself ever ends up in `_data/downloads`.
+//!
+//! The download is read once: it goes through a hasher (for checksum files
+//! that list the compressed file) and then through the decompressor (and tar
+//! for tarballs) straight into the image file.
+//!
+//! ---
+
+use sha2::{Digest, Sha256, Sha512};
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+
+/// The compressions a catalog image can have.
+pub const COMPRESSIONS: [&str; 8] = ["none", "xz", "gz", "zst", "tar", "tar.gz", "tar.xz", "tar.zst"];
+
+/// The extensions of the files in a tarball that are taken to be the image
+/// (when the catalog doesn't name the file).
+///
+/// ---
+const IMAGE_EXTENSIONS: [&str; 4] = [".img", ".raw", ".qcow2", ".vmdk"];
+
+enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+/// A reader that hashes everything read through it.
+pub struct HashingReader<R: Read> {
+    inner: R,
+    hasher: Hasher,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R, checksum_type: &str) -> Result<HashingReader<R>, Box<dyn Error>> {
+        let hasher = match checksum_type {
+            "sha256" => Hasher::Sha256(Sha256::new()),
+            "sha512" => Hasher::Sha512(Sha512::new()),
+            other => {
+                return Err(Box::new(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Unknown checksum type '{}' (sha256 or sha512)", other),
+                )))
+            }
+        };
+        Ok(HashingReader { inner, hasher })
+    }
+
+    /// Reads whatever is left (so the hash covers all of it) and gets the hash
+    /// as hex.
+    ///
+    /// ---
+    pub fn finish(mut self) -> io::Result<String> {
+        io::copy(&mut self, &mut io::sink())?;
+        let digest = match self.hasher {
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha512(hasher) => hasher.finalize().to_vec(),
+        };
+        Ok(digest.iter().map(|b| format!("{:02x}", b)).collect())
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        match &mut self.hasher {
+            Hasher::Sha256(hasher) => hasher.update(&buf[..read]),
+            Hasher::Sha512(hasher) => hasher.update(&buf[..read]),
+        }
+        Ok(read)
+    }
+}
+
+/// Checks a compression is one that can be unpacked.
+pub fn check_compression(compression: &str) -> Result<(), Box<dyn Error>> {
+    if !COMPRESSIONS.contains(&compression) {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Unknown compression '{}' (one of -> {})", compression, COMPRESSIONS.join(", ")),
+        )));
+    }
+    Ok(())
+}
+
+/// The name of the file a download unpacks to, which is what the checksum
+/// file lists when the image (not the download) is checksummed.
+///
+/// ---
+pub fn unpacked_file_name(download_name: &str, compression: &str, archive_member: Option<&str>) -> String {
+    if let Some(member) = archive_member {
+        return member.rsplit('/').next().unwrap_or(member).to_string();
+    }
+    let suffix = match compression {
+        "none" | "tar" => return download_name.to_string(),
+        "tar.gz" => &[".tar.gz", ".tgz"][..],
+        "tar.xz" => &[".tar.xz", ".txz"][..],
+        "tar.zst" => &[".tar.zst"][..],
+        "xz" => &[".xz"][..],
+        "gz" => &[".gz"][..],
+        "zst" => &[".zst"][..],
+        _ => &[][..],
+    };
+    suffix
+        .iter()
+        .find_map(|s| download_name.strip_suffix(s))
+        .unwrap_or(download_name)
+        .to_string()
+}
+
+/// Wraps a reader in the decompressor for a compression.
+fn decoder<'a, R: Read + 'a>(reader: R, codec: &str) -> Result<Box<dyn Read + 'a>, Box<dyn Error>> {
+    Ok(match codec {
+        "xz" => Box::new(xz2::read::XzDecoder::new_multi_decoder(reader)),
+        "gz" => Box::new(flate2::read::MultiGzDecoder::new(reader)),
+        "zst" => Box::new(zstd::stream::read::Decoder::new(reader)?),
+        _ => Box::new(reader),
+    })
+}
+
+/// Unpacks a (compressed) download into `out_path`, returning the name of
+/// the file taken out of the tarball for tarballs.
+///
+/// The image in a tarball is `archive_member` if given, otherwise the first
+/// file that looks like a disk image (`disk.raw` in GCE style tarballs).
+///
+/// ---
+pub fn unpack<R: Read>(
+    reader: R,
+    compression: &str,
+    archive_member: Option<&str>,
+    out_path: &Path,
+) -> Result<Option<String>, Box<dyn Error>> {
+    check_compression(compression)?;
+    let (is_tar, codec) = match compression.strip_prefix("tar") {
+        Some(rest) => (true, rest.trim_start_matches('.')),
+        None => (false, compression),
+    };
+    let mut stream = decoder(reader, codec)?;
+
+    if !is_tar {
+        let mut out = BufWriter::new(File::create(out_path)?);
+        io::copy(&mut stream, &mut out)?;
+        out.flush()?;
+        return Ok(None);
+    }
+
+    let mut archive = tar::Archive::new(stream);
+    let mut seen = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path()?.to_string_lossy().trim_start_matches("./").to_string();
+        let wanted = match archive_member {
+            Some(member) => path == member.trim_start_matches("./") || path.rsplit('/').next() == Some(member),
+            None => IMAGE_EXTENSIONS.iter().any(|ext| path.ends_with(ext)),
+        };
+        if !wanted {
+            seen.push(path);
+            continue;
+        }
+
+        println!("INFO: Extracting {} from the tarball", path);
+        let mut out = BufWriter::new(File::create(out_path)?);
+        io::copy(&mut entry, &mut out)?;
+        out.flush()?;
+        return Ok(Some(path));
+    }
+
+    Err(Box::new(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!(
+            "No {} in the tarball (files -> {})",
+            archive_member.unwrap_or("disk image"),
+            if seen.is_empty() { "-".to_string() } else { seen.join(", ") }
+        ),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// Something that compresses a bit but isn't all the same byte.
+    fn image_bytes() -> Vec<u8> {
+        (0..300_000u32).map(|i| (i % 251) as u8 ^ (i / 4096) as u8).collect()
+    }
+
+    fn out_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("autovirt-unpack-test-{}-{}", std::process::id(), name))
+    }
+
+    fn xz(data: &[u8]) -> Vec<u8> {
+        let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn gz(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn zst(data: &[u8]) -> Vec<u8> {
+        zstd::encode_all(data, 0).unwrap()
+    }
+
+    fn tarball(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, path, *data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn unpack_to_vec(download: &[u8], compression: &str, member: Option<&str>) -> (Option<String>, Vec<u8>) {
+        let out = out_path(&format!("{}-{}", compression, member.unwrap_or("-").replace('/', "_")));
+        let taken = unpack(download, compression, member, &out).unwrap();
+        let unpacked = fs::read(&out).unwrap();
+        fs::remove_file(&out).unwrap();
+        (taken, unpacked)
+    }
+
+    #[test]
+    fn unpacks_every_compression() {
+        let image = image_bytes();
+        let tar = tarball(&[("./README.txt", b"not the image"), ("./disk.raw", &image)]);
+        let downloads = [
+            ("none", image.clone()),
+            ("xz", xz(&image)),
+            ("gz", gz(&image)),
+            ("zst", zst(&image)),
+            ("tar", tar.clone()),
+            ("tar.gz", gz(&tar)),
+            ("tar.xz", xz(&tar)),
+            ("tar.zst", zst(&tar)),
+        ];
+        assert_eq!(downloads.len(), COMPRESSIONS.len());
+
+        for (compression, download) in downloads {
+            let (taken, unpacked) = unpack_to_vec(&download, compression, None);
+            assert!(unpacked == image, "{} didn't unpack to the image", compression);
+            let expected = compression.starts_with("tar").then(|| "disk.raw".to_string());
+            assert_eq!(taken, expected, "{}", compression);
+        }
+    }
+
+    #[test]
+    fn unpacks_multi_stream_files() {
+        // pigz/pixz style files are several streams one after another
+        let image = image_bytes();
+        let (first, second) = image.split_at(100_000);
+        for (compression, download) in [("gz", [gz(first), gz(second)].concat()), ("xz", [xz(first), xz(second)].concat())] {
+            let (_, unpacked) = unpack_to_vec(&download, compression, None);
+            assert!(unpacked == image, "{} stopped after the first stream", compression);
+        }
+    }
+
+    #[test]
+    fn tarball_member_is_picked_by_path_or_name() {
+        let tar = gz(&tarball(&[("images/a.qcow2", b"a"), ("images/b.qcow2", b"b")]));
+
+        assert_eq!(unpack_to_vec(&tar, "tar.gz", None), (Some("images/a.qcow2".to_string()), b"a".to_vec()));
+        assert_eq!(unpack_to_vec(&tar, "tar.gz", Some("images/b.qcow2")), (Some("images/b.qcow2".to_string()), b"b".to_vec()));
+        assert_eq!(unpack_to_vec(&tar, "tar.gz", Some("b.qcow2")), (Some("images/b.qcow2".to_string()), b"b".to_vec()));
+
+        let out = out_path("missing-member");
+        let error = unpack(&tar[..], "tar.gz", Some("c.qcow2"), &out).unwrap_err().to_string();
+        assert!(error.contains("images/a.qcow2, images/b.qcow2"), "{}", error);
+        assert!(!out.exists());
+    }
+
+    #[test]
+    fn broken_and_unknown_downloads_fail() {
+        let out = out_path("broken");
+        assert!(unpack(&b"not xz at all"[..], "xz", None, &out).is_err());
+        assert!(unpack(&gz(b"no image in here")[..], "tar.gz", None, &out).is_err());
+        assert!(unpack(&b"x"[..], "rar", None, &out).is_err());
+        let _ = fs::remove_file(&out);
+    }
+
+    #[test]
+    fn hashing_reader_hashes_all_of_the_download() {
+        let download = xz(&image_bytes());
+        let sha256: String = Sha256::digest(&download).iter().map(|b| format!("{:02x}", b)).collect();
+        let sha512: String = Sha512::digest(&download).iter().map(|b| format!("{:02x}", b)).collect();
+
+        // the xz stream is done before the end of the file is read, finish
+        // reads the rest so it's still part of the hash
+        let out = out_path("hashing");
+        let mut reader = HashingReader::new(&download[..], "sha256").unwrap();
+        unpack(&mut reader, "xz", None, &out).unwrap();
+        assert_eq!(reader.finish().unwrap(), sha256);
+        fs::remove_file(&out).unwrap();
+
+        assert_eq!(HashingReader::new(&download[..], "sha512").unwrap().finish().unwrap(), sha512);
+        assert!(HashingReader::new(&download[..], "md5").is_err());
+    }
+
+    #[test]
+    fn unpacked_file_names() {
+        assert_eq!(unpacked_file_name("debian.qcow2.xz", "xz", None), "debian.qcow2");
+        assert_eq!(unpacked_file_name("image.img.gz", "gz", None), "image.img");
+        assert_eq!(unpacked_file_name("image.raw.zst", "zst", None), "image.raw");
+        assert_eq!(unpacked_file_name("image.img", "none", None), "image.img");
+        assert_eq!(unpacked_file_name("gce.tar.gz", "tar.gz", None), "gce");
+        assert_eq!(unpacked_file_name("gce.tgz", "tar.gz", None), "gce");
+        assert_eq!(unpacked_file_name("gce.tar.gz", "tar.gz", Some("images/disk.raw")), "disk.raw");
+        // a name without the suffix is kept as is
+        assert_eq!(unpacked_file_name("download", "xz", None), "download");
+    }
+}
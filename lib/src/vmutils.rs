@@ -7,14 +7,24 @@
 //!
 //! ---
 
-use std::fmt::format;
 use std::process::Command;
+use std::error::Error;
 use std::fs;
+use std::io;
 use std::path::PathBuf;
 use serde_json::Value;
 use colored::*;
 
+use crate::catalog;
+use crate::cloudinit;
+use crate::disk;
+use crate::diskspace;
 use crate::filesystem;
+use crate::hotplug;
+use crate::images;
+use crate::inventory;
+use crate::run;
+use crate::selector;
 
 /// This function is used to get the checksum of a specified image file.
 ///
@@ -32,21 +42,34 @@ pub fn get_image_checksum(file: &String) {
 }
 
 
-/// Shows available VM images (using the autovirt json config file)
+/// Shows available VM images (the image catalog, see catalog.rs), which of
+/// them are downloaded already and which are unsigned (need `--insecure`).
+///
+/// ---
 pub fn show_available_images() {
-    let autovirt_json_path = filesystem::get_autovirt_json_path();
-    let autovirt_config = fs::read_to_string(&autovirt_json_path)
-        .and_then(|content| serde_json::from_str::<Value>(&content).map_err(Into::into))
-        .expect("ERROR: Failed to read autovirt.json");
-
-    if let Some(images) = autovirt_config.get("images").and_then(|v| v.as_object()) {
-        // loop through the images and only print out the images and nothing
-        // under the level of the images key
-        for (image_name, _) in images {
-            println!("- {}", image_name.color("green"));
-        }
-    } else {
+    let catalog_images = catalog::all_images();
+    if catalog_images.is_empty() {
         println!("No images found.");
+        return;
+    }
+
+    for (image_name, image) in &catalog_images {
+        let downloaded = images::base_image_path(image_name, image).is_ok_and(|path| path.is_file());
+        println!(
+            "- {} {}",
+            format!("{:<14}", image_name).color("green"),
+            image.description
+        );
+        println!(
+            "    {} family: {}, format: {}, default user: {}{}{}",
+            "└─".color("white"),
+            image.os_family.color("magenta"),
+            image.format.color("cyan"),
+            image.default_user.as_deref().unwrap_or("-").color("yellow"),
+            if downloaded { ", downloaded".color("green").to_string() } else { String::new() },
+            // there's nothing to verify the download with (see signature.rs)
+            if image.signature.is_none() { ", unsigned (needs --insecure)".color("red").to_string() } else { String::new() },
+        );
     }
 }
 
@@ -65,7 +88,6 @@ pub fn get_vm_info_by_name(vm_name: &String, raw_output: bool) {
     if raw_output {
         let vm_details_raw_json =  filesystem::get_value_from_autovirt_json(&format!("vms.{}", vm_name));
         println!("{}", vm_details_raw_json.unwrap_or_default());
-        return;
     } else {
 
     // let vm_details_raw_json =  filesystem::get_value_from_autovirt_json(&format!("vms.{}", vm_name));
@@ -91,10 +113,11 @@ pub fn get_vm_info_by_name(vm_name: &String, raw_output: bool) {
 }
 
 
-/// Function to list all the currently installed vms
+/// Function to list all the currently installed vms (or just the ones matching
+/// a selector)
 ///
 /// ---
-pub fn list_vms() {
+pub fn list_vms(selector: Option<&selector::Selector>) {
     // Load the autovirt.json configuration
     let autovirt_json_path = filesystem::get_autovirt_json_path();
     let autovirt_config = fs::read_to_string(&autovirt_json_path)
@@ -105,6 +128,10 @@ pub fn list_vms() {
 
     if let Some(vms) = autovirt_config.get("vms").and_then(|v| v.as_object()) {
         for (vm_name, vm_data) in vms {
+            if selector.is_some_and(|s| !s.matches(vm_data)) {
+                continue;
+            }
+
             let distro = vm_data.get("distro").and_then(Value::as_str).unwrap_or("Unknown distro");
             let size = vm_data.get("size").and_then(Value::as_str).unwrap_or("Unknown size");
             let memory_mb = vm_data.get("memory_mb").and_then(Value::as_str).unwrap_or("Unknown memory");
@@ -113,9 +140,30 @@ pub fn list_vms() {
             println!("{}", format!("├── {}", vm_name).color("green"));
             println!("{}{}", "│   ├── DISTRO: ".color("white"), distro.color("magenta"));
             println!("{}{}{}", "│   ├── SIZE: ".color("white"), size.color("cyan"), " G".color("cyan"));
-            println!("{}{}{}", "│   └── MEMORY: ".color("white"), memory_mb.color("yellow"), " Mb".color("yellow"));
-            println!("{}{}{}", "│   └── CPUS: ".color("white"), vcpus.color("green"), " vCPUs".color("green"));
-            // println!("{}{}", "│   └── MEMORY: ".color("white"), memory_mb.truecolor(63, 00, 189));
+            println!("{}{}{}", "│   ├── MEMORY: ".color("white"), memory_mb.color("yellow"), " Mb".color("yellow"));
+            println!("{}{}{}", "│   ├── CPUS: ".color("white"), vcpus.color("green"), " vCPUs".color("green"));
+
+            let tags = vm_data
+                .get("tags")
+                .and_then(Value::as_object)
+                .map(|tags| {
+                    tags.iter()
+                        .map(|(key, value)| format!("{}={}", key, value.as_str().unwrap_or_default()))
+                        .collect::<Vec<String>>()
+                        .join(",")
+                })
+                .unwrap_or_default();
+            let groups = vm_data
+                .get("groups")
+                .and_then(Value::as_array)
+                .map(|groups| groups.iter().filter_map(Value::as_str).collect::<Vec<&str>>().join(","))
+                .unwrap_or_default();
+            let state = if run::is_vm_running(vm_name) { "running".color("green") } else { "stopped".color("red") };
+
+            println!("{}{}", "│   ├── TAGS: ".color("white"), tags.color("blue"));
+            println!("{}{}", "│   ├── GROUPS: ".color("white"), groups.color("blue"));
+            println!("{}{}", "│   └── STATE: ".color("white"), state);
+            // println!("{}{}", "│   ├── MEMORY: ".color("white"), memory_mb.truecolor(63, 00, 189));
         }
         println!("{}", "└── (End of VMs)".color("white"));
     } else {
@@ -151,15 +199,19 @@ pub fn list_vms() {
     // }
 
 
-/// Func to delete the vm image file based on the args passed to this function
-/// and also updates the `autovirtt.json` config file with the new data (deosnt
-/// include the deleted vm details).
+/// Deletes VMs (asks once for all of them). Running VMs can't be deleted so
+/// nothing is deleted if any of them are running.
 ///
 /// ---
-pub fn delete_vm(vm_name: &String) {
+pub fn delete_vms(vm_names: &[String]) {
+    let running: Vec<&String> = vm_names.iter().filter(|vm_name| run::is_vm_running(vm_name)).collect();
+    if !running.is_empty() {
+        eprintln!("ERROR: Stop these VMs before deleting them -> {:?}", running);
+        std::process::exit(1);
+    }
 
-    // prompt the user to confirm to delete the vm
-    println!("Are you sure you want to delete the VM: {}? (yes please/N)", vm_name);
+    // prompt the user to confirm to delete the vms
+    println!("Are you sure you want to delete the VM(s): {}? (yes please/N)", vm_names.join(", "));
     let mut user_input = String::new();
     std::io::stdin().read_line(&mut user_input).expect("Failed to read user input");
 
@@ -168,6 +220,19 @@ pub fn delete_vm(vm_name: &String) {
         return;
     }
 
+    for vm_name in vm_names {
+        println!("Deleting VM (name): {}", vm_name);
+        delete_vm(vm_name);
+    }
+}
+
+/// Func to delete the vm image file based on the args passed to this function
+/// and also updates the `autovirtt.json` config file with the new data (deosnt
+/// include the deleted vm details).
+///
+/// ---
+fn delete_vm(vm_name: &String) {
+
     // get vm img ptah from the conf file
     let vm_image_path = filesystem::get_value_from_autovirt_json(&format!("vms.{}.image_path", vm_name))
         .and_then(|v| v.as_str().map(String::from))
@@ -184,6 +249,21 @@ pub fn delete_vm(vm_name: &String) {
         eprintln!("ERROR: VM image file not found -> {:?}", vm_image_path_buf);
     }
 
+    // and its data disks
+    for data_disk in disk::load_data_disks(vm_name) {
+        if PathBuf::from(&data_disk.path).exists() {
+            fs::remove_file(&data_disk.path).expect("ERROR: Failed to delete VM data disk");
+            println!("LOG:: VM data disk deleted -> {}", data_disk.path);
+        }
+    }
+
+    // and its data directory (cloud-init files, extra user-data parts, logs and sockets)
+    let vm_data_dir = filesystem::vm_data_dir_path(vm_name).expect("ERROR: Could not find VM data directory");
+    if vm_data_dir.exists() {
+        fs::remove_dir_all(&vm_data_dir).expect("ERROR: Failed to delete VM data directory");
+        println!("LOG:: VM data directory deleted -> {:?}", vm_data_dir);
+    }
+
     // removing the vm entry from the autovirt config file
     let autovirt_json_path = filesystem::get_autovirt_json_path();
     let mut autovirt_config = fs::read_to_string(&autovirt_json_path)
@@ -198,6 +278,7 @@ pub fn delete_vm(vm_name: &String) {
                 serde_json::to_string_pretty(&autovirt_config).expect("ERROR: Failed to jsonifyyy updated config"),
             ).expect("ERROR: Failed to write updated autovirt.json conf file");
             println!("LOG:: VM entry deleted from conf file -> {}", vm_name);
+            inventory::sync_managed_ssh_config_or_warn();
         } else {
             eprintln!("ERROR: VM entry not found in autovirt.json conifig file -> {}", vm_name);
         }
@@ -215,11 +296,31 @@ pub fn resize_vm(
     vm_disk_resize_args: &String,
     vm_memory_resize_args: &String,
     vm_cpus_resize_args: &String,
+    vm_max_cpus: Option<u64>,
+    vm_max_memory_mb: Option<u64>,
 ) {
+    let unchanged = |arg: &String| arg == "0" || arg == "none";
+    let current = |key: &str| {
+        filesystem::get_value_from_autovirt_json(&format!("vms.{}.{}", vm_name, key))
+            .and_then(|v| v.as_str().map(String::from))
+            .unwrap_or_default()
+    };
+    let new_cpus = if unchanged(vm_cpus_resize_args) { current("cpus") } else { vm_cpus_resize_args.clone() };
+    let new_memory = if unchanged(vm_memory_resize_args) { current("memory_mb") } else { vm_memory_resize_args.clone() };
+    if let Err(e) = hotplug::check_sizes(&new_cpus, &new_memory, vm_max_cpus, vm_max_memory_mb) {
+        eprintln!("ERROR: {}", e);
+        std::process::exit(1);
+    }
+    let running = run::is_vm_running(vm_name);
+
     println!("Resizing VM...");
     println!("First resizing the disk...");
     println!("If 0/none provided for the disk then it will stay the same.");
-    println!("The rest of the VM (cpus, memory, etc) will be simply updated\nin the autovirt.json file and the vm will have to be stopped and started \nagain for the changes to take into effect.");
+    if running {
+        println!("The VM is running so the disk, cpus and memory are changed live as far as the\nVM was started with room for (the rest is used from the next boot).");
+    } else {
+        println!("The rest of the VM (cpus, memory, etc) will be simply updated\nin the autovirt.json file and the vm will have to be stopped and started \nagain for the changes to take into effect.");
+    }
 
 
     println!("Proceed? (yes please/N)");
@@ -245,9 +346,23 @@ pub fn resize_vm(
 
     let vm_disk_size_formatted = format!("+{}G", vm_disk_resize_args);
 
+    // the image is locked while the VM runs so qemu grows it itself
+    if running && !unchanged(vm_disk_resize_args) {
+        match vm_disk_resize_args.parse::<u64>() {
+            Ok(add_gb) => {
+                if let Err(e) = hotplug::grow_running_disk(vm_name, add_gb) {
+                    eprintln!("ERROR: Failed to resize disk -> {}", e);
+                }
+            }
+            Err(_) => eprintln!("ERROR: Invalid disk size -> {}", vm_disk_resize_args),
+        }
+    } else if !running {
     // if vm_disk_resize_args != "+0" && vm_disk_resize_args != "none" {
+        let vm_disk_format = disk::vm_disk_format(vm_name).expect("ERROR: Could not find out the VM disk format");
         let mut cmd = Command::new("qemu-img");
         cmd.arg("resize");
+        cmd.arg("-f");
+        cmd.arg(&vm_disk_format);
         cmd.arg(&vm_image_path);
         cmd.arg(&vm_disk_size_formatted);
 
@@ -259,6 +374,7 @@ pub fn resize_vm(
         }
         // println!("Disk resize output: {}", output.to_);
     // }
+    }
 
     // Updating the autovirt.json file with the new vm size, memory and CPUs
     let autovirt_json_path = filesystem::get_autovirt_json_path();
@@ -279,15 +395,12 @@ pub fn resize_vm(
                 vm_data["cpus"] = serde_json::Value::String(vm_cpus_resize_args.clone());
             }
 
-            // update disk size
-            if vm_disk_size_formatted != "+0" && vm_disk_size_formatted != "none" {
-                // add the original disk size to the new disk size to get the
-                // new disk size
-                let new_disk_size = vm_data.get("size").and_then(Value::as_str).unwrap_or("0");
-                let new_disk_size_int: i32 = new_disk_size.parse().unwrap();
-                let vm_disk_resize_args_int: i32 = vm_disk_resize_args.parse().unwrap();
-                let new_disk_size_final = new_disk_size_int + vm_disk_resize_args_int;
-                vm_data["size"] = serde_json::Value::String(new_disk_size_final.to_string());
+            // update the room the VM is started with for live resizes
+            if let Some(max_cpus) = vm_max_cpus {
+                vm_data["max_cpus"] = serde_json::Value::String(max_cpus.to_string());
+            }
+            if let Some(max_memory_mb) = vm_max_memory_mb {
+                vm_data["max_memory_mb"] = serde_json::Value::String(max_memory_mb.to_string());
             }
 
             // Updating the autovirt.json file with the new shit
@@ -296,6 +409,23 @@ pub fn resize_vm(
                 serde_json::to_string_pretty(&autovirt_config).expect("ERROR: Failed to jsonifyyy updated config"),
             ).expect("ERROR: Failed to write updated autovirt.json conf file");
             println!("LOG:: VM resized in autovirt.json conf file -> {}", vm_name);
+
+            // the size is what the disk really is now rather than the sum of
+            // what it was grown by (which drifts)
+            if let Err(e) = diskspace::record_vm_disk_size(vm_name) {
+                eprintln!("ERROR: Failed to record the new disk size -> {}", e);
+            }
+
+            if running {
+                let parse = |arg: &String| if unchanged(arg) { None } else { arg.parse().ok() };
+                if let Err(e) = hotplug::resize_running_vm(
+                    vm_name,
+                    parse(vm_cpus_resize_args),
+                    parse(vm_memory_resize_args),
+                ) {
+                    eprintln!("ERROR: Failed to resize the running VM (the new sizes are used from the next boot) -> {}", e);
+                }
+            }
         } else {
             eprintln!("ERROR: VM entry not found in autovirt.json conifig file -> {}", vm_name);
         }
@@ -312,6 +442,11 @@ pub fn resize_vm(
 /// This will create a duplicate entry in the autovirt json file along with the
 /// new name and will update the image path + changes the image name in the path
 /// and the name of the vm in the new duplicate entry.
+///
+/// The data disks, the extra user-data and the user-data parts are copied for
+/// the new VM as well.
+///
+/// ---
 pub fn clone_vm(vm_name: &String, vm_new_name: &String) {
     println!("LOG:: Cloning VM...");
 
@@ -344,11 +479,71 @@ pub fn clone_vm(vm_name: &String, vm_new_name: &String) {
     // update the json config file contents with the new vm name and new vm path
     // with the new vm path having the new vm name instead of the current vm name
 
-    let new_vm_image_path = vm_image_path.replace(vm_name, vm_new_name);
+    // the clone's images are named like create and `disk add` name them
+    let vm_image_file_name = PathBuf::from(&vm_image_path)
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .expect("ERROR: VM image path has no file name");
+    let base_image_file_name = vm_image_file_name
+        .strip_prefix(&format!("{}-autovirt-", vm_name))
+        .unwrap_or(&vm_image_file_name);
+    let new_vm_image_path = PathBuf::from(&vm_image_path)
+        .with_file_name(format!("{}-autovirt-{}", vm_new_name, base_image_file_name))
+        .to_string_lossy()
+        .to_string();
     let new_vm_data = vm_data.clone();
     autovirt_config["vms"][vm_new_name] = new_vm_data;
+    autovirt_config["vms"][vm_new_name]["name"] = serde_json::Value::String(vm_new_name.clone());
     autovirt_config["vms"][vm_new_name]["image_path"] = serde_json::Value::String(new_vm_image_path.clone());
 
+    // the data disks get copied too, next to the new main image
+    let data_disks = disk::load_data_disks(vm_name);
+    let new_data_disks: Vec<disk::DataDisk> = data_disks
+        .iter()
+        .map(|data_disk| disk::DataDisk {
+            path: PathBuf::from(&new_vm_image_path)
+                .with_file_name(format!("{}-autovirt-disk-{}.{}", vm_new_name, data_disk.name, data_disk.format))
+                .to_string_lossy()
+                .to_string(),
+            ..data_disk.clone()
+        })
+        .collect();
+    if !data_disks.is_empty() {
+        autovirt_config["vms"][vm_new_name]["data_disks"] =
+            serde_json::to_value(&new_data_disks).expect("ERROR: Failed to jsonify data disks");
+    }
+
+    for path in std::iter::once(&new_vm_image_path).chain(new_data_disks.iter().map(|d| &d.path)) {
+        if PathBuf::from(path).exists() {
+            eprintln!("ERROR: Image for the cloned VM already exists -> {}", path);
+            std::process::exit(1);
+        }
+    }
+
+    // the user-data parts are kept in the VM's data directory, the clone gets
+    // its own copies of them
+    let vm_data_dir = filesystem::vm_data_dir_path(vm_name).expect("ERROR: Could not find VM data directory");
+    let new_vm_data_dir = filesystem::vm_data_dir_path(vm_new_name).expect("ERROR: Could not find VM data directory");
+    let parts: Vec<cloudinit::UserDataPart> = match autovirt_config["vms"][vm_new_name].get("cloud_init_parts") {
+        Some(value) => serde_json::from_value(value.clone()).expect("ERROR: Invalid user-data parts in the config"),
+        None => Vec::new(),
+    };
+    let new_parts: Vec<cloudinit::UserDataPart> = parts
+        .iter()
+        .map(|part| cloudinit::UserDataPart {
+            path: new_vm_data_dir
+                .join("parts")
+                .join(PathBuf::from(&part.path).file_name().expect("ERROR: User-data part path has no file name"))
+                .to_string_lossy()
+                .to_string(),
+            ..part.clone()
+        })
+        .collect();
+    if !parts.is_empty() {
+        autovirt_config["vms"][vm_new_name]["cloud_init_parts"] =
+            serde_json::to_value(&new_parts).expect("ERROR: Failed to jsonify user-data parts");
+    }
+
     println!("INFO:: New VM data -> {}", autovirt_config["vms"][vm_new_name]);
 
     // confirmation  prompt for the user to confirm
@@ -373,10 +568,113 @@ pub fn clone_vm(vm_name: &String, vm_new_name: &String) {
     // copy the vm to the new path
     fs::copy(&vm_image_path, &new_vm_image_path).expect("ERROR: Failed to copy base image to _VMS directory");
     println!("LOG:: VM image cloned to: {:?}", new_vm_image_path);
+    for (data_disk, new_data_disk) in data_disks.iter().zip(&new_data_disks) {
+        fs::copy(&data_disk.path, &new_data_disk.path).expect("ERROR: Failed to copy VM data disk");
+        println!("LOG:: VM data disk cloned to: {:?}", new_data_disk.path);
+    }
+    fs::create_dir_all(new_vm_data_dir.join("parts")).expect("ERROR: Could not create VM data directory");
+    let extra_user_data_path = vm_data_dir.join("user-data-extra");
+    if extra_user_data_path.exists() {
+        fs::copy(&extra_user_data_path, new_vm_data_dir.join("user-data-extra"))
+            .expect("ERROR: Failed to copy VM user-data file");
+    }
+    for (part, new_part) in parts.iter().zip(&new_parts) {
+        fs::copy(&part.path, &new_part.path).expect("ERROR: Failed to copy VM user-data part");
+    }
+    println!("LOG:: VM data directory cloned to: {:?}", new_vm_data_dir);
     println!("LOG:: VM cloned successfully.");
+    inventory::sync_managed_ssh_config_or_warn();
+}
+
+
+/// Adds `key=value` tags to a VM (existing keys get the new value).
+///
+/// ---
+pub fn add_vm_tags(vm_name: &str, tags: &[String]) -> Result<(), Box<dyn Error>> {
+    vm_must_exist(vm_name)?;
+
+    for tag in tags {
+        let (key, value) = selector::parse_tag(tag)?;
+        filesystem::insert_value_into_autovirt_json_object(
+            &format!("vms.{}.tags.{}", vm_name, key),
+            Value::String(value),
+        );
+        println!("LOG:: Tagged VM {} -> {}", vm_name, tag);
+    }
+    Ok(())
+}
+
+/// Removes tags (by key) from a VM.
+///
+/// ---
+pub fn remove_vm_tags(vm_name: &str, keys: &[String]) -> Result<(), Box<dyn Error>> {
+    vm_must_exist(vm_name)?;
+
+    let mut vm_tags = filesystem::get_value_from_autovirt_json(&format!("vms.{}.tags", vm_name))
+        .and_then(|v| v.as_object().cloned())
+        .unwrap_or_default();
+    for key in keys {
+        if vm_tags.remove(key).is_some() {
+            println!("LOG:: Removed tag from VM {} -> {}", vm_name, key);
+        } else {
+            println!("INFO:: VM {} doesn't have the tag -> {}", vm_name, key);
+        }
+    }
+
+    filesystem::insert_value_into_autovirt_json_object(&format!("vms.{}.tags", vm_name), Value::Object(vm_tags));
+    Ok(())
+}
+
+/// Adds a VM to groups.
+///
+/// ---
+pub fn add_vm_groups(vm_name: &str, groups: &[String]) -> Result<(), Box<dyn Error>> {
+    vm_must_exist(vm_name)?;
+
+    let mut vm_groups = get_vm_groups(vm_name);
+    for group in groups {
+        let group = selector::parse_group(group)?;
+        if !vm_groups.contains(&group) {
+            vm_groups.push(group.clone());
+            println!("LOG:: Added VM {} to group -> {}", vm_name, group);
+        }
+    }
 
-    return;
+    filesystem::insert_value_into_autovirt_json_object(&format!("vms.{}.groups", vm_name), serde_json::json!(vm_groups));
+    Ok(())
+}
 
+/// Removes a VM from groups.
+///
+/// ---
+pub fn remove_vm_groups(vm_name: &str, groups: &[String]) -> Result<(), Box<dyn Error>> {
+    vm_must_exist(vm_name)?;
+
+    let mut vm_groups = get_vm_groups(vm_name);
+    vm_groups.retain(|group| {
+        let remove = groups.contains(group);
+        if remove {
+            println!("LOG:: Removed VM {} from group -> {}", vm_name, group);
+        }
+        !remove
+    });
+
+    filesystem::insert_value_into_autovirt_json_object(&format!("vms.{}.groups", vm_name), serde_json::json!(vm_groups));
+    Ok(())
+}
 
+fn get_vm_groups(vm_name: &str) -> Vec<String> {
+    filesystem::get_value_from_autovirt_json(&format!("vms.{}.groups", vm_name))
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default()
 }
 
+fn vm_must_exist(vm_name: &str) -> Result<(), Box<dyn Error>> {
+    if filesystem::get_value_from_autovirt_json(&format!("vms.{}", vm_name)).is_none() {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("VM '{}' not found", vm_name),
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,271 @@
+//! This file contains things for waiting until a VM is ready for something
+//! (`autovirt wait` and `--wait` on create/run) so scripts don't have to guess
+//! how long a VM takes to boot before they ssh into it.
+//!
+//! The conditions are checked in the order they are given and share one
+//! timeout:
+//! - `process` -> the VM's qemu process is running
+//! - `port` -> the host port forwarded to a guest port accepts connections
+//! - `ssh` -> sshd in the VM answers with its banner
+//! - `cloud-init` -> cloud-init is done (from the console log or the agent)
+//!
+//! The exit codes tell the failures apart: 1 for errors (unknown VM, no port
+//! forward etc.), 2 for timeouts, 3 if the VM isn't running (or stops while
+//! waiting) and 4 if cloud-init finished with errors.
+//!
+//! ---
+
+use serde_json::Value;
+use std::fmt;
+use std::fs;
+use std::io::{BufRead, BufReader, Read};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::backend;
+use crate::console;
+use crate::filesystem;
+use crate::guestagent;
+use crate::run;
+use crate::ssh;
+
+/// The things that can be waited for.
+pub const CONDITIONS: [&str; 4] = ["process", "port", "ssh", "cloud-init"];
+
+pub const EXIT_ERROR: i32 = 1;
+pub const EXIT_TIMED_OUT: i32 = 2;
+pub const EXIT_NOT_RUNNING: i32 = 3;
+pub const EXIT_CLOUD_INIT_FAILED: i32 = 4;
+
+/// How often the conditions are checked.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What to wait for (from the wait command or `--wait` on create/run).
+#[derive(Debug, Clone)]
+pub struct WaitOptions {
+    pub conditions: Vec<String>,
+    /// The guest port for the `port` condition.
+    pub guest_port: u16,
+    pub timeout: Duration,
+}
+
+/// Why waiting didn't work out (and the exit code for it).
+#[derive(Debug)]
+pub struct WaitFailure {
+    pub exit_code: i32,
+    pub message: String,
+}
+
+impl WaitFailure {
+    fn new(exit_code: i32, message: String) -> WaitFailure {
+        WaitFailure { exit_code, message }
+    }
+}
+
+impl fmt::Display for WaitFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Gets the host address/port forwarded to a guest port of a VM.
+fn host_forward(vm_name: &str, guest_port: u16) -> Result<(String, u16), WaitFailure> {
+    let ports = filesystem::get_value_from_autovirt_json(&format!("vms.{}.ports", vm_name))
+        .and_then(|v| v.as_str().map(String::from))
+        .unwrap_or_default();
+    ssh::find_host_forward(&ports, guest_port).ok_or_else(|| {
+        WaitFailure::new(
+            EXIT_ERROR,
+            format!("VM '{}' has no port forward to guest port {} (i.e. -> 'hostfwd=tcp::2244-:{}')", vm_name, guest_port, guest_port),
+        )
+    })
+}
+
+/// Checks if something in the VM accepts connections on a forwarded port.
+///
+/// qemu's user networking accepts on the host port as soon as the VM starts
+/// and only closes the connection once the guest refuses it, so a connection
+/// only counts if it stays open (or gets data) for a moment.
+///
+/// ---
+fn forward_accepts(host: &str, port: u16) -> bool {
+    let addrs = match (host, port).to_socket_addrs() {
+        Ok(addrs) => addrs,
+        Err(_) => return false,
+    };
+
+    addrs.into_iter().any(|addr| {
+        let Ok(mut stream) = TcpStream::connect_timeout(&addr, Duration::from_secs(2)) else {
+            return false;
+        };
+        let _ = stream.set_read_timeout(Some(Duration::from_secs(1)));
+        match stream.read(&mut [0u8; 1]) {
+            Ok(read) => read > 0,
+            Err(e) => matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut),
+        }
+    })
+}
+
+/// Checks if sshd answers on a forwarded port (the `SSH-2.0-...` banner).
+fn ssh_banner(host: &str, port: u16) -> Option<String> {
+    let addr = (host, port).to_socket_addrs().ok()?.next()?;
+    let stream = TcpStream::connect_timeout(&addr, Duration::from_secs(2)).ok()?;
+    stream.set_read_timeout(Some(Duration::from_secs(5))).ok()?;
+
+    let mut banner = String::new();
+    BufReader::new(stream.take(255)).read_line(&mut banner).ok()?;
+    banner.starts_with("SSH-").then(|| banner.trim_end().to_string())
+}
+
+/// When the VM's hypervisor was started (the pid file is written at
+/// startup), in whole seconds like the console log times.
+///
+/// ---
+fn vm_started_at(vm_name: &str) -> chrono::DateTime<chrono::FixedOffset> {
+    let started = fs::metadata(backend::for_vm(vm_name).pid_file(vm_name))
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    chrono::DateTime::from_timestamp(started, 0).unwrap_or_default().fixed_offset()
+}
+
+/// Checks if cloud-init is done in the VM, erroring if it finished with
+/// errors.
+///
+/// The `Cloud-init v. .. finished at` line on the console is the quickest
+/// way to tell (when the console goes to the serial port), otherwise the
+/// guest agent is asked for cloud-init's `result.json`.
+///
+/// ---
+fn cloud_init_done(vm_name: &str, booted_at: chrono::DateTime<chrono::FixedOffset>) -> Result<bool, WaitFailure> {
+    if let Some(line) = console::find_log_line(vm_name, booted_at, "Cloud-init v.").filter(|l| l.contains(" finished at ")) {
+        if line.contains("DataSourceNone") {
+            return Err(WaitFailure::new(
+                EXIT_CLOUD_INIT_FAILED,
+                format!("cloud-init in VM '{}' finished without finding its data (DataSourceNone)", vm_name),
+            ));
+        }
+        return Ok(true);
+    }
+
+    // the agent is installed by cloud-init itself so it not answering yet
+    // just means it's not done
+    let result = match guestagent::read_file(vm_name, "/run/cloud-init/result.json", Duration::from_secs(2)) {
+        Ok(Some(result)) => result,
+        Ok(None) | Err(_) => return Ok(false),
+    };
+    let errors: Vec<String> = serde_json::from_slice::<Value>(&result)
+        .ok()
+        .and_then(|r| r.pointer("/v1/errors").and_then(Value::as_array).cloned())
+        .unwrap_or_default()
+        .iter()
+        .map(|e| e.as_str().map(String::from).unwrap_or_else(|| e.to_string()))
+        .collect();
+    if !errors.is_empty() {
+        return Err(WaitFailure::new(
+            EXIT_CLOUD_INIT_FAILED,
+            format!("cloud-init in VM '{}' finished with errors -> {}", vm_name, errors.join("; ")),
+        ));
+    }
+    Ok(true)
+}
+
+/// Checks a condition until it holds, the VM stops or the deadline passes.
+fn poll<F>(vm_name: &str, condition: &str, deadline: Instant, timeout: Duration, mut check: F) -> Result<(), WaitFailure>
+where
+    F: FnMut() -> Result<bool, WaitFailure>,
+{
+    loop {
+        if check()? {
+            return Ok(());
+        }
+        if !run::is_vm_running(vm_name) {
+            return Err(WaitFailure::new(
+                EXIT_NOT_RUNNING,
+                format!("VM '{}' stopped while waiting for {}", vm_name, condition),
+            ));
+        }
+        if Instant::now() >= deadline {
+            return Err(WaitFailure::new(
+                EXIT_TIMED_OUT,
+                format!("Timed out after {}s waiting for {} on VM '{}'", timeout.as_secs(), condition, vm_name),
+            ));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Waits until all the conditions hold for a VM (in order).
+///
+/// The VM has to be running for anything but `process` so that's always
+/// waited for first (a VM that was just started in the background may not
+/// have written its pid file yet).
+///
+/// ---
+pub fn wait_for_vm(vm_name: &str, options: &WaitOptions) -> Result<(), WaitFailure> {
+    if filesystem::get_value_from_autovirt_json(&format!("vms.{}", vm_name)).is_none() {
+        return Err(WaitFailure::new(EXIT_ERROR, format!("VM '{}' not found", vm_name)));
+    }
+
+    let started = Instant::now();
+    let deadline = started + options.timeout;
+
+    let process_wanted = options.conditions.iter().any(|c| c == "process");
+    loop {
+        if run::is_vm_running(vm_name) {
+            break;
+        }
+        // only worth waiting for if it was asked for, otherwise the VM is
+        // just not running
+        if !process_wanted {
+            return Err(WaitFailure::new(EXIT_NOT_RUNNING, format!("VM '{}' is not running", vm_name)));
+        }
+        if Instant::now() >= deadline {
+            return Err(WaitFailure::new(
+                EXIT_TIMED_OUT,
+                format!("Timed out after {}s waiting for VM '{}' to start", options.timeout.as_secs(), vm_name),
+            ));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    let booted_at = vm_started_at(vm_name);
+
+    for condition in &options.conditions {
+        match condition.as_str() {
+            "process" => {}
+            "port" => {
+                let (host, port) = host_forward(vm_name, options.guest_port)?;
+                poll(vm_name, condition, deadline, options.timeout, || Ok(forward_accepts(&host, port)))?;
+            }
+            "ssh" => {
+                let (host, port) = host_forward(vm_name, 22)?;
+                poll(vm_name, condition, deadline, options.timeout, || Ok(ssh_banner(&host, port).is_some()))?;
+            }
+            "cloud-init" => {
+                poll(vm_name, condition, deadline, options.timeout, || cloud_init_done(vm_name, booted_at))?;
+            }
+            other => {
+                return Err(WaitFailure::new(
+                    EXIT_ERROR,
+                    format!("Unknown condition '{}' (one of -> {})", other, CONDITIONS.join(", ")),
+                ));
+            }
+        }
+        println!("LOG:: VM {} -> {} ready ({}s)", vm_name, condition, started.elapsed().as_secs());
+    }
+    Ok(())
+}
+
+/// Waits for a VM and exits with the wait exit code if it doesn't work out
+/// (used by create/run with `--wait`).
+///
+/// ---
+pub fn wait_for_vm_or_exit(vm_name: &str, options: &WaitOptions) {
+    if let Err(e) = wait_for_vm(vm_name, options) {
+        eprintln!("ERROR: {}", e);
+        std::process::exit(e.exit_code);
+    }
+}
@@ -0,0 +1,115 @@
+#!/usr/bin/env python3
+# A stand-in for qemu, cloud-hypervisor and firecracker in the backend tests.
+#
+# It records its args in $HOME/fake-hypervisor.argv (json), makes the sockets
+# the real ones would (serial console, QMP, http api), writes the qemu pid
+# file and daemonizes on -daemonize. QMP commands and api requests are
+# appended to $HOME/fake-hypervisor.requests, it exits on the ones that shut
+# a VM down (system_powerdown/quit, the power button, SendCtrlAltDel).
+
+import json
+import os
+import select
+import socket
+import sys
+
+HOME = os.environ["HOME"]
+args = sys.argv[1:]
+
+with open(os.path.join(HOME, "fake-hypervisor.argv"), "w") as f:
+    json.dump(args, f)
+
+
+def after(flag):
+    return args[args.index(flag) + 1] if flag in args else None
+
+
+def record(request):
+    with open(os.path.join(HOME, "fake-hypervisor.requests"), "a") as f:
+        f.write(request + "\n")
+
+
+def listen(path):
+    if os.path.exists(path):
+        os.remove(path)
+    server = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+    server.bind(path)
+    server.listen(4)
+    return server
+
+
+sockets = {}
+for i, arg in enumerate(args[:-1]):
+    value = args[i + 1]
+    if arg == "-chardev" and "id=serial0" in value:
+        path = [p for p in value.split(",") if p.startswith("path=")][0][len("path="):]
+        sockets[listen(path)] = "serial"
+    elif arg == "-qmp":
+        sockets[listen(value[len("unix:"):].split(",")[0])] = "qmp"
+    elif arg == "--api-socket":
+        sockets[listen(value[len("path="):])] = "api"
+    elif arg == "--api-sock":
+        sockets[listen(value)] = "api"
+    elif arg == "--serial" and value.startswith("socket="):
+        sockets[listen(value[len("socket="):])] = "serial"
+
+if "-daemonize" in args:
+    ready_read, ready_write = os.pipe()
+    if os.fork() > 0:
+        # the parent goes away once the VM is up, like qemu's
+        os.read(ready_read, 1)
+        sys.exit(0)
+    os.setsid()
+    devnull = os.open(os.devnull, os.O_RDWR)
+    for fd in (0, 1, 2):
+        os.dup2(devnull, fd)
+
+if after("-pidfile"):
+    with open(after("-pidfile"), "w") as f:
+        f.write(str(os.getpid()))
+
+if "-daemonize" in args:
+    os.write(ready_write, b"1")
+
+
+def serve_qmp(conn):
+    conn.sendall(b'{"QMP": {"version": {}, "capabilities": []}}\n')
+    for line in conn.makefile("rb"):
+        command = json.loads(line)["execute"]
+        record("qmp " + command)
+        conn.sendall(b'{"return": {}}\n')
+        if command in ("system_powerdown", "quit"):
+            sys.exit(0)
+
+
+def serve_api(conn):
+    request = b""
+    while b"\r\n\r\n" not in request:
+        chunk = conn.recv(4096)
+        if not chunk:
+            return
+        request += chunk
+    head, body = request.split(b"\r\n\r\n", 1)
+    length = [int(l.split(b":")[1]) for l in head.split(b"\r\n") if l.lower().startswith(b"content-length")]
+    while length and len(body) < length[0]:
+        body += conn.recv(4096)
+    method, path = head.split(b" ")[:2]
+    record("{} {} {}".format(method.decode(), path.decode(), body.decode()).strip())
+    conn.sendall(b"HTTP/1.1 204 No Content\r\n\r\n")
+    conn.close()
+    if path in (b"/api/v1/vm.power-button", b"/actions"):
+        sys.exit(0)
+
+
+consoles = []
+while True:
+    readable, _, _ = select.select(list(sockets), [], [])
+    for server in readable:
+        conn, _ = server.accept()
+        kind = sockets[server]
+        if kind == "serial":
+            consoles.append(conn)
+        elif kind == "qmp":
+            serve_qmp(conn)
+        else:
+            serve_api(conn)